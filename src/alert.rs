@@ -0,0 +1,89 @@
+use std::net::{IpAddr, SocketAddr};
+
+use tokio::sync::broadcast;
+
+/// Alerts kept for subscribers that fall behind, older ones are dropped
+const CAPACITY: usize = 1024;
+
+/// Something that happened to a torrent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Alert {
+    pub info_hash: [u8; 20],
+    pub event: Event,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    TorrentAdded,
+    TorrentPaused,
+    TorrentResumed,
+    TorrentRemoved,
+    /// Every wanted piece is verified
+    TorrentFinished,
+    /// The torrent stopped on its own
+    TorrentFailed {
+        error: String,
+    },
+    PieceVerified {
+        piece: usize,
+    },
+    /// A piece did not match its hash and is downloaded again
+    HashFailed {
+        piece: usize,
+    },
+    PeerConnected {
+        addr: SocketAddr,
+    },
+    /// A peer connection ended, with the error that ended it
+    PeerDisconnected {
+        addr: SocketAddr,
+        error: Option<String>,
+    },
+    /// A peer sent corrupt data, it is refused from now on
+    PeerBanned {
+        ip: IpAddr,
+    },
+    TrackerReply {
+        tracker: String,
+        peers: usize,
+    },
+    TrackerError {
+        tracker: String,
+        error: String,
+    },
+    /// Reading or writing the data of the torrent failed
+    StorageError {
+        error: String,
+    },
+}
+
+/// Where alerts go. Clones post to the same subscribers, so torrents sharing
+/// one report to a single stream.
+#[derive(Clone)]
+pub struct Alerts {
+    sender: broadcast::Sender<Alert>,
+}
+
+impl Default for Alerts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Alerts {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        Self { sender }
+    }
+
+    /// The alerts posted from now on. A subscriber that falls behind misses
+    /// the oldest ones and is told how many with a lag error.
+    pub fn subscribe(&self) -> broadcast::Receiver<Alert> {
+        self.sender.subscribe()
+    }
+
+    /// Sends an alert to the current subscribers, if any
+    pub fn post(&self, info_hash: [u8; 20], event: Event) {
+        let _ = self.sender.send(Alert { info_hash, event });
+    }
+}
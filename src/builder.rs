@@ -0,0 +1,414 @@
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use sha1::{Digest, Sha1};
+
+use crate::torrent::{File, Info, Pieces, Torrent};
+
+/// Smallest piece length picked automatically, also the size of a block
+pub const MIN_PIECE_LENGTH: usize = 16 * 1024;
+/// Biggest piece length picked automatically
+pub const MAX_PIECE_LENGTH: usize = 16 * 1024 * 1024;
+/// Amount of pieces the automatic piece length aims for
+const TARGET_PIECES: usize = 1500;
+
+/// Builds a [`Torrent`] from a file or a directory in the local file system.
+///
+/// Pieces are hashed in parallel, one chunk of consecutive pieces per thread.
+#[derive(Debug, Clone)]
+pub struct TorrentBuilder {
+    path: PathBuf,
+    name: Option<String>,
+    piece_length: Option<usize>,
+    trackers: Vec<Vec<String>>,
+    comment: Option<String>,
+    created_by: Option<String>,
+    creation_date: Option<i64>,
+    private: bool,
+    source: Option<String>,
+    web_seeds: Vec<String>,
+    threads: Option<usize>,
+}
+
+impl TorrentBuilder {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let creation_date = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .ok();
+        Self {
+            path: path.into(),
+            name: None,
+            piece_length: None,
+            trackers: Vec::new(),
+            comment: None,
+            created_by: Some(crate::CLIENT_VERSION.to_string()),
+            creation_date,
+            private: false,
+            source: None,
+            web_seeds: Vec::new(),
+            threads: None,
+        }
+    }
+
+    /// Overrides the name of the torrent, defaults to the file or directory name
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Fixed piece length, it has to be a power of two of at least 16KiB.
+    /// When not set the length is chosen from the size of the content.
+    pub fn piece_length(mut self, piece_length: usize) -> Self {
+        self.piece_length = Some(piece_length);
+        self
+    }
+
+    /// Adds a tracker to a new tier
+    pub fn tracker(mut self, url: impl Into<String>) -> Self {
+        self.trackers.push(vec![url.into()]);
+        self
+    }
+
+    /// Adds a tier of trackers
+    pub fn tier(mut self, urls: Vec<String>) -> Self {
+        if !urls.is_empty() {
+            self.trackers.push(urls);
+        }
+        self
+    }
+
+    pub fn comment(mut self, comment: impl Into<String>) -> Self {
+        self.comment = Some(comment.into());
+        self
+    }
+
+    pub fn created_by(mut self, created_by: Option<String>) -> Self {
+        self.created_by = created_by;
+        self
+    }
+
+    /// Creation date in seconds since the unix epoch, defaults to now
+    pub fn creation_date(mut self, creation_date: Option<i64>) -> Self {
+        self.creation_date = creation_date;
+        self
+    }
+
+    pub fn private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    /// Source tag, it changes the info hash so cross seeded torrents differ
+    pub fn source(mut self, source: impl Into<String>) -> Self {
+        self.source = Some(source.into());
+        self
+    }
+
+    pub fn web_seed(mut self, url: impl Into<String>) -> Self {
+        self.web_seeds.push(url.into());
+        self
+    }
+
+    /// Number of hashing threads, defaults to the available parallelism
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = Some(threads.max(1));
+        self
+    }
+
+    pub fn build(self) -> Result<Torrent> {
+        let metadata = fs::metadata(&self.path)
+            .with_context(|| format!("reading metadata of {}", self.path.display()))?;
+        let name = match self.name {
+            Some(name) => name,
+            None => self
+                .path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .context("path has no valid file name")?
+                .to_string(),
+        };
+
+        let (files, length, torrent_files) = if metadata.is_dir() {
+            let mut files = Vec::new();
+            collect_files(&self.path, &mut files)?;
+            files.sort();
+            let mut entries = Vec::with_capacity(files.len());
+            let mut torrent_files = Vec::with_capacity(files.len());
+            for path in files {
+                let length = fs::metadata(&path)?.len() as usize;
+                let relative = path.strip_prefix(&self.path)?;
+                let components = relative
+                    .components()
+                    .map(|c| {
+                        c.as_os_str()
+                            .to_str()
+                            .map(String::from)
+                            .context("file path is not valid utf-8")
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                torrent_files.push(File {
+                    length,
+                    path: components,
+                    extra: Default::default(),
+                });
+                entries.push((path, length));
+            }
+            if torrent_files.is_empty() {
+                bail!("directory {} has no files", self.path.display());
+            }
+            (entries, None, Some(torrent_files))
+        } else {
+            let length = metadata.len() as usize;
+            (vec![(self.path.clone(), length)], Some(length), None)
+        };
+
+        let total: usize = files.iter().map(|(_, l)| l).sum();
+        if total == 0 {
+            bail!("cannot create a torrent without content");
+        }
+        let plength = match self.piece_length {
+            Some(plength) => {
+                if !plength.is_power_of_two() || plength < MIN_PIECE_LENGTH {
+                    bail!("piece length must be a power of two of at least {MIN_PIECE_LENGTH}");
+                }
+                plength
+            }
+            None => auto_piece_length(total),
+        };
+        let threads = self
+            .threads
+            .or_else(|| thread::available_parallelism().ok().map(|n| n.get()))
+            .unwrap_or(1);
+        let pieces = hash_pieces(&files, plength, threads)?;
+
+        let announce = self.trackers.first().and_then(|t| t.first()).cloned();
+        let announce_list = (self.trackers.iter().flatten().count() > 1).then_some(self.trackers);
+
+        Ok(Torrent {
+            announce,
+            announce_list,
+            comment: self.comment,
+            created_by: self.created_by,
+            creation_date: self.creation_date,
+            url_list: self.web_seeds,
+            info: Info {
+                name,
+                plength,
+                pieces: Pieces(pieces),
+                length,
+                files: torrent_files,
+                private: self.private.then_some(1),
+                source: self.source,
+                extra: Default::default(),
+            },
+        })
+    }
+}
+
+/// Picks a power of two piece length for `total` bytes of content, aiming for
+/// around 1500 pieces between 16KiB and 16MiB.
+pub fn auto_piece_length(total: usize) -> usize {
+    let mut plength = MIN_PIECE_LENGTH;
+    while total.div_ceil(plength) > TARGET_PIECES && plength < MAX_PIECE_LENGTH {
+        plength *= 2;
+    }
+    plength
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir).with_context(|| format!("reading {}", dir.display()))? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            collect_files(&entry.path(), files)?;
+        } else if file_type.is_file() {
+            files.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+/// Hashes the concatenation of `files` in pieces of `plength` bytes, each thread
+/// hashes a contiguous range of pieces so reads stay sequential.
+fn hash_pieces(
+    files: &[(PathBuf, usize)],
+    plength: usize,
+    threads: usize,
+) -> Result<Vec<[u8; 20]>> {
+    let total: usize = files.iter().map(|(_, l)| l).sum();
+    let count = total.div_ceil(plength);
+    let per_thread = count.div_ceil(threads);
+
+    let chunks = thread::scope(|s| {
+        let handles = (0..count)
+            .step_by(per_thread.max(1))
+            .map(|start| {
+                let end = (start + per_thread).min(count);
+                s.spawn(move || hash_range(files, plength, total, start..end))
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|h| h.join().expect("hashing thread panicked"))
+            .collect::<Result<Vec<_>>>()
+    })?;
+    Ok(chunks.concat())
+}
+
+fn hash_range(
+    files: &[(PathBuf, usize)],
+    plength: usize,
+    total: usize,
+    pieces: std::ops::Range<usize>,
+) -> Result<Vec<[u8; 20]>> {
+    let mut hashes = Vec::with_capacity(pieces.len());
+    let mut buffer = vec![0; plength];
+    let mut open: Option<(usize, fs::File)> = None;
+
+    for piece in pieces {
+        let start = piece * plength;
+        let length = plength.min(total - start);
+        let mut filled = 0;
+        let mut file_start = 0;
+        for (index, (path, file_length)) in files.iter().enumerate() {
+            let file_end = file_start + file_length;
+            let offset = start + filled;
+            if filled < length && offset < file_end {
+                let n = (file_end - offset).min(length - filled);
+                let file = match &mut open {
+                    Some((i, file)) if *i == index => file,
+                    _ => {
+                        let file = fs::File::open(path)
+                            .with_context(|| format!("opening {}", path.display()))?;
+                        &mut open.insert((index, file)).1
+                    }
+                };
+                file.seek(SeekFrom::Start((offset - file_start) as u64))?;
+                file.read_exact(&mut buffer[filled..filled + n])
+                    .with_context(|| format!("reading {}", path.display()))?;
+                filled += n;
+            }
+            file_start = file_end;
+        }
+        hashes.push(Sha1::digest(&buffer[..length]).into());
+    }
+    Ok(hashes)
+}
+
+#[test]
+fn auto_piece_length_bounds() {
+    assert_eq!(auto_piece_length(1), MIN_PIECE_LENGTH);
+    assert_eq!(auto_piece_length(1500 * MIN_PIECE_LENGTH), MIN_PIECE_LENGTH);
+    assert_eq!(
+        auto_piece_length(1500 * MIN_PIECE_LENGTH + 1),
+        2 * MIN_PIECE_LENGTH
+    );
+    assert_eq!(auto_piece_length(usize::MAX / 2), MAX_PIECE_LENGTH);
+}
+
+#[test]
+fn build_single_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("data.bin");
+    let content: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+    fs::write(&path, &content).unwrap();
+
+    let torrent = TorrentBuilder::new(&path)
+        .piece_length(MIN_PIECE_LENGTH)
+        .tracker("http://tracker.example/announce")
+        .creation_date(Some(1))
+        .threads(3)
+        .build()
+        .unwrap();
+
+    assert_eq!(torrent.info.name, "data.bin");
+    assert_eq!(torrent.info.length(), content.len());
+    assert!(torrent.announce_list.is_none());
+    let expected = content
+        .chunks(MIN_PIECE_LENGTH)
+        .map(|c| Sha1::digest(c).into())
+        .collect::<Vec<[u8; 20]>>();
+    assert_eq!(torrent.info.pieces.0, expected);
+
+    let bytes = torrent.to_bytes().unwrap();
+    let parsed: Torrent = serde_bencode::from_bytes(&bytes).unwrap();
+    assert_eq!(parsed.to_bytes().unwrap(), bytes);
+    assert_eq!(parsed.info_hash().unwrap(), torrent.info_hash().unwrap());
+}
+
+#[test]
+fn build_multi_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("release");
+    fs::create_dir_all(root.join("docs")).unwrap();
+    fs::write(root.join("b.txt"), vec![1; 20_000]).unwrap();
+    fs::write(root.join("a.txt"), vec![2; 10_000]).unwrap();
+    fs::write(root.join("docs").join("c.txt"), vec![3; 5_000]).unwrap();
+
+    let torrent = TorrentBuilder::new(&root)
+        .piece_length(MIN_PIECE_LENGTH)
+        .tracker("http://one.example/announce")
+        .tier(vec![
+            "http://two.example/announce".to_string(),
+            "http://three.example/announce".to_string(),
+        ])
+        .comment("nightly")
+        .private(true)
+        .source("ci")
+        .web_seed("http://mirror.example/")
+        .build()
+        .unwrap();
+
+    let files = torrent.info.files();
+    assert_eq!(
+        files,
+        vec![
+            (PathBuf::from("release/a.txt"), 10_000),
+            (PathBuf::from("release/b.txt"), 20_000),
+            (PathBuf::from("release/docs/c.txt"), 5_000),
+        ]
+    );
+    let mut content = vec![2; 10_000];
+    content.extend(vec![1; 20_000]);
+    content.extend(vec![3; 5_000]);
+    let expected = content
+        .chunks(MIN_PIECE_LENGTH)
+        .map(|c| Sha1::digest(c).into())
+        .collect::<Vec<[u8; 20]>>();
+    assert_eq!(torrent.info.pieces.0, expected);
+    assert_eq!(torrent.trackers().len(), 2);
+
+    let bytes = torrent.to_bytes().unwrap();
+    let parsed: Torrent = serde_bencode::from_bytes(&bytes).unwrap();
+    assert!(parsed.info.is_private());
+    assert_eq!(parsed.info.source.as_deref(), Some("ci"));
+    assert_eq!(parsed.url_list, vec!["http://mirror.example/".to_string()]);
+    assert_eq!(parsed.to_bytes().unwrap(), bytes);
+}
+
+#[test]
+fn canonical_key_order() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("a");
+    fs::write(&path, b"hello").unwrap();
+    let torrent = TorrentBuilder::new(&path)
+        .tracker("http://t/a")
+        .comment("c")
+        .creation_date(Some(7))
+        .build()
+        .unwrap();
+    let bytes = torrent.to_bytes().unwrap();
+    let text = String::from_utf8_lossy(&bytes);
+    let announce = text.find("8:announce").unwrap();
+    let comment = text.find("7:comment").unwrap();
+    let created = text.find("10:created by").unwrap();
+    let date = text.find("13:creation date").unwrap();
+    let info = text.find("4:info").unwrap();
+    assert!(announce < comment && comment < created && created < date && date < info);
+    assert!(text.contains("d6:lengthi5e4:name1:a12:piece lengthi16384e6:pieces20:"));
+}
@@ -0,0 +1,682 @@
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Deserializer};
+use serde_json::{Map, Value};
+
+use crate::mse::EncryptionPolicy;
+use crate::session::SessionConfig;
+use crate::{connections, dht, peer, picker, tracker, worker};
+
+/// Settings of the command line, read from a TOML file. Every key is
+/// optional, missing ones keep their default:
+///
+/// ```toml
+/// listen = "0.0.0.0:6881"
+/// download_dir = "~/Downloads"
+/// encryption = "forced"
+/// pex = false
+///
+/// [limits]
+/// download = 2048
+///
+/// [tracker]
+/// timeout = 30
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Address peers connect to, its port is the one announced
+    pub listen: SocketAddr,
+    /// Where the daemon writes the torrents it is given without a path
+    pub download_dir: PathBuf,
+    #[serde(deserialize_with = "from_str")]
+    pub encryption: EncryptionPolicy,
+    pub dht: bool,
+    /// Nodes to join the DHT through, as host:port
+    pub dht_bootstrap: Vec<String>,
+    /// Look for peers on the local network
+    pub lsd: bool,
+    /// Exchange peer lists with peers
+    pub pex: bool,
+    /// Reach peers over uTP first and accept uTP connections
+    pub utp: bool,
+    /// Peer id sent in handshakes and announces, 20 bytes
+    pub peer_id: String,
+    /// Size of the blocks pieces are requested in, in bytes, at most 16 KiB
+    pub block_size: usize,
+    pub limits: LimitsConfig,
+    pub tracker: TrackerConfig,
+}
+
+/// Rates in KiB/s, zero for none, and connection caps
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Download rate of every torrent together
+    pub download: u64,
+    /// Upload rate of every torrent together
+    pub upload: u64,
+    /// Download rate of each peer
+    pub peer_download: u64,
+    /// Upload rate of each peer
+    pub peer_upload: u64,
+    /// Most peer connections of every torrent together
+    pub max_connections: usize,
+    /// Most connection attempts in flight at once
+    pub max_half_open: usize,
+    /// Most peer connections of each torrent
+    pub max_peers: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrackerConfig {
+    /// Seconds a tracker has to answer an announce
+    pub timeout: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: SocketAddr::from(([0, 0, 0, 0], 6881)),
+            download_dir: PathBuf::from("."),
+            encryption: EncryptionPolicy::Enabled,
+            dht: true,
+            dht_bootstrap: dht::BOOTSTRAP_NODES.iter().map(|n| n.to_string()).collect(),
+            lsd: true,
+            pex: true,
+            utp: true,
+            peer_id: String::from_utf8_lossy(&peer::DEFAULT_PEER_ID).into_owned(),
+            block_size: picker::BLOCK_SIZE,
+            limits: LimitsConfig::default(),
+            tracker: TrackerConfig::default(),
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            download: 0,
+            upload: 0,
+            peer_download: 0,
+            peer_upload: 0,
+            max_connections: connections::MAX_CONNECTIONS,
+            max_half_open: connections::MAX_HALF_OPEN,
+            max_peers: worker::MAX_PEERS,
+        }
+    }
+}
+
+impl Default for TrackerConfig {
+    fn default() -> Self {
+        Self {
+            timeout: tracker::TIMEOUT.as_secs(),
+        }
+    }
+}
+
+impl Config {
+    /// `$XDG_CONFIG_HOME/oxitorrent/config.toml`, or under `~/.config`
+    pub fn default_path() -> Option<PathBuf> {
+        let config_home = std::env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+        Some(config_home.join("oxitorrent").join("config.toml"))
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let text =
+            std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("invalid config {}", path.display()))
+    }
+
+    /// Reads a config from TOML, a leading `~/` in `download_dir` stands for
+    /// the home directory
+    pub fn parse(text: &str) -> Result<Self> {
+        let table = Parser::new(text).document()?;
+        let mut config: Self = serde_json::from_value(Value::Object(table))?;
+        if config.peer_id.len() != 20 {
+            bail!("peer_id must be 20 bytes long");
+        }
+        if !(1..=picker::MAX_BLOCK_SIZE).contains(&config.block_size) {
+            bail!(
+                "block_size must be between 1 and {}",
+                picker::MAX_BLOCK_SIZE
+            );
+        }
+        let limits = &config.limits;
+        for (name, kib) in [
+            ("download", limits.download),
+            ("upload", limits.upload),
+            ("peer_download", limits.peer_download),
+            ("peer_upload", limits.peer_upload),
+        ] {
+            if kib.checked_mul(1024).is_none() {
+                bail!("limits.{name} of {kib} KiB/s is too large");
+            }
+        }
+        if let Ok(rest) = config.download_dir.strip_prefix("~") {
+            if let Some(home) = std::env::var_os("HOME") {
+                config.download_dir = PathBuf::from(home).join(rest);
+            }
+        }
+        Ok(config)
+    }
+
+    /// The config as TOML that [`Config::parse`] reads back
+    pub fn to_toml(&self) -> String {
+        let limits = &self.limits;
+        let mut out = String::new();
+        let _ = writeln!(out, "listen = {}", quote(&self.listen.to_string()));
+        let _ = writeln!(
+            out,
+            "download_dir = {}",
+            quote(&self.download_dir.to_string_lossy())
+        );
+        let _ = writeln!(out, "encryption = {}", quote(&self.encryption.to_string()));
+        let _ = writeln!(out, "dht = {}", self.dht);
+        let nodes: Vec<String> = self.dht_bootstrap.iter().map(|n| quote(n)).collect();
+        let _ = writeln!(out, "dht_bootstrap = [{}]", nodes.join(", "));
+        let _ = writeln!(out, "lsd = {}", self.lsd);
+        let _ = writeln!(out, "pex = {}", self.pex);
+        let _ = writeln!(out, "utp = {}", self.utp);
+        let _ = writeln!(out, "peer_id = {}", quote(&self.peer_id));
+        let _ = writeln!(out, "block_size = {}", self.block_size);
+        let _ = writeln!(out, "\n[limits]");
+        let _ = writeln!(out, "download = {}", limits.download);
+        let _ = writeln!(out, "upload = {}", limits.upload);
+        let _ = writeln!(out, "peer_download = {}", limits.peer_download);
+        let _ = writeln!(out, "peer_upload = {}", limits.peer_upload);
+        let _ = writeln!(out, "max_connections = {}", limits.max_connections);
+        let _ = writeln!(out, "max_half_open = {}", limits.max_half_open);
+        let _ = writeln!(out, "max_peers = {}", limits.max_peers);
+        let _ = writeln!(out, "\n[tracker]");
+        let _ = writeln!(out, "timeout = {}", self.tracker.timeout);
+        out
+    }
+
+    pub fn tracker_timeout(&self) -> Duration {
+        Duration::from_secs(self.tracker.timeout)
+    }
+
+    /// The peer id as sent on the wire
+    pub fn peer_id(&self) -> [u8; 20] {
+        self.peer_id
+            .as_bytes()
+            .try_into()
+            .unwrap_or(peer::DEFAULT_PEER_ID)
+    }
+
+    /// A rate of the config in bytes per second, None for no limit. Rates
+    /// too large to count in bytes are no limit either, [`Config::parse`]
+    /// refuses them.
+    pub fn rate(kib: u64) -> Option<u64> {
+        kib.checked_mul(1024).filter(|&bytes| bytes > 0)
+    }
+
+    /// The session the config describes, without a DHT state file
+    pub fn session_config(&self) -> SessionConfig {
+        SessionConfig {
+            listen: self.listen,
+            dht_bootstrap: self.dht.then(|| self.dht_bootstrap.clone()),
+            dht_state: None,
+            lsd: self.lsd,
+            utp: self.utp,
+            encryption: self.encryption,
+            download_limit: Self::rate(self.limits.download),
+            upload_limit: Self::rate(self.limits.upload),
+            max_connections: self.limits.max_connections,
+            max_half_open: self.limits.max_half_open,
+            peer_download_limit: Self::rate(self.limits.peer_download),
+            peer_upload_limit: Self::rate(self.limits.peer_upload),
+            max_peers: self.limits.max_peers,
+            pex: self.pex,
+            tracker_timeout: self.tracker_timeout(),
+            block_size: self.block_size,
+        }
+    }
+}
+
+fn from_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr<Err = anyhow::Error>,
+{
+    let s = String::deserialize(deserializer)?;
+    s.parse().map_err(serde::de::Error::custom)
+}
+
+/// A TOML basic string, whose escapes are the JSON ones
+fn quote(s: &str) -> String {
+    serde_json::to_string(s).unwrap_or_default()
+}
+
+/// Reads the part of TOML configs need: tables, dotted keys, strings,
+/// integers, floats, booleans, arrays and inline tables. Dates, multi-line
+/// strings and arrays of tables are refused.
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn new(text: &str) -> Self {
+        Self {
+            chars: text.chars().collect(),
+            pos: 0,
+        }
+    }
+
+    fn document(mut self) -> Result<Map<String, Value>> {
+        let mut root = Map::new();
+        let mut table = Vec::new();
+        let mut defined: Vec<Vec<String>> = Vec::new();
+        loop {
+            self.skip_blank_lines();
+            match self.peek() {
+                None => return Ok(root),
+                Some('[') => {
+                    self.pos += 1;
+                    if self.peek() == Some('[') {
+                        return Err(self.error("arrays of tables are not supported"));
+                    }
+                    self.skip_spaces();
+                    table = self.key()?;
+                    self.expect(']')?;
+                    self.end_of_line()?;
+                    if defined.contains(&table) {
+                        return Err(self.error(&format!("table {} defined twice", table.join("."))));
+                    }
+                    defined.push(table.clone());
+                    self.table(&mut root, &table)?;
+                }
+                Some(_) => {
+                    let (key, value) = self.key_value()?;
+                    self.end_of_line()?;
+                    let path: Vec<String> = table.iter().chain(&key).cloned().collect();
+                    self.insert(&mut root, &path, value)?;
+                }
+            }
+        }
+    }
+
+    fn key_value(&mut self) -> Result<(Vec<String>, Value)> {
+        let key = self.key()?;
+        self.expect('=')?;
+        self.skip_spaces();
+        let value = self.value()?;
+        Ok((key, value))
+    }
+
+    /// A dotted key, followed by the spaces after it
+    fn key(&mut self) -> Result<Vec<String>> {
+        let mut key = Vec::new();
+        loop {
+            let part = match self.peek() {
+                Some('"') => self.basic_string()?,
+                Some('\'') => self.literal_string()?,
+                _ => {
+                    let start = self.pos;
+                    while self
+                        .peek()
+                        .is_some_and(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+                    {
+                        self.pos += 1;
+                    }
+                    if start == self.pos {
+                        return Err(self.error("expected a key"));
+                    }
+                    self.chars[start..self.pos].iter().collect()
+                }
+            };
+            key.push(part);
+            self.skip_spaces();
+            if self.peek() != Some('.') {
+                return Ok(key);
+            }
+            self.pos += 1;
+            self.skip_spaces();
+        }
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        match self.peek() {
+            Some('"') => Ok(Value::String(self.basic_string()?)),
+            Some('\'') => Ok(Value::String(self.literal_string()?)),
+            Some('[') => self.array(),
+            Some('{') => self.inline_table(),
+            Some(_) => self.scalar(),
+            None => Err(self.error("expected a value")),
+        }
+    }
+
+    fn basic_string(&mut self) -> Result<String> {
+        if self.starts_with("\"\"\"") {
+            return Err(self.error("multi-line strings are not supported"));
+        }
+        self.pos += 1;
+        let mut s = String::new();
+        loop {
+            let c = self
+                .next()
+                .ok_or_else(|| self.error("unterminated string"))?;
+            match c {
+                '"' => return Ok(s),
+                '\n' => return Err(self.error("unterminated string")),
+                '\\' => {
+                    let escaped = self
+                        .next()
+                        .ok_or_else(|| self.error("unterminated string"))?;
+                    s.push(match escaped {
+                        '"' => '"',
+                        '\\' => '\\',
+                        'b' => '\u{8}',
+                        'f' => '\u{c}',
+                        'n' => '\n',
+                        'r' => '\r',
+                        't' => '\t',
+                        'u' | 'U' => {
+                            let digits = if escaped == 'u' { 4 } else { 8 };
+                            let hex: String = (0..digits).filter_map(|_| self.next()).collect();
+                            u32::from_str_radix(&hex, 16)
+                                .ok()
+                                .and_then(char::from_u32)
+                                .ok_or_else(|| self.error("invalid unicode escape"))?
+                        }
+                        _ => return Err(self.error(&format!("invalid escape \\{escaped}"))),
+                    });
+                }
+                c => s.push(c),
+            }
+        }
+    }
+
+    fn literal_string(&mut self) -> Result<String> {
+        if self.starts_with("'''") {
+            return Err(self.error("multi-line strings are not supported"));
+        }
+        self.pos += 1;
+        let mut s = String::new();
+        loop {
+            match self.next() {
+                Some('\'') => return Ok(s),
+                Some('\n') | None => return Err(self.error("unterminated string")),
+                Some(c) => s.push(c),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Value> {
+        self.pos += 1;
+        let mut values = Vec::new();
+        loop {
+            self.skip_blank_lines();
+            if self.peek() == Some(']') {
+                self.pos += 1;
+                return Ok(Value::Array(values));
+            }
+            values.push(self.value()?);
+            self.skip_blank_lines();
+            match self.next() {
+                Some(',') => {}
+                Some(']') => return Ok(Value::Array(values)),
+                _ => return Err(self.error("expected , or ] in array")),
+            }
+        }
+    }
+
+    fn inline_table(&mut self) -> Result<Value> {
+        self.pos += 1;
+        let mut table = Map::new();
+        self.skip_spaces();
+        if self.peek() == Some('}') {
+            self.pos += 1;
+            return Ok(Value::Object(table));
+        }
+        loop {
+            self.skip_spaces();
+            let (key, value) = self.key_value()?;
+            self.insert(&mut table, &key, value)?;
+            self.skip_spaces();
+            match self.next() {
+                Some(',') => {}
+                Some('}') => return Ok(Value::Object(table)),
+                _ => return Err(self.error("expected , or } in inline table")),
+            }
+        }
+    }
+
+    /// A boolean, an integer or a float
+    fn scalar(&mut self) -> Result<Value> {
+        let start = self.pos;
+        while self
+            .peek()
+            .is_some_and(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '+' | '-' | '.'))
+        {
+            self.pos += 1;
+        }
+        let word: String = self.chars[start..self.pos].iter().collect();
+        match word.as_str() {
+            "true" => return Ok(Value::Bool(true)),
+            "false" => return Ok(Value::Bool(false)),
+            _ => {}
+        }
+        let digits = word.replace('_', "");
+        if let Ok(n) = digits.parse::<i64>() {
+            return Ok(Value::from(n));
+        }
+        let is_float = digits.contains(['.', 'e', 'E']) && !digits.contains("inf");
+        match digits.parse::<f64>() {
+            Ok(n) if is_float && n.is_finite() => Ok(Value::from(n)),
+            _ => Err(self.error(&format!("invalid value {word:?}"))),
+        }
+    }
+
+    /// The table at `path` of `root`, created if missing
+    fn table<'a>(
+        &self,
+        root: &'a mut Map<String, Value>,
+        path: &[String],
+    ) -> Result<&'a mut Map<String, Value>> {
+        let mut table = root;
+        for key in path {
+            let value = table
+                .entry(key.clone())
+                .or_insert_with(|| Value::Object(Map::new()));
+            table = value
+                .as_object_mut()
+                .ok_or_else(|| self.error(&format!("{key} is not a table")))?;
+        }
+        Ok(table)
+    }
+
+    fn insert(&self, root: &mut Map<String, Value>, path: &[String], value: Value) -> Result<()> {
+        let (key, tables) = path.split_last().context("empty key")?;
+        let table = self.table(root, tables)?;
+        if table.contains_key(key) {
+            return Err(self.error(&format!("{} defined twice", path.join("."))));
+        }
+        table.insert(key.clone(), value);
+        Ok(())
+    }
+
+    /// Spaces and a comment, then a new line or the end of the text
+    fn end_of_line(&mut self) -> Result<()> {
+        self.skip_spaces();
+        if self.peek() == Some('#') {
+            while self.peek().is_some_and(|c| c != '\n') {
+                self.pos += 1;
+            }
+        }
+        match self.next() {
+            None | Some('\n') => Ok(()),
+            Some('\r') if self.next() == Some('\n') => Ok(()),
+            _ => Err(self.error("expected the end of the line")),
+        }
+    }
+
+    /// Spaces, new lines and comments
+    fn skip_blank_lines(&mut self) {
+        loop {
+            self.skip_spaces();
+            match self.peek() {
+                Some('\n' | '\r') => self.pos += 1,
+                Some('#') => {
+                    while self.peek().is_some_and(|c| c != '\n') {
+                        self.pos += 1;
+                    }
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn skip_spaces(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t')) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<()> {
+        self.skip_spaces();
+        if self.next() != Some(expected) {
+            return Err(self.error(&format!("expected {expected}")));
+        }
+        Ok(())
+    }
+
+    fn starts_with(&self, s: &str) -> bool {
+        s.chars()
+            .enumerate()
+            .all(|(i, c)| self.chars.get(self.pos + i) == Some(&c))
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        Some(c)
+    }
+
+    fn error(&self, message: &str) -> anyhow::Error {
+        let end = self.pos.min(self.chars.len());
+        let line = self.chars[..end].iter().filter(|&&c| c == '\n').count() + 1;
+        anyhow!("line {line}: {message}")
+    }
+}
+
+#[test]
+fn parse_the_toml_subset() {
+    let text = r#"
+# comment
+title = "a \"quoted\" \u00e9 string" # trailing comment
+path = 'C:\raw'
+count = 1_000
+ratio = 1.5
+negative = -3
+on = true
+nodes = [
+    "a:1", # first
+    "b:2",
+]
+point = { x = 1, y.z = 2 }
+
+[server.http]
+port = 8080
+"quoted key" = []
+"#;
+    let table = Parser::new(text).document().unwrap();
+    assert_eq!(
+        Value::Object(table),
+        serde_json::json!({
+            "title": "a \"quoted\" é string",
+            "path": "C:\\raw",
+            "count": 1000,
+            "ratio": 1.5,
+            "negative": -3,
+            "on": true,
+            "nodes": ["a:1", "b:2"],
+            "point": { "x": 1, "y": { "z": 2 } },
+            "server": { "http": { "port": 8080, "quoted key": [] } },
+        })
+    );
+}
+
+#[test]
+fn toml_errors_name_their_line() {
+    let error = |text: &str| Parser::new(text).document().unwrap_err().to_string();
+    assert_eq!(error("a = 1\na = 2"), "line 2: a defined twice");
+    assert_eq!(
+        error("a = 1\n\nb = \"open\n"),
+        "line 4: unterminated string"
+    );
+    assert_eq!(
+        error("a = 1979-05-27"),
+        "line 1: invalid value \"1979-05-27\""
+    );
+    assert_eq!(error("a = 1 b = 2"), "line 1: expected the end of the line");
+    assert_eq!(error("[t]\n[t]"), "line 2: table t defined twice");
+    assert_eq!(error("[[t]]"), "line 1: arrays of tables are not supported");
+}
+
+#[test]
+fn configs_round_trip() {
+    let config = Config::parse(
+        "listen = \"127.0.0.1:7000\"\nencryption = \"forced\"\npex = false\n\
+         [limits]\ndownload = 512\n[tracker]\ntimeout = 5\n",
+    )
+    .unwrap();
+    assert_eq!(config.listen, "127.0.0.1:7000".parse().unwrap());
+    assert_eq!(config.encryption, EncryptionPolicy::Forced);
+    assert!(!config.pex && config.dht);
+    assert_eq!(config.limits.download, 512);
+    assert_eq!(config.limits.max_connections, connections::MAX_CONNECTIONS);
+    assert_eq!(config.tracker_timeout(), Duration::from_secs(5));
+    assert_eq!(Config::parse(&config.to_toml()).unwrap(), config);
+    assert_eq!(Config::parse("").unwrap(), Config::default());
+
+    let session = config.session_config();
+    assert_eq!(session.download_limit, Some(512 * 1024));
+    assert_eq!(session.upload_limit, None);
+
+    assert!(Config::parse("port = 1").is_err());
+    assert!(Config::parse("encryption = \"sometimes\"").is_err());
+    assert!(Config::parse("[limits]\ndownload = -1").is_err());
+}
+
+#[test]
+fn out_of_range_values_are_refused() {
+    let config = Config::parse("peer_id = \"-OX0100-abcdefghijkl\"\nblock_size = 8192").unwrap();
+    assert_eq!(&config.peer_id(), b"-OX0100-abcdefghijkl");
+    assert_eq!(config.session_config().block_size, 8192);
+    assert_eq!(Config::default().peer_id(), peer::DEFAULT_PEER_ID);
+
+    let error = |text: &str| Config::parse(text).unwrap_err().to_string();
+    assert_eq!(
+        error("peer_id = \"short\""),
+        "peer_id must be 20 bytes long"
+    );
+    assert_eq!(
+        error("block_size = 0"),
+        "block_size must be between 1 and 16384"
+    );
+    assert!(Config::parse("block_size = 16385").is_err());
+    assert_eq!(
+        error("[limits]\nupload = 18014398509481984"),
+        "limits.upload of 18014398509481984 KiB/s is too large"
+    );
+    assert!(Config::parse("[limits]\nupload = 18014398509481983").is_ok());
+    assert_eq!(Config::rate(u64::MAX), None);
+    assert_eq!(Config::rate(0), None);
+    assert_eq!(Config::rate(2), Some(2048));
+}
@@ -0,0 +1,270 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Most peer connections of every torrent together
+pub const MAX_CONNECTIONS: usize = 200;
+/// Most outgoing connections still connecting or handshaking at once
+pub const MAX_HALF_OPEN: usize = 20;
+/// Failed attempts after which a peer is banned
+pub const MAX_FAILURES: u32 = 5;
+/// Wait before retrying a peer after its first failure, doubled on each
+/// following one
+const BASE_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+/// Wait before reconnecting to a peer that closed a working connection
+const RECONNECT_DELAY: Duration = Duration::from_secs(2 * 60);
+
+/// Caps on the connections of every torrent sharing it. Clones share the caps.
+#[derive(Clone)]
+pub struct ConnectionLimits {
+    connections: Arc<Semaphore>,
+    half_open: Arc<Semaphore>,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self::new(MAX_CONNECTIONS, MAX_HALF_OPEN)
+    }
+}
+
+impl ConnectionLimits {
+    pub fn new(connections: usize, half_open: usize) -> Self {
+        Self {
+            connections: Arc::new(Semaphore::new(connections)),
+            half_open: Arc::new(Semaphore::new(half_open)),
+        }
+    }
+
+    /// A connection slot, held for as long as the connection lives, or None
+    /// when every slot is taken
+    pub fn connection(&self) -> Option<OwnedSemaphorePermit> {
+        self.connections.clone().try_acquire_owned().ok()
+    }
+
+    /// A slot for a connection attempt, held until its handshake is done
+    pub fn half_open(&self) -> Option<OwnedSemaphorePermit> {
+        self.half_open.clone().try_acquire_owned().ok()
+    }
+
+    /// Connection slots left
+    pub fn available(&self) -> usize {
+        self.connections.available_permits()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    /// Waiting for its next attempt
+    Idle,
+    /// Being connected to, or connected
+    Busy,
+}
+
+struct Candidate {
+    status: Status,
+    failures: u32,
+    next_attempt: Instant,
+}
+
+/// Every peer address a torrent knows of, with when to try it next.
+///
+/// Peers that fail get exponential backoff and are banned after
+/// [`MAX_FAILURES`] failures in a row. Bans are by IP, so they also refuse
+/// incoming connections.
+#[derive(Default)]
+pub struct PeerList {
+    peers: HashMap<SocketAddr, Candidate>,
+    /// Addresses in the order they were learnt, tried first to last
+    order: VecDeque<SocketAddr>,
+    banned: HashSet<IpAddr>,
+}
+
+impl PeerList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a peer to connect to, false when it was already known
+    pub fn add(&mut self, addr: SocketAddr, now: Instant) -> bool {
+        if self.peers.contains_key(&addr) {
+            return false;
+        }
+        self.peers.insert(
+            addr,
+            Candidate {
+                status: Status::Idle,
+                failures: 0,
+                next_attempt: now,
+            },
+        );
+        self.order.push_back(addr);
+        true
+    }
+
+    /// Takes the next peer due for a connection attempt, it counts as busy
+    /// until it fails or disconnects
+    pub fn next_candidate(&mut self, now: Instant) -> Option<SocketAddr> {
+        let position = self.order.iter().position(|addr| {
+            let candidate = &self.peers[addr];
+            candidate.status == Status::Idle
+                && candidate.next_attempt <= now
+                && !self.banned.contains(&addr.ip())
+        })?;
+        // Tried peers go to the back, so all get their turn
+        let addr = self.order.remove(position).expect("position is in range");
+        self.order.push_back(addr);
+        self.peers.get_mut(&addr).expect("candidate exists").status = Status::Busy;
+        Some(addr)
+    }
+
+    /// Marks a peer connected, known or not, so nobody connects to it again
+    pub fn connected(&mut self, addr: SocketAddr, now: Instant) {
+        self.add(addr, now);
+        self.peers.get_mut(&addr).expect("candidate exists").status = Status::Busy;
+    }
+
+    /// A connection attempt or a connection failed, the peer is retried later
+    /// or banned when it failed too often
+    pub fn failed(&mut self, addr: SocketAddr, now: Instant) {
+        let Some(candidate) = self.peers.get_mut(&addr) else {
+            return;
+        };
+        candidate.status = Status::Idle;
+        candidate.failures += 1;
+        if candidate.failures >= MAX_FAILURES {
+            self.banned.insert(addr.ip());
+            return;
+        }
+        let backoff = BASE_BACKOFF * 2u32.pow(candidate.failures - 1);
+        candidate.next_attempt = now + backoff.min(MAX_BACKOFF);
+    }
+
+    /// A working connection was closed, the peer may be tried again after a
+    /// while
+    pub fn disconnected(&mut self, addr: SocketAddr, now: Instant) {
+        if let Some(candidate) = self.peers.get_mut(&addr) {
+            candidate.status = Status::Idle;
+            candidate.failures = 0;
+            candidate.next_attempt = now + RECONNECT_DELAY;
+        }
+    }
+
+    /// We closed a working connection, the peer may be tried again right
+    /// away
+    pub fn released(&mut self, addr: SocketAddr, now: Instant) {
+        if let Some(candidate) = self.peers.get_mut(&addr) {
+            candidate.status = Status::Idle;
+            candidate.next_attempt = now;
+        }
+    }
+
+    pub fn ban(&mut self, ip: IpAddr) {
+        self.banned.insert(ip);
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.banned.contains(&ip)
+    }
+
+    pub fn banned(&self) -> Vec<IpAddr> {
+        self.banned.iter().copied().collect()
+    }
+
+    /// Failures in a row of a peer
+    pub fn failures(&self, addr: SocketAddr) -> u32 {
+        self.peers
+            .get(&addr)
+            .map_or(0, |candidate| candidate.failures)
+    }
+
+    /// When the next peer waiting for its backoff is due, None when no peer
+    /// is waiting
+    pub fn next_attempt(&self) -> Option<Instant> {
+        self.waiting().map(|candidate| candidate.next_attempt).min()
+    }
+
+    /// Whether some peer may still be tried, now or later
+    pub fn has_candidates(&self) -> bool {
+        self.waiting().next().is_some()
+    }
+
+    fn waiting(&self) -> impl Iterator<Item = &Candidate> {
+        self.peers
+            .iter()
+            .filter(|(addr, candidate)| {
+                candidate.status == Status::Idle && !self.banned.contains(&addr.ip())
+            })
+            .map(|(_, candidate)| candidate)
+    }
+}
+
+#[test]
+fn failing_peers_back_off_and_get_banned() {
+    let now = Instant::now();
+    let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+    let mut peers = PeerList::new();
+    assert!(peers.add(addr, now));
+    assert!(!peers.add(addr, now));
+    assert_eq!(peers.next_candidate(now), Some(addr));
+    assert_eq!(peers.next_candidate(now), None);
+
+    let mut at = now;
+    for failure in 1..MAX_FAILURES {
+        peers.failed(addr, at);
+        let backoff = BASE_BACKOFF * 2u32.pow(failure - 1);
+        assert_eq!(peers.next_attempt(), Some(at + backoff));
+        assert_eq!(peers.next_candidate(at + backoff / 2), None);
+        at += backoff;
+        assert_eq!(peers.next_candidate(at), Some(addr));
+    }
+    peers.failed(addr, at);
+    assert!(peers.is_banned(addr.ip()));
+    assert!(!peers.has_candidates());
+    assert_eq!(peers.next_candidate(at + MAX_BACKOFF), None);
+}
+
+#[test]
+fn disconnected_peers_are_retried_later() {
+    let now = Instant::now();
+    let first: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+    let second: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+    let mut peers = PeerList::new();
+    peers.add(first, now);
+    peers.add(second, now);
+    assert_eq!(peers.next_candidate(now), Some(first));
+    peers.failed(first, now);
+    assert_eq!(peers.next_candidate(now), Some(second));
+
+    // A working connection clears the failures
+    assert_eq!(peers.next_candidate(now + BASE_BACKOFF), Some(first));
+    peers.disconnected(first, now);
+    assert_eq!(peers.failures(first), 0);
+    assert_eq!(peers.next_candidate(now + BASE_BACKOFF), None);
+    assert_eq!(peers.next_candidate(now + RECONNECT_DELAY), Some(first));
+
+    // Incoming peers are known too
+    let incoming: SocketAddr = "10.0.0.3:51413".parse().unwrap();
+    peers.connected(incoming, now);
+    assert!(!peers.add(incoming, now));
+    assert!(!peers.has_candidates());
+}
+
+#[test]
+fn limits_hand_out_slots() {
+    let limits = ConnectionLimits::new(2, 1);
+    let half_open = limits.half_open().unwrap();
+    assert!(limits.half_open().is_none());
+    drop(half_open);
+    assert!(limits.half_open().is_some());
+
+    let shared = limits.clone();
+    let first = limits.connection().unwrap();
+    let _second = shared.connection().unwrap();
+    assert!(limits.connection().is_none());
+    drop(first);
+    assert_eq!(shared.available(), 1);
+}
@@ -0,0 +1,656 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use tokio::net::{lookup_host, ToSocketAddrs, UdpSocket};
+use tokio::sync::oneshot;
+use tokio::task::{JoinHandle, JoinSet};
+
+use crate::{debug, trace, warn};
+
+pub mod krpc;
+mod routing;
+
+use krpc::{error, Arguments, Message, Response};
+pub use routing::{NodeId, NodeInfo, RoutingTable, K};
+
+/// Public routers used to join the DHT when no other node is known
+pub const BOOTSTRAP_NODES: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+/// Time to wait for the answer to a query
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+/// Queries in flight during a lookup
+const ALPHA: usize = 3;
+/// Tokens handed out are valid for one to two rotations
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+/// Announced peers are forgotten after this time
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+/// Most peers stored for one info hash
+const MAX_STORED_PEERS: usize = 200;
+/// Most info hashes peers are stored for, announces for others are dropped
+/// until some expire
+const MAX_STORED_INFO_HASHES: usize = 2000;
+/// Most peers returned in one `get_peers` response, so it fits in a packet
+const MAX_VALUES: usize = 50;
+/// Most nodes written to the state file
+const MAX_SAVED_NODES: usize = 200;
+
+/// A node of the mainline DHT (BEP 5), it answers queries as soon as it is
+/// bound and runs lookups for peers of info hashes
+#[derive(Clone)]
+pub struct Dht {
+    shared: Arc<Shared>,
+    _receiver: Arc<Receiver>,
+}
+
+/// Stops the receive loop once every handle of the node is dropped
+struct Receiver(JoinHandle<()>);
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+struct Shared {
+    id: NodeId,
+    socket: UdpSocket,
+    state: Mutex<State>,
+}
+
+struct State {
+    table: RoutingTable,
+    /// Queries waiting for their answer, by transaction id
+    pending: HashMap<u16, Pending>,
+    next_transaction: u16,
+    /// Peers announced to us, by info hash
+    peers: HashMap<NodeId, HashMap<SocketAddr, Instant>>,
+    secret: [u8; 20],
+    previous_secret: [u8; 20],
+    secret_created: Instant,
+    /// Nodes of a previous run, contacted by the next bootstrap
+    saved_nodes: Vec<SocketAddr>,
+}
+
+impl State {
+    /// Stores a peer announced for `info_hash`
+    fn store_peer(&mut self, info_hash: NodeId, addr: SocketAddr, now: Instant) {
+        if !self.peers.contains_key(&info_hash) && self.peers.len() >= MAX_STORED_INFO_HASHES {
+            return;
+        }
+        let peers = self.peers.entry(info_hash).or_default();
+        peers.retain(|_, announced| now.duration_since(*announced) < PEER_TTL);
+        if peers.len() < MAX_STORED_PEERS {
+            peers.insert(addr, now);
+        }
+    }
+
+    /// Forgets the peers announced too long ago, and the info hashes left
+    /// without peers
+    fn expire_peers(&mut self, now: Instant) {
+        self.peers.retain(|_, peers| {
+            peers.retain(|_, announced| now.duration_since(*announced) < PEER_TTL);
+            !peers.is_empty()
+        });
+    }
+}
+
+/// Node id and routing table kept across restarts, bencoded in the state file
+#[derive(Debug, Deserialize, Serialize)]
+struct SavedState {
+    id: ByteBuf,
+    /// Compact node info of the good nodes
+    nodes: ByteBuf,
+}
+
+struct Pending {
+    addr: SocketAddr,
+    reply: oneshot::Sender<Result<Response>>,
+}
+
+/// Result of a lookup
+struct Lookup {
+    peers: HashSet<SocketAddr>,
+    /// Closest nodes that answered, with the token to announce to them
+    nodes: Vec<(NodeInfo, Option<ByteBuf>)>,
+}
+
+impl Dht {
+    /// Binds a node with a random id
+    pub async fn bind(addr: impl ToSocketAddrs) -> Result<Self> {
+        Self::with_id(addr, NodeId::random()).await
+    }
+
+    pub async fn with_id(addr: impl ToSocketAddrs, id: NodeId) -> Result<Self> {
+        let socket = UdpSocket::bind(addr).await.context("binding dht socket")?;
+        let secret = crate::random_bytes();
+        let shared = Arc::new(Shared {
+            id,
+            socket,
+            state: Mutex::new(State {
+                table: RoutingTable::new(id),
+                pending: HashMap::new(),
+                next_transaction: 0,
+                peers: HashMap::new(),
+                secret,
+                previous_secret: secret,
+                secret_created: Instant::now(),
+                saved_nodes: Vec::new(),
+            }),
+        });
+        let receiver = tokio::spawn(receive(shared.clone()));
+        Ok(Self {
+            shared,
+            _receiver: Arc::new(Receiver(receiver)),
+        })
+    }
+
+    /// Binds a node with the id saved in `path` by [`Dht::save`], the saved
+    /// nodes are contacted by the next bootstrap. A missing or invalid file
+    /// gives a new node with a random id.
+    pub async fn restore(addr: impl ToSocketAddrs, path: &Path) -> Result<Self> {
+        let saved = fs::read(path)
+            .ok()
+            .and_then(|bytes| serde_bencode::from_bytes::<SavedState>(&bytes).ok())
+            .and_then(|saved| Some((NodeId::from_bytes(&saved.id).ok()?, saved.nodes)));
+        let Some((id, nodes)) = saved else {
+            return Self::bind(addr).await;
+        };
+        let dht = Self::with_id(addr, id).await?;
+        dht.shared.state().saved_nodes = NodeInfo::from_compact(&nodes)
+            .into_iter()
+            .map(|node| node.addr)
+            .collect();
+        Ok(dht)
+    }
+
+    /// Writes the node id and the good nodes of the routing table to `path`
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut nodes = self.nodes();
+        nodes.truncate(MAX_SAVED_NODES);
+        let saved = SavedState {
+            id: ByteBuf::from(self.id().0),
+            nodes: ByteBuf::from(NodeInfo::to_compact(&nodes)),
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Replaced at once, so an interrupted save keeps the previous state
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, serde_bencode::to_bytes(&saved)?)
+            .with_context(|| format!("writing {}", temporary.display()))?;
+        fs::rename(&temporary, path)?;
+        Ok(())
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.shared.id
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.shared.socket.local_addr()?)
+    }
+
+    /// Good nodes of the routing table
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        self.shared.state().table.nodes()
+    }
+
+    /// Joins the DHT through the given nodes and the nodes of the restored
+    /// state, `host:port` names are resolved. Returns the size of the routing
+    /// table, an error if nobody answered.
+    pub async fn bootstrap(&self, nodes: &[String]) -> Result<usize> {
+        let mut addrs = std::mem::take(&mut self.shared.state().saved_nodes);
+        for node in nodes {
+            match lookup_host(node.as_str()).await {
+                Ok(resolved) => addrs.extend(resolved.filter(SocketAddr::is_ipv4)),
+                Err(e) => warn!({ node = node }, "resolving dht node failed: {e}"),
+            }
+        }
+
+        let mut pings = JoinSet::new();
+        for addr in addrs {
+            let dht = self.clone();
+            pings.spawn(async move { dht.find_node_query(addr, dht.id()).await });
+        }
+        while pings.join_next().await.is_some() {}
+
+        // Looking up our own id fills the buckets close to us
+        self.find_node(self.id()).await;
+        let len = self.shared.state().table.len();
+        if len == 0 {
+            bail!("no dht node answered");
+        }
+        Ok(len)
+    }
+
+    /// Adds a node learned outside the DHT, such as from a PORT message, once
+    /// it answers a ping
+    pub fn add_node(&self, addr: SocketAddr) {
+        let dht = self.clone();
+        tokio::spawn(async move { dht.ping(addr).await });
+    }
+
+    pub async fn ping(&self, addr: SocketAddr) -> Result<NodeId> {
+        let response = self.query(addr, "ping", Arguments::new(self.id())).await?;
+        response.id()
+    }
+
+    /// Finds the nodes closest to `target`
+    pub async fn find_node(&self, target: NodeId) -> Vec<NodeInfo> {
+        let lookup = self.lookup(target, false).await;
+        lookup.nodes.into_iter().map(|(node, _)| node).collect()
+    }
+
+    /// Finds peers of the torrent without announcing ourselves
+    pub async fn get_peers(&self, info_hash: [u8; 20]) -> Vec<SocketAddr> {
+        let lookup = self.lookup(NodeId(info_hash), true).await;
+        lookup.peers.into_iter().collect()
+    }
+
+    /// Finds peers of the torrent and announces that we accept connections
+    /// for it on `port`
+    pub async fn announce(&self, info_hash: [u8; 20], port: u16) -> Vec<SocketAddr> {
+        let lookup = self.lookup(NodeId(info_hash), true).await;
+
+        let mut announces = JoinSet::new();
+        for (node, token) in lookup.nodes {
+            let Some(token) = token else {
+                continue;
+            };
+            let mut arguments = Arguments::new(self.id());
+            arguments.info_hash = Some(ByteBuf::from(info_hash));
+            arguments.port = Some(port);
+            arguments.token = Some(token);
+            let dht = self.clone();
+            announces.spawn(async move { dht.query(node.addr, "announce_peer", arguments).await });
+        }
+        while announces.join_next().await.is_some() {}
+
+        lookup.peers.into_iter().collect()
+    }
+
+    /// Iterative lookup of the nodes closest to `target`, asking them for
+    /// peers when `get_peers` is set
+    async fn lookup(&self, target: NodeId, get_peers: bool) -> Lookup {
+        let mut candidates: BTreeMap<[u8; 20], NodeInfo> = self
+            .shared
+            .state()
+            .table
+            .closest(&target, K)
+            .into_iter()
+            .map(|node| (node.id.distance(&target), node))
+            .collect();
+        let mut queried = HashSet::new();
+        let mut responded: BTreeMap<[u8; 20], (NodeInfo, Option<ByteBuf>)> = BTreeMap::new();
+        let mut peers = HashSet::new();
+        let mut in_flight = JoinSet::new();
+
+        loop {
+            while in_flight.len() < ALPHA {
+                // The lookup is over once the K closest nodes answered
+                let kth = responded.keys().nth(K - 1).copied();
+                let next = candidates
+                    .iter()
+                    .find(|(_, node)| !queried.contains(&node.addr))
+                    .filter(|(distance, _)| kth.is_none_or(|kth| **distance < kth))
+                    .map(|(_, node)| *node);
+                let Some(node) = next else {
+                    break;
+                };
+                queried.insert(node.addr);
+                let dht = self.clone();
+                in_flight.spawn(async move {
+                    let response = if get_peers {
+                        dht.get_peers_query(node.addr, target).await
+                    } else {
+                        dht.find_node_query(node.addr, target).await
+                    };
+                    (node, response)
+                });
+            }
+
+            let Some(joined) = in_flight.join_next().await else {
+                break;
+            };
+            let Ok((node, Ok(response))) = joined else {
+                continue;
+            };
+            let Ok(id) = response.id() else {
+                continue;
+            };
+            for found in response.nodes() {
+                if found.id != self.id() {
+                    candidates.insert(found.id.distance(&target), found);
+                }
+            }
+            peers.extend(response.peers());
+            let node = NodeInfo { id, ..node };
+            responded.insert(id.distance(&target), (node, response.token));
+        }
+
+        Lookup {
+            peers,
+            nodes: responded.into_values().take(K).collect(),
+        }
+    }
+
+    async fn find_node_query(&self, addr: SocketAddr, target: NodeId) -> Result<Response> {
+        let mut arguments = Arguments::new(self.id());
+        arguments.target = Some(ByteBuf::from(target.0));
+        self.query(addr, "find_node", arguments).await
+    }
+
+    async fn get_peers_query(&self, addr: SocketAddr, info_hash: NodeId) -> Result<Response> {
+        let mut arguments = Arguments::new(self.id());
+        arguments.info_hash = Some(ByteBuf::from(info_hash.0));
+        self.query(addr, "get_peers", arguments).await
+    }
+
+    /// Sends a query and waits for its response, nodes that answer are added
+    /// to the routing table
+    async fn query(
+        &self,
+        addr: SocketAddr,
+        method: &str,
+        arguments: Arguments,
+    ) -> Result<Response> {
+        let (reply, response) = oneshot::channel();
+        let transaction = {
+            let mut state = self.shared.state();
+            let transaction = state.next_transaction;
+            state.next_transaction = transaction.wrapping_add(1);
+            state.pending.insert(transaction, Pending { addr, reply });
+            transaction
+        };
+
+        let message = Message::query(&transaction.to_be_bytes(), method, arguments);
+        let sent = self
+            .shared
+            .socket
+            .send_to(&serde_bencode::to_bytes(&message)?, addr)
+            .await;
+        if let Err(e) = sent {
+            self.shared.state().pending.remove(&transaction);
+            return Err(e).context("sending dht query");
+        }
+        let result = tokio::time::timeout(QUERY_TIMEOUT, response).await;
+
+        let mut state = self.shared.state();
+        state.pending.remove(&transaction);
+        match result {
+            Ok(Ok(Ok(response))) => {
+                let id = response.id()?;
+                state.table.insert(NodeInfo { id, addr });
+                Ok(response)
+            }
+            Ok(Ok(Err(e))) => Err(e),
+            _ => {
+                state.table.failed(&addr);
+                bail!("{addr} did not answer {method}")
+            }
+        }
+    }
+}
+
+impl Shared {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("dht state lock poisoned")
+    }
+
+    /// Token a node has to present to announce from `ip`
+    fn token(&self, state: &mut State, ip: IpAddr) -> ByteBuf {
+        if state.secret_created.elapsed() >= TOKEN_ROTATION {
+            state.previous_secret = state.secret;
+            state.secret = crate::random_bytes();
+            state.secret_created = Instant::now();
+            state.expire_peers(Instant::now());
+        }
+        token(&state.secret, ip)
+    }
+
+    fn valid_token(&self, state: &mut State, ip: IpAddr, presented: &[u8]) -> bool {
+        self.token(state, ip).as_slice() == presented
+            || token(&state.previous_secret, ip).as_slice() == presented
+    }
+
+    async fn handle_packet(&self, packet: &[u8], from: SocketAddr) -> Result<()> {
+        let message: Message = serde_bencode::from_bytes(packet)?;
+        match message.y.as_str() {
+            "q" => {
+                let reply = self.handle_query(&message, from);
+                self.socket
+                    .send_to(&serde_bencode::to_bytes(&reply)?, from)
+                    .await?;
+            }
+            "r" | "e" => {
+                let Ok(transaction) = <[u8; 2]>::try_from(message.t.as_slice()) else {
+                    return Ok(());
+                };
+                let mut state = self.state();
+                let transaction = u16::from_be_bytes(transaction);
+                // Answers from another address than the one queried are ignored
+                if state
+                    .pending
+                    .get(&transaction)
+                    .is_some_and(|p| p.addr == from)
+                {
+                    let pending = state.pending.remove(&transaction).expect("pending exists");
+                    let result = match (message.error_details(), message.r) {
+                        (Some((code, text)), _) => Err(anyhow!("error {code}: {text}")),
+                        (None, Some(response)) => Ok(response),
+                        (None, None) => Err(anyhow!("invalid response")),
+                    };
+                    let _ = pending.reply.send(result);
+                }
+            }
+            y => bail!("unknown message type {y}"),
+        }
+        Ok(())
+    }
+
+    fn handle_query(&self, message: &Message, from: SocketAddr) -> Message {
+        let t = message.t.as_slice();
+        let (Some(method), Some(arguments)) = (&message.q, &message.a) else {
+            return Message::error(t, error::PROTOCOL, "missing method or arguments");
+        };
+        let Ok(id) = arguments.id() else {
+            return Message::error(t, error::PROTOCOL, "invalid id");
+        };
+
+        let mut state = self.state();
+        let mut response = Response::new(self.id);
+        match method.as_str() {
+            "ping" => {}
+            "find_node" => {
+                let Ok(target) = arguments.target() else {
+                    return Message::error(t, error::PROTOCOL, "invalid target");
+                };
+                let nodes = state.table.closest(&target, K);
+                response.nodes = Some(ByteBuf::from(NodeInfo::to_compact(&nodes)));
+            }
+            "get_peers" => {
+                let Ok(info_hash) = arguments.info_hash() else {
+                    return Message::error(t, error::PROTOCOL, "invalid info_hash");
+                };
+                response.token = Some(self.token(&mut state, from.ip()));
+                let peers: Vec<ByteBuf> = state
+                    .peers
+                    .get(&info_hash)
+                    .into_iter()
+                    .flatten()
+                    .filter(|(_, announced)| announced.elapsed() < PEER_TTL)
+                    .filter(|(addr, _)| addr.is_ipv4())
+                    .take(MAX_VALUES)
+                    .map(|(addr, _)| ByteBuf::from(crate::pex::compact(addr)))
+                    .collect();
+                if peers.is_empty() {
+                    let nodes = state.table.closest(&info_hash, K);
+                    response.nodes = Some(ByteBuf::from(NodeInfo::to_compact(&nodes)));
+                } else {
+                    response.values = Some(peers);
+                }
+            }
+            "announce_peer" => {
+                let Ok(info_hash) = arguments.info_hash() else {
+                    return Message::error(t, error::PROTOCOL, "invalid info_hash");
+                };
+                let token = arguments
+                    .token
+                    .as_deref()
+                    .map(Vec::as_slice)
+                    .unwrap_or_default();
+                if !self.valid_token(&mut state, from.ip(), token) {
+                    return Message::error(t, error::PROTOCOL, "bad token");
+                }
+                let port = match (arguments.implied_port, arguments.port) {
+                    (Some(1), _) => from.port(),
+                    (_, Some(port)) => port,
+                    _ => return Message::error(t, error::PROTOCOL, "missing port"),
+                };
+                state.store_peer(info_hash, SocketAddr::new(from.ip(), port), Instant::now());
+            }
+            _ => return Message::error(t, error::METHOD_UNKNOWN, "Method Unknown"),
+        }
+        // Nodes querying us are alive, they may be added to the table
+        state.table.insert(NodeInfo { id, addr: from });
+        Message::response(t, response)
+    }
+}
+
+fn token(secret: &[u8; 20], ip: IpAddr) -> ByteBuf {
+    let mut hasher = Sha1::new();
+    hasher.update(secret);
+    match ip {
+        IpAddr::V4(ip) => hasher.update(ip.octets()),
+        IpAddr::V6(ip) => hasher.update(ip.octets()),
+    }
+    ByteBuf::from(&hasher.finalize()[..8])
+}
+
+/// Answers queries and hands responses to the queries waiting for them
+async fn receive(shared: Arc<Shared>) {
+    let mut buffer = vec![0; 4096];
+    loop {
+        let (length, from) = match shared.socket.recv_from(&mut buffer).await {
+            Ok(received) => received,
+            Err(e) => {
+                debug!("dht socket error: {e}");
+                continue;
+            }
+        };
+        if let Err(e) = shared.handle_packet(&buffer[..length], from).await {
+            trace!({ from = from }, "invalid dht packet: {e:#}");
+        }
+    }
+}
+
+#[tokio::test]
+async fn lookup_between_local_nodes() {
+    let mut nodes = Vec::new();
+    for _ in 0..10 {
+        nodes.push(Dht::bind("127.0.0.1:0").await.unwrap());
+    }
+    let router = vec![nodes[0].local_addr().unwrap().to_string()];
+    for node in &nodes[1..] {
+        node.bootstrap(&router).await.unwrap();
+    }
+    // The first nodes joined before the others existed, they learn them now
+    for node in &nodes[1..] {
+        node.bootstrap(&router).await.unwrap();
+    }
+    assert!(nodes[5].nodes().len() >= K);
+
+    let target = nodes[7].id();
+    let found = nodes[3].find_node(target).await;
+    assert_eq!(found[0].id, target);
+
+    let info_hash = [7; 20];
+    assert!(nodes[2].get_peers(info_hash).await.is_empty());
+    nodes[4].announce(info_hash, 51413).await;
+    let peers = nodes[9].get_peers(info_hash).await;
+    assert_eq!(peers, vec!["127.0.0.1:51413".parse().unwrap()]);
+}
+
+#[tokio::test]
+async fn restore_saved_state() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("dht").join("state.dat");
+    let other = Dht::bind("127.0.0.1:0").await.unwrap();
+
+    // Nothing saved yet, the node gets a new id
+    let node = Dht::restore("127.0.0.1:0", &path).await.unwrap();
+    node.ping(other.local_addr().unwrap()).await.unwrap();
+    node.save(&path).unwrap();
+    let id = node.id();
+    drop(node);
+
+    let node = Dht::restore("127.0.0.1:0", &path).await.unwrap();
+    assert_eq!(node.id(), id);
+    // The saved node is enough to join, without any bootstrap node
+    assert_eq!(node.bootstrap(&[]).await.unwrap(), 1);
+    assert_eq!(node.nodes()[0].id, other.id());
+}
+
+#[tokio::test]
+async fn rejects_bad_token_and_unknown_method() {
+    let node = Dht::bind("127.0.0.1:0").await.unwrap();
+    let client = Dht::bind("127.0.0.1:0").await.unwrap();
+    let addr = node.local_addr().unwrap();
+    assert_eq!(client.ping(addr).await.unwrap(), node.id());
+
+    let mut arguments = Arguments::new(client.id());
+    arguments.info_hash = Some(ByteBuf::from([1; 20]));
+    arguments.port = Some(1);
+    arguments.token = Some(ByteBuf::from(*b"forged"));
+    let error = client
+        .query(addr, "announce_peer", arguments)
+        .await
+        .unwrap_err();
+    assert!(error.to_string().contains("203"));
+
+    let error = client
+        .query(addr, "vote", Arguments::new(client.id()))
+        .await
+        .unwrap_err();
+    assert!(error.to_string().contains("204"));
+}
+
+#[tokio::test]
+async fn stored_info_hashes_are_capped_and_expire() {
+    let node = Dht::bind("127.0.0.1:0").await.unwrap();
+    let mut state = node.shared.state();
+    let peer: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+    let info_hash = |i: usize| {
+        let mut id = [0; 20];
+        id[..8].copy_from_slice(&(i as u64).to_be_bytes());
+        NodeId(id)
+    };
+    let start = Instant::now();
+    for i in 0..=MAX_STORED_INFO_HASHES {
+        state.store_peer(info_hash(i), peer, start);
+    }
+    assert_eq!(state.peers.len(), MAX_STORED_INFO_HASHES);
+    assert!(!state.peers.contains_key(&info_hash(MAX_STORED_INFO_HASHES)));
+
+    // Known info hashes still take peers, then everything expires
+    let later = start + PEER_TTL / 2;
+    state.store_peer(info_hash(0), "10.0.0.2:6881".parse().unwrap(), later);
+    assert_eq!(state.peers[&info_hash(0)].len(), 2);
+    state.expire_peers(start + PEER_TTL);
+    assert_eq!(state.peers.len(), 1);
+    assert_eq!(state.peers[&info_hash(0)].len(), 1);
+    state.expire_peers(later + PEER_TTL);
+    assert!(state.peers.is_empty());
+}
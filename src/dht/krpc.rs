@@ -0,0 +1,196 @@
+use std::net::SocketAddr;
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use serde_bencode::value::Value;
+use serde_bytes::ByteBuf;
+
+use super::routing::{NodeId, NodeInfo};
+use crate::pex;
+
+/// Error codes of KRPC error messages
+pub mod error {
+    pub const GENERIC: i64 = 201;
+    pub const SERVER: i64 = 202;
+    pub const PROTOCOL: i64 = 203;
+    pub const METHOD_UNKNOWN: i64 = 204;
+}
+
+/// A KRPC message: a query, its response or an error, matched by the
+/// transaction id `t`
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Message {
+    pub t: ByteBuf,
+    pub y: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub q: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub a: Option<Arguments>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub r: Option<Response>,
+    /// Error code and message, a list since bencode has no tuples
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub e: Option<Vec<Value>>,
+    /// Client version
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<ByteBuf>,
+}
+
+impl Message {
+    pub fn query(transaction: &[u8], method: &str, arguments: Arguments) -> Self {
+        Self {
+            t: ByteBuf::from(transaction),
+            y: "q".into(),
+            q: Some(method.into()),
+            a: Some(arguments),
+            ..Self::default()
+        }
+    }
+
+    pub fn response(transaction: &[u8], response: Response) -> Self {
+        Self {
+            t: ByteBuf::from(transaction),
+            y: "r".into(),
+            r: Some(response),
+            ..Self::default()
+        }
+    }
+
+    pub fn error(transaction: &[u8], code: i64, message: &str) -> Self {
+        Self {
+            t: ByteBuf::from(transaction),
+            y: "e".into(),
+            e: Some(vec![Value::Int(code), Value::Bytes(message.into())]),
+            ..Self::default()
+        }
+    }
+
+    /// Code and message of an error message
+    pub fn error_details(&self) -> Option<(i64, String)> {
+        match self.e.as_deref()? {
+            [Value::Int(code), Value::Bytes(message), ..] => {
+                Some((*code, String::from_utf8_lossy(message).into_owned()))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Arguments of every query, the fields used depend on the method
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Arguments {
+    pub id: ByteBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub info_hash: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<ByteBuf>,
+    /// Use the source port of the packet instead of `port`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub implied_port: Option<u8>,
+}
+
+impl Arguments {
+    pub fn new(id: NodeId) -> Self {
+        Self {
+            id: ByteBuf::from(id.0),
+            ..Self::default()
+        }
+    }
+
+    pub fn id(&self) -> Result<NodeId> {
+        NodeId::from_bytes(&self.id)
+    }
+
+    pub fn target(&self) -> Result<NodeId> {
+        match &self.target {
+            Some(target) => NodeId::from_bytes(target),
+            None => bail!("missing target"),
+        }
+    }
+
+    pub fn info_hash(&self) -> Result<NodeId> {
+        match &self.info_hash {
+            Some(info_hash) => NodeId::from_bytes(info_hash),
+            None => bail!("missing info_hash"),
+        }
+    }
+}
+
+/// Values of every response, the fields set depend on the query
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Response {
+    pub id: ByteBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nodes: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub values: Option<Vec<ByteBuf>>,
+}
+
+impl Response {
+    pub fn new(id: NodeId) -> Self {
+        Self {
+            id: ByteBuf::from(id.0),
+            ..Self::default()
+        }
+    }
+
+    pub fn id(&self) -> Result<NodeId> {
+        NodeId::from_bytes(&self.id)
+    }
+
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        self.nodes
+            .as_ref()
+            .map(|nodes| NodeInfo::from_compact(nodes))
+            .unwrap_or_default()
+    }
+
+    /// Peers of a `get_peers` response, malformed values are skipped
+    pub fn peers(&self) -> Vec<SocketAddr> {
+        self.values
+            .iter()
+            .flatten()
+            .filter(|value| value.len() == 6)
+            .flat_map(|value| pex::parse_compact(value, 6))
+            .collect()
+    }
+}
+
+#[test]
+fn query_encoding() {
+    // Example of BEP 5
+    let mut arguments = Arguments::new(NodeId(*b"abcdefghij0123456789"));
+    arguments.target = Some(ByteBuf::from(*b"mnopqrstuvwxyz123456"));
+    let message = Message::query(b"aa", "find_node", arguments);
+    assert_eq!(
+        serde_bencode::to_bytes(&message).unwrap(),
+        b"d1:ad2:id20:abcdefghij01234567896:target20:mnopqrstuvwxyz123456e1:q9:find_node1:t2:aa1:y1:qe"
+    );
+}
+
+#[test]
+fn response_and_error_roundtrip() {
+    let mut response = Response::new(NodeId([1; 20]));
+    response.token = Some(ByteBuf::from(*b"aoeusnth"));
+    response.values = Some(vec![ByteBuf::from(pex::compact(
+        &"10.0.0.1:6881".parse().unwrap(),
+    ))]);
+    let bytes = serde_bencode::to_bytes(&Message::response(b"xy", response)).unwrap();
+    let message: Message = serde_bencode::from_bytes(&bytes).unwrap();
+    let response = message.r.unwrap();
+    assert_eq!(response.id().unwrap(), NodeId([1; 20]));
+    assert_eq!(response.peers(), vec!["10.0.0.1:6881".parse().unwrap()]);
+
+    let bytes = b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee";
+    let message: Message = serde_bencode::from_bytes(bytes).unwrap();
+    assert_eq!(
+        message.error_details(),
+        Some((error::GENERIC, "A Generic Error Ocurred".into()))
+    );
+}
@@ -0,0 +1,259 @@
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+
+use crate::pex;
+
+/// Nodes per bucket, and nodes returned by `find_node` and `get_peers`
+pub const K: usize = 8;
+/// Unanswered queries after which a node is replaced by any new node
+const MAX_FAILURES: u32 = 2;
+/// Size of a node in compact node info: the id then the compact address
+const COMPACT_NODE_SIZE: usize = 26;
+
+/// Identifier of a node, also used for the info hashes stored in the DHT
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(pub [u8; 20]);
+
+impl NodeId {
+    /// A random id, unpredictable enough for the DHT but not for cryptography
+    pub fn random() -> Self {
+        Self(crate::random_bytes())
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Ok(Self(bytes.try_into().context("node id is not 20 bytes")?))
+    }
+
+    /// XOR distance between two ids, bigger arrays are farther
+    pub fn distance(&self, other: &NodeId) -> [u8; 20] {
+        std::array::from_fn(|i| self.0[i] ^ other.0[i])
+    }
+
+    /// Number of leading bits shared with `other`
+    fn common_prefix(&self, other: &NodeId) -> usize {
+        let distance = self.distance(other);
+        distance
+            .iter()
+            .position(|&b| b != 0)
+            .map(|i| i * 8 + distance[i].leading_zeros() as usize)
+            .unwrap_or(160)
+    }
+}
+
+impl fmt::Debug for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "NodeId({})", hex::encode(self.0))
+    }
+}
+
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+impl FromStr for NodeId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::from_bytes(&hex::decode(s).context("node id is not hex")?)
+    }
+}
+
+/// A node of the DHT and where to reach it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+}
+
+impl NodeInfo {
+    /// Encodes IPv4 nodes as compact node info, other nodes are skipped
+    pub fn to_compact(nodes: &[NodeInfo]) -> Vec<u8> {
+        nodes
+            .iter()
+            .filter(|node| node.addr.is_ipv4())
+            .flat_map(|node| node.id.0.into_iter().chain(pex::compact(&node.addr)))
+            .collect()
+    }
+
+    /// Decodes compact node info, a trailing partial entry is ignored
+    pub fn from_compact(bytes: &[u8]) -> Vec<NodeInfo> {
+        bytes
+            .chunks_exact(COMPACT_NODE_SIZE)
+            .filter_map(|chunk| {
+                let (id, addr) = chunk.split_at(20);
+                Some(NodeInfo {
+                    id: NodeId::from_bytes(id).ok()?,
+                    addr: *pex::parse_compact(addr, 6).first()?,
+                })
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    node: NodeInfo,
+    last_seen: Instant,
+    failures: u32,
+}
+
+impl Entry {
+    fn is_bad(&self) -> bool {
+        self.failures >= MAX_FAILURES
+    }
+}
+
+/// Kademlia routing table, bucket `i` holds the nodes sharing exactly `i`
+/// leading bits with our id
+#[derive(Debug)]
+pub struct RoutingTable {
+    id: NodeId,
+    buckets: Vec<Vec<Entry>>,
+}
+
+impl RoutingTable {
+    pub fn new(id: NodeId) -> Self {
+        Self {
+            id,
+            buckets: (0..160).map(|_| Vec::new()).collect(),
+        }
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    /// Number of nodes that are not known to be bad
+    pub fn len(&self) -> usize {
+        self.entries().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn entries(&self) -> impl Iterator<Item = &Entry> {
+        self.buckets
+            .iter()
+            .flatten()
+            .filter(|entry| !entry.is_bad())
+    }
+
+    fn bucket(&mut self, id: &NodeId) -> &mut Vec<Entry> {
+        let index = self.id.common_prefix(id).min(159);
+        &mut self.buckets[index]
+    }
+
+    /// Records a node that answered or queried us. It is added if its bucket
+    /// has room or holds a bad node, returns whether the node is in the table.
+    pub fn insert(&mut self, node: NodeInfo) -> bool {
+        if node.id == self.id || !is_routable(&node.addr) {
+            return false;
+        }
+        let bucket = self.bucket(&node.id);
+        if let Some(entry) = bucket.iter_mut().find(|e| e.node.id == node.id) {
+            entry.node.addr = node.addr;
+            entry.last_seen = Instant::now();
+            entry.failures = 0;
+            return true;
+        }
+        let entry = Entry {
+            node,
+            last_seen: Instant::now(),
+            failures: 0,
+        };
+        if bucket.len() < K {
+            bucket.push(entry);
+            return true;
+        }
+        match bucket.iter_mut().find(|e| e.is_bad()) {
+            Some(bad) => {
+                *bad = entry;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Counts an unanswered query to the node at `addr`
+    pub fn failed(&mut self, addr: &SocketAddr) {
+        if let Some(entry) = self
+            .buckets
+            .iter_mut()
+            .flatten()
+            .find(|e| e.node.addr == *addr)
+        {
+            entry.failures += 1;
+        }
+    }
+
+    /// The `count` good nodes closest to `target`
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeInfo> {
+        let mut nodes: Vec<NodeInfo> = self.entries().map(|e| e.node).collect();
+        nodes.sort_by_key(|node| node.id.distance(target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    /// Every good node, most recently seen first
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        let mut entries: Vec<&Entry> = self.entries().collect();
+        entries.sort_by_key(|e| std::cmp::Reverse(e.last_seen));
+        entries.into_iter().map(|e| e.node).collect()
+    }
+}
+
+/// Addresses a node can be reached at, port zero and unspecified addresses
+/// are useless
+fn is_routable(addr: &SocketAddr) -> bool {
+    addr.port() != 0
+        && match addr.ip() {
+            IpAddr::V4(ip) => !ip.is_unspecified() && !ip.is_broadcast(),
+            IpAddr::V6(ip) => !ip.is_unspecified(),
+        }
+}
+
+#[cfg(test)]
+fn node(first: u8, port: u16) -> NodeInfo {
+    let mut id = [0; 20];
+    id[0] = first;
+    NodeInfo {
+        id: NodeId(id),
+        addr: SocketAddr::from(([127, 0, 0, 1], port)),
+    }
+}
+
+#[test]
+fn buckets_and_closest() {
+    let mut table = RoutingTable::new(NodeId([0; 20]));
+    // Every id starting with a set high bit goes to bucket 0
+    for i in 0..K as u8 + 2 {
+        table.insert(node(0x80 | i, 1000 + i as u16));
+    }
+    assert_eq!(table.len(), K);
+    assert!(table.insert(node(0x01, 1)));
+    assert!(!table.insert(node(0x81 + K as u8 + 2, 2)));
+
+    // A bad node makes room in its bucket
+    table.failed(&node(0x80, 1000).addr);
+    table.failed(&node(0x80, 1000).addr);
+    assert!(table.insert(node(0xf0, 3)));
+
+    let closest = table.closest(&NodeId([0; 20]), 2);
+    assert_eq!(closest[0], node(0x01, 1));
+    assert_eq!(closest[1], node(0x81, 1001));
+}
+
+#[test]
+fn compact_node_info() {
+    let nodes = vec![node(1, 6881), node(2, 6882)];
+    let bytes = NodeInfo::to_compact(&nodes);
+    assert_eq!(bytes.len(), 52);
+    assert_eq!(NodeInfo::from_compact(&bytes), nodes);
+}
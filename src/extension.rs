@@ -0,0 +1,271 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::peer::{Message, MessageTag};
+
+/// Extended message id reserved for the extended handshake
+pub const HANDSHAKE_ID: u8 = 0;
+/// Outstanding requests we accept from a peer, sent as `reqq`
+pub const LOCAL_REQQ: usize = 250;
+/// Pipeline limit for peers that do not tell us their `reqq`
+pub const DEFAULT_REQQ: usize = 5;
+
+/// Bencoded dictionary sent as the first extended message (BEP 10)
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ExtendedHandshake {
+    /// Extension names mapped to the message id the sender expects for them
+    #[serde(default)]
+    pub m: BTreeMap<String, u8>,
+    /// Client name and version
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<String>,
+    /// Local TCP listen port of the sender
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub p: Option<u16>,
+    /// Number of outstanding requests the sender accepts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<usize>,
+    /// Our address as seen by the sender, 4 or 16 bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub yourip: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<usize>,
+}
+
+impl ExtendedHandshake {
+    /// Message id the peer registered for `extension`, zero means disabled
+    pub fn id(&self, extension: &str) -> Option<u8> {
+        self.m.get(extension).copied().filter(|&id| id != 0)
+    }
+
+    pub fn your_ip(&self) -> Option<IpAddr> {
+        let bytes: &[u8] = self.yourip.as_ref()?;
+        match bytes.len() {
+            4 => Some(IpAddr::from(<[u8; 4]>::try_from(bytes).ok()?)),
+            16 => Some(IpAddr::from(<[u8; 16]>::try_from(bytes).ok()?)),
+            _ => None,
+        }
+    }
+
+    pub fn set_your_ip(&mut self, ip: IpAddr) {
+        let bytes = match ip {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        self.yourip = Some(ByteBuf::from(bytes));
+    }
+}
+
+/// A protocol extension that plugs into the extension registry of a peer.
+///
+/// The payloads returned by the handlers are sent back to the peer as extended
+/// messages of this extension, they are dropped if the peer does not support it.
+pub trait Extension: Send {
+    /// Name of the extension in the `m` dictionary, like `ut_metadata`
+    fn name(&self) -> &'static str;
+
+    /// Adds the extension specific keys to our handshake
+    fn extend_handshake(&self, _handshake: &mut ExtendedHandshake) {}
+
+    /// Called when the extended handshake of the peer arrives
+    fn on_handshake(&mut self, _remote: &ExtendedHandshake) -> Result<Vec<Vec<u8>>> {
+        Ok(Vec::new())
+    }
+
+    /// Called for every message the peer sends for this extension
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>>;
+
+    /// Called periodically once the peer supports the extension, for the
+    /// messages that are not replies
+    fn tick(&mut self) -> Result<Vec<Vec<u8>>> {
+        Ok(Vec::new())
+    }
+}
+
+/// Extensions enabled on one peer connection. The local message id of an
+/// extension is its registration order, starting at one.
+#[derive(Default)]
+pub struct Extensions {
+    extensions: Vec<Box<dyn Extension>>,
+    remote: Option<ExtendedHandshake>,
+    /// Our TCP listen port, advertised as `p`
+    pub port: Option<u16>,
+}
+
+impl Extensions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers an extension, returning the id the peer has to use for it
+    pub fn register(&mut self, extension: Box<dyn Extension>) -> u8 {
+        assert!(
+            self.extensions.len() < u8::MAX as usize,
+            "too many extensions"
+        );
+        self.extensions.push(extension);
+        self.extensions.len() as u8
+    }
+
+    /// The handshake of the peer, once received
+    pub fn remote(&self) -> Option<&ExtendedHandshake> {
+        self.remote.as_ref()
+    }
+
+    /// Requests we may have outstanding with the peer
+    pub fn pipeline_limit(&self) -> usize {
+        self.remote
+            .as_ref()
+            .and_then(|r| r.reqq)
+            .unwrap_or(DEFAULT_REQQ)
+            .max(1)
+    }
+
+    /// Builds our handshake, `peer_ip` is the address of the peer as we see it
+    pub fn handshake(&self, peer_ip: Option<IpAddr>) -> ExtendedHandshake {
+        let mut handshake = ExtendedHandshake {
+            v: Some(crate::CLIENT_VERSION.to_string()),
+            p: self.port,
+            reqq: Some(LOCAL_REQQ),
+            ..Default::default()
+        };
+        if let Some(ip) = peer_ip {
+            handshake.set_your_ip(ip);
+        }
+        for (index, extension) in self.extensions.iter().enumerate() {
+            handshake
+                .m
+                .insert(extension.name().to_string(), index as u8 + 1);
+            extension.extend_handshake(&mut handshake);
+        }
+        handshake
+    }
+
+    /// Collects the periodic messages of the extensions the peer supports
+    pub fn tick(&mut self) -> Result<Vec<Message>> {
+        let Some(remote) = &self.remote else {
+            return Ok(Vec::new());
+        };
+        let mut messages = Vec::new();
+        for extension in &mut self.extensions {
+            if remote.id(extension.name()).is_some() {
+                let payloads = extension.tick()?;
+                messages.extend(reply(remote, extension.name(), payloads));
+            }
+        }
+        Ok(messages)
+    }
+
+    /// Dispatches an extended message to its extension, returning the messages
+    /// to send back to the peer.
+    pub fn handle(&mut self, message: &Message) -> Result<Vec<Message>> {
+        let (id, payload) = split_extended(message)?;
+        let mut replies = Vec::new();
+        if id == HANDSHAKE_ID {
+            let remote: ExtendedHandshake =
+                serde_bencode::from_bytes(payload).context("decoding extended handshake")?;
+            for extension in &mut self.extensions {
+                let payloads = extension.on_handshake(&remote)?;
+                replies.extend(reply(&remote, extension.name(), payloads));
+            }
+            self.remote = Some(remote);
+        } else if let Some(extension) = self.extensions.get_mut(id as usize - 1) {
+            let payloads = extension.on_message(payload)?;
+            if let Some(remote) = &self.remote {
+                replies.extend(reply(remote, extension.name(), payloads));
+            }
+        }
+        Ok(replies)
+    }
+}
+
+fn reply<'a>(
+    remote: &'a ExtendedHandshake,
+    name: &str,
+    payloads: Vec<Vec<u8>>,
+) -> impl Iterator<Item = Message> + 'a {
+    let id = remote.id(name);
+    payloads
+        .into_iter()
+        .filter_map(move |payload| Some(extended_message(id?, &payload)))
+}
+
+/// Builds an extended message, the payload starts with the extended message id
+pub fn extended_message(id: u8, payload: &[u8]) -> Message {
+    let mut bytes = Vec::with_capacity(payload.len() + 1);
+    bytes.push(id);
+    bytes.extend_from_slice(payload);
+    Message {
+        tag: MessageTag::Extended,
+        payload: bytes,
+    }
+}
+
+/// Splits the payload of an extended message in its id and content
+pub fn split_extended(message: &Message) -> Result<(u8, &[u8])> {
+    message
+        .payload
+        .split_first()
+        .map(|(id, payload)| (*id, payload))
+        .context("empty extended message")
+}
+
+#[cfg(test)]
+struct Echo;
+
+#[cfg(test)]
+impl Extension for Echo {
+    fn name(&self) -> &'static str {
+        "echo"
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>> {
+        Ok(vec![payload.to_vec()])
+    }
+}
+
+#[test]
+fn handshake_roundtrip() {
+    let mut extensions = Extensions::new();
+    extensions.port = Some(6881);
+    assert_eq!(extensions.register(Box::new(Echo)), 1);
+
+    let handshake = extensions.handshake(Some("10.0.0.2".parse().unwrap()));
+    let bytes = serde_bencode::to_bytes(&handshake).unwrap();
+    let parsed: ExtendedHandshake = serde_bencode::from_bytes(&bytes).unwrap();
+    assert_eq!(parsed.id("echo"), Some(1));
+    assert_eq!(parsed.p, Some(6881));
+    assert_eq!(parsed.reqq, Some(LOCAL_REQQ));
+    assert_eq!(parsed.your_ip(), Some("10.0.0.2".parse().unwrap()));
+    assert_eq!(parsed.v.as_deref(), Some(crate::CLIENT_VERSION));
+}
+
+#[test]
+fn dispatch_to_registered_extension() {
+    let mut extensions = Extensions::new();
+    extensions.register(Box::new(Echo));
+    assert_eq!(extensions.pipeline_limit(), DEFAULT_REQQ);
+
+    // The peer maps echo to id 7 and accepts 32 outstanding requests
+    let remote = b"d1:md4:echoi7ee4:reqqi32e1:v5:peer1e";
+    let replies = extensions
+        .handle(&extended_message(HANDSHAKE_ID, remote))
+        .unwrap();
+    assert!(replies.is_empty());
+    assert_eq!(extensions.pipeline_limit(), 32);
+    assert_eq!(extensions.remote().unwrap().v.as_deref(), Some("peer1"));
+
+    let replies = extensions.handle(&extended_message(1, b"ping")).unwrap();
+    assert_eq!(replies.len(), 1);
+    assert_eq!(replies[0].payload, b"\x07ping");
+
+    // Unknown ids are ignored
+    assert!(extensions
+        .handle(&extended_message(9, b"ping"))
+        .unwrap()
+        .is_empty());
+}
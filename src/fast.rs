@@ -0,0 +1,54 @@
+use std::net::IpAddr;
+
+use sha1::{Digest, Sha1};
+
+/// Pieces a choked peer may download from us, the allowed fast set (BEP 6)
+pub const ALLOWED_FAST_COUNT: usize = 10;
+
+/// The `count` pieces a peer at `ip` may request while choked, derived from
+/// its /24 network so reconnecting from another address of it gives nothing
+/// more. Only IPv4 peers get a set.
+pub fn allowed_fast_set(
+    ip: IpAddr,
+    info_hash: &[u8; 20],
+    pieces: usize,
+    count: usize,
+) -> Vec<usize> {
+    let IpAddr::V4(ip) = ip else {
+        return Vec::new();
+    };
+    let count = count.min(pieces);
+    let mut set = Vec::with_capacity(count);
+
+    let mut x = (u32::from(ip) & 0xffff_ff00).to_be_bytes().to_vec();
+    x.extend(info_hash);
+    while set.len() < count {
+        x = Sha1::digest(&x).to_vec();
+        for chunk in x.chunks_exact(4) {
+            if set.len() == count {
+                break;
+            }
+            let y = u32::from_be_bytes(chunk.try_into().expect("chunk is 4 bytes"));
+            let index = (y % pieces as u32) as usize;
+            if !set.contains(&index) {
+                set.push(index);
+            }
+        }
+    }
+    set
+}
+
+#[test]
+fn allowed_fast_set_of_bep_6() {
+    let ip = "80.4.4.200".parse().unwrap();
+    assert_eq!(
+        allowed_fast_set(ip, &[0xaa; 20], 1313, 7),
+        vec![1059, 431, 808, 1217, 287, 376, 1188]
+    );
+    assert_eq!(
+        allowed_fast_set(ip, &[0xaa; 20], 1313, 9),
+        vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508]
+    );
+    assert_eq!(allowed_fast_set(ip, &[0xaa; 20], 3, 10).len(), 3);
+    assert!(allowed_fast_set("::1".parse().unwrap(), &[0xaa; 20], 1313, 7).is_empty());
+}
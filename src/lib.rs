@@ -1,9 +1,62 @@
+pub mod alert;
+pub mod builder;
+pub mod config;
+pub mod connections;
+pub mod dht;
+pub mod extension;
+pub mod fast;
+pub mod logging;
+pub mod lsd;
+pub mod magnet;
+pub mod metadata;
+pub mod metrics;
+pub mod mse;
 pub mod peer;
+pub mod pex;
+pub mod picker;
+pub mod progress;
+pub mod ratelimit;
+pub mod reader;
+pub mod rpc;
+pub mod selection;
+pub mod session;
+pub mod smartban;
+pub mod storage;
+pub mod stream;
 pub mod torrent;
 pub mod tracker;
+pub mod transmission;
+pub mod transport;
+pub mod utp;
+pub mod watch;
 pub mod worker;
 
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use serde_json::{self, Value};
+use sha1::{Digest, Sha1};
+
+/// Client name and version, sent in the extended handshake and written as the
+/// creator of new torrents
+pub const CLIENT_VERSION: &str = concat!("oxitorrent/", env!("CARGO_PKG_VERSION"));
+
+/// 20 bytes out of the randomly keyed std hasher and the clock, unpredictable
+/// enough for ids, tokens and padding but not for long term keys
+pub(crate) fn random_bytes() -> [u8; 20] {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    let mut hasher = Sha1::new();
+    for _ in 0..4 {
+        let mut keyed = RandomState::new().build_hasher();
+        keyed.write_u128(nanos);
+        hasher.update(keyed.finish().to_be_bytes());
+    }
+    hasher.finalize().into()
+}
 
 pub fn decode_bencoded_value(encoded_value: &str) -> (Value, &str) {
     match encoded_value.chars().next() {
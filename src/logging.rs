@@ -0,0 +1,382 @@
+use std::fmt::{self, Display, Write as _};
+use std::future::Future;
+use std::io::Write as _;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Result};
+
+/// Environment variable with the filter directives, as `RUST_LOG` elsewhere
+pub const FILTER_ENV: &str = "RUST_LOG";
+
+/// Severity of a log event, from the most to the least severe
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    /// The default level raised `verbosity` times, as `-v` flags do
+    pub fn from_verbosity(verbosity: u8) -> Self {
+        match verbosity {
+            0 => Level::Info,
+            1 => Level::Debug,
+            _ => Level::Trace,
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "error" => Level::Error,
+            "warn" => Level::Warn,
+            "info" => Level::Info,
+            "debug" => Level::Debug,
+            "trace" => Level::Trace,
+            _ => bail!("unknown log level {s:?}"),
+        })
+    }
+}
+
+impl Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        })
+    }
+}
+
+/// Which events are written, as in `info,bittorrent_starter_rust::peer=trace`.
+/// A directive is a level, or a module path and a level that apply to that
+/// module and the ones inside it. `off` silences them. The longest matching
+/// path wins.
+#[derive(Debug, Clone)]
+pub struct Filter {
+    default: Option<Level>,
+    directives: Vec<(String, Option<Level>)>,
+}
+
+impl Filter {
+    /// Writes the events of `level` and the more severe ones
+    pub fn new(level: Level) -> Self {
+        Self {
+            default: Some(level),
+            directives: Vec::new(),
+        }
+    }
+
+    /// Adds comma separated directives, later ones override earlier ones
+    pub fn add_directives(&mut self, directives: &str) -> Result<()> {
+        let level = |s: &str| match s {
+            "off" => Ok(None),
+            s => s.parse().map(Some),
+        };
+        for directive in directives.split(',').map(str::trim) {
+            if directive.is_empty() {
+                continue;
+            }
+            match directive.split_once('=') {
+                Some((target, s)) => {
+                    let level = level(s)?;
+                    self.directives.retain(|(t, _)| t != target);
+                    self.directives.push((target.to_string(), level));
+                }
+                None => self.default = level(directive)?,
+            }
+        }
+        Ok(())
+    }
+
+    pub fn enabled(&self, level: Level, target: &str) -> bool {
+        let within = |path: &str| {
+            target
+                .strip_prefix(path)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+        };
+        let max = self
+            .directives
+            .iter()
+            .filter(|(path, _)| within(path))
+            .max_by_key(|(path, _)| path.len())
+            .map_or(self.default, |(_, level)| *level);
+        max.is_some_and(|max| level <= max)
+    }
+}
+
+impl FromStr for Filter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut filter = Self::new(Level::Error);
+        filter.add_directives(s)?;
+        Ok(filter)
+    }
+}
+
+/// How events are written to stderr
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    /// One line of text per event, fields as `key=value`
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => bail!("unknown log format {s:?}, expected text or json"),
+        }
+    }
+}
+
+impl Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Format::Text => "text",
+            Format::Json => "json",
+        })
+    }
+}
+
+struct Logger {
+    filter: Filter,
+    format: Format,
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+/// Writes the events `filter` lets through to stderr from now on. Nothing is
+/// written before, and later calls are ignored.
+pub fn init(filter: Filter, format: Format) {
+    let _ = LOGGER.set(Logger { filter, format });
+}
+
+#[doc(hidden)]
+pub fn enabled(level: Level, target: &str) -> bool {
+    LOGGER
+        .get()
+        .is_some_and(|logger| logger.filter.enabled(level, target))
+}
+
+#[doc(hidden)]
+pub fn emit(level: Level, target: &str, fields: &[(&str, &dyn Display)], message: fmt::Arguments) {
+    let Some(logger) = LOGGER.get() else {
+        return;
+    };
+    let span = Span::current();
+    let fields: Vec<(&str, String)> = span
+        .fields
+        .iter()
+        .map(|(key, value)| (*key, value.clone()))
+        .chain(fields.iter().map(|(key, value)| (*key, value.to_string())))
+        .collect();
+    let line = match logger.format {
+        Format::Text => {
+            let mut line = format!("{} {level:>5} {target}: {message}", timestamp());
+            for (key, value) in fields {
+                if value.contains(char::is_whitespace) || value.is_empty() {
+                    let _ = write!(line, " {key}={value:?}");
+                } else {
+                    let _ = write!(line, " {key}={value}");
+                }
+            }
+            line
+        }
+        Format::Json => {
+            let fields: serde_json::Map<String, serde_json::Value> = fields
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value.into()))
+                .collect();
+            serde_json::json!({
+                "timestamp": timestamp(),
+                "level": level.to_string(),
+                "target": target,
+                "message": message.to_string(),
+                "fields": fields,
+            })
+            .to_string()
+        }
+    };
+    let _ = writeln!(std::io::stderr().lock(), "{line}");
+}
+
+/// Current time in RFC 3339, UTC
+fn timestamp() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let seconds = now.as_secs();
+    let (year, month, day) = civil_date((seconds / 86_400) as i64);
+    let time = seconds % 86_400;
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        time / 3600,
+        time / 60 % 60,
+        time % 60,
+        now.subsec_millis()
+    )
+}
+
+/// Year, month and day of a number of days since 1970-01-01
+fn civil_date(days: i64) -> (i64, u32, u32) {
+    // Counted in eras of 400 years from 0000-03-01, leap days fall last
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month + 2) / 5 + 1) as u32;
+    let month = if month < 10 { month + 3 } else { month - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+tokio::task_local! {
+    static SPAN: Span;
+}
+
+/// Fields added to every event logged within it, such as the torrent or the
+/// peer a task works for. Spans nest: a span made inside another starts with
+/// its fields.
+#[derive(Debug, Clone, Default)]
+pub struct Span {
+    fields: Vec<(&'static str, String)>,
+}
+
+impl Span {
+    /// The span of the running task or closure, empty outside of any
+    pub fn current() -> Self {
+        SPAN.try_with(Span::clone).unwrap_or_default()
+    }
+
+    pub fn with(mut self, key: &'static str, value: impl Display) -> Self {
+        self.fields.push((key, value.to_string()));
+        self
+    }
+
+    /// Runs `future` within the span
+    pub fn instrument<F: Future>(self, future: F) -> impl Future<Output = F::Output> {
+        SPAN.scope(self, future)
+    }
+
+    /// Runs `f` within the span
+    pub fn in_scope<R>(self, f: impl FnOnce() -> R) -> R {
+        SPAN.sync_scope(self, f)
+    }
+}
+
+/// Logs an event at a level, with optional fields before the message, as in
+/// `event!(Level::Debug, { piece = index, bytes = length }, "piece {} written", index)`
+#[macro_export]
+macro_rules! event {
+    ($level:expr, { $($key:ident = $value:expr),* $(,)? }, $($arg:tt)+) => {{
+        let level = $level;
+        if $crate::logging::enabled(level, module_path!()) {
+            $crate::logging::emit(
+                level,
+                module_path!(),
+                &[$((stringify!($key), &$value as &dyn ::std::fmt::Display)),*],
+                format_args!($($arg)+),
+            );
+        }
+    }};
+    ($level:expr, $($arg:tt)+) => {
+        $crate::event!($level, {}, $($arg)+)
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => { $crate::event!($crate::logging::Level::Error, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => { $crate::event!($crate::logging::Level::Warn, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => { $crate::event!($crate::logging::Level::Info, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => { $crate::event!($crate::logging::Level::Debug, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)+) => { $crate::event!($crate::logging::Level::Trace, $($arg)+) };
+}
+
+#[test]
+fn filter_directives() {
+    let mut filter = Filter::new(Level::Info);
+    filter
+        .add_directives("crate::peer=trace, crate::peer::mse=off,crate::dht=warn")
+        .unwrap();
+    assert!(filter.enabled(Level::Info, "crate::worker"));
+    assert!(!filter.enabled(Level::Debug, "crate::worker"));
+    assert!(filter.enabled(Level::Trace, "crate::peer"));
+    assert!(filter.enabled(Level::Trace, "crate::peer::extension"));
+    assert!(!filter.enabled(Level::Error, "crate::peer::mse"));
+    assert!(!filter.enabled(Level::Info, "crate::dht"));
+    // Paths match whole modules only
+    assert!(!filter.enabled(Level::Trace, "crate::peers"));
+
+    filter.add_directives("debug,crate::dht=error").unwrap();
+    assert!(filter.enabled(Level::Debug, "crate::worker"));
+    assert!(!filter.enabled(Level::Warn, "crate::dht"));
+    assert!("verbose".parse::<Filter>().is_err());
+}
+
+#[test]
+fn dates_of_days() {
+    assert_eq!(civil_date(0), (1970, 1, 1));
+    assert_eq!(civil_date(11_016), (2000, 2, 29));
+    assert_eq!(civil_date(19_723), (2024, 1, 1));
+    assert_eq!(civil_date(-1), (1969, 12, 31));
+}
+
+#[tokio::test]
+async fn spans_nest_across_awaits() {
+    let outer = Span::current().with("torrent", "debian.iso");
+    let fields = outer
+        .instrument(async {
+            tokio::task::yield_now().await;
+            Span::current()
+                .with("peer", "10.0.0.1:6881")
+                .instrument(async { Span::current().fields })
+                .await
+        })
+        .await;
+    assert_eq!(
+        fields,
+        [
+            ("torrent", "debian.iso".to_string()),
+            ("peer", "10.0.0.1:6881".to_string())
+        ]
+    );
+    assert!(Span::current().fields.is_empty());
+}
@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use tokio::net::UdpSocket;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+
+use crate::{debug, warn};
+
+/// Multicast groups of local service discovery (BEP 14)
+pub const MULTICAST_V4: SocketAddr =
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(239, 192, 152, 143)), 6771);
+pub const MULTICAST_V6: SocketAddr = SocketAddr::new(
+    IpAddr::V6(Ipv6Addr::new(0xff15, 0, 0, 0, 0, 0, 0xefc0, 0x988f)),
+    6771,
+);
+/// Time between two announces of a torrent by a worker
+pub const INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// Announces of a torrent are never sent, or accepted from a host, more often
+const MIN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A `BT-SEARCH` message, announcing the torrents a host shares on `port`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Announce {
+    pub port: u16,
+    pub info_hashes: Vec<[u8; 20]>,
+    /// Random value of the sender, to recognize our own announces
+    pub cookie: Option<String>,
+}
+
+impl Announce {
+    /// Formats the message for the multicast group `host`
+    pub fn to_bytes(&self, host: &SocketAddr) -> Vec<u8> {
+        let mut message = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: {host}\r\nPort: {}\r\n",
+            self.port
+        );
+        for info_hash in &self.info_hashes {
+            message += &format!("Infohash: {}\r\n", hex::encode(info_hash));
+        }
+        if let Some(cookie) = &self.cookie {
+            message += &format!("cookie: {cookie}\r\n");
+        }
+        message += "\r\n\r\n";
+        message.into_bytes()
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        let text = std::str::from_utf8(bytes).context("announce is not text")?;
+        let mut lines = text.split("\r\n");
+        if lines.next() != Some("BT-SEARCH * HTTP/1.1") {
+            bail!("not a BT-SEARCH message");
+        }
+
+        let mut port = None;
+        let mut info_hashes = Vec::new();
+        let mut cookie = None;
+        for line in lines.take_while(|line| !line.is_empty()) {
+            let Some((name, value)) = line.split_once(':') else {
+                bail!("invalid header line");
+            };
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "port" => port = Some(value.parse().context("invalid port")?),
+                "infohash" => {
+                    let info_hash = hex::decode(value).context("invalid info hash")?;
+                    info_hashes.push(info_hash.try_into().ok().context("invalid info hash")?);
+                }
+                "cookie" => cookie = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        let Some(port) = port.filter(|&port| port != 0) else {
+            bail!("missing port");
+        };
+        if info_hashes.is_empty() {
+            bail!("no info hash");
+        }
+        Ok(Self {
+            port,
+            info_hashes,
+            cookie,
+        })
+    }
+}
+
+/// Local service discovery: announces torrents to the multicast groups and
+/// reports the peers announcing on the local network
+#[derive(Clone)]
+pub struct Lsd {
+    shared: Arc<Shared>,
+    _receivers: Arc<Receivers>,
+}
+
+/// Stops the receive loops once every handle is dropped
+struct Receivers(Vec<JoinHandle<()>>);
+
+impl Drop for Receivers {
+    fn drop(&mut self) {
+        self.0.iter().for_each(JoinHandle::abort);
+    }
+}
+
+struct Shared {
+    cookie: String,
+    /// Multicast groups joined, with the socket announcing to each
+    groups: Vec<(SocketAddr, UdpSocket)>,
+    peers: broadcast::Sender<([u8; 20], SocketAddr)>,
+    last_sent: Mutex<HashMap<[u8; 20], Instant>>,
+    last_received: Mutex<HashMap<(IpAddr, [u8; 20]), Instant>>,
+}
+
+impl Lsd {
+    /// Joins the IPv4 and IPv6 groups, failing only when neither works
+    pub async fn bind() -> Result<Self> {
+        Self::with_groups(&[MULTICAST_V4, MULTICAST_V6]).await
+    }
+
+    pub async fn with_groups(groups: &[SocketAddr]) -> Result<Self> {
+        let mut joined = Vec::new();
+        let mut receivers = Vec::new();
+        for &group in groups {
+            match join(group).await {
+                Ok((receiver, sender)) => {
+                    joined.push((group, sender));
+                    receivers.push(receiver);
+                }
+                Err(e) => warn!(
+                    { group = group },
+                    "joining the multicast group failed: {e:#}"
+                ),
+            }
+        }
+        if joined.is_empty() {
+            bail!("no multicast group could be joined");
+        }
+
+        let cookie = hex::encode(&crate::random_bytes()[..8]);
+        let (peers, _) = broadcast::channel(64);
+        let shared = Arc::new(Shared {
+            cookie,
+            groups: joined,
+            peers,
+            last_sent: Mutex::new(HashMap::new()),
+            last_received: Mutex::new(HashMap::new()),
+        });
+        let handles = receivers
+            .into_iter()
+            .map(|socket| tokio::spawn(receive(shared.clone(), socket)))
+            .collect();
+        Ok(Self {
+            shared,
+            _receivers: Arc::new(Receivers(handles)),
+        })
+    }
+
+    /// Peers announced by other hosts, with the info hash they announced
+    pub fn subscribe(&self) -> broadcast::Receiver<([u8; 20], SocketAddr)> {
+        self.shared.peers.subscribe()
+    }
+
+    /// Announces that we share the torrent on `port`. Returns false without
+    /// sending when the torrent was announced less than a minute ago.
+    pub async fn announce(&self, info_hash: [u8; 20], port: u16) -> Result<bool> {
+        {
+            let mut last_sent = self.shared.last_sent.lock().expect("lsd lock poisoned");
+            if last_sent
+                .get(&info_hash)
+                .is_some_and(|last| last.elapsed() < MIN_INTERVAL)
+            {
+                return Ok(false);
+            }
+            last_sent.insert(info_hash, Instant::now());
+        }
+
+        let announce = Announce {
+            port,
+            info_hashes: vec![info_hash],
+            cookie: Some(self.shared.cookie.clone()),
+        };
+        let mut sent = false;
+        for (group, socket) in &self.shared.groups {
+            match socket.send_to(&announce.to_bytes(group), group).await {
+                Ok(_) => sent = true,
+                Err(e) => debug!({ group = group }, "local announce failed: {e}"),
+            }
+        }
+        if !sent {
+            bail!("no announce could be sent");
+        }
+        Ok(true)
+    }
+}
+
+/// Binds a socket receiving the group and one sending to it. Binding to the
+/// group address, rather than the unspecified one, lets the IPv4 and IPv6
+/// sockets share the port.
+async fn join(group: SocketAddr) -> Result<(UdpSocket, UdpSocket)> {
+    let receiver = match UdpSocket::bind(group).await {
+        Ok(socket) => socket,
+        Err(_) => {
+            let unspecified = match group {
+                SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            };
+            UdpSocket::bind((unspecified, group.port())).await?
+        }
+    };
+    let sender = match group.ip() {
+        IpAddr::V4(ip) => {
+            receiver.join_multicast_v4(ip, Ipv4Addr::UNSPECIFIED)?;
+            UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?
+        }
+        IpAddr::V6(ip) => {
+            receiver.join_multicast_v6(&ip, 0)?;
+            UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await?
+        }
+    };
+    Ok((receiver, sender))
+}
+
+async fn receive(shared: Arc<Shared>, socket: UdpSocket) {
+    let mut buffer = vec![0; 1500];
+    loop {
+        let Ok((length, from)) = socket.recv_from(&mut buffer).await else {
+            continue;
+        };
+        let announce = match Announce::parse(&buffer[..length]) {
+            Ok(announce) => announce,
+            Err(e) => {
+                debug!({ from = from }, "invalid lsd announce: {e:#}");
+                continue;
+            }
+        };
+        // Our own announces come back through the multicast loopback
+        if announce.cookie.as_ref() == Some(&shared.cookie) {
+            continue;
+        }
+        let mut last_received = shared.last_received.lock().expect("lsd lock poisoned");
+        last_received.retain(|_, received| received.elapsed() < MIN_INTERVAL);
+        for info_hash in announce.info_hashes {
+            if last_received
+                .insert((from.ip(), info_hash), Instant::now())
+                .is_none()
+            {
+                let _ = shared
+                    .peers
+                    .send((info_hash, SocketAddr::new(from.ip(), announce.port)));
+            }
+        }
+    }
+}
+
+#[test]
+fn announce_format() {
+    let announce = Announce {
+        port: 6881,
+        info_hashes: vec![[0xab; 20], [0x01; 20]],
+        cookie: Some("c00k1e".into()),
+    };
+    let bytes = announce.to_bytes(&MULTICAST_V4);
+    assert!(bytes.starts_with(
+        b"BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\nPort: 6881\r\nInfohash: abab"
+    ));
+    assert!(bytes.ends_with(b"\r\n\r\n\r\n"));
+    assert_eq!(Announce::parse(&bytes).unwrap(), announce);
+
+    let other = b"BT-SEARCH * HTTP/1.1\r\nhost: [ff15::efc0:988f]:6771\r\nport: 51413\r\ninfohash: ABABABABABABABABABABABABABABABABABABABAB\r\n\r\n\r\n";
+    let parsed = Announce::parse(other).unwrap();
+    assert_eq!(parsed.port, 51413);
+    assert_eq!(parsed.info_hashes, vec![[0xab; 20]]);
+    assert_eq!(parsed.cookie, None);
+    assert!(Announce::parse(b"M-SEARCH * HTTP/1.1\r\n\r\n").is_err());
+}
+
+#[tokio::test]
+async fn discover_announces_on_the_group() {
+    // A free port, so the test does not need the real LSD port
+    let port = std::net::UdpSocket::bind("0.0.0.0:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let group = SocketAddr::new(MULTICAST_V4.ip(), port);
+    let lsd = Lsd::with_groups(&[group]).await.unwrap();
+    let mut peers = lsd.subscribe();
+
+    // Our own announce is filtered by its cookie, and not repeated too fast
+    assert!(lsd.announce([1; 20], 1111).await.unwrap());
+    assert!(!lsd.announce([1; 20], 1111).await.unwrap());
+
+    let other = Announce {
+        port: 2222,
+        info_hashes: vec![[2; 20]],
+        cookie: Some("other".into()),
+    };
+    let socket = UdpSocket::bind("0.0.0.0:0").await.unwrap();
+    for _ in 0..2 {
+        socket
+            .send_to(&other.to_bytes(&group), group)
+            .await
+            .unwrap();
+    }
+
+    let (info_hash, addr) = tokio::time::timeout(Duration::from_secs(5), peers.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(info_hash, [2; 20]);
+    assert_eq!(addr.port(), 2222);
+    // The repeated announce is dropped
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(peers.try_recv().is_err());
+}
@@ -0,0 +1,260 @@
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+
+use crate::dht::Dht;
+use crate::metadata;
+use crate::peer::Peer;
+use crate::torrent::{Info, Torrent};
+use crate::{tracker, warn};
+
+/// Most file indices a `so` parameter may select
+const MAX_SELECT_ONLY: usize = 100_000;
+
+/// A parsed magnet URI (BEP 9), with the peer address (`x.pe`) and select
+/// only (`so`, BEP 53) extensions.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Magnet {
+    pub info_hash: [u8; 20],
+    /// Display name, `dn`
+    pub name: Option<String>,
+    /// Tracker urls, `tr`
+    pub trackers: Vec<String>,
+    /// Peers to connect to directly, `x.pe`
+    pub peers: Vec<SocketAddr>,
+    /// Web seeds, `ws`
+    pub web_seeds: Vec<String>,
+    /// Indices of the files to download, `so`, empty means every file
+    pub select_only: Vec<usize>,
+}
+
+impl Magnet {
+    /// Builds a torrent from the info dictionary downloaded for this magnet,
+    /// failing if it does not match the info hash.
+    pub fn to_torrent(&self, metadata: &[u8]) -> Result<Torrent> {
+        let info = Info::from_bytes(metadata)?;
+        let announce_list = (self.trackers.len() > 1)
+            .then(|| self.trackers.iter().map(|t| vec![t.clone()]).collect());
+        let torrent = Torrent {
+            announce: self.trackers.first().cloned(),
+            announce_list,
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            url_list: self.web_seeds.clone(),
+            info,
+        };
+        if torrent.info_hash()? != self.info_hash {
+            bail!("metadata does not match the magnet info hash");
+        }
+        Ok(torrent)
+    }
+
+    /// Downloads the info dictionary from the first peer that has it, among
+    /// the peers of the link, of its trackers and of the DHT if given.
+    /// `port` is the one announced to the trackers.
+    pub async fn resolve(&self, port: u16, dht: Option<&Dht>) -> Result<Torrent> {
+        let mut peers = self.peers.clone();
+        for tracker in &self.trackers {
+            // The length is unknown until the metadata arrives, any non zero
+            // value announces us as a leecher
+            match tracker::announce(tracker, &self.info_hash, port, 1, None, tracker::TIMEOUT).await
+            {
+                Ok(response) => peers.extend(response.peers.0.into_iter().map(SocketAddr::V4)),
+                Err(e) => warn!({ tracker = tracker }, "announce failed: {e:#}"),
+            }
+        }
+        if let Some(dht) = dht {
+            peers.extend(dht.get_peers(self.info_hash).await);
+        }
+
+        for address in peers {
+            let metadata = async {
+                let mut peer = Peer::connect_peer(address, self.info_hash).await?;
+                metadata::fetch_metadata(&mut peer, self.info_hash).await
+            };
+            match metadata.await {
+                Ok(metadata) => return self.to_torrent(&metadata),
+                Err(e) => warn!({ peer = address }, "fetching metadata failed: {e:#}"),
+            }
+        }
+        bail!("no peer provided the metadata")
+    }
+}
+
+impl FromStr for Magnet {
+    type Err = anyhow::Error;
+
+    fn from_str(uri: &str) -> Result<Self> {
+        let query = uri
+            .strip_prefix("magnet:?")
+            .context("magnet link must start with magnet:?")?;
+        let params: Vec<(String, String)> =
+            serde_urlencoded::from_str(query).context("invalid magnet query")?;
+
+        let mut info_hash = None;
+        let mut magnet = Magnet::default();
+        for (key, value) in params {
+            // Repeated keys may be numbered, as in `tr.1`
+            let key = match key.rsplit_once('.') {
+                Some((key, n)) if n.chars().all(|c| c.is_ascii_digit()) => key.to_string(),
+                _ => key,
+            };
+            match key.as_str() {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(parse_info_hash(hash)?);
+                    }
+                }
+                "dn" => magnet.name = Some(value),
+                "tr" => magnet.trackers.push(value),
+                "ws" => magnet.web_seeds.push(value),
+                "x.pe" => {
+                    // Hostnames can not be resolved here, they are skipped
+                    if let Ok(peer) = value.parse() {
+                        magnet.peers.push(peer);
+                    }
+                }
+                "so" => magnet.select_only = parse_select_only(&value)?,
+                _ => {}
+            }
+        }
+        magnet.info_hash = info_hash.context("magnet link has no urn:btih exact topic")?;
+        Ok(magnet)
+    }
+}
+
+/// Info hashes are either 40 hex characters or 32 base32 characters
+fn parse_info_hash(hash: &str) -> Result<[u8; 20]> {
+    let bytes = match hash.len() {
+        40 => hex::decode(hash).context("invalid hex info hash")?,
+        32 => base32_decode(hash).context("invalid base32 info hash")?,
+        len => bail!("invalid info hash length {len}"),
+    };
+    Ok(bytes.try_into().expect("length is 20"))
+}
+
+/// Decodes RFC 4648 base32 without padding
+fn base32_decode(input: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(input.len() * 5 / 8);
+    let mut buffer: u64 = 0;
+    let mut bits = 0;
+    for c in input.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | value as u64;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+/// Parses a list of file indices and inclusive ranges, like `0,2,4-6`. The
+/// link is untrusted, so at most `MAX_SELECT_ONLY` indices are accepted.
+fn parse_select_only(value: &str) -> Result<Vec<usize>> {
+    let mut indices = Vec::new();
+    for part in value.split(',').filter(|p| !p.is_empty()) {
+        match part.split_once('-') {
+            Some((start, end)) => {
+                let start: usize = start.parse().context("invalid so range")?;
+                let end: usize = end.parse().context("invalid so range")?;
+                if start > end {
+                    bail!("invalid so range {part}, its start is past its end");
+                }
+                if end - start >= MAX_SELECT_ONLY - indices.len() {
+                    bail!("so selects more than {MAX_SELECT_ONLY} files");
+                }
+                indices.extend(start..=end);
+            }
+            None => indices.push(part.parse().context("invalid so index")?),
+        }
+        if indices.len() > MAX_SELECT_ONLY {
+            bail!("so selects more than {MAX_SELECT_ONLY} files");
+        }
+    }
+    Ok(indices)
+}
+
+#[test]
+fn parse_hex_magnet() {
+    let magnet: Magnet = "magnet:?xt=urn:btih:ad42ce8109f54c99613ce38f9b4d87e70f24a165&dn=magnet1.gif&tr=http%3A%2F%2Fbittorrent-test-tracker.codecrafters.io%2Fannounce"
+        .parse()
+        .unwrap();
+    assert_eq!(
+        hex::encode(magnet.info_hash),
+        "ad42ce8109f54c99613ce38f9b4d87e70f24a165"
+    );
+    assert_eq!(magnet.name.as_deref(), Some("magnet1.gif"));
+    assert_eq!(
+        magnet.trackers,
+        vec!["http://bittorrent-test-tracker.codecrafters.io/announce".to_string()]
+    );
+}
+
+#[test]
+fn parse_base32_magnet() {
+    let magnet: Magnet = "magnet:?xt=urn:btih:VVBM5AIJ6VGJSYJ44OHZWTMH44HSJILF&tr.1=udp%3A%2F%2Fa&tr.2=http%3A%2F%2Fb&x.pe=10.0.0.1%3A6881&x.pe=%5B%3A%3A1%5D%3A51413&x.pe=host.example%3A1&ws=http%3A%2F%2Fmirror%2F&so=0,2,4-6"
+        .parse()
+        .unwrap();
+    assert_eq!(
+        hex::encode(magnet.info_hash),
+        "ad42ce8109f54c99613ce38f9b4d87e70f24a165"
+    );
+    assert_eq!(magnet.trackers.len(), 2);
+    assert_eq!(
+        magnet.peers,
+        vec![
+            "10.0.0.1:6881".parse().unwrap(),
+            "[::1]:51413".parse().unwrap()
+        ]
+    );
+    assert_eq!(magnet.web_seeds, vec!["http://mirror/".to_string()]);
+    assert_eq!(magnet.select_only, vec![0, 2, 4, 5, 6]);
+}
+
+#[test]
+fn reject_unbounded_select_only() {
+    let hash = "ad42ce8109f54c99613ce38f9b4d87e70f24a165";
+    let parse = |so: &str| format!("magnet:?xt=urn:btih:{hash}&so={so}").parse::<Magnet>();
+    assert!(parse("0-18446744073709551615").is_err());
+    assert!(parse("5-2").is_err());
+    assert!(parse("0-99999,100000").is_err());
+    assert_eq!(parse("3-3").unwrap().select_only, vec![3]);
+    assert_eq!(parse("0-99999").unwrap().select_only.len(), MAX_SELECT_ONLY);
+}
+
+#[test]
+fn reject_invalid_magnet() {
+    assert!("http://example.com".parse::<Magnet>().is_err());
+    assert!("magnet:?dn=foo".parse::<Magnet>().is_err());
+    assert!("magnet:?xt=urn:btih:abcd".parse::<Magnet>().is_err());
+}
+
+#[test]
+fn torrent_from_metadata_keeps_info_hash() {
+    use sha1::{Digest, Sha1};
+
+    // Keys unknown to us must survive so the info hash still matches
+    let metadata = b"d5:filesld4:attr1:p6:lengthi3e4:pathl1:aeed6:lengthi5e4:pathl1:beee4:name3:dir12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:x-extrai1ee";
+    let magnet = Magnet {
+        info_hash: Sha1::digest(metadata).into(),
+        trackers: vec!["http://a/announce".to_string()],
+        ..Default::default()
+    };
+    let torrent = magnet.to_torrent(metadata).unwrap();
+    assert_eq!(torrent.info.length(), 8);
+    assert_eq!(torrent.announce.as_deref(), Some("http://a/announce"));
+
+    let other = Magnet {
+        info_hash: [0; 20],
+        ..Default::default()
+    };
+    assert!(other.to_torrent(metadata).is_err());
+}
@@ -1,21 +1,163 @@
 use anyhow::{self, Context, Result};
+use bittorrent_starter_rust::builder::TorrentBuilder;
+use bittorrent_starter_rust::config::{Config, LimitsConfig};
+use bittorrent_starter_rust::dht::{Dht, NodeId};
+use bittorrent_starter_rust::logging::{self, Filter, Format, Level, Span};
+use bittorrent_starter_rust::magnet::Magnet;
+use bittorrent_starter_rust::metadata::MetadataExtension;
+use bittorrent_starter_rust::metrics::{MetricsServer, TorrentMetrics};
+use bittorrent_starter_rust::mse::EncryptionPolicy;
 use bittorrent_starter_rust::peer::{self, *};
+use bittorrent_starter_rust::progress::Progress;
+use bittorrent_starter_rust::rpc::{RpcClient, RpcServer};
+use bittorrent_starter_rust::selection::FileSelection;
+use bittorrent_starter_rust::session::{Session, SessionConfig, TorrentHandle, TorrentState};
+use bittorrent_starter_rust::stream::StreamServer;
 use bittorrent_starter_rust::torrent::Torrent;
-use bittorrent_starter_rust::tracker::{self, TrackerRequest, TrackerResponse};
+use bittorrent_starter_rust::tracker;
+use bittorrent_starter_rust::transmission::TransmissionServer;
+use bittorrent_starter_rust::watch::{self, CompletionActions, Watcher};
+use bittorrent_starter_rust::{debug, info, warn};
 use clap::{Parser, Subcommand};
+use serde_json::{json, Value};
 use sha1::{Digest, Sha1};
 use std::fs;
 use std::io::Write;
-use std::net::SocketAddrV4;
-use std::path::PathBuf;
-
-const BLOCK_MAX: u32 = 16384;
+use std::net::{SocketAddr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about=None)]
 struct Args {
     #[command(subcommand)]
     command: Commands,
+    /// Log more, -v for debug and -vv for trace. RUST_LOG directives such as
+    /// bittorrent_starter_rust::peer=trace apply on top.
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+    /// Log as text or as JSON lines
+    #[arg(long, default_value_t = Format::Text, global = true)]
+    log_format: Format,
+    /// Unix socket of the daemon, $XDG_RUNTIME_DIR/oxitorrent/rpc.sock by
+    /// default
+    #[arg(long, global = true)]
+    socket: Option<PathBuf>,
+    /// Config file, $XDG_CONFIG_HOME/oxitorrent/config.toml by default.
+    /// Flags override what it sets.
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+}
+
+/// Bandwidth limits in KiB/s, zero for none, the config ones when missing
+#[derive(Debug, clap::Args)]
+#[clap(rename_all = "snake_case")]
+struct RateArgs {
+    /// Download rate of everything
+    #[arg(long)]
+    download_limit: Option<u64>,
+    /// Upload rate of everything
+    #[arg(long)]
+    upload_limit: Option<u64>,
+    /// Download rate of each peer
+    #[arg(long)]
+    peer_download_limit: Option<u64>,
+    /// Upload rate of each peer
+    #[arg(long)]
+    peer_upload_limit: Option<u64>,
+}
+
+impl RateArgs {
+    /// Overrides the limits of the config with the given ones
+    fn apply(&self, limits: &mut LimitsConfig) {
+        let pairs = [
+            (self.download_limit, &mut limits.download),
+            (self.upload_limit, &mut limits.upload),
+            (self.peer_download_limit, &mut limits.peer_download),
+            (self.peer_upload_limit, &mut limits.peer_upload),
+        ];
+        for (flag, limit) in pairs {
+            if let Some(flag) = flag {
+                *limit = flag;
+            }
+        }
+    }
+}
+
+/// How `download` and `serve` find and talk to peers
+#[derive(Debug, clap::Args)]
+#[clap(rename_all = "snake_case")]
+struct DownloadArgs {
+    /// Only get peers from the trackers
+    #[arg(long)]
+    no_dht: bool,
+    /// DHT node to join through instead of the public routers, as host:port
+    #[arg(long = "dht-bootstrap")]
+    dht_bootstrap: Vec<String>,
+    /// Do not look for peers on the local network
+    #[arg(long)]
+    no_lsd: bool,
+    /// Do not exchange peer lists with peers
+    #[arg(long)]
+    no_pex: bool,
+    /// Peer connection encryption: disabled, enabled or forced
+    #[arg(long)]
+    encryption: Option<EncryptionPolicy>,
+    /// Only connect to peers over TCP
+    #[arg(long)]
+    no_utp: bool,
+    #[command(flatten)]
+    rates: RateArgs,
+    /// Most peer connections of the torrent
+    #[arg(long)]
+    max_peers: Option<usize>,
+    /// Most peer connections of every torrent together
+    #[arg(long)]
+    max_connections: Option<usize>,
+    /// Files to download, by index, index range or glob, as in 0,3-5,*.mkv
+    #[arg(long)]
+    files: Option<FileSelection>,
+    /// Download pieces in order, to play files while they download
+    #[arg(long)]
+    sequential: bool,
+    /// Address to serve Prometheus metrics on, at /metrics
+    #[arg(long)]
+    metrics: Option<SocketAddr>,
+    /// Show live progress, peers, trackers and files on the terminal
+    #[arg(long)]
+    progress: bool,
+}
+
+impl DownloadArgs {
+    /// The options of `config` overridden by the flags
+    fn options(self, mut config: Config) -> DownloadOptions {
+        config.dht &= !self.no_dht;
+        if !self.dht_bootstrap.is_empty() {
+            config.dht_bootstrap = self.dht_bootstrap;
+        }
+        config.lsd &= !self.no_lsd;
+        config.pex &= !self.no_pex;
+        config.utp &= !self.no_utp;
+        if let Some(encryption) = self.encryption {
+            config.encryption = encryption;
+        }
+        self.rates.apply(&mut config.limits);
+        if let Some(max_peers) = self.max_peers {
+            config.limits.max_peers = max_peers;
+        }
+        if let Some(max_connections) = self.max_connections {
+            config.limits.max_connections = max_connections;
+        }
+        DownloadOptions {
+            config,
+            files: self.files,
+            sequential: self.sequential,
+            metrics: self.metrics,
+            progress: self.progress,
+        }
+    }
 }
 
 #[derive(Debug, Subcommand)]
@@ -37,19 +179,198 @@ enum Commands {
     DownloadPiece {
         #[arg(short)]
         output: PathBuf,
-        torrent: PathBuf,
+        /// Path to a .torrent file or a magnet link
+        torrent: String,
         piece_index: usize,
     },
     Download {
         #[arg(short)]
         output: PathBuf,
-        torrent: PathBuf,
+        /// Path to a .torrent file or a magnet link
+        torrent: String,
+        #[command(flatten)]
+        options: DownloadArgs,
+    },
+    /// Downloads a torrent and serves its files over HTTP meanwhile, with
+    /// support for byte ranges
+    Serve {
+        #[arg(short)]
+        output: PathBuf,
+        /// Path to a .torrent file or a magnet link
+        torrent: String,
+        /// Address the HTTP server listens on
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        http: SocketAddr,
+        #[command(flatten)]
+        options: DownloadArgs,
+    },
+    Magnet {
+        #[arg(short)]
+        output: PathBuf,
+        magnet: String,
+    },
+    /// Looks up the peers of a torrent in the DHT
+    Dht {
+        /// Info hash in hex, magnet link or path to a .torrent file
+        torrent: String,
+        /// UDP port of our DHT node, the one of the config by default
+        #[arg(long)]
+        port: Option<u16>,
+        /// DHT node to join through instead of the public routers, as host:port
+        #[arg(long = "dht-bootstrap")]
+        dht_bootstrap: Vec<String>,
+    },
+    /// Runs torrents in the background, controlled over JSON-RPC on a Unix
+    /// socket by the commands below
+    Daemon {
+        /// Address peers connect to
+        #[arg(long)]
+        listen: Option<SocketAddr>,
+        /// Only get peers from the trackers
+        #[arg(long)]
+        no_dht: bool,
+        /// Do not look for peers on the local network
+        #[arg(long)]
+        no_lsd: bool,
+        /// Do not exchange peer lists with peers
+        #[arg(long)]
+        no_pex: bool,
+        /// Only connect to peers over TCP
+        #[arg(long)]
+        no_utp: bool,
+        /// Peer connection encryption: disabled, enabled or forced
+        #[arg(long)]
+        encryption: Option<EncryptionPolicy>,
+        /// Download rate of every torrent together, in KiB/s
+        #[arg(long)]
+        download_limit: Option<u64>,
+        /// Upload rate of every torrent together, in KiB/s
+        #[arg(long)]
+        upload_limit: Option<u64>,
+        /// Most peer connections of every torrent together
+        #[arg(long)]
+        max_connections: Option<usize>,
+        /// Address to serve Prometheus metrics on, at /metrics
+        #[arg(long)]
+        metrics: Option<SocketAddr>,
+        /// Address to speak the Transmission RPC protocol on, as in
+        /// 127.0.0.1:9091. It has no authentication, keep it on localhost.
+        #[arg(long)]
+        transmission: Option<SocketAddr>,
+        /// Where torrents added over the Transmission protocol or from the
+        /// watch directory go, the one of the config by default
+        #[arg(long)]
+        download_dir: Option<PathBuf>,
+        /// Directory to add the .torrent files and .magnet files, holding a
+        /// magnet link, dropped in
+        #[arg(long)]
+        watch: Option<PathBuf>,
+        /// Directory completed torrents of the watch directory are moved to
+        #[arg(long)]
+        move_to: Option<PathBuf>,
+        /// Shell command run when a torrent of the watch directory
+        /// completes, with TORRENT_NAME, TORRENT_HASH, TORRENT_PATH and
+        /// TORRENT_SIZE set
+        #[arg(long)]
+        on_complete: Option<String>,
+        /// Ratio of uploaded bytes to length at which completed torrents of
+        /// the watch directory are removed
+        #[arg(long)]
+        seed_ratio: Option<f64>,
+    },
+    /// Adds a torrent to the daemon
+    Add {
+        #[arg(short)]
+        output: PathBuf,
+        /// Path to a .torrent file or a magnet link
+        torrent: String,
+    },
+    /// Lists the torrents of the daemon
+    List {
+        /// Print the JSON result
+        #[arg(long)]
+        json: bool,
+    },
+    /// Pauses a torrent of the daemon
+    Pause {
+        info_hash: String,
     },
+    /// Resumes a paused torrent of the daemon
+    Resume {
+        info_hash: String,
+    },
+    /// Removes a torrent from the daemon, its data stays on disk
+    Remove {
+        info_hash: String,
+    },
+    /// Totals of the daemon
+    Stats {
+        /// Print the JSON result
+        #[arg(long)]
+        json: bool,
+    },
+    /// Sets the rates of every torrent of the daemon together, in KiB/s, zero
+    /// or missing for none
+    SetLimits {
+        #[arg(long)]
+        download_limit: Option<u64>,
+        #[arg(long)]
+        upload_limit: Option<u64>,
+    },
+    Create {
+        #[arg(short)]
+        output: PathBuf,
+        /// File or directory to create the torrent from
+        path: PathBuf,
+        /// Tracker url, comma separated urls form a single tier
+        #[arg(short, long = "announce")]
+        announce: Vec<String>,
+        /// Piece length in bytes, picked from the content size when missing
+        #[arg(long)]
+        piece_length: Option<usize>,
+        #[arg(long)]
+        name: Option<String>,
+        #[arg(long)]
+        comment: Option<String>,
+        #[arg(long)]
+        created_by: Option<String>,
+        /// Creation date in seconds since the unix epoch, defaults to now
+        #[arg(long)]
+        creation_date: Option<i64>,
+        #[arg(long)]
+        no_creation_date: bool,
+        #[arg(long)]
+        private: bool,
+        #[arg(long)]
+        source: Option<String>,
+        #[arg(long = "web-seed")]
+        web_seeds: Vec<String>,
+    },
+    /// Inspects the configuration
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum ConfigCommand {
+    /// Prints the configuration in effect, the file merged over the defaults
+    Show,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
+    let mut filter = Filter::new(Level::from_verbosity(args.verbose));
+    if let Ok(directives) = std::env::var(logging::FILTER_ENV) {
+        filter
+            .add_directives(&directives)
+            .with_context(|| format!("invalid {}", logging::FILTER_ENV))?;
+    }
+    logging::init(filter, args.log_format);
+    // Commands that do without the config work whatever the file holds
+    let config_file = args.config;
     match args.command {
         Commands::Decode { value } => {
             let decoded_value = bittorrent_starter_rust::decode_bencoded_value(&value);
@@ -59,28 +380,37 @@ async fn main() -> Result<()> {
         Commands::Info { torrent } => {
             let torrent = read_torrent(torrent)?;
             let info_hash = torrent.info_hash()?;
-            println!("Tracker URL: {}", torrent.announce);
-            println!("Length: {}", torrent.info.length);
+            println!("Tracker URL: {}", torrent.announce.unwrap_or_default());
+            println!("Length: {}", torrent.info.length());
             println!("Info Hash: {}", hex::encode(info_hash));
             println!("Piece Length: {}", torrent.info.plength);
             println!("Piece Hashes:");
-            for piece in torrent.info.pieces.0 {
+            for piece in &torrent.info.pieces.0 {
                 println!("{}", hex::encode(piece));
             }
+            // Indexes to choose files with `download --files`
+            if let Some(files) = &torrent.info.files {
+                println!("Files:");
+                for (index, file) in files.iter().enumerate() {
+                    println!("{index}: {} ({} bytes)", file.path.join("/"), file.length);
+                }
+            }
         }
         Commands::Peers { torrent } => {
+            let config = use_config(&config_file)?;
             let torrent = read_torrent(torrent)?;
 
-            let peers = get_peers(&torrent).await?;
+            let peers = get_peers(&torrent, &config).await?;
             for peer in peers {
                 println!("{peer}");
             }
         }
         Commands::Handshake { torrent, peer } => {
+            use_config(&config_file)?;
             let torrent = read_torrent(torrent)?;
             let info_hash = torrent.info_hash()?;
 
-            let peer = peer.parse::<SocketAddrV4>()?;
+            let peer = peer.parse::<SocketAddr>()?;
             let peer = Peer::connect_peer(peer, info_hash).await?;
 
             println!("Peer ID: {}", hex::encode(peer.peer_id));
@@ -90,93 +420,637 @@ async fn main() -> Result<()> {
             torrent,
             piece_index,
         } => {
-            download_piece(torrent, output, piece_index).await?;
+            let config = use_config(&config_file)?;
+            let torrent = load_torrent(&torrent, &config).await?;
+            download_piece(torrent, output, piece_index, &config).await?;
+        }
+        Commands::Download {
+            output,
+            torrent,
+            options,
+        } => {
+            let config = use_config(&config_file)?;
+            let torrent = load_torrent(&torrent, &config).await?;
+            download(torrent, output, options.options(config)).await?;
         }
-        Commands::Download { output, torrent } => {
-            download(torrent, output).await?;
+        Commands::Serve {
+            output,
+            torrent,
+            http,
+            options,
+        } => {
+            let config = use_config(&config_file)?;
+            let torrent = load_torrent(&torrent, &config).await?;
+            serve(torrent, output, http, options.options(config)).await?;
+        }
+        Commands::Magnet { output, magnet } => {
+            let config = use_config(&config_file)?;
+            let magnet = magnet.parse::<Magnet>()?;
+            let torrent = magnet.resolve(config.listen.port(), None).await?;
+            fs::write(&output, torrent.to_bytes()?).context("Writing torrent file failed")?;
+            println!("Info Hash: {}", hex::encode(torrent.info_hash()?));
+        }
+        Commands::Dht {
+            torrent,
+            port,
+            dht_bootstrap,
+        } => {
+            let config = use_config(&config_file)?;
+            let info_hash = if torrent.starts_with("magnet:") {
+                torrent.parse::<Magnet>()?.info_hash
+            } else if let Ok(id) = torrent.parse::<NodeId>() {
+                id.0
+            } else {
+                read_torrent(torrent.into())?.info_hash()?
+            };
+            let addr = SocketAddr::new(config.listen.ip(), port.unwrap_or(config.listen.port()));
+            let dht = match dht_state_path() {
+                Some(path) => Dht::restore(addr, &path).await?,
+                None => Dht::bind(addr).await?,
+            };
+            let bootstrap = if dht_bootstrap.is_empty() {
+                config.dht_bootstrap
+            } else {
+                dht_bootstrap
+            };
+            let nodes = dht.bootstrap(&bootstrap).await?;
+            info!("joined the dht with {nodes} nodes");
+            for peer in dht.get_peers(info_hash).await {
+                println!("{peer}");
+            }
+            save_dht(&dht);
+        }
+        Commands::Create {
+            output,
+            path,
+            announce,
+            piece_length,
+            name,
+            comment,
+            created_by,
+            creation_date,
+            no_creation_date,
+            private,
+            source,
+            web_seeds,
+        } => {
+            let mut builder = TorrentBuilder::new(path).private(private);
+            for tier in announce {
+                builder = builder.tier(tier.split(',').map(String::from).collect());
+            }
+            if let Some(piece_length) = piece_length {
+                builder = builder.piece_length(piece_length);
+            }
+            if let Some(name) = name {
+                builder = builder.name(name);
+            }
+            if let Some(comment) = comment {
+                builder = builder.comment(comment);
+            }
+            if created_by.is_some() {
+                builder = builder.created_by(created_by);
+            }
+            if no_creation_date {
+                builder = builder.creation_date(None);
+            } else if creation_date.is_some() {
+                builder = builder.creation_date(creation_date);
+            }
+            if let Some(source) = source {
+                builder = builder.source(source);
+            }
+            for url in web_seeds {
+                builder = builder.web_seed(url);
+            }
+            let torrent = builder.build()?;
+            fs::write(&output, torrent.to_bytes()?).context("Writing torrent file failed")?;
+            println!("Info Hash: {}", hex::encode(torrent.info_hash()?));
+        }
+        Commands::Daemon {
+            listen,
+            no_dht,
+            no_lsd,
+            no_pex,
+            no_utp,
+            encryption,
+            download_limit,
+            upload_limit,
+            max_connections,
+            metrics,
+            transmission,
+            download_dir,
+            watch,
+            move_to,
+            on_complete,
+            seed_ratio,
+        } => {
+            let mut config = use_config(&config_file)?;
+            if let Some(listen) = listen {
+                config.listen = listen;
+            }
+            config.dht &= !no_dht;
+            config.lsd &= !no_lsd;
+            config.pex &= !no_pex;
+            config.utp &= !no_utp;
+            if let Some(encryption) = encryption {
+                config.encryption = encryption;
+            }
+            let rates = RateArgs {
+                download_limit,
+                upload_limit,
+                peer_download_limit: None,
+                peer_upload_limit: None,
+            };
+            rates.apply(&mut config.limits);
+            if let Some(max_connections) = max_connections {
+                config.limits.max_connections = max_connections;
+            }
+            let download_dir = match download_dir {
+                Some(dir) => std::path::absolute(dir)?,
+                None => config.download_dir.clone(),
+            };
+            let config = SessionConfig {
+                dht_state: dht_state_path(),
+                ..config.session_config()
+            };
+            let on_complete = CompletionActions {
+                move_to: move_to.map(std::path::absolute).transpose()?,
+                hook: on_complete,
+                seed_ratio,
+            };
+            let services = Services {
+                metrics,
+                transmission,
+                watch: watch.map(|dir| (dir, on_complete)),
+                download_dir,
+            };
+            daemon(config, &socket_path(args.socket), services).await?;
+        }
+        Commands::Add { output, torrent } => {
+            let config = use_config(&config_file)?;
+            let torrent = load_torrent(&torrent, &config).await?;
+            let output = std::path::absolute(&output)?;
+            let mut client = RpcClient::connect(&socket_path(args.socket)).await?;
+            let params = json!({ "torrent": hex::encode(torrent.to_bytes()?), "output": output });
+            let added = client.call("add", params).await?;
+            println!(
+                "Info Hash: {}",
+                added["info_hash"].as_str().unwrap_or_default()
+            );
+        }
+        Commands::List { json } => {
+            let mut client = RpcClient::connect(&socket_path(args.socket)).await?;
+            let list = client.call("list", Value::Null).await?;
+            if json {
+                println!("{list}");
+            } else {
+                for torrent in list.as_array().into_iter().flatten() {
+                    let pieces = torrent["pieces"].as_u64().unwrap_or_default();
+                    let total = torrent["total_pieces"].as_u64().unwrap_or_default().max(1);
+                    println!(
+                        "{}  {:<11}  {:>5.1}%  {:>3} peers  {}",
+                        torrent["info_hash"].as_str().unwrap_or_default(),
+                        torrent["state"].as_str().unwrap_or_default(),
+                        pieces as f64 * 100.0 / total as f64,
+                        torrent["peers"],
+                        torrent["name"].as_str().unwrap_or_default(),
+                    );
+                }
+            }
+        }
+        Commands::Pause { info_hash } => rpc_call(args.socket, "pause", &info_hash).await?,
+        Commands::Resume { info_hash } => rpc_call(args.socket, "resume", &info_hash).await?,
+        Commands::Remove { info_hash } => rpc_call(args.socket, "remove", &info_hash).await?,
+        Commands::Stats { json } => {
+            let mut client = RpcClient::connect(&socket_path(args.socket)).await?;
+            let stats = client.call("stats", Value::Null).await?;
+            if json {
+                println!("{stats}");
+            } else {
+                for (key, value) in stats.as_object().into_iter().flatten() {
+                    println!("{key}: {value}");
+                }
+            }
+        }
+        Commands::SetLimits {
+            download_limit,
+            upload_limit,
+        } => {
+            let bytes = |limit: Option<u64>| limit.and_then(Config::rate);
+            let mut client = RpcClient::connect(&socket_path(args.socket)).await?;
+            let params = json!({
+                "download": bytes(download_limit),
+                "upload": bytes(upload_limit),
+            });
+            client.call("set_limits", params).await?;
+        }
+        Commands::Config {
+            command: ConfigCommand::Show,
+        } => {
+            let (config, config_path) = load_config(config_file)?;
+            match config_path {
+                Some(path) => println!("# read from {}", path.display()),
+                None => println!("# defaults, no config file"),
+            }
+            print!("{}", config.to_toml());
         }
     }
     Ok(())
 }
 
+/// The config of `path`, or of the default path when there is a file there,
+/// with the file it came from
+fn load_config(path: Option<PathBuf>) -> Result<(Config, Option<PathBuf>)> {
+    let path = path.or_else(|| Config::default_path().filter(|path| path.exists()));
+    let mut config = match &path {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    config.download_dir = std::path::absolute(&config.download_dir)?;
+    Ok((config, path))
+}
+
+/// The config of `path` as [`load_config`] finds it, with our peer id set
+/// from it
+fn use_config(path: &Option<PathBuf>) -> Result<Config> {
+    let (config, _) = load_config(path.clone())?;
+    peer::set_local_id(config.peer_id());
+    Ok(config)
+}
+
 fn read_torrent(torrent: PathBuf) -> Result<Torrent> {
     let file = fs::read(torrent)?;
     Ok(serde_bencode::from_bytes::<Torrent>(&file)?)
 }
 
-async fn get_peers(torrent: &Torrent) -> Result<Vec<SocketAddrV4>> {
+/// Reads a torrent file, or downloads the metadata when given a magnet link
+async fn load_torrent(torrent: &str, config: &Config) -> Result<Torrent> {
+    if torrent.starts_with("magnet:") {
+        let magnet = torrent.parse::<Magnet>()?;
+        magnet.resolve(config.listen.port(), None).await
+    } else {
+        read_torrent(torrent.into())
+    }
+}
+
+/// Announces to the trackers of the torrent, returning the peers of the first
+/// tracker that answers.
+async fn get_peers(torrent: &Torrent, config: &Config) -> Result<Vec<SocketAddrV4>> {
     let info_hash = torrent.info_hash()?;
-    let tracker_request = TrackerRequest {
-        peer_id: String::from("00112233445566778899"),
-        port: 6881,
-        uploaded: 0,
-        downloaded: 0,
-        left: torrent.info.length,
-        compact: 1,
-    };
+    let mut last_error = None;
+    for tracker in torrent.trackers().iter().flatten() {
+        match announce(tracker, &info_hash, torrent.info.length(), config).await {
+            Ok(peers) => return Ok(peers),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| anyhow::anyhow!("torrent has no tracker")))
+}
 
-    let query = serde_urlencoded::to_string(&tracker_request)?;
-    let url = format!(
-        "{}?{}&info_hash={}",
-        torrent.announce,
-        query,
-        tracker::hash_encoder(&info_hash)
-    );
-    let response = reqwest::get(url).await?;
-    let response = response.bytes().await?;
-    let response: TrackerResponse = serde_bencode::from_bytes(&response)?;
+async fn announce(
+    tracker: &str,
+    info_hash: &[u8; 20],
+    left: usize,
+    config: &Config,
+) -> Result<Vec<SocketAddrV4>> {
+    let port = config.listen.port();
+    let response = tracker::announce(
+        tracker,
+        info_hash,
+        port,
+        left,
+        None,
+        config.tracker_timeout(),
+    )
+    .await?;
     Ok(response.peers.0)
 }
 
-async fn download_piece(torrent: PathBuf, output: PathBuf, piece_index: usize) -> Result<()> {
-    let torrent = read_torrent(torrent)?;
+async fn download_piece(
+    torrent: Torrent,
+    output: PathBuf,
+    piece_index: usize,
+    config: &Config,
+) -> Result<()> {
     let info_hash = torrent.info_hash()?;
     assert!(piece_index < torrent.info.pieces.0.len());
 
-    let peers = get_peers(&torrent).await?;
-    let peer_address = peers[0];
+    // A peer that fails or sends corrupt data leaves the piece to the next
+    let peers = get_peers(&torrent, config).await?;
+    for peer_address in peers {
+        let attempt = async {
+            let mut peer = Peer::connect_peer(peer_address.into(), info_hash).await?;
+            prepare_peer(&torrent, &mut peer).await?;
 
-    let mut peer = Peer::connect_peer(peer_address, info_hash).await?;
+            // Request a piece by blocks
+            request_piece(&torrent, piece_index, config.block_size, &mut peer, &output).await
+        };
+        let span = Span::current()
+            .with("peer", peer_address)
+            .with("piece", piece_index);
+        match span.instrument(attempt).await {
+            Ok(_) => return Ok(()),
+            Err(e) => warn!("downloading the piece failed: {e:#}"),
+        }
+    }
+    anyhow::bail!("no peer could send piece {piece_index}")
+}
 
-    let msg_bitfield = peer.read_message().await?;
-    // Bitfield has to be th first message always
-    assert_eq!(msg_bitfield.tag, MessageTag::Bitfield);
-    eprintln!("Got bitfield");
+/// How `download` and `serve` find and talk to peers
+struct DownloadOptions {
+    /// The config with the flags applied
+    config: Config,
+    /// Files to download, all of them when None
+    files: Option<FileSelection>,
+    sequential: bool,
+    /// Where metrics are served, if anywhere
+    metrics: Option<SocketAddr>,
+    progress: bool,
+}
 
-    // Send interested
-    peer.send_message(Message {
-        tag: MessageTag::Interested,
-        payload: Vec::new(),
+/// A session running the torrent alone
+struct Started {
+    session: Session,
+    handle: TorrentHandle,
+    metrics: Option<TcpListener>,
+    progress: bool,
+}
+
+/// Downloads with the peers of the trackers, of the DHT and of the local
+/// network as `options` allow
+async fn download(torrent: Torrent, output: PathBuf, options: DownloadOptions) -> Result<()> {
+    let Started {
+        session,
+        handle,
+        metrics,
+        progress,
+    } = start(torrent, output, options).await?;
+    let mut view = progress.then(|| Progress::new(handle.worker().clone()));
+    let source = session.clone();
+    let result = tokio::select! {
+        _ = handle.worker().completed() => Ok(()),
+        e = failure(&handle) => Err(e),
+        result = serve_metrics(metrics, move || source.metrics()) => result,
+        _ = show_progress(view.as_mut()) => unreachable!("progress is shown forever"),
+        _ = tokio::signal::ctrl_c() => Err(anyhow::anyhow!("interrupted")),
+    };
+    // The last state, as the download ended
+    if let Some(view) = &mut view {
+        view.draw();
+    }
+    result
+}
+
+/// Downloads and seeds like `download`, serving the files over HTTP on
+/// `http` until interrupted
+async fn serve(
+    torrent: Torrent,
+    output: PathBuf,
+    http: SocketAddr,
+    options: DownloadOptions,
+) -> Result<()> {
+    let info = torrent.info.clone();
+    let Started {
+        session,
+        handle,
+        metrics,
+        progress,
+    } = start(torrent, output, options).await?;
+    let mut view = progress.then(|| Progress::new(handle.worker().clone()));
+    let http = TcpListener::bind(http)
+        .await
+        .with_context(|| format!("listening on {http}"))?;
+    info!("serving the files on http://{}", http.local_addr()?);
+    let server = StreamServer::new(handle.worker().clone(), &info);
+    let source = session.clone();
+    tokio::select! {
+        result = server.run(http) => result,
+        e = failure(&handle) => Err(e),
+        result = serve_metrics(metrics, move || source.metrics()) => result,
+        _ = show_progress(view.as_mut()) => unreachable!("progress is shown forever"),
+        _ = tokio::signal::ctrl_c() => Ok(()),
+    }
+}
+
+/// Starts a session of the torrent alone, with the files and the order
+/// `options` ask for
+async fn start(torrent: Torrent, output: PathBuf, options: DownloadOptions) -> Result<Started> {
+    let DownloadOptions {
+        config,
+        files,
+        sequential,
+        metrics,
+        progress,
+    } = options;
+    let priorities = match files {
+        Some(files) => Some(files.priorities(&torrent.info)?),
+        None => None,
+    };
+    let metrics = bind_metrics(metrics).await?;
+    let config = SessionConfig {
+        dht_state: dht_state_path(),
+        ..config.session_config()
+    };
+    // Incoming connections are welcome but not required to download
+    let session = match Session::new(config.clone()).await {
+        Ok(session) => session,
+        Err(e) if config.listen.port() != 0 => {
+            warn!("{e:#}, listening on another port");
+            let listen = SocketAddr::new(config.listen.ip(), 0);
+            Session::new(SessionConfig { listen, ..config }).await?
+        }
+        Err(e) => return Err(e),
+    };
+    let handle = session.add_torrent(torrent, &output)?;
+    if let Some(priorities) = priorities {
+        handle.set_file_priorities(priorities)?;
+    }
+    handle.worker().set_sequential(sequential);
+    Ok(Started {
+        session,
+        handle,
+        metrics,
+        progress,
     })
-    .await?;
-    eprintln!("sent interested");
+}
+
+/// Waits until the torrent stops on its own, with why
+async fn failure(handle: &TorrentHandle) -> anyhow::Error {
+    let mut tick = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        tick.tick().await;
+        if let TorrentState::Failed(error) = handle.status().state {
+            return anyhow::anyhow!(error);
+        }
+    }
+}
+
+/// Redraws the progress of a download every second, pends forever
+/// without a view
+async fn show_progress(view: Option<&mut Progress>) {
+    let Some(view) = view else {
+        return std::future::pending().await;
+    };
+    let mut tick = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        tick.tick().await;
+        view.draw();
+    }
+}
+
+/// A listener for metrics on `addr`, if given
+async fn bind_metrics(addr: Option<SocketAddr>) -> Result<Option<TcpListener>> {
+    let Some(addr) = addr else {
+        return Ok(None);
+    };
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("listening on {addr}"))?;
+    info!(
+        "serving metrics on http://{}/metrics",
+        listener.local_addr()?
+    );
+    Ok(Some(listener))
+}
 
-    // Await for unchoke
-    let msg_unchocked = peer.read_message().await?;
-    // Bitfield has to be th first message always
-    assert_eq!(msg_unchocked.tag, MessageTag::Unchoke);
-    assert!(msg_unchocked.payload.is_empty());
-    eprintln!("got unchocked");
+/// Serves the metrics of `source` on `listener`, never returns without one
+async fn serve_metrics(
+    listener: Option<TcpListener>,
+    source: impl Fn() -> Vec<TorrentMetrics> + Send + Sync + 'static,
+) -> Result<()> {
+    let Some(listener) = listener else {
+        return std::future::pending().await;
+    };
+    MetricsServer::new(source).run(listener).await
+}
+
+/// What a daemon runs besides its session and socket
+struct Services {
+    metrics: Option<SocketAddr>,
+    transmission: Option<SocketAddr>,
+    /// Directory to watch and what to do with its completed torrents
+    watch: Option<(PathBuf, CompletionActions)>,
+    download_dir: PathBuf,
+}
 
-    // Request a piece by blocks
-    request_piece(&torrent, piece_index, &mut peer, output).await?;
+/// Runs a session controlled over `socket`, along with `services`, until
+/// interrupted
+async fn daemon(config: SessionConfig, socket: &Path, services: Services) -> Result<()> {
+    let Services {
+        metrics,
+        transmission,
+        watch,
+        download_dir,
+    } = services;
+    let session = Session::new(config).await?;
+    let listener = RpcServer::bind(socket).await?;
+    info!(
+        "listening for peers on port {} and for commands on {}",
+        session.port(),
+        socket.display()
+    );
+    let metrics = bind_metrics(metrics).await?;
+    let transmission = match transmission {
+        Some(addr) => {
+            if !addr.ip().is_loopback() {
+                warn!("the transmission protocol has no authentication, anyone reaching {addr} controls the daemon");
+            }
+            let listener = TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("listening on {addr}"))?;
+            info!(
+                "speaking the transmission protocol on http://{}/transmission/rpc",
+                listener.local_addr()?
+            );
+            Some((
+                TransmissionServer::new(session.clone(), download_dir.clone()),
+                listener,
+            ))
+        }
+        None => None,
+    };
+    let mut watcher = watch.map(|(dir, actions)| {
+        info!("watching {} for torrents", dir.display());
+        Watcher::new(session.clone(), dir, download_dir, actions)
+    });
+    let server = RpcServer::new(session.clone());
+    let result = tokio::select! {
+        result = server.run(listener) => result,
+        result = async {
+            match transmission {
+                Some((server, listener)) => server.run(listener).await,
+                None => std::future::pending().await,
+            }
+        } => result,
+        result = async {
+            match &mut watcher {
+                Some(watcher) => watcher.run(watch::SCAN_INTERVAL).await,
+                None => std::future::pending().await,
+            }
+        } => result,
+        result = serve_metrics(metrics, move || session.metrics()) => result,
+        _ = tokio::signal::ctrl_c() => Ok(()),
+    };
+    let _ = fs::remove_file(socket);
+    result
+}
+
+/// Calls a method of the daemon that takes an info hash
+async fn rpc_call(socket: Option<PathBuf>, method: &str, info_hash: &str) -> Result<()> {
+    let mut client = RpcClient::connect(&socket_path(socket)).await?;
+    client
+        .call(method, json!({ "info_hash": info_hash }))
+        .await?;
     Ok(())
 }
 
-async fn download(torrent: PathBuf, output: PathBuf) -> Result<()> {
-    let torrent = read_torrent(torrent)?;
-    let info_hash = torrent.info_hash()?;
+/// Socket of the daemon, `$XDG_RUNTIME_DIR/oxitorrent/rpc.sock` unless given,
+/// or in the temporary directory without a runtime directory
+fn socket_path(socket: Option<PathBuf>) -> PathBuf {
+    if let Some(socket) = socket {
+        return socket;
+    }
+    let dir = std::env::var_os("XDG_RUNTIME_DIR")
+        .filter(|dir| !dir.is_empty())
+        .map(|dir| PathBuf::from(dir).join("oxitorrent"));
+    match dir {
+        Some(dir) => dir.join("rpc.sock"),
+        None => {
+            let user = std::env::var("USER").unwrap_or_else(|_| "user".to_string());
+            std::env::temp_dir().join(format!("oxitorrent-{user}.sock"))
+        }
+    }
+}
 
-    let peers = get_peers(&torrent).await?;
-    // TODO: Use the worker module to add each peer to allow the download of each part simultaneously
-    let peer_address = peers[0];
+/// State file of the DHT node, `$XDG_STATE_HOME/oxitorrent/dht.dat`
+fn dht_state_path() -> Option<PathBuf> {
+    let state_home = std::env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state"))
+        })?;
+    Some(state_home.join("oxitorrent").join("dht.dat"))
+}
 
-    let mut peer = Peer::connect_peer(peer_address, info_hash).await?;
+fn save_dht(dht: &Dht) {
+    if let Some(path) = dht_state_path() {
+        if let Err(e) = dht.save(&path) {
+            warn!("saving the dht state failed: {e:#}");
+        }
+    }
+}
 
-    let msg_bitfield = peer.read_message().await?;
-    // Bitfield has to be th first message always
-    assert_eq!(msg_bitfield.tag, MessageTag::Bitfield);
-    eprintln!("Got bitfield");
+/// Exchanges the extended handshake, declares interest and waits until the
+/// peer unchokes us
+async fn prepare_peer(torrent: &Torrent, peer: &mut Peer) -> Result<()> {
+    if peer.supports_extensions() {
+        let metadata = Arc::new(serde_bencode::to_bytes(&torrent.info)?);
+        peer.extensions.register(Box::new(MetadataExtension::serve(
+            torrent.info_hash()?,
+            metadata,
+        )));
+        peer.send_extended_handshake().await?;
+    }
 
     // Send interested
     peer.send_message(Message {
@@ -184,69 +1058,78 @@ async fn download(torrent: PathBuf, output: PathBuf) -> Result<()> {
         payload: Vec::new(),
     })
     .await?;
-    eprintln!("sent interested");
-
-    // Await for unchoke
-    let msg_unchocked = peer.read_message().await?;
-    // Bitfield has to be th first message always
-    assert_eq!(msg_unchocked.tag, MessageTag::Unchoke);
-    assert!(msg_unchocked.payload.is_empty());
-    eprintln!("got unchocked");
-
-    let mut pieces: Vec<u8> = Vec::with_capacity(torrent.info.length);
-    for piece_index in 0..torrent.info.pieces.0.len() {
-        let path = format!("{}-part-{piece_index}", output.to_str().unwrap()).into();
-        let piece = request_piece(&torrent, piece_index, &mut peer, path).await?;
-        pieces.extend(piece);
-    }
-
-    let mut file = fs::File::create(output).context("Creating output file failed")?;
-    file.write_all(&pieces)
-        .context("Writing to output file failed")?;
-    file.flush().context("Output file flush failed")?;
+    debug!("sent interested");
 
+    // Await for unchoke, the bitfield and the extended handshake may come first
+    loop {
+        let message = peer.read_message().await?;
+        match message.tag {
+            MessageTag::Unchoke => break,
+            MessageTag::Extended => peer.handle_extended(&message).await?,
+            _ => {}
+        }
+    }
+    debug!("got unchoked");
     Ok(())
 }
 
 async fn request_piece(
     torrent: &Torrent,
     piece_index: usize,
+    block_max: usize,
     peer: &mut Peer,
-    output: PathBuf,
+    output: &Path,
 ) -> Result<Vec<u8>> {
     let piece_hash = &torrent.info.pieces.0[piece_index];
-    let piece_size =
-        (torrent.info.plength).min(torrent.info.length - torrent.info.plength * piece_index);
+    let piece_size = torrent.info.piece_length(piece_index);
+    let blocks_count = piece_size.div_ceil(block_max);
 
-    let mut blocks: Vec<u8> = Vec::with_capacity(piece_size);
-    loop {
-        let block_size = BLOCK_MAX.min((piece_size - blocks.len()) as u32);
-        let mut request = Request::new(piece_index as u32, blocks.len() as u32, block_size);
+    let mut blocks: Vec<u8> = vec![0; piece_size];
+    let mut requested = 0;
+    let mut received = 0;
+    while received < blocks_count {
+        // Keep as many requests in flight as the peer accepts
+        while requested < blocks_count && requested - received < peer.pipeline_limit() {
+            let begin = requested * block_max;
+            let block_size = block_max.min(piece_size - begin);
+            let mut request = Request::new(piece_index as u32, begin as u32, block_size as u32);
 
-        peer.send_message(Message {
-            tag: MessageTag::Request,
-            payload: Vec::from(peer::as_bytes_mut(&mut request)),
-        })
-        .await?;
+            peer.send_message(Message {
+                tag: MessageTag::Request,
+                payload: Vec::from(peer::as_bytes_mut(&mut request)),
+            })
+            .await?;
+            requested += 1;
+        }
 
         // Waits for a piece
         let piece_msg = peer.read_message().await?;
-        assert_eq!(piece_msg.tag, MessageTag::Piece);
-        assert!(!piece_msg.payload.is_empty());
+        match piece_msg.tag {
+            MessageTag::Piece => {}
+            MessageTag::Extended => {
+                peer.handle_extended(&piece_msg).await?;
+                continue;
+            }
+            MessageTag::Choke => anyhow::bail!("peer choked us"),
+            MessageTag::RejectRequest => anyhow::bail!("peer rejected a request"),
+            _ => continue,
+        }
 
         let piece = Piece::from_u8(&piece_msg.payload[..])?;
-        assert_eq!(piece.block().len(), block_size as usize);
-        blocks.extend(piece.block());
-        if blocks.len() >= piece_size {
-            break;
+        let begin = piece.begin() as usize;
+        if piece.index() as usize != piece_index || begin + piece.block().len() > piece_size {
+            anyhow::bail!("peer sent a block we did not ask for");
         }
+        blocks[begin..begin + piece.block().len()].copy_from_slice(piece.block());
+        received += 1;
     }
 
-    assert_eq!(blocks.len(), piece_size);
     let mut hasher = Sha1::new();
     hasher.update(&blocks);
-    let hash: [u8; 20] = hasher.finalize().try_into()?;
-    assert_eq!(&hash, piece_hash);
+    let hash: [u8; 20] = hasher.finalize().into();
+    if &hash != piece_hash {
+        anyhow::bail!("piece {piece_index} failed the hash check");
+    }
 
     let mut file = fs::File::create(output).context("Creating output file failed")?;
     file.write_all(&blocks)
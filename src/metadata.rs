@@ -0,0 +1,332 @@
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use tokio::sync::oneshot;
+
+use crate::extension::{ExtendedHandshake, Extension};
+use crate::peer::{MessageTag, Peer};
+use crate::torrent::Info;
+
+/// Name of the metadata extension in the extended handshake (BEP 9)
+pub const EXTENSION_NAME: &str = "ut_metadata";
+/// Metadata is exchanged in pieces of 16KiB, the last one may be shorter
+pub const PIECE_SIZE: usize = 16 * 1024;
+/// Upper bound for the metadata size announced by a peer
+const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MetadataMessage {
+    Request {
+        piece: usize,
+    },
+    Data {
+        piece: usize,
+        total_size: usize,
+        data: Vec<u8>,
+    },
+    Reject {
+        piece: usize,
+    },
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Header {
+    msg_type: u8,
+    piece: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    total_size: Option<usize>,
+}
+
+impl MetadataMessage {
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let (header, data) = match self {
+            MetadataMessage::Request { piece } => (
+                Header {
+                    msg_type: 0,
+                    piece: *piece,
+                    total_size: None,
+                },
+                &[][..],
+            ),
+            MetadataMessage::Data {
+                piece,
+                total_size,
+                data,
+            } => (
+                Header {
+                    msg_type: 1,
+                    piece: *piece,
+                    total_size: Some(*total_size),
+                },
+                &data[..],
+            ),
+            MetadataMessage::Reject { piece } => (
+                Header {
+                    msg_type: 2,
+                    piece: *piece,
+                    total_size: None,
+                },
+                &[][..],
+            ),
+        };
+        let mut bytes = serde_bencode::to_bytes(&header)?;
+        bytes.extend_from_slice(data);
+        Ok(bytes)
+    }
+
+    /// Parses a metadata message, data messages carry the piece right after the
+    /// bencoded dictionary.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut rest = bytes;
+        let mut deserializer = serde_bencode::de::Deserializer::new(&mut rest);
+        let header = Header::deserialize(&mut deserializer).context("decoding metadata header")?;
+        let message = match header.msg_type {
+            0 => MetadataMessage::Request {
+                piece: header.piece,
+            },
+            1 => MetadataMessage::Data {
+                piece: header.piece,
+                total_size: header
+                    .total_size
+                    .context("data message without total_size")?,
+                data: rest.to_vec(),
+            },
+            2 => MetadataMessage::Reject {
+                piece: header.piece,
+            },
+            msg_type => bail!("unknown metadata message type {msg_type}"),
+        };
+        Ok(message)
+    }
+}
+
+/// The `ut_metadata` extension. It serves the info dictionary to peers when we
+/// have it, or downloads it when we only know the info hash.
+pub struct MetadataExtension {
+    info_hash: [u8; 20],
+    metadata: Option<Arc<Vec<u8>>>,
+    download: Option<Download>,
+}
+
+struct Download {
+    buffer: Vec<u8>,
+    received: Vec<bool>,
+    done: Option<oneshot::Sender<Vec<u8>>>,
+}
+
+impl MetadataExtension {
+    /// Serves the raw bencoded info dictionary to peers that ask for it
+    pub fn serve(info_hash: [u8; 20], metadata: Arc<Vec<u8>>) -> Self {
+        Self {
+            info_hash,
+            metadata: Some(metadata),
+            download: None,
+        }
+    }
+
+    /// Downloads the info dictionary from the peer, the receiver gets it once
+    /// it matches the info hash.
+    pub fn download(info_hash: [u8; 20]) -> (Self, oneshot::Receiver<Vec<u8>>) {
+        let (tx, rx) = oneshot::channel();
+        let extension = Self {
+            info_hash,
+            metadata: None,
+            download: Some(Download {
+                buffer: Vec::new(),
+                received: Vec::new(),
+                done: Some(tx),
+            }),
+        };
+        (extension, rx)
+    }
+}
+
+impl Extension for MetadataExtension {
+    fn name(&self) -> &'static str {
+        EXTENSION_NAME
+    }
+
+    fn extend_handshake(&self, handshake: &mut ExtendedHandshake) {
+        handshake.metadata_size = self.metadata.as_ref().map(|m| m.len());
+    }
+
+    fn on_handshake(&mut self, remote: &ExtendedHandshake) -> Result<Vec<Vec<u8>>> {
+        let Some(download) = &mut self.download else {
+            return Ok(Vec::new());
+        };
+        remote
+            .id(EXTENSION_NAME)
+            .context("peer does not support ut_metadata")?;
+        let size = remote
+            .metadata_size
+            .context("peer did not announce the metadata size")?;
+        if size == 0 || size > MAX_METADATA_SIZE {
+            bail!("invalid metadata size {size}");
+        }
+
+        let pieces = size.div_ceil(PIECE_SIZE);
+        download.buffer = vec![0; size];
+        download.received = vec![false; pieces];
+        (0..pieces)
+            .map(|piece| MetadataMessage::Request { piece }.to_bytes())
+            .collect()
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>> {
+        match MetadataMessage::from_bytes(payload)? {
+            MetadataMessage::Request { piece } => {
+                let reply = match &self.metadata {
+                    // The index comes from the peer, it may be past any piece
+                    Some(metadata) if piece < metadata.len().div_ceil(PIECE_SIZE) => {
+                        let start = piece * PIECE_SIZE;
+                        let end = (start + PIECE_SIZE).min(metadata.len());
+                        MetadataMessage::Data {
+                            piece,
+                            total_size: metadata.len(),
+                            data: metadata[start..end].to_vec(),
+                        }
+                    }
+                    _ => MetadataMessage::Reject { piece },
+                };
+                Ok(vec![reply.to_bytes()?])
+            }
+            MetadataMessage::Data {
+                piece,
+                total_size,
+                data,
+            } => {
+                let Some(download) = &mut self.download else {
+                    return Ok(Vec::new());
+                };
+                if piece >= download.received.len() {
+                    bail!("invalid metadata piece {piece}");
+                }
+                let size = download.buffer.len();
+                let start = piece * PIECE_SIZE;
+                let expected = PIECE_SIZE.min(size - start);
+                if total_size != size || data.len() != expected {
+                    bail!("invalid metadata piece {piece}");
+                }
+                download.buffer[start..start + expected].copy_from_slice(&data);
+                download.received[piece] = true;
+
+                if download.received.iter().all(|r| *r) {
+                    let hash: [u8; 20] = Sha1::digest(&download.buffer).into();
+                    if hash != self.info_hash {
+                        bail!("metadata does not match the info hash");
+                    }
+                    // Make sure the metadata is a valid info dictionary before handing it out
+                    Info::from_bytes(&download.buffer)?;
+                    let metadata = Arc::new(std::mem::take(&mut download.buffer));
+                    if let Some(done) = download.done.take() {
+                        let _ = done.send(metadata.to_vec());
+                    }
+                    // From now on we can serve it as well
+                    self.metadata = Some(metadata);
+                    self.download = None;
+                }
+                Ok(Vec::new())
+            }
+            MetadataMessage::Reject { piece } => {
+                if self.download.is_some() {
+                    bail!("peer rejected metadata piece {piece}");
+                }
+                Ok(Vec::new())
+            }
+        }
+    }
+}
+
+/// Downloads the info dictionary from a connected peer and verifies it against
+/// `info_hash`. Returns the raw bencoded dictionary.
+pub async fn fetch_metadata(peer: &mut Peer, info_hash: [u8; 20]) -> Result<Vec<u8>> {
+    if !peer.supports_extensions() {
+        bail!("peer does not support the extension protocol");
+    }
+    let (extension, mut metadata) = MetadataExtension::download(info_hash);
+    peer.extensions.register(Box::new(extension));
+    peer.send_extended_handshake().await?;
+
+    loop {
+        let message = peer.read_message().await?;
+        if message.tag == MessageTag::Extended {
+            peer.handle_extended(&message).await?;
+        }
+        if let Ok(metadata) = metadata.try_recv() {
+            return Ok(metadata);
+        }
+    }
+}
+
+#[test]
+fn metadata_message_roundtrip() {
+    let data = MetadataMessage::Data {
+        piece: 1,
+        total_size: 20_000,
+        data: b"d4:name3:fooe".to_vec(),
+    };
+    let bytes = data.to_bytes().unwrap();
+    assert!(bytes.starts_with(b"d8:msg_typei1e5:piecei1e10:total_sizei20000ee"));
+    assert_eq!(MetadataMessage::from_bytes(&bytes).unwrap(), data);
+
+    let request = MetadataMessage::Request { piece: 0 };
+    let bytes = request.to_bytes().unwrap();
+    assert_eq!(bytes, b"d8:msg_typei0e5:piecei0ee");
+    assert_eq!(MetadataMessage::from_bytes(&bytes).unwrap(), request);
+}
+
+#[test]
+fn out_of_range_pieces_are_refused() {
+    let metadata = Arc::new(vec![b'x'; PIECE_SIZE + 1]);
+    let mut server = MetadataExtension::serve([0; 20], metadata);
+    let (mut client, _) = MetadataExtension::download([0; 20]);
+    if let Some(download) = &mut client.download {
+        download.buffer = vec![0; PIECE_SIZE + 1];
+        download.received = vec![false; 2];
+    }
+    // Bencode integers stop at i64::MAX, so usize::MAX does not even decode
+    let request = format!("d8:msg_typei0e5:piecei{}ee", usize::MAX);
+    assert!(server.on_message(request.as_bytes()).is_err());
+    for piece in [2, usize::MAX / PIECE_SIZE + 1, i64::MAX as usize] {
+        let request = format!("d8:msg_typei0e5:piecei{piece}ee");
+        let reply = server.on_message(request.as_bytes()).unwrap();
+        assert_eq!(
+            MetadataMessage::from_bytes(&reply[0]).unwrap(),
+            MetadataMessage::Reject { piece }
+        );
+        let data = format!("d8:msg_typei1e5:piecei{piece}e10:total_sizei16385eex");
+        assert!(client.on_message(data.as_bytes()).is_err());
+    }
+}
+
+#[tokio::test]
+async fn fetch_metadata_from_peer() {
+    let metadata =
+        b"d6:lengthi5e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae".to_vec();
+    let info_hash: [u8; 20] = Sha1::digest(&metadata).into();
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let seeder = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut peer = Peer::accept_peer(stream, info_hash).await.unwrap();
+        peer.extensions.register(Box::new(MetadataExtension::serve(
+            info_hash,
+            Arc::new(metadata),
+        )));
+        peer.send_extended_handshake().await.unwrap();
+        while let Ok(message) = peer.read_message().await {
+            if message.tag == MessageTag::Extended {
+                peer.handle_extended(&message).await.unwrap();
+            }
+        }
+    });
+
+    let mut peer = Peer::connect_peer(addr, info_hash).await.unwrap();
+    let fetched = fetch_metadata(&mut peer, info_hash).await.unwrap();
+    assert_eq!(Sha1::digest(&fetched).as_slice(), info_hash);
+    drop(peer);
+    seeder.await.unwrap();
+}
@@ -0,0 +1,347 @@
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::debug;
+use crate::stream::{read_request, respond};
+
+/// Upper bounds of the tracker announce latency buckets, in seconds
+const LATENCY_BUCKETS: [f64; 9] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+/// Counters a worker updates as it runs, read into [`TorrentMetrics`]
+#[derive(Default)]
+pub(crate) struct Counters {
+    pub downloaded: AtomicU64,
+    pub uploaded: AtomicU64,
+    pub pieces_verified: AtomicU64,
+    pub pieces_failed: AtomicU64,
+    pub announce_failures: AtomicU64,
+    announce_buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    announce_count: AtomicU64,
+    announce_micros: AtomicU64,
+    disk_queue: AtomicUsize,
+}
+
+impl Counters {
+    /// Counts an announce that got an answer after `elapsed`
+    pub fn announced(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|&le| seconds <= le) {
+            add(&self.announce_buckets[bucket], 1);
+        }
+        add(&self.announce_count, 1);
+        add(&self.announce_micros, elapsed.as_micros() as u64);
+    }
+
+    /// Counts a disk read or write in progress until the guard is dropped
+    pub fn disk_operation(&self) -> DiskOperation<'_> {
+        self.disk_queue.fetch_add(1, Ordering::Relaxed);
+        DiskOperation(&self.disk_queue)
+    }
+
+    pub fn disk_queue(&self) -> usize {
+        self.disk_queue.load(Ordering::Relaxed)
+    }
+
+    pub fn announce_latency(&self) -> Histogram {
+        let mut seen = 0;
+        let buckets = LATENCY_BUCKETS
+            .iter()
+            .zip(&self.announce_buckets)
+            .map(|(&le, count)| {
+                seen += count.load(Ordering::Relaxed);
+                (le, seen)
+            })
+            .collect();
+        Histogram {
+            buckets,
+            count: self.announce_count.load(Ordering::Relaxed),
+            sum: self.announce_micros.load(Ordering::Relaxed) as f64 / 1e6,
+        }
+    }
+}
+
+fn add(counter: &AtomicU64, n: u64) {
+    counter.fetch_add(n, Ordering::Relaxed);
+}
+
+pub(crate) struct DiskOperation<'a>(&'a AtomicUsize);
+
+impl Drop for DiskOperation<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Observations counted by upper bound, as Prometheus histograms
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Histogram {
+    /// Upper bound in seconds and the observations at most that long, so
+    /// counts only grow from one bucket to the next
+    pub buckets: Vec<(f64, u64)>,
+    pub count: u64,
+    /// Seconds of every observation together
+    pub sum: f64,
+}
+
+/// A snapshot of the counters and gauges of one torrent
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TorrentMetrics {
+    pub info_hash: [u8; 20],
+    pub name: String,
+    /// Block payload received and sent, in bytes
+    pub downloaded: u64,
+    pub uploaded: u64,
+    /// Peers we are connected to
+    pub peers: usize,
+    /// Connected peers we do not upload to
+    pub choked: usize,
+    /// Connected peers we upload to
+    pub unchoked: usize,
+    /// Connected peers that let us download from them
+    pub unchoking_us: usize,
+    pub pieces_verified: u64,
+    /// Pieces that did not match their hash
+    pub pieces_failed: u64,
+    /// Time the trackers took to answer announces
+    pub announce_latency: Histogram,
+    pub announce_failures: u64,
+    /// Disk reads and writes in progress
+    pub disk_queue: usize,
+}
+
+/// Writes the metrics of `torrents` in the Prometheus text format, each
+/// labelled with the info hash and the name of its torrent
+pub fn render(torrents: &[TorrentMetrics]) -> String {
+    let mut out = String::new();
+    let mut family =
+        |name: &str, kind: &str, help: &str, value: &dyn Fn(&TorrentMetrics) -> f64| {
+            let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}");
+            for torrent in torrents {
+                let _ = writeln!(out, "{name}{{{}}} {}", labels(torrent), value(torrent));
+            }
+        };
+    family(
+        "oxitorrent_downloaded_bytes_total",
+        "counter",
+        "Block payload received",
+        &|t| t.downloaded as f64,
+    );
+    family(
+        "oxitorrent_uploaded_bytes_total",
+        "counter",
+        "Block payload sent",
+        &|t| t.uploaded as f64,
+    );
+    family("oxitorrent_peers", "gauge", "Connected peers", &|t| {
+        t.peers as f64
+    });
+    family(
+        "oxitorrent_peers_choked",
+        "gauge",
+        "Connected peers we do not upload to",
+        &|t| t.choked as f64,
+    );
+    family(
+        "oxitorrent_peers_unchoked",
+        "gauge",
+        "Connected peers we upload to",
+        &|t| t.unchoked as f64,
+    );
+    family(
+        "oxitorrent_peers_unchoking_us",
+        "gauge",
+        "Connected peers we may download from",
+        &|t| t.unchoking_us as f64,
+    );
+    family(
+        "oxitorrent_pieces_verified_total",
+        "counter",
+        "Pieces that passed the hash check",
+        &|t| t.pieces_verified as f64,
+    );
+    family(
+        "oxitorrent_pieces_failed_total",
+        "counter",
+        "Pieces that failed the hash check",
+        &|t| t.pieces_failed as f64,
+    );
+    family(
+        "oxitorrent_tracker_announce_failures_total",
+        "counter",
+        "Tracker announces that failed",
+        &|t| t.announce_failures as f64,
+    );
+    family(
+        "oxitorrent_disk_queue_depth",
+        "gauge",
+        "Disk reads and writes in progress",
+        &|t| t.disk_queue as f64,
+    );
+
+    let name = "oxitorrent_tracker_announce_duration_seconds";
+    let _ = writeln!(
+        out,
+        "# HELP {name} Time trackers took to answer\n# TYPE {name} histogram"
+    );
+    for torrent in torrents {
+        let labels = labels(torrent);
+        let latency = &torrent.announce_latency;
+        for (le, count) in &latency.buckets {
+            let _ = writeln!(out, "{name}_bucket{{{labels},le=\"{le}\"}} {count}");
+        }
+        let _ = writeln!(
+            out,
+            "{name}_bucket{{{labels},le=\"+Inf\"}} {}",
+            latency.count
+        );
+        let _ = writeln!(out, "{name}_sum{{{labels}}} {}", latency.sum);
+        let _ = writeln!(out, "{name}_count{{{labels}}} {}", latency.count);
+    }
+    out
+}
+
+fn labels(torrent: &TorrentMetrics) -> String {
+    let name = torrent
+        .name
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+    format!(
+        "info_hash=\"{}\",name=\"{name}\"",
+        hex::encode(torrent.info_hash)
+    )
+}
+
+/// Serves the metrics of `source` on `/metrics` for Prometheus to scrape
+pub struct MetricsServer {
+    source: Arc<dyn Fn() -> Vec<TorrentMetrics> + Send + Sync>,
+}
+
+impl MetricsServer {
+    /// `source` is called on every scrape, as in
+    /// `MetricsServer::new(move || session.metrics())`
+    pub fn new(source: impl Fn() -> Vec<TorrentMetrics> + Send + Sync + 'static) -> Self {
+        Self {
+            source: Arc::new(source),
+        }
+    }
+
+    /// Answers requests until the future is dropped
+    pub async fn run(&self, listener: TcpListener) -> Result<()> {
+        let mut connections = tokio::task::JoinSet::new();
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let (stream, _) = accepted.context("accepting http connection")?;
+                    let source = self.source.clone();
+                    connections.spawn(async move {
+                        if let Err(e) = handle(stream, &*source).await {
+                            debug!("metrics request failed: {e:#}");
+                        }
+                    });
+                }
+                Some(_) = connections.join_next() => {}
+            }
+        }
+    }
+}
+
+async fn handle(
+    mut stream: TcpStream,
+    source: &(dyn Fn() -> Vec<TorrentMetrics> + Send + Sync),
+) -> Result<()> {
+    let request = read_request(&mut stream).await?;
+    if request.method != "GET" {
+        return respond(&mut stream, "405 Method Not Allowed", &[], "").await;
+    }
+    if request.path.split('?').next() != Some("/metrics") {
+        return respond(&mut stream, "404 Not Found", &[], "see /metrics\n").await;
+    }
+    let headers = [(
+        "Content-Type",
+        "text/plain; version=0.0.4; charset=utf-8".to_string(),
+    )];
+    respond(&mut stream, "200 OK", &headers, &render(&source())).await
+}
+
+#[test]
+fn latency_buckets_are_cumulative() {
+    let counters = Counters::default();
+    counters.announced(Duration::from_millis(80));
+    counters.announced(Duration::from_millis(700));
+    counters.announced(Duration::from_secs(60));
+    let latency = counters.announce_latency();
+    assert_eq!(latency.count, 3);
+    assert!((latency.sum - 60.78).abs() < 1e-9);
+    assert_eq!(latency.buckets[0], (0.05, 0));
+    assert_eq!(latency.buckets[1], (0.1, 1));
+    assert_eq!(latency.buckets[4], (1.0, 2));
+    assert_eq!(latency.buckets.last(), Some(&(30.0, 2)));
+
+    let operation = counters.disk_operation();
+    assert_eq!(counters.disk_queue(), 1);
+    drop(operation);
+    assert_eq!(counters.disk_queue(), 0);
+}
+
+#[test]
+fn render_prometheus_text() {
+    let text = render(&[TorrentMetrics {
+        info_hash: [0xab; 20],
+        name: "say \"hi\".txt".to_string(),
+        downloaded: 1024,
+        peers: 3,
+        announce_latency: Histogram {
+            buckets: vec![(0.5, 1), (1.0, 2)],
+            count: 3,
+            sum: 12.5,
+        },
+        ..Default::default()
+    }]);
+    let labels = format!(
+        "info_hash=\"{}\",name=\"say \\\"hi\\\".txt\"",
+        "ab".repeat(20)
+    );
+    assert!(text.contains("# TYPE oxitorrent_downloaded_bytes_total counter\n"));
+    assert!(text.contains(&format!(
+        "oxitorrent_downloaded_bytes_total{{{labels}}} 1024\n"
+    )));
+    assert!(text.contains(&format!("oxitorrent_peers{{{labels}}} 3\n")));
+    let latency = "oxitorrent_tracker_announce_duration_seconds";
+    assert!(text.contains(&format!("{latency}_bucket{{{labels},le=\"1\"}} 2\n")));
+    assert!(text.contains(&format!("{latency}_bucket{{{labels},le=\"+Inf\"}} 3\n")));
+    assert!(text.contains(&format!("{latency}_sum{{{labels}}} 12.5\n")));
+}
+
+#[tokio::test]
+async fn serve_metrics_over_http() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = MetricsServer::new(|| {
+        vec![TorrentMetrics {
+            peers: 7,
+            ..Default::default()
+        }]
+    });
+    tokio::spawn(async move { server.run(listener).await });
+
+    let get = |path: &'static str| async move {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    };
+    let response = get("/metrics").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("\noxitorrent_peers{") && response.contains("} 7\n"));
+    assert!(get("/").await.starts_with("HTTP/1.1 404"));
+}
@@ -0,0 +1,570 @@
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{ready, Poll};
+
+use anyhow::{bail, Context, Result};
+use bytes::{Buf, BytesMut};
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+mod dh;
+mod rc4;
+
+use dh::{KeyPair, KEY_SIZE};
+use rc4::Rc4;
+
+/// Bits of `crypto_provide` and `crypto_select`: header only encryption, or
+/// RC4 on the whole stream
+pub const CRYPTO_PLAINTEXT: u32 = 0x01;
+pub const CRYPTO_RC4: u32 = 0x02;
+/// Start of a plaintext handshake, told apart from a public key on accept
+pub const PLAINTEXT_HEADER: &[u8; 20] = b"\x13BitTorrent protocol";
+/// Verification constant, its encryption marks where the encrypted part starts
+const VC: [u8; 8] = [0; 8];
+/// Longest padding after a public key or in the encrypted header
+const MAX_PAD: usize = 512;
+/// Keystream bytes dropped before use, the first ones leak the key
+const RC4_DISCARD: usize = 1024;
+
+/// What to do about message stream encryption on peer connections
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EncryptionPolicy {
+    /// Plaintext only, encrypted connections are refused
+    Disabled,
+    /// Encrypt outgoing connections when the peer can, accept both
+    #[default]
+    Enabled,
+    /// Only RC4 encrypted connections
+    Forced,
+}
+
+impl EncryptionPolicy {
+    /// Crypto methods offered on outgoing connections and accepted on
+    /// incoming ones
+    pub fn methods(self) -> u32 {
+        match self {
+            EncryptionPolicy::Disabled => 0,
+            EncryptionPolicy::Enabled => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+            EncryptionPolicy::Forced => CRYPTO_RC4,
+        }
+    }
+}
+
+impl FromStr for EncryptionPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "disabled" => Ok(EncryptionPolicy::Disabled),
+            "enabled" => Ok(EncryptionPolicy::Enabled),
+            "forced" => Ok(EncryptionPolicy::Forced),
+            _ => bail!("unknown encryption policy {s:?}, expected disabled, enabled or forced"),
+        }
+    }
+}
+
+impl fmt::Display for EncryptionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            EncryptionPolicy::Disabled => "disabled",
+            EncryptionPolicy::Enabled => "enabled",
+            EncryptionPolicy::Forced => "forced",
+        })
+    }
+}
+
+/// Runs the message stream encryption handshake of an outgoing connection,
+/// offering the methods of `provide`. The BitTorrent handshake follows.
+pub async fn initiate<S>(
+    mut stream: S,
+    info_hash: [u8; 20],
+    provide: u32,
+) -> Result<CryptoStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let keys = KeyPair::generate();
+    stream
+        .write_all(&[&keys.public[..], &padding()].concat())
+        .await?;
+    let mut buffer = BytesMut::new();
+    let remote = read_exact(&mut stream, &mut buffer, KEY_SIZE).await?;
+    let secret = keys
+        .shared_secret(remote[..].try_into().expect("read a whole key"))
+        .context("peer sent an invalid public key")?;
+    let (mut encrypt, mut decrypt) = ciphers(&secret, &info_hash, true);
+
+    let obfuscated = xor(&hash(&[b"req2", &info_hash]), &hash(&[b"req3", &secret]));
+    let mut header = VC.to_vec();
+    header.extend(provide.to_be_bytes());
+    // No padding and no initial payload, the handshake is sent afterwards
+    header.extend(0u16.to_be_bytes());
+    header.extend(0u16.to_be_bytes());
+    encrypt.apply(&mut header);
+    stream
+        .write_all(&[&hash(&[b"req1", &secret])[..], &obfuscated, &header].concat())
+        .await?;
+
+    let mut vc = VC;
+    decrypt.apply(&mut vc);
+    scan(&mut stream, &mut buffer, &vc).await?;
+    let mut select = read_exact(&mut stream, &mut buffer, 6).await?;
+    decrypt.apply(&mut select);
+    let method = u32::from_be_bytes(select[..4].try_into().expect("4 bytes"));
+    let pad = u16::from_be_bytes(select[4..].try_into().expect("2 bytes")) as usize;
+    if method.count_ones() != 1 || method & provide == 0 {
+        bail!("peer selected crypto method {method:#x} we did not offer");
+    }
+    if pad > MAX_PAD {
+        bail!("padding of {pad} bytes is too long");
+    }
+    let mut pad = read_exact(&mut stream, &mut buffer, pad).await?;
+    decrypt.apply(&mut pad);
+    Ok(CryptoStream::new(
+        stream,
+        method,
+        (encrypt, decrypt),
+        &[],
+        buffer,
+    ))
+}
+
+/// Takes an incoming connection, plaintext or encrypted as `policy` allows
+pub async fn accept<S>(
+    stream: S,
+    info_hash: [u8; 20],
+    policy: EncryptionPolicy,
+) -> Result<CryptoStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (stream, _) = accept_any(stream, &[info_hash], policy).await?;
+    Ok(stream)
+}
+
+/// Takes an incoming connection for any of `info_hashes`, as when torrents
+/// share a listener. The torrent is known here for encrypted connections
+/// only, plaintext ones tell it in the BitTorrent handshake.
+pub async fn accept_any<S>(
+    mut stream: S,
+    info_hashes: &[[u8; 20]],
+    policy: EncryptionPolicy,
+) -> Result<(CryptoStream<S>, Option<[u8; 20]>)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buffer = BytesMut::new();
+    fill(&mut stream, &mut buffer, PLAINTEXT_HEADER.len()).await?;
+    if buffer.starts_with(PLAINTEXT_HEADER) {
+        if policy == EncryptionPolicy::Forced {
+            bail!("plaintext connection refused, encryption is forced");
+        }
+        let mut stream = CryptoStream::plaintext(stream);
+        stream.prefix = buffer;
+        return Ok((stream, None));
+    }
+    if policy == EncryptionPolicy::Disabled {
+        bail!("encrypted connection refused, encryption is disabled");
+    }
+    let (stream, info_hash) = respond(stream, buffer, info_hashes, policy.methods()).await?;
+    Ok((stream, Some(info_hash)))
+}
+
+/// Answers the handshake of an incoming connection whose first bytes are in
+/// `buffer`, selecting RC4 over plaintext when both are offered and allowed.
+/// Returns the info hash among `info_hashes` the peer asked for.
+async fn respond<S>(
+    mut stream: S,
+    mut buffer: BytesMut,
+    info_hashes: &[[u8; 20]],
+    allowed: u32,
+) -> Result<(CryptoStream<S>, [u8; 20])>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let remote = read_exact(&mut stream, &mut buffer, KEY_SIZE).await?;
+    let keys = KeyPair::generate();
+    let secret = keys
+        .shared_secret(remote[..].try_into().expect("read a whole key"))
+        .context("peer sent an invalid public key")?;
+    stream
+        .write_all(&[&keys.public[..], &padding()].concat())
+        .await?;
+
+    scan(&mut stream, &mut buffer, &hash(&[b"req1", &secret])).await?;
+    let obfuscated = read_exact(&mut stream, &mut buffer, 20).await?;
+    let requested = xor(&obfuscated, &hash(&[b"req3", &secret]));
+    let Some(&info_hash) = info_hashes
+        .iter()
+        .find(|info_hash| hash(&[b"req2", &info_hash[..]]) == requested)
+    else {
+        bail!("peer asked for an unknown info hash");
+    };
+    let (mut encrypt, mut decrypt) = ciphers(&secret, &info_hash, false);
+
+    let mut header = read_exact(&mut stream, &mut buffer, 14).await?;
+    decrypt.apply(&mut header);
+    if header[..8] != VC {
+        bail!("invalid verification constant");
+    }
+    let provide = u32::from_be_bytes(header[8..12].try_into().expect("4 bytes"));
+    let pad = u16::from_be_bytes(header[12..].try_into().expect("2 bytes")) as usize;
+    if pad > MAX_PAD {
+        bail!("padding of {pad} bytes is too long");
+    }
+    let mut pad = read_exact(&mut stream, &mut buffer, pad + 2).await?;
+    decrypt.apply(&mut pad);
+    let initial = u16::from_be_bytes(pad[pad.len() - 2..].try_into().expect("2 bytes"));
+    let mut initial = read_exact(&mut stream, &mut buffer, initial as usize).await?;
+    decrypt.apply(&mut initial);
+
+    let common = provide & allowed;
+    let method = if common & CRYPTO_RC4 != 0 {
+        CRYPTO_RC4
+    } else if common & CRYPTO_PLAINTEXT != 0 {
+        CRYPTO_PLAINTEXT
+    } else {
+        bail!("no common crypto method, peer provides {provide:#x}");
+    };
+    let mut answer = VC.to_vec();
+    answer.extend(method.to_be_bytes());
+    answer.extend(0u16.to_be_bytes());
+    encrypt.apply(&mut answer);
+    stream.write_all(&answer).await?;
+    let stream = CryptoStream::new(stream, method, (encrypt, decrypt), &initial, buffer);
+    Ok((stream, info_hash))
+}
+
+/// A connection after the encryption handshake, RC4 is applied to what is
+/// read and written when it was selected
+pub struct CryptoStream<S> {
+    inner: S,
+    /// Payload received with the handshake, already decrypted
+    prefix: BytesMut,
+    encrypt: Option<Rc4>,
+    decrypt: Option<Rc4>,
+    /// Encrypted bytes not written to `inner` yet
+    pending: BytesMut,
+    /// Method selected by the handshake, None for a plaintext connection
+    method: Option<u32>,
+}
+
+impl<S> CryptoStream<S> {
+    /// A connection that skipped the encryption handshake
+    pub fn plaintext(inner: S) -> Self {
+        Self {
+            inner,
+            prefix: BytesMut::new(),
+            encrypt: None,
+            decrypt: None,
+            pending: BytesMut::new(),
+            method: None,
+        }
+    }
+
+    /// `initial` is payload decrypted during the handshake, `received` the
+    /// bytes read past the handshake
+    fn new(
+        inner: S,
+        method: u32,
+        (encrypt, mut decrypt): (Rc4, Rc4),
+        initial: &[u8],
+        mut received: BytesMut,
+    ) -> Self {
+        let rc4 = method == CRYPTO_RC4;
+        if rc4 {
+            decrypt.apply(&mut received);
+        }
+        let mut prefix = BytesMut::from(initial);
+        prefix.extend_from_slice(&received);
+        Self {
+            inner,
+            prefix,
+            encrypt: rc4.then_some(encrypt),
+            decrypt: rc4.then_some(decrypt),
+            pending: BytesMut::new(),
+            method: Some(method),
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Whether the connection went through the encryption handshake, the
+    /// payload is still plaintext when only the header was encrypted
+    pub fn is_encrypted(&self) -> bool {
+        self.method.is_some()
+    }
+
+    /// Crypto method selected by the handshake
+    pub fn method(&self) -> Option<u32> {
+        self.method
+    }
+}
+
+impl<S: AsyncWrite + Unpin> CryptoStream<S> {
+    fn poll_pending(&mut self, cx: &mut std::task::Context<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pending.advance(written);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CryptoStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.prefix.is_empty() {
+            let length = this.prefix.len().min(buf.remaining());
+            buf.put_slice(&this.prefix.split_to(length));
+            return Poll::Ready(Ok(()));
+        }
+        let start = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if let Some(decrypt) = &mut this.decrypt {
+            decrypt.apply(&mut buf.filled_mut()[start..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CryptoStream<S> {
+    /// Encrypted data is accepted whole once the previous write went out, the
+    /// rest of it is written by the next write or flush
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.encrypt.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, data);
+        }
+        ready!(this.poll_pending(cx))?;
+        let encrypt = this.encrypt.as_mut().expect("checked above");
+        this.pending.extend_from_slice(data);
+        encrypt.apply(&mut this.pending);
+        if let Poll::Ready(Err(e)) = this.poll_pending(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+/// Ciphers for what we send and what we receive, the initiator encrypts
+/// with keyA and the receiver with keyB
+fn ciphers(secret: &[u8], info_hash: &[u8; 20], initiator: bool) -> (Rc4, Rc4) {
+    let mut a = Rc4::new(&hash(&[b"keyA", secret, info_hash]));
+    let mut b = Rc4::new(&hash(&[b"keyB", secret, info_hash]));
+    a.discard(RC4_DISCARD);
+    b.discard(RC4_DISCARD);
+    if initiator {
+        (a, b)
+    } else {
+        (b, a)
+    }
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    parts.iter().for_each(|part| hasher.update(part));
+    hasher.finalize().into()
+}
+
+fn xor(a: &[u8], b: &[u8; 20]) -> [u8; 20] {
+    std::array::from_fn(|i| a[i] ^ b[i])
+}
+
+/// Random bytes of random length, hiding the size of the handshake
+fn padding() -> Vec<u8> {
+    let random = crate::random_bytes();
+    let length = u16::from_be_bytes([random[0], random[1]]) as usize % (MAX_PAD + 1);
+    std::iter::repeat_with(crate::random_bytes)
+        .flatten()
+        .take(length)
+        .collect()
+}
+
+/// Reads until `buffer` holds at least `length` bytes
+async fn fill<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buffer: &mut BytesMut,
+    length: usize,
+) -> Result<()> {
+    while buffer.len() < length {
+        buffer.reserve(length - buffer.len());
+        if stream.read_buf(buffer).await? == 0 {
+            bail!("connection closed during the encryption handshake");
+        }
+    }
+    Ok(())
+}
+
+async fn read_exact<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buffer: &mut BytesMut,
+    length: usize,
+) -> Result<BytesMut> {
+    fill(stream, buffer, length).await?;
+    Ok(buffer.split_to(length))
+}
+
+/// Skips the padding of the peer, up to its longest, and `pattern` after it
+async fn scan<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buffer: &mut BytesMut,
+    pattern: &[u8],
+) -> Result<()> {
+    let mut searched = 0;
+    loop {
+        let window = buffer.len().min(MAX_PAD + pattern.len());
+        if let Some(position) = buffer[..window]
+            .windows(pattern.len())
+            .skip(searched)
+            .position(|bytes| bytes == pattern)
+        {
+            buffer.advance(searched + position + pattern.len());
+            return Ok(());
+        }
+        if window == MAX_PAD + pattern.len() {
+            bail!("no synchronisation pattern after the padding");
+        }
+        searched = (window + 1).saturating_sub(pattern.len());
+        fill(stream, buffer, buffer.len() + 1).await?;
+    }
+}
+
+#[cfg(test)]
+async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(
+    a: &mut CryptoStream<S>,
+    b: &mut CryptoStream<S>,
+) {
+    a.write_all(b"hello from a").await.unwrap();
+    a.flush().await.unwrap();
+    let mut received = [0; 12];
+    b.read_exact(&mut received).await.unwrap();
+    assert_eq!(&received, b"hello from a");
+
+    b.write_all(b"hello from b").await.unwrap();
+    b.flush().await.unwrap();
+    a.read_exact(&mut received).await.unwrap();
+    assert_eq!(&received, b"hello from b");
+}
+
+#[tokio::test]
+async fn encrypted_and_header_only_connections() {
+    for (provide, policy, method) in [
+        (
+            CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+            EncryptionPolicy::Enabled,
+            CRYPTO_RC4,
+        ),
+        (CRYPTO_RC4, EncryptionPolicy::Forced, CRYPTO_RC4),
+        (
+            CRYPTO_PLAINTEXT,
+            EncryptionPolicy::Enabled,
+            CRYPTO_PLAINTEXT,
+        ),
+    ] {
+        let (a, b) = tokio::io::duplex(4096);
+        let (a, b) = tokio::join!(initiate(a, [7; 20], provide), accept(b, [7; 20], policy));
+        let (mut a, mut b) = (a.unwrap(), b.unwrap());
+        assert_eq!(a.method(), Some(method));
+        assert_eq!(b.method(), Some(method));
+        exchange(&mut a, &mut b).await;
+    }
+}
+
+#[tokio::test]
+async fn payload_is_encrypted_on_the_wire() {
+    let (a, mut b) = tokio::io::duplex(4096);
+    let (a, mut b) = tokio::join!(initiate(a, [7; 20], CRYPTO_RC4), async {
+        let mut buffer = BytesMut::new();
+        fill(&mut b, &mut buffer, 20).await.unwrap();
+        respond(b, buffer, &[[7; 20]], CRYPTO_RC4).await.unwrap().0
+    });
+    let mut a = a.unwrap();
+    a.write_all(PLAINTEXT_HEADER).await.unwrap();
+    a.flush().await.unwrap();
+    let mut raw = [0; 20];
+    b.inner.read_exact(&mut raw).await.unwrap();
+    assert_ne!(&raw, PLAINTEXT_HEADER);
+}
+
+#[tokio::test]
+async fn policies_of_incoming_connections() {
+    // Plaintext handshakes are read back through the stream
+    let (mut a, b) = tokio::io::duplex(4096);
+    a.write_all(PLAINTEXT_HEADER).await.unwrap();
+    let mut b = accept(b, [7; 20], EncryptionPolicy::Enabled).await.unwrap();
+    assert!(!b.is_encrypted());
+    let mut header = [0; 20];
+    b.read_exact(&mut header).await.unwrap();
+    assert_eq!(&header, PLAINTEXT_HEADER);
+
+    let (mut a, b) = tokio::io::duplex(4096);
+    a.write_all(PLAINTEXT_HEADER).await.unwrap();
+    assert!(accept(b, [7; 20], EncryptionPolicy::Forced).await.is_err());
+
+    let (a, b) = tokio::io::duplex(4096);
+    let (a, b) = tokio::join!(
+        initiate(a, [7; 20], CRYPTO_RC4),
+        accept(b, [7; 20], EncryptionPolicy::Disabled)
+    );
+    assert!(a.is_err() && b.is_err());
+
+    // Header only encryption is not enough when it is forced
+    let (a, b) = tokio::io::duplex(4096);
+    let (a, b) = tokio::join!(
+        initiate(a, [7; 20], CRYPTO_PLAINTEXT),
+        accept(b, [7; 20], EncryptionPolicy::Forced)
+    );
+    assert!(a.is_err() && b.is_err());
+
+    let (a, b) = tokio::io::duplex(4096);
+    let (a, b) = tokio::join!(
+        initiate(a, [7; 20], CRYPTO_RC4),
+        accept(b, [8; 20], EncryptionPolicy::Enabled)
+    );
+    assert!(a.is_err() && b.is_err());
+
+    // A shared listener learns which torrent the peer wants
+    let (a, b) = tokio::io::duplex(4096);
+    let (a, b) = tokio::join!(
+        initiate(a, [7; 20], CRYPTO_RC4),
+        accept_any(b, &[[8; 20], [7; 20]], EncryptionPolicy::Enabled)
+    );
+    let (mut a, (mut b, info_hash)) = (a.unwrap(), b.unwrap());
+    assert_eq!(info_hash, Some([7; 20]));
+    exchange(&mut a, &mut b).await;
+}
@@ -0,0 +1,217 @@
+/// Limbs of 32 bits in a 768 bit number
+const LIMBS: usize = 24;
+/// Size of a public key or shared secret, big endian
+pub const KEY_SIZE: usize = LIMBS * 4;
+
+/// The 768 bit safe prime of message stream encryption, generator 2
+const PRIME: [u8; KEY_SIZE] = [
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xc9, 0x0f, 0xda, 0xa2, 0x21, 0x68, 0xc2, 0x34,
+    0xc4, 0xc6, 0x62, 0x8b, 0x80, 0xdc, 0x1c, 0xd1, 0x29, 0x02, 0x4e, 0x08, 0x8a, 0x67, 0xcc, 0x74,
+    0x02, 0x0b, 0xbe, 0xa6, 0x3b, 0x13, 0x9b, 0x22, 0x51, 0x4a, 0x08, 0x79, 0x8e, 0x34, 0x04, 0xdd,
+    0xef, 0x95, 0x19, 0xb3, 0xcd, 0x3a, 0x43, 0x1b, 0x30, 0x2b, 0x0a, 0x6d, 0xf2, 0x5f, 0x14, 0x37,
+    0x4f, 0xe1, 0x35, 0x6d, 0x6d, 0x51, 0xc2, 0x45, 0xe4, 0x85, 0xb5, 0x76, 0x62, 0x5e, 0x7e, 0xc6,
+    0xf4, 0x4c, 0x42, 0xe9, 0xa6, 0x3a, 0x36, 0x21, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x05, 0x63,
+];
+const GENERATOR: u8 = 2;
+
+/// A number modulo the prime, little endian limbs
+type Limbs = [u32; LIMBS];
+
+/// Our half of a Diffie-Hellman exchange
+pub struct KeyPair {
+    private: Vec<u8>,
+    pub public: [u8; KEY_SIZE],
+}
+
+impl KeyPair {
+    /// A pair with a 160 bit private key, as recommended for MSE
+    pub fn generate() -> Self {
+        let private = crate::random_bytes().to_vec();
+        let mut generator = [0; KEY_SIZE];
+        generator[KEY_SIZE - 1] = GENERATOR;
+        let public = pow(&generator, &private);
+        Self { private, public }
+    }
+
+    /// The secret shared with the owner of `remote`, None when the remote key
+    /// is not a valid element of the group
+    pub fn shared_secret(&self, remote: &[u8; KEY_SIZE]) -> Option<[u8; KEY_SIZE]> {
+        let value = from_bytes(remote);
+        let one = {
+            let mut one = [0; LIMBS];
+            one[0] = 1;
+            one
+        };
+        if value <= one || !less(&value, &from_bytes(&PRIME)) {
+            return None;
+        }
+        Some(pow(remote, &self.private))
+    }
+}
+
+/// `base ^ exponent mod PRIME`, `base` must be below the prime
+pub fn pow(base: &[u8; KEY_SIZE], exponent: &[u8]) -> [u8; KEY_SIZE] {
+    let modulus = Montgomery::new();
+    let base = modulus.to_montgomery(&from_bytes(base));
+    let mut result = modulus.one;
+    for byte in exponent {
+        for bit in (0..8).rev() {
+            result = modulus.mul(&result, &result);
+            if byte >> bit & 1 == 1 {
+                result = modulus.mul(&result, &base);
+            }
+        }
+    }
+    let mut one = [0; LIMBS];
+    one[0] = 1;
+    to_bytes(&modulus.mul(&result, &one))
+}
+
+/// Montgomery arithmetic modulo the prime, with R = 2^768
+struct Montgomery {
+    prime: Limbs,
+    /// `-prime^-1 mod 2^32`
+    inverse: u32,
+    /// R mod prime, the Montgomery form of 1
+    one: Limbs,
+    /// R^2 mod prime, to convert into Montgomery form
+    r2: Limbs,
+}
+
+impl Montgomery {
+    fn new() -> Self {
+        let prime = from_bytes(&PRIME);
+        // Newton iteration doubles the correct low bits of the inverse, an
+        // odd number is its own inverse modulo 8
+        let mut inverse = prime[0];
+        for _ in 0..4 {
+            inverse = inverse.wrapping_mul(2u32.wrapping_sub(prime[0].wrapping_mul(inverse)));
+        }
+        let mut one = [0; LIMBS];
+        one[0] = 1;
+        for _ in 0..LIMBS * 32 {
+            one = double(&one, &prime);
+        }
+        let mut r2 = one;
+        for _ in 0..LIMBS * 32 {
+            r2 = double(&r2, &prime);
+        }
+        Self {
+            prime,
+            inverse: inverse.wrapping_neg(),
+            one,
+            r2,
+        }
+    }
+
+    fn to_montgomery(&self, value: &Limbs) -> Limbs {
+        self.mul(value, &self.r2)
+    }
+
+    /// `a * b / R mod prime`
+    fn mul(&self, a: &Limbs, b: &Limbs) -> Limbs {
+        let mut t = [0u32; LIMBS + 2];
+        for &bi in b {
+            let mut carry = 0u64;
+            for j in 0..LIMBS {
+                let sum = t[j] as u64 + a[j] as u64 * bi as u64 + carry;
+                t[j] = sum as u32;
+                carry = sum >> 32;
+            }
+            let sum = t[LIMBS] as u64 + carry;
+            t[LIMBS] = sum as u32;
+            t[LIMBS + 1] = (sum >> 32) as u32;
+
+            let m = t[0].wrapping_mul(self.inverse);
+            let mut carry = (t[0] as u64 + m as u64 * self.prime[0] as u64) >> 32;
+            for j in 1..LIMBS {
+                let sum = t[j] as u64 + m as u64 * self.prime[j] as u64 + carry;
+                t[j - 1] = sum as u32;
+                carry = sum >> 32;
+            }
+            let sum = t[LIMBS] as u64 + carry;
+            t[LIMBS - 1] = sum as u32;
+            t[LIMBS] = t[LIMBS + 1] + (sum >> 32) as u32;
+            t[LIMBS + 1] = 0;
+        }
+        let mut result: Limbs = t[..LIMBS].try_into().expect("slice has LIMBS limbs");
+        if t[LIMBS] != 0 || !less(&result, &self.prime) {
+            subtract(&mut result, &self.prime);
+        }
+        result
+    }
+}
+
+/// `2 * value mod prime`, for a value below the prime
+fn double(value: &Limbs, prime: &Limbs) -> Limbs {
+    let mut result = [0; LIMBS];
+    let mut carry = 0;
+    for i in 0..LIMBS {
+        result[i] = value[i] << 1 | carry;
+        carry = value[i] >> 31;
+    }
+    if carry != 0 || !less(&result, prime) {
+        subtract(&mut result, prime);
+    }
+    result
+}
+
+/// `value -= other`, wrapping around
+fn subtract(value: &mut Limbs, other: &Limbs) {
+    let mut borrow = 0u64;
+    for i in 0..LIMBS {
+        let difference = (value[i] as u64).wrapping_sub(other[i] as u64 + borrow);
+        value[i] = difference as u32;
+        borrow = difference >> 63;
+    }
+}
+
+fn less(a: &Limbs, b: &Limbs) -> bool {
+    a.iter().rev().lt(b.iter().rev())
+}
+
+fn from_bytes(bytes: &[u8; KEY_SIZE]) -> Limbs {
+    std::array::from_fn(|i| {
+        let start = KEY_SIZE - 4 * (i + 1);
+        u32::from_be_bytes(bytes[start..start + 4].try_into().expect("4 bytes"))
+    })
+}
+
+fn to_bytes(limbs: &Limbs) -> [u8; KEY_SIZE] {
+    let mut bytes = [0; KEY_SIZE];
+    for (i, limb) in limbs.iter().enumerate() {
+        let start = KEY_SIZE - 4 * (i + 1);
+        bytes[start..start + 4].copy_from_slice(&limb.to_be_bytes());
+    }
+    bytes
+}
+
+#[cfg(test)]
+fn small(value: u8) -> [u8; KEY_SIZE] {
+    let mut bytes = [0; KEY_SIZE];
+    bytes[KEY_SIZE - 1] = value;
+    bytes
+}
+
+#[test]
+fn modular_exponentiation() {
+    let mut expected = [0; KEY_SIZE];
+    expected[KEY_SIZE - 2] = 4;
+    assert_eq!(pow(&small(2), &[10]), expected);
+    assert_eq!(pow(&small(3), &[0]), small(1));
+    // Fermat: x^(p-1) = 1 for a prime p
+    let mut exponent = PRIME;
+    exponent[KEY_SIZE - 1] -= 1;
+    assert_eq!(pow(&small(3), &exponent), small(1));
+    assert_eq!(pow(&small(2), &exponent), small(1));
+}
+
+#[test]
+fn key_agreement() {
+    let a = KeyPair::generate();
+    let b = KeyPair::generate();
+    assert_ne!(a.public, b.public);
+    assert_eq!(a.shared_secret(&b.public), b.shared_secret(&a.public));
+    assert!(a.shared_secret(&PRIME).is_none());
+    assert!(a.shared_secret(&small(1)).is_none());
+}
@@ -0,0 +1,49 @@
+/// The RC4 stream cipher, the only cipher of message stream encryption
+#[derive(Clone)]
+pub struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    pub fn new(key: &[u8]) -> Self {
+        let mut state: [u8; 256] = std::array::from_fn(|i| i as u8);
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+        Self { state, i: 0, j: 0 }
+    }
+
+    /// Encrypts or decrypts `data` in place
+    pub fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let k = self.state[self.i as usize].wrapping_add(self.state[self.j as usize]);
+            *byte ^= self.state[k as usize];
+        }
+    }
+
+    /// Drops the first `count` bytes of keystream, the weakest ones
+    pub fn discard(&mut self, count: usize) {
+        self.apply(&mut vec![0; count]);
+    }
+}
+
+#[test]
+fn rc4_test_vector() {
+    let mut data = *b"Plaintext";
+    Rc4::new(b"Key").apply(&mut data);
+    assert_eq!(hex::encode(data), "bbf316e8d940af0ad3");
+
+    let mut rc4 = Rc4::new(b"Key");
+    let mut first = *b"Plain";
+    let mut second = *b"text";
+    rc4.apply(&mut first);
+    rc4.apply(&mut second);
+    assert_eq!([&first[..], &second[..]].concat(), data);
+}
@@ -1,16 +1,59 @@
-use std::net::SocketAddrV4;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
 
-use anyhow::{Context, Result};
-use bytes::{BufMut, BytesMut};
+use anyhow::{bail, Context, Result};
+use bytes::{Buf, BufMut, BytesMut};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 
+use crate::extension::{self, Extensions};
+use crate::mse::{self, CryptoStream, EncryptionPolicy};
+use crate::ratelimit::{RateLimiter, Throttled};
+use crate::transport::Transport;
+use crate::utp::UtpSocket;
+use crate::{debug, trace};
+
 pub fn as_bytes_mut<T: Sized>(data: &mut T) -> &mut [u8] {
     let ptr = data as *mut T as *mut u8;
     let len = std::mem::size_of::<T>();
     unsafe { std::slice::from_raw_parts_mut(ptr, len) }
 }
 
+/// Size of a piece message carrying a full block, the usual biggest message
+const BLOCK_MESSAGE_SIZE: usize = 16 * 1024 + 13;
+/// Messages bigger than this are treated as a protocol error
+const MAX_MESSAGE_LENGTH: usize = 1024 * 1024;
+/// Time allowed to open a TCP connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time allowed for the encryption handshake of a new connection
+const ENCRYPTION_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time allowed for the BitTorrent handshake of a new connection
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Peer id sent in handshakes and announces unless configured otherwise
+pub const DEFAULT_PEER_ID: [u8; 20] = *b"00112233445566778899";
+
+static LOCAL_ID: OnceLock<[u8; 20]> = OnceLock::new();
+
+/// Sets the peer id we send from now on, later calls are ignored
+pub fn set_local_id(peer_id: [u8; 20]) {
+    let _ = LOCAL_ID.set(peer_id);
+}
+
+/// Our peer id, [`DEFAULT_PEER_ID`] until [`set_local_id`] is called
+pub fn local_id() -> [u8; 20] {
+    LOCAL_ID.get().copied().unwrap_or(DEFAULT_PEER_ID)
+}
+
+/// Reserved byte and bit that advertise the extension protocol (BEP 10)
+pub const EXTENSION_BIT: (usize, u8) = (5, 0x10);
+/// Reserved byte and bit that advertise a DHT node, announced with PORT (BEP 5)
+pub const DHT_BIT: (usize, u8) = (7, 0x01);
+/// Reserved byte and bit that advertise the fast extension (BEP 6)
+pub const FAST_BIT: (usize, u8) = (7, 0x04);
+
 #[repr(C)]
 pub struct Handshake {
     pub length: u8,
@@ -21,11 +64,18 @@ pub struct Handshake {
 }
 
 impl Handshake {
-    pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
+    /// Our handshake, it advertises a DHT node when `dht` is set
+    pub fn new(info_hash: [u8; 20], peer_id: [u8; 20], dht: bool) -> Self {
+        let mut reserved_bytes = [0; 8];
+        reserved_bytes[EXTENSION_BIT.0] |= EXTENSION_BIT.1;
+        if dht {
+            reserved_bytes[DHT_BIT.0] |= DHT_BIT.1;
+        }
+        reserved_bytes[FAST_BIT.0] |= FAST_BIT.1;
         Self {
             length: 19,
             protocol: *b"BitTorrent protocol",
-            reserved_bytes: [0; 8],
+            reserved_bytes,
             info_hash,
             peer_id,
         }
@@ -67,7 +117,12 @@ pub struct Piece {
 }
 
 impl Piece {
+    /// Parses the payload of a piece message, which comes from the peer and
+    /// may be too short
     pub fn from_u8(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < 8 {
+            bail!("piece message of {} bytes is too short", bytes.len());
+        }
         Ok(Self {
             index: bytes[..4].try_into()?,
             begin: bytes[4..8].try_into()?,
@@ -120,6 +175,13 @@ pub enum MessageTag {
     Request = 6,
     Piece = 7,
     Cancel = 8,
+    Port = 9,
+    Suggest = 13,
+    HaveAll = 14,
+    HaveNone = 15,
+    RejectRequest = 16,
+    AllowedFast = 17,
+    Extended = 20,
 }
 
 impl MessageTag {
@@ -134,6 +196,13 @@ impl MessageTag {
             6 => MessageTag::Request,
             7 => MessageTag::Piece,
             8 => MessageTag::Cancel,
+            9 => MessageTag::Port,
+            13 => MessageTag::Suggest,
+            14 => MessageTag::HaveAll,
+            15 => MessageTag::HaveNone,
+            16 => MessageTag::RejectRequest,
+            17 => MessageTag::AllowedFast,
+            20 => MessageTag::Extended,
             tag => {
                 return Err(anyhow::anyhow!("Unknown tag: {}", tag));
             }
@@ -143,74 +212,504 @@ impl MessageTag {
 }
 
 pub struct Peer {
-    stream: TcpStream,
+    stream: CryptoStream<Throttled<Transport>>,
+    buffer: BytesMut,
+    pub addr: SocketAddr,
+    /// Torrent the connection is for
+    pub info_hash: [u8; 20],
     pub peer_id: [u8; 20],
+    pub reserved_bytes: [u8; 8],
+    /// Extensions enabled on this connection, register them before sending
+    /// the extended handshake
+    pub extensions: Extensions,
+    /// When the peer last sent anything, keep-alives included
+    last_received: Instant,
 }
 
 impl Peer {
     /// Creates a new Peer, by creating a Tcp stream, then attempting a Handshake
     /// with the given peer address
     /// Returns an error if the handshake fails.
-    pub async fn connect_peer(peer: SocketAddrV4, info_hash: [u8; 20]) -> Result<Self> {
-        let mut connection = TcpStream::connect(peer)
+    pub async fn connect_peer(peer: SocketAddr, info_hash: [u8; 20]) -> Result<Self> {
+        Self::connect_with(peer, info_hash, EncryptionPolicy::Disabled, false).await
+    }
+
+    /// Connects to a peer over TCP, encrypting the connection as `policy`
+    /// asks. The handshake advertises our DHT node when `dht` is set.
+    pub async fn connect_with(
+        peer: SocketAddr,
+        info_hash: [u8; 20],
+        policy: EncryptionPolicy,
+        dht: bool,
+    ) -> Result<Self> {
+        let connect = || async {
+            let connection = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(peer))
+                .await
+                .context("connecting to peer timed out")?
+                .context("connecting to peer")?;
+            Ok(Transport::from(connection))
+        };
+        Self::connect_over(connect, peer, info_hash, policy, dht).await
+    }
+
+    /// Connects to a peer over uTP from `socket`, like
+    /// [`Peer::connect_with`]
+    pub async fn connect_utp(
+        socket: &UtpSocket,
+        peer: SocketAddr,
+        info_hash: [u8; 20],
+        policy: EncryptionPolicy,
+        dht: bool,
+    ) -> Result<Self> {
+        let connect = || async { Ok(Transport::from(socket.connect(peer).await?)) };
+        Self::connect_over(connect, peer, info_hash, policy, dht).await
+    }
+
+    /// Runs the handshakes on a connection opened by `connect`. When
+    /// encryption is only enabled, a peer failing the encryption handshake
+    /// gets a second, plaintext connection.
+    async fn connect_over<F, Fut>(
+        connect: F,
+        peer: SocketAddr,
+        info_hash: [u8; 20],
+        policy: EncryptionPolicy,
+        dht: bool,
+    ) -> Result<Self>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<Transport>>,
+    {
+        let connection = Throttled::new(connect().await?);
+        if policy == EncryptionPolicy::Disabled {
+            return Self::handshake(CryptoStream::plaintext(connection), peer, info_hash, dht)
+                .await;
+        }
+        let encrypted = async {
+            let stream = tokio::time::timeout(
+                ENCRYPTION_TIMEOUT,
+                mse::initiate(connection, info_hash, policy.methods()),
+            )
             .await
-            .context("connecting to peer")?;
+            .context("encryption handshake timed out")??;
+            Self::handshake(stream, peer, info_hash, dht).await
+        };
+        match encrypted.await {
+            Err(e) if policy == EncryptionPolicy::Enabled => {
+                debug!(
+                    { peer = peer },
+                    "encrypted connection failed, retrying in plaintext: {e:#}"
+                );
+                let connection = CryptoStream::plaintext(Throttled::new(connect().await?));
+                Self::handshake(connection, peer, info_hash, dht).await
+            }
+            result => result,
+        }
+    }
 
-        let mut handshake = Handshake::new(info_hash, *b"00112233445566778899");
+    async fn handshake(
+        mut stream: CryptoStream<Throttled<Transport>>,
+        peer: SocketAddr,
+        info_hash: [u8; 20],
+        dht: bool,
+    ) -> Result<Self> {
+        let mut handshake = Handshake::new(info_hash, local_id(), dht);
 
         // Drops unsafe slice pointer after reading it
         {
             // Generates a mutable slice pointer to handshake
             let bytes = as_bytes_mut(&mut handshake);
 
-            connection
-                .write_all(bytes)
-                .await
-                .context("sending request")?;
+            stream.write_all(bytes).await.context("sending request")?;
+            stream.flush().await.context("sending request")?;
 
             // Reads to the same bytes slice pointing to the handshake struct
-            connection
-                .read_exact(bytes)
+            tokio::time::timeout(HANDSHAKE_TIMEOUT, stream.read_exact(bytes))
                 .await
+                .context("handshake timed out")?
                 .context("recieving handshake")?;
         }
+        if handshake.info_hash != info_hash {
+            anyhow::bail!("peer answered with a different info hash");
+        }
         Ok(Self {
-            stream: connection,
+            stream,
+            buffer: BytesMut::with_capacity(BLOCK_MESSAGE_SIZE),
+            addr: peer,
+            info_hash,
             peer_id: handshake.peer_id,
+            reserved_bytes: handshake.reserved_bytes,
+            extensions: Extensions::new(),
+            last_received: Instant::now(),
         })
     }
 
+    /// Completes the handshake of an incoming plaintext connection, the peer
+    /// talks first and has to ask for `info_hash`
+    pub async fn accept_peer(connection: TcpStream, info_hash: [u8; 20]) -> Result<Self> {
+        Self::accept_with(connection, info_hash, EncryptionPolicy::Disabled, false).await
+    }
+
+    /// Completes the handshake of an incoming connection, encrypted or not as
+    /// `policy` allows. Our handshake advertises our DHT node when `dht` is
+    /// set.
+    pub async fn accept_with(
+        connection: impl Into<Transport>,
+        info_hash: [u8; 20],
+        policy: EncryptionPolicy,
+        dht: bool,
+    ) -> Result<Self> {
+        Self::accept_any(connection, &[info_hash], policy, |_| dht).await
+    }
+
+    /// Completes the handshake of an incoming connection for any of
+    /// `info_hashes`, the one the peer asked for ends up in `info_hash`.
+    /// `dht` tells whether to advertise our DHT node to the peers of an info
+    /// hash.
+    pub async fn accept_any(
+        connection: impl Into<Transport>,
+        info_hashes: &[[u8; 20]],
+        policy: EncryptionPolicy,
+        dht: impl Fn(&[u8; 20]) -> bool,
+    ) -> Result<Self> {
+        let connection = connection.into();
+        let addr = connection.peer_addr()?;
+        let (mut connection, encrypted_for) = tokio::time::timeout(
+            ENCRYPTION_TIMEOUT,
+            mse::accept_any(Throttled::new(connection), info_hashes, policy),
+        )
+        .await
+        .context("encryption handshake timed out")??;
+        let mut remote = Handshake::new([0; 20], [0; 20], false);
+        tokio::time::timeout(
+            HANDSHAKE_TIMEOUT,
+            connection.read_exact(as_bytes_mut(&mut remote)),
+        )
+        .await
+        .context("handshake timed out")?
+        .context("recieving handshake")?;
+        // An encrypted connection is bound to the torrent of its handshake
+        let known = match encrypted_for {
+            Some(info_hash) => remote.info_hash == info_hash,
+            None => info_hashes.contains(&remote.info_hash),
+        };
+        if !known {
+            anyhow::bail!("peer asked for an unknown info hash");
+        }
+        let info_hash = remote.info_hash;
+
+        let mut handshake = Handshake::new(info_hash, local_id(), dht(&info_hash));
+        connection
+            .write_all(as_bytes_mut(&mut handshake))
+            .await
+            .context("sending handshake")?;
+        connection.flush().await.context("sending handshake")?;
+        Ok(Self {
+            stream: connection,
+            buffer: BytesMut::with_capacity(BLOCK_MESSAGE_SIZE),
+            addr,
+            info_hash,
+            peer_id: remote.peer_id,
+            reserved_bytes: remote.reserved_bytes,
+            extensions: Extensions::new(),
+            last_received: Instant::now(),
+        })
+    }
+
+    /// Whether the connection went through the encryption handshake
+    pub fn is_encrypted(&self) -> bool {
+        self.stream.is_encrypted()
+    }
+
+    /// Whether the connection runs over uTP rather than TCP
+    pub fn is_utp(&self) -> bool {
+        self.stream.get_ref().get_ref().is_utp()
+    }
+
+    /// Time since the peer last sent anything
+    pub fn idle_time(&self) -> Duration {
+        self.last_received.elapsed()
+    }
+
+    /// Limits the connection by every limiter given, the bytes of messages
+    /// and of their framing count alike
+    pub fn set_rate_limits(&mut self, download: Vec<RateLimiter>, upload: Vec<RateLimiter>) {
+        self.stream.get_mut().set_limits(download, upload);
+    }
+
+    /// Sends our extended handshake with the extensions registered so far
+    pub async fn send_extended_handshake(&mut self) -> Result<()> {
+        let handshake = self.extensions.handshake(Some(self.addr.ip()));
+        let payload = serde_bencode::to_bytes(&handshake)?;
+        self.send_message(extension::extended_message(
+            extension::HANDSHAKE_ID,
+            &payload,
+        ))
+        .await
+    }
+
+    /// Dispatches an extended message to the registered extensions and sends
+    /// their replies
+    pub async fn handle_extended(&mut self, message: &Message) -> Result<()> {
+        for reply in self.extensions.handle(message)? {
+            self.send_message(reply).await?;
+        }
+        Ok(())
+    }
+
+    /// Lets the registered extensions send their periodic messages
+    pub async fn tick_extensions(&mut self) -> Result<()> {
+        for message in self.extensions.tick()? {
+            self.send_message(message).await?;
+        }
+        Ok(())
+    }
+
+    /// Number of requests that can be outstanding with this peer, as told by
+    /// its `reqq`
+    pub fn pipeline_limit(&self) -> usize {
+        self.extensions.pipeline_limit()
+    }
+
+    /// Whether the peer advertised the extension protocol in its handshake
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved_bytes[EXTENSION_BIT.0] & EXTENSION_BIT.1 != 0
+    }
+
+    /// Whether the peer advertised the fast extension, we always do so it is
+    /// enabled on the connection
+    pub fn supports_fast(&self) -> bool {
+        self.reserved_bytes[FAST_BIT.0] & FAST_BIT.1 != 0
+    }
+
+    /// Client name and version, from the extended handshake or else from an
+    /// Azureus style peer id such as `-qB4250-`
+    pub fn client(&self) -> String {
+        if let Some(v) = self.extensions.remote().and_then(|r| r.v.clone()) {
+            return v;
+        }
+        client_from_peer_id(&self.peer_id)
+    }
+
+    /// Whether the peer runs a DHT node and may send its port
+    pub fn supports_dht(&self) -> bool {
+        self.reserved_bytes[DHT_BIT.0] & DHT_BIT.1 != 0
+    }
+
     pub async fn send_message(&mut self, message: Message) -> Result<()> {
-        eprintln!("Sending message: {:?}", message);
         let bytes = message.to_bytes();
         self.stream.write_all(&bytes).await?;
+        self.stream.flush().await?;
+        trace!(
+            { peer = self.addr, bytes = bytes.len() },
+            "sent {:?}",
+            message.tag
+        );
+        Ok(())
+    }
 
-        eprintln!("Message sent!\n");
-
+    /// Sends a zero length message so the peer does not drop an idle connection
+    pub async fn send_keep_alive(&mut self) -> Result<()> {
+        self.stream.write_all(&[0; 4]).await?;
+        self.stream.flush().await?;
         Ok(())
     }
 
+    /// Reads the next message, skipping keep-alives.
+    ///
+    /// Bytes are buffered until a whole message arrives, so the future can be
+    /// dropped in a `select!` without losing data.
     pub async fn read_message(&mut self) -> Result<Message> {
-        let mut message_length: [u8; 4] = [0; 4];
+        loop {
+            if let Some(message) = self.parse_message()? {
+                trace!(
+                    { peer = self.addr, bytes = message.payload.len() },
+                    "received {:?}",
+                    message.tag
+                );
+                return Ok(message);
+            }
+            if self.buffer.len() == self.buffer.capacity() {
+                self.buffer.reserve(BLOCK_MESSAGE_SIZE);
+            }
+            if self.stream.read_buf(&mut self.buffer).await? == 0 {
+                anyhow::bail!("connection closed by peer");
+            }
+            self.last_received = Instant::now();
+        }
+    }
 
-        self.stream.read_exact(&mut message_length).await?;
+    fn parse_message(&mut self) -> Result<Option<Message>> {
+        loop {
+            if self.buffer.len() < 4 {
+                return Ok(None);
+            }
+            let message_length =
+                u32::from_be_bytes(self.buffer[..4].try_into().expect("length is 4")) as usize;
 
-        let message_length = u32::from_be_bytes(message_length);
+            // A zero length message is a keep-alive, it carries no tag
+            if message_length == 0 {
+                self.buffer.advance(4);
+                continue;
+            }
+            if message_length > MAX_MESSAGE_LENGTH {
+                anyhow::bail!("message of {message_length} bytes is too long");
+            }
+            if self.buffer.len() < 4 + message_length {
+                self.buffer.reserve(4 + message_length - self.buffer.len());
+                return Ok(None);
+            }
 
-        let mut message_type: [u8; 1] = [0; 1];
-        self.stream.read_exact(&mut message_type).await?;
+            self.buffer.advance(4);
+            let frame = self.buffer.split_to(message_length);
+            let message = Message {
+                tag: MessageTag::from_u8(frame[0])?,
+                payload: frame[1..].to_vec(),
+            };
+            return Ok(Some(message));
+        }
+    }
+}
+
+fn client_from_peer_id(peer_id: &[u8; 20]) -> String {
+    match peer_id {
+        [b'-', a, b, version @ .., b'-', _, _, _, _, _, _, _, _, _, _, _, _]
+            if a.is_ascii_alphanumeric()
+                && b.is_ascii_alphanumeric()
+                && version.iter().all(u8::is_ascii_alphanumeric) =>
+        {
+            let version: Vec<String> = version.iter().map(|&d| (d as char).to_string()).collect();
+            format!("{}{} {}", *a as char, *b as char, version.join("."))
+        }
+        _ => "unknown".to_string(),
+    }
+}
 
-        let tag = message_type[0];
-        let message_tag = MessageTag::from_u8(tag)?;
+/// Pieces a peer has, the high bit of the first byte is piece zero
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl Bitfield {
+    pub fn new(len: usize) -> Self {
+        Self {
+            bytes: vec![0; len.div_ceil(8)],
+            len,
+        }
+    }
 
-        let mut payload: Vec<u8> = vec![0; message_length as usize - 1];
-        // Read a message of length message_length - 1 (message_tag is already read)
-        self.stream.read_exact(&mut payload).await?;
+    /// Parses the payload of a bitfield message, spare bits must be cleared
+    pub fn from_bytes(bytes: &[u8], len: usize) -> Result<Self> {
+        if bytes.len() != len.div_ceil(8) {
+            anyhow::bail!("bitfield has {} bytes for {len} pieces", bytes.len());
+        }
+        let bitfield = Self {
+            bytes: bytes.to_vec(),
+            len,
+        };
+        if (len..bytes.len() * 8).any(|i| bitfield.bit(i)) {
+            anyhow::bail!("bitfield has spare bits set");
+        }
+        Ok(bitfield)
+    }
+
+    pub fn full(len: usize) -> Self {
+        let mut bitfield = Self::new(len);
+        (0..len).for_each(|i| bitfield.set(i));
+        bitfield
+    }
+
+    fn bit(&self, index: usize) -> bool {
+        self.bytes[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    pub fn has(&self, index: usize) -> bool {
+        index < self.len && self.bit(index)
+    }
+
+    pub fn set(&mut self, index: usize) {
+        if index < self.len {
+            self.bytes[index / 8] |= 0x80 >> (index % 8);
+        }
+    }
+
+    pub fn unset(&mut self, index: usize) {
+        if index < self.len {
+            self.bytes[index / 8] &= !(0x80 >> (index % 8));
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn count(&self) -> usize {
+        self.bytes.iter().map(|b| b.count_ones() as usize).sum()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.count() == self.len
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(|&i| self.bit(i))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+#[test]
+fn bitfield_bits() {
+    let mut bitfield = Bitfield::new(10);
+    bitfield.set(0);
+    bitfield.set(9);
+    assert_eq!(bitfield.as_bytes(), &[0x80, 0x40]);
+    assert_eq!(bitfield.iter().collect::<Vec<_>>(), vec![0, 9]);
+    bitfield.unset(0);
+    assert!(!bitfield.has(0) && bitfield.has(9) && !bitfield.has(10));
+
+    assert!(Bitfield::from_bytes(&[0xff, 0xc0], 10).unwrap().is_full());
+    assert!(Bitfield::from_bytes(&[0xff, 0xe0], 10).is_err());
+    assert!(Bitfield::from_bytes(&[0xff], 10).is_err());
+}
+
+#[test]
+fn short_piece_messages_are_errors() {
+    assert!(Piece::from_u8(&[0; 7]).is_err());
+    let piece = Piece::from_u8(&[0, 0, 0, 1, 0, 0, 0, 2, 9]).unwrap();
+    assert_eq!(
+        (piece.index(), piece.begin(), piece.block()),
+        (1, 2, &[9][..])
+    );
+}
+
+#[test]
+fn clients_from_peer_ids() {
+    assert_eq!(client_from_peer_id(b"-qB4250-abcdefghijkl"), "qB 4.2.5.0");
+    assert_eq!(client_from_peer_id(b"M7-2-2--abcdefghijkl"), "unknown");
+    assert_eq!(client_from_peer_id(&[0; 20]), "unknown");
+}
 
-        let message = Message {
-            tag: message_tag,
-            payload,
+#[tokio::test]
+async fn the_dht_bit_is_only_set_with_a_dht_node() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let info_hash = [5; 20];
+    for dht in [false, true] {
+        let accept = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            Peer::accept_with(stream, info_hash, EncryptionPolicy::Disabled, !dht).await
         };
-        Ok(message)
+        let connect = Peer::connect_with(addr, info_hash, EncryptionPolicy::Disabled, dht);
+        let (accepted, connected) = tokio::join!(accept, connect);
+        assert_eq!(accepted.unwrap().supports_dht(), dht);
+        assert_eq!(connected.unwrap().supports_dht(), !dht);
     }
 }
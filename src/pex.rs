@@ -0,0 +1,309 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::extension::Extension;
+
+/// Name of the peer exchange extension in the extended handshake (BEP 11)
+pub const EXTENSION_NAME: &str = "ut_pex";
+/// Time between two messages sent to the same peer
+pub const INTERVAL: Duration = Duration::from_secs(60);
+/// Most peers added, and dropped, in a single message
+const MAX_PEERS: usize = 50;
+
+/// Flags describing a peer in the `added.f` lists
+pub mod flags {
+    pub const ENCRYPTION: u8 = 0x01;
+    pub const SEED: u8 = 0x02;
+    pub const UTP: u8 = 0x04;
+    pub const HOLEPUNCH: u8 = 0x08;
+    /// We connected to it, so it accepts incoming connections
+    pub const REACHABLE: u8 = 0x10;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PexPeer {
+    pub addr: SocketAddr,
+    pub flags: u8,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct PexMessage {
+    #[serde(default)]
+    pub added: ByteBuf,
+    #[serde(rename = "added.f", default)]
+    pub added_f: ByteBuf,
+    #[serde(default)]
+    pub added6: ByteBuf,
+    #[serde(rename = "added6.f", default)]
+    pub added6_f: ByteBuf,
+    #[serde(default)]
+    pub dropped: ByteBuf,
+    #[serde(default)]
+    pub dropped6: ByteBuf,
+}
+
+impl PexMessage {
+    pub fn new(added: &[PexPeer], dropped: &[SocketAddr]) -> Self {
+        let mut message = Self::default();
+        for peer in added {
+            match peer.addr {
+                SocketAddr::V4(_) => {
+                    message.added.extend(compact(&peer.addr));
+                    message.added_f.push(peer.flags);
+                }
+                SocketAddr::V6(_) => {
+                    message.added6.extend(compact(&peer.addr));
+                    message.added6_f.push(peer.flags);
+                }
+            }
+        }
+        for addr in dropped {
+            match addr {
+                SocketAddr::V4(_) => message.dropped.extend(compact(addr)),
+                SocketAddr::V6(_) => message.dropped6.extend(compact(addr)),
+            }
+        }
+        message
+    }
+
+    pub fn added(&self) -> Vec<PexPeer> {
+        let v4 = parse_compact(&self.added, 6)
+            .into_iter()
+            .enumerate()
+            .map(|(i, addr)| PexPeer {
+                addr,
+                flags: self.added_f.get(i).copied().unwrap_or_default(),
+            });
+        let v6 = parse_compact(&self.added6, 18)
+            .into_iter()
+            .enumerate()
+            .map(|(i, addr)| PexPeer {
+                addr,
+                flags: self.added6_f.get(i).copied().unwrap_or_default(),
+            });
+        v4.chain(v6).collect()
+    }
+
+    pub fn dropped(&self) -> Vec<SocketAddr> {
+        let mut dropped = parse_compact(&self.dropped, 6);
+        dropped.extend(parse_compact(&self.dropped6, 18));
+        dropped
+    }
+}
+
+/// Encodes an address as its ip followed by the port, in network order
+pub fn compact(addr: &SocketAddr) -> Vec<u8> {
+    let mut bytes = match addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    bytes.extend(addr.port().to_be_bytes());
+    bytes
+}
+
+/// Decodes a list of compact addresses of `size` bytes each, 6 for IPv4 and
+/// 18 for IPv6, a trailing partial entry is ignored
+pub fn parse_compact(bytes: &[u8], size: usize) -> Vec<SocketAddr> {
+    bytes
+        .chunks_exact(size)
+        .map(|chunk| {
+            let (ip, port) = chunk.split_at(size - 2);
+            let ip = match size {
+                6 => IpAddr::V4(Ipv4Addr::from(
+                    <[u8; 4]>::try_from(ip).expect("length is 4"),
+                )),
+                _ => IpAddr::V6(Ipv6Addr::from(
+                    <[u8; 16]>::try_from(ip).expect("length is 16"),
+                )),
+            };
+            SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]]))
+        })
+        .collect()
+}
+
+/// The swarm side of peer exchange: the peers we tell others about and the
+/// destination of the peers we learn
+pub trait PexSwarm: Send + Sync {
+    /// Peers we are connected to
+    fn connected(&self) -> Vec<PexPeer>;
+
+    /// Peers learned from a peer exchange message
+    fn discovered(&self, peers: Vec<PexPeer>);
+}
+
+/// The `ut_pex` extension, it sends the changes of our peer list every minute.
+/// It must not be registered for private torrents.
+pub struct PexExtension {
+    swarm: Arc<dyn PexSwarm>,
+    /// The peer this extension talks to, never sent back to itself
+    remote: SocketAddr,
+    /// Peers the remote knows about from us
+    sent: HashMap<SocketAddr, u8>,
+    last_sent: Option<Instant>,
+    last_received: Option<Instant>,
+}
+
+impl PexExtension {
+    pub fn new(swarm: Arc<dyn PexSwarm>, remote: SocketAddr) -> Self {
+        Self {
+            swarm,
+            remote,
+            sent: HashMap::new(),
+            last_sent: None,
+            last_received: None,
+        }
+    }
+}
+
+impl Extension for PexExtension {
+    fn name(&self) -> &'static str {
+        EXTENSION_NAME
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>> {
+        // Peers sending faster than the interval allows are ignored
+        if self
+            .last_received
+            .is_some_and(|last| last.elapsed() < INTERVAL / 2)
+        {
+            return Ok(Vec::new());
+        }
+        self.last_received = Some(Instant::now());
+
+        let message: PexMessage = serde_bencode::from_bytes(payload)?;
+        let mut added = message.added();
+        added.truncate(MAX_PEERS * 4);
+        if !added.is_empty() {
+            self.swarm.discovered(added);
+        }
+        Ok(Vec::new())
+    }
+
+    fn tick(&mut self) -> Result<Vec<Vec<u8>>> {
+        if self.last_sent.is_some_and(|last| last.elapsed() < INTERVAL) {
+            return Ok(Vec::new());
+        }
+        self.last_sent = Some(Instant::now());
+
+        let current: HashMap<SocketAddr, u8> = self
+            .swarm
+            .connected()
+            .into_iter()
+            .filter(|p| p.addr != self.remote)
+            .map(|p| (p.addr, p.flags))
+            .collect();
+        let added: Vec<PexPeer> = current
+            .iter()
+            .filter(|(addr, _)| !self.sent.contains_key(addr))
+            .take(MAX_PEERS)
+            .map(|(&addr, &flags)| PexPeer { addr, flags })
+            .collect();
+        let dropped: Vec<SocketAddr> = self
+            .sent
+            .keys()
+            .filter(|addr| !current.contains_key(addr))
+            .take(MAX_PEERS)
+            .copied()
+            .collect();
+        if added.is_empty() && dropped.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        for peer in &added {
+            self.sent.insert(peer.addr, peer.flags);
+        }
+        for addr in &dropped {
+            self.sent.remove(addr);
+        }
+        let message = PexMessage::new(&added, &dropped);
+        Ok(vec![serde_bencode::to_bytes(&message)?])
+    }
+}
+
+#[cfg(test)]
+#[derive(Default)]
+struct TestSwarm {
+    connected: std::sync::Mutex<Vec<PexPeer>>,
+    discovered: std::sync::Mutex<Vec<PexPeer>>,
+}
+
+#[cfg(test)]
+impl PexSwarm for TestSwarm {
+    fn connected(&self) -> Vec<PexPeer> {
+        self.connected.lock().unwrap().clone()
+    }
+
+    fn discovered(&self, peers: Vec<PexPeer>) {
+        self.discovered.lock().unwrap().extend(peers);
+    }
+}
+
+#[test]
+fn message_roundtrip() {
+    let added = vec![
+        PexPeer {
+            addr: "10.0.0.1:6881".parse().unwrap(),
+            flags: flags::SEED | flags::REACHABLE,
+        },
+        PexPeer {
+            addr: "[2001:db8::1]:51413".parse().unwrap(),
+            flags: flags::UTP,
+        },
+    ];
+    let dropped = vec!["10.0.0.2:1".parse().unwrap()];
+    let message = PexMessage::new(&added, &dropped);
+    let bytes = serde_bencode::to_bytes(&message).unwrap();
+    let parsed: PexMessage = serde_bencode::from_bytes(&bytes).unwrap();
+    assert_eq!(parsed.added(), added);
+    assert_eq!(parsed.dropped(), dropped);
+}
+
+#[test]
+fn sends_deltas() {
+    let swarm = Arc::new(TestSwarm::default());
+    let remote: SocketAddr = "10.0.0.9:1".parse().unwrap();
+    let a = PexPeer {
+        addr: "10.0.0.1:1".parse().unwrap(),
+        flags: flags::SEED,
+    };
+    let b = PexPeer {
+        addr: "10.0.0.2:1".parse().unwrap(),
+        flags: 0,
+    };
+    swarm.connected.lock().unwrap().extend([
+        a,
+        b,
+        PexPeer {
+            addr: remote,
+            flags: 0,
+        },
+    ]);
+    let mut pex = PexExtension::new(swarm.clone(), remote);
+
+    let payloads = pex.tick().unwrap();
+    let message: PexMessage = serde_bencode::from_bytes(&payloads[0]).unwrap();
+    let mut added = message.added();
+    added.sort_by_key(|p| p.addr);
+    assert_eq!(added, vec![a, b]);
+
+    // Nothing is sent before the interval elapses
+    swarm.connected.lock().unwrap().retain(|p| p.addr != b.addr);
+    assert!(pex.tick().unwrap().is_empty());
+
+    pex.last_sent = Some(Instant::now() - INTERVAL);
+    let payloads = pex.tick().unwrap();
+    let message: PexMessage = serde_bencode::from_bytes(&payloads[0]).unwrap();
+    assert!(message.added().is_empty());
+    assert_eq!(message.dropped(), vec![b.addr]);
+
+    let incoming = PexMessage::new(&[b], &[]);
+    pex.on_message(&serde_bencode::to_bytes(&incoming).unwrap())
+        .unwrap();
+    assert_eq!(*swarm.discovered.lock().unwrap(), vec![b]);
+}
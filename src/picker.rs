@@ -0,0 +1,481 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Instant;
+
+use anyhow::bail;
+
+use crate::peer::Bitfield;
+
+/// Size of the blocks pieces are requested in unless configured otherwise
+pub const BLOCK_SIZE: usize = 16 * 1024;
+/// Biggest block size, most peers drop or choke on bigger requests
+pub const MAX_BLOCK_SIZE: usize = 16 * 1024;
+
+/// Part of a piece requested with a single request message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Block {
+    pub piece: usize,
+    pub begin: usize,
+    pub length: usize,
+}
+
+/// How much a file or a piece is wanted, skipped ones are not downloaded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl FromStr for Priority {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "skip" => Self::Skip,
+            "low" => Self::Low,
+            "normal" => Self::Normal,
+            "high" => Self::High,
+            _ => bail!("unknown priority {s:?}, expected skip, low, normal or high"),
+        })
+    }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Skip => "skip",
+            Self::Low => "low",
+            Self::Normal => "normal",
+            Self::High => "high",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BlockState {
+    Open,
+    Requested,
+    Received,
+}
+
+/// Decides which blocks to request from a peer. Pieces with a deadline come
+/// first, earliest deadline first, then the pieces already started are
+/// finished, then the rarest of the most wanted pieces are picked, or the
+/// first of them in sequential mode.
+#[derive(Debug)]
+pub struct PiecePicker {
+    have: Bitfield,
+    availability: Vec<u32>,
+    priorities: Vec<Priority>,
+    /// Pieces needed by a time, fetched even when skipped
+    deadlines: HashMap<usize, Instant>,
+    sequential: bool,
+    partial: BTreeMap<usize, Vec<BlockState>>,
+    plength: usize,
+    length: usize,
+    block_size: usize,
+}
+
+impl PiecePicker {
+    pub fn new(length: usize, plength: usize) -> Self {
+        let pieces = length.div_ceil(plength);
+        Self {
+            have: Bitfield::new(pieces),
+            availability: vec![0; pieces],
+            priorities: vec![Priority::Normal; pieces],
+            deadlines: HashMap::new(),
+            sequential: false,
+            partial: BTreeMap::new(),
+            plength,
+            length,
+            block_size: BLOCK_SIZE,
+        }
+    }
+
+    /// Pieces we have verified
+    pub fn have(&self) -> &Bitfield {
+        &self.have
+    }
+
+    pub fn has(&self, piece: usize) -> bool {
+        self.have.has(piece)
+    }
+
+    /// Whether every wanted piece is verified
+    pub fn is_complete(&self) -> bool {
+        self.wanted().all(|piece| self.have.has(piece))
+    }
+
+    /// Sets the priority of each piece, skipped pieces are not picked
+    pub fn set_priorities(&mut self, priorities: Vec<Priority>) {
+        assert_eq!(priorities.len(), self.priorities.len());
+        self.priorities = priorities;
+    }
+
+    pub fn priority(&self, piece: usize) -> Priority {
+        self.priorities[piece]
+    }
+
+    /// Picks new pieces in order rather than rarest first
+    pub fn set_sequential(&mut self, sequential: bool) {
+        self.sequential = sequential;
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Sets the size of the blocks picked. Blocks keep the size they were
+    /// picked with, so set it before picking.
+    pub fn set_block_size(&mut self, size: usize) {
+        assert!(self.partial.is_empty(), "blocks were already picked");
+        self.block_size = size;
+    }
+
+    /// Asks for a piece by `deadline`, it is picked before the others
+    pub fn set_deadline(&mut self, piece: usize, deadline: Instant) {
+        if piece < self.have.len() && !self.have.has(piece) {
+            self.deadlines.insert(piece, deadline);
+        }
+    }
+
+    pub fn clear_deadlines(&mut self) {
+        self.deadlines.clear();
+    }
+
+    fn wanted(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.have.len()).filter(|&piece| self.priorities[piece] != Priority::Skip)
+    }
+
+    fn is_wanted(&self, piece: usize) -> bool {
+        self.priorities[piece] != Priority::Skip || self.deadlines.contains_key(&piece)
+    }
+
+    pub fn piece_length(&self, piece: usize) -> usize {
+        self.plength.min(self.length - self.plength * piece)
+    }
+
+    fn blocks(&self, piece: usize) -> usize {
+        self.piece_length(piece).div_ceil(self.block_size)
+    }
+
+    fn block(&self, piece: usize, index: usize) -> Block {
+        let begin = index * self.block_size;
+        Block {
+            piece,
+            begin,
+            length: self.block_size.min(self.piece_length(piece) - begin),
+        }
+    }
+
+    /// Counts the pieces of a new peer in the availability
+    pub fn add_peer(&mut self, bitfield: &Bitfield) {
+        for piece in bitfield.iter() {
+            self.availability[piece] += 1;
+        }
+    }
+
+    /// Removes the pieces of a disconnected peer from the availability
+    pub fn remove_peer(&mut self, bitfield: &Bitfield) {
+        for piece in bitfield.iter() {
+            self.availability[piece] = self.availability[piece].saturating_sub(1);
+        }
+    }
+
+    /// A peer announced a new piece
+    pub fn peer_has(&mut self, piece: usize) {
+        if let Some(availability) = self.availability.get_mut(piece) {
+            *availability += 1;
+        }
+    }
+
+    /// Connected peers that have the piece
+    pub fn availability(&self, piece: usize) -> u32 {
+        self.availability[piece]
+    }
+
+    /// Whether the peer has a wanted piece we are missing
+    pub fn interesting(&self, peer: &Bitfield) -> bool {
+        peer.iter()
+            .any(|piece| !self.have.has(piece) && self.is_wanted(piece))
+    }
+
+    /// Picks up to `count` blocks the peer has and nobody is downloading yet,
+    /// marking them as requested
+    pub fn pick(&mut self, peer: &Bitfield, count: usize) -> Vec<Block> {
+        let mut picked = Vec::new();
+
+        // Pieces with a deadline, the earliest first
+        let mut urgent: Vec<(Instant, usize)> = self
+            .deadlines
+            .iter()
+            .filter(|(&piece, _)| peer.has(piece))
+            .map(|(&piece, &deadline)| (deadline, piece))
+            .collect();
+        urgent.sort_unstable();
+        for (_, piece) in urgent {
+            self.start(piece);
+            self.pick_in(piece, count, &mut picked);
+            if picked.len() >= count {
+                return picked;
+            }
+        }
+
+        // Finish the pieces already started
+        let started: Vec<usize> = self
+            .partial
+            .keys()
+            .copied()
+            .filter(|&piece| peer.has(piece) && self.is_wanted(piece))
+            .collect();
+        for piece in started {
+            self.pick_in(piece, count, &mut picked);
+            if picked.len() >= count {
+                return picked;
+            }
+        }
+
+        // Then start the rarest of the most wanted pieces, or the first
+        while picked.len() < count {
+            let next = self
+                .wanted()
+                .filter(|&piece| {
+                    peer.has(piece) && !self.have.has(piece) && !self.partial.contains_key(&piece)
+                })
+                .min_by_key(|&piece| {
+                    let order = if self.sequential {
+                        piece
+                    } else {
+                        self.availability[piece] as usize
+                    };
+                    (Reverse(self.priorities[piece]), order)
+                });
+            let Some(piece) = next else {
+                break;
+            };
+            self.start(piece);
+            self.pick_in(piece, count, &mut picked);
+        }
+        picked
+    }
+
+    /// Tracks the blocks of a piece, unless it is started already
+    fn start(&mut self, piece: usize) {
+        let blocks = self.blocks(piece);
+        self.partial
+            .entry(piece)
+            .or_insert_with(|| vec![BlockState::Open; blocks]);
+    }
+
+    fn pick_in(&mut self, piece: usize, count: usize, picked: &mut Vec<Block>) {
+        let Some(states) = self.partial.get(&piece) else {
+            return;
+        };
+        let open: Vec<usize> = states
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| **s == BlockState::Open)
+            .map(|(i, _)| i)
+            .take(count - picked.len())
+            .collect();
+        for index in open {
+            picked.push(self.block(piece, index));
+            if let Some(states) = self.partial.get_mut(&piece) {
+                states[index] = BlockState::Requested;
+            }
+        }
+    }
+
+    /// Marks a block as received, returns false if it was not expected
+    pub fn received(&mut self, block: &Block) -> bool {
+        let index = block.begin / self.block_size;
+        if block.piece >= self.have.len()
+            || !block.begin.is_multiple_of(self.block_size)
+            || block.begin >= self.piece_length(block.piece)
+            || self.block(block.piece, index) != *block
+        {
+            return false;
+        }
+        match self
+            .partial
+            .get_mut(&block.piece)
+            .and_then(|states| states.get_mut(index))
+        {
+            Some(state) if *state != BlockState::Received => {
+                *state = BlockState::Received;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether every block of the piece arrived
+    pub fn is_piece_received(&self, piece: usize) -> bool {
+        self.partial
+            .get(&piece)
+            .is_some_and(|states| states.iter().all(|s| *s == BlockState::Received))
+    }
+
+    /// Gives back a requested block that will not arrive
+    pub fn abort(&mut self, block: &Block) {
+        let index = block.begin / self.block_size;
+        if let Some(state) = self
+            .partial
+            .get_mut(&block.piece)
+            .and_then(|states| states.get_mut(index))
+        {
+            if *state == BlockState::Requested {
+                *state = BlockState::Open;
+            }
+        }
+    }
+
+    /// The piece matched its hash
+    pub fn piece_passed(&mut self, piece: usize) {
+        self.partial.remove(&piece);
+        self.deadlines.remove(&piece);
+        self.have.set(piece);
+    }
+
+    /// The piece did not match its hash, all its blocks are requested again
+    pub fn piece_failed(&mut self, piece: usize) {
+        self.partial.remove(&piece);
+    }
+
+    /// Bytes of the wanted pieces we are still missing
+    pub fn left(&self) -> usize {
+        self.wanted()
+            .filter(|&piece| !self.have.has(piece))
+            .map(|piece| self.piece_length(piece))
+            .sum()
+    }
+}
+
+#[test]
+fn pick_rarest_and_finish_started() {
+    let mut picker = PiecePicker::new(3 * 2 * BLOCK_SIZE, 2 * BLOCK_SIZE);
+    let all = Bitfield::full(3);
+    let mut some = Bitfield::new(3);
+    some.set(1);
+    some.set(2);
+    picker.add_peer(&all);
+    picker.add_peer(&some);
+    picker.add_peer(&some);
+
+    // Piece 0 is the rarest
+    let blocks = picker.pick(&all, 1);
+    assert_eq!(blocks[0].piece, 0);
+    // The started piece is finished before a new one
+    let blocks = picker.pick(&all, 2);
+    assert_eq!(
+        blocks.iter().map(|b| b.piece).collect::<Vec<_>>(),
+        vec![0, 1]
+    );
+
+    picker.abort(&blocks[0]);
+    let again = picker.pick(&some, 1);
+    assert_eq!(again[0].piece, 1);
+    let again = picker.pick(&all, 1);
+    assert_eq!(again[0], blocks[0]);
+}
+
+#[test]
+fn receive_and_verify() {
+    let mut picker = PiecePicker::new(BLOCK_SIZE + 10, 2 * BLOCK_SIZE);
+    let peer = Bitfield::full(1);
+    let blocks = picker.pick(&peer, 10);
+    assert_eq!(blocks.len(), 2);
+    assert_eq!(blocks[1].length, 10);
+
+    assert!(picker.received(&blocks[0]));
+    assert!(!picker.received(&blocks[0]));
+    assert!(!picker.is_piece_received(0));
+    assert!(picker.received(&blocks[1]));
+    assert!(picker.is_piece_received(0));
+
+    picker.piece_failed(0);
+    assert_eq!(picker.pick(&peer, 10).len(), 2);
+    picker.piece_passed(0);
+    assert!(picker.is_complete());
+    assert_eq!(picker.left(), 0);
+    assert!(picker.pick(&peer, 10).is_empty());
+}
+
+#[test]
+fn blocks_of_the_configured_size() {
+    let mut picker = PiecePicker::new(BLOCK_SIZE + 10, 2 * BLOCK_SIZE);
+    picker.set_block_size(BLOCK_SIZE / 2);
+    let blocks = picker.pick(&Bitfield::full(1), 10);
+    assert_eq!(blocks.len(), 3);
+    assert_eq!(blocks[1].begin, BLOCK_SIZE / 2);
+    assert_eq!(blocks[2].length, 10);
+
+    // Blocks of another size are not ours
+    let whole = Block {
+        piece: 0,
+        begin: 0,
+        length: BLOCK_SIZE,
+    };
+    assert!(!picker.received(&whole));
+    assert!(blocks.iter().all(|block| picker.received(block)));
+    assert!(picker.is_piece_received(0));
+}
+
+#[test]
+fn priorities_order_and_skip_pieces() {
+    let mut picker = PiecePicker::new(4 * BLOCK_SIZE, BLOCK_SIZE);
+    let all = Bitfield::full(4);
+    picker.add_peer(&all);
+    picker.set_priorities(vec![
+        Priority::Skip,
+        Priority::Low,
+        Priority::High,
+        Priority::Normal,
+    ]);
+    let order: Vec<usize> = picker.pick(&all, 10).iter().map(|b| b.piece).collect();
+    assert_eq!(order, vec![2, 3, 1]);
+    assert_eq!(picker.left(), 3 * BLOCK_SIZE);
+
+    for piece in 1..4 {
+        picker.piece_passed(piece);
+    }
+    assert!(picker.is_complete());
+    assert!(!picker.interesting(&all));
+    assert_eq!("high".parse::<Priority>().unwrap(), Priority::High);
+    assert!("urgent".parse::<Priority>().is_err());
+}
+
+#[test]
+fn deadlines_and_sequential_order() {
+    let mut picker = PiecePicker::new(6 * BLOCK_SIZE, BLOCK_SIZE);
+    let all = Bitfield::full(6);
+    let mut rare = Bitfield::new(6);
+    rare.set(5);
+    picker.add_peer(&all);
+    picker.add_peer(&rare);
+    picker.set_priorities(
+        vec![Priority::Normal; 5]
+            .into_iter()
+            .chain([Priority::Skip])
+            .collect(),
+    );
+    picker.set_sequential(true);
+    assert_eq!(picker.pick(&all, 1)[0].piece, 0);
+
+    // Deadlines come first, even for skipped pieces, then the started piece
+    let now = Instant::now();
+    picker.set_deadline(5, now + std::time::Duration::from_secs(2));
+    picker.set_deadline(3, now + std::time::Duration::from_secs(1));
+    let order: Vec<usize> = picker.pick(&all, 4).iter().map(|b| b.piece).collect();
+    assert_eq!(order, vec![3, 5, 1, 2]);
+
+    // Verified pieces need no deadline
+    picker.piece_passed(3);
+    picker.set_deadline(3, now);
+    assert!(!picker.deadlines.contains_key(&3));
+}
@@ -0,0 +1,261 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::peer::Bitfield;
+use crate::picker::Priority;
+use crate::worker::Worker;
+
+/// Most peers and files listed, the busiest peers and the first files
+const MAX_ROWS: usize = 10;
+/// Terminal width when `COLUMNS` does not tell
+const DEFAULT_WIDTH: usize = 80;
+/// Clears the terminal and moves the cursor to its top left corner
+const CLEAR: &str = "\x1b[H\x1b[2J";
+
+/// Draws the live state of a worker on a terminal: progress, rates and ETA,
+/// a map of the pieces, the trackers, the busiest peers and the files.
+/// Everything comes from the worker itself.
+pub struct Progress {
+    worker: Worker,
+    /// When the rates were last measured, with the bytes transferred then
+    sample: Option<(Instant, u64, u64)>,
+    peer_samples: HashMap<SocketAddr, (u64, u64)>,
+}
+
+impl Progress {
+    pub fn new(worker: Worker) -> Self {
+        Self {
+            worker,
+            sample: None,
+            peer_samples: HashMap::new(),
+        }
+    }
+
+    /// Redraws the state on stderr, over what the terminal showed
+    pub fn draw(&mut self) {
+        let width = std::env::var("COLUMNS")
+            .ok()
+            .and_then(|columns| columns.parse().ok())
+            .unwrap_or(DEFAULT_WIDTH);
+        let frame = self.frame(width);
+        let _ = write!(std::io::stderr().lock(), "{CLEAR}{frame}");
+    }
+
+    /// The state as lines of at most `width` characters, rates are measured
+    /// since the previous frame
+    pub fn frame(&mut self, width: usize) -> String {
+        let worker = &self.worker;
+        let now = Instant::now();
+        let metrics = worker.metrics();
+        let seconds = match self.sample {
+            Some((then, _, _)) => now.duration_since(then).as_secs_f64().max(1e-3),
+            None => 0.0,
+        };
+        let rate = |bytes: u64, before: u64| {
+            if seconds == 0.0 {
+                0
+            } else {
+                (bytes.saturating_sub(before) as f64 / seconds) as u64
+            }
+        };
+        let (download_rate, upload_rate) = match self.sample {
+            Some((_, downloaded, uploaded)) => (
+                rate(metrics.downloaded, downloaded),
+                rate(metrics.uploaded, uploaded),
+            ),
+            None => (0, 0),
+        };
+        self.sample = Some((now, metrics.downloaded, metrics.uploaded));
+
+        let mut out = String::new();
+        let files = worker.files();
+        let progress = worker.file_progress();
+        let priorities = worker.file_priorities();
+        let wanted: usize = files
+            .iter()
+            .zip(&priorities)
+            .filter(|(_, priority)| **priority != Priority::Skip)
+            .map(|(file, _)| file.length)
+            .sum();
+        let left = worker.left();
+        let done = wanted.saturating_sub(left);
+        let eta = match download_rate {
+            _ if left == 0 => "done".to_string(),
+            0 => "--".to_string(),
+            rate => duration(Duration::from_secs(left as u64 / rate)),
+        };
+        let _ = writeln!(
+            out,
+            "{}  {:.1}%  {} / {}  down {}/s  up {}/s  ETA {eta}",
+            metrics.name,
+            percent(done, wanted),
+            bytes(done as u64),
+            bytes(wanted as u64),
+            bytes(download_rate),
+            bytes(upload_rate),
+        );
+        let _ = writeln!(
+            out,
+            "[{}]",
+            piece_map(&worker.have(), width.saturating_sub(2))
+        );
+
+        let trackers = worker.trackers();
+        if !trackers.is_empty() {
+            let _ = writeln!(out, "\nTrackers");
+            for tracker in trackers {
+                let result = match tracker.result {
+                    Ok(peers) => format!("{peers} peers"),
+                    Err(error) => format!("failed: {error}"),
+                };
+                let line = format!(
+                    "  {}  {result}, {} ago",
+                    tracker.url,
+                    duration(tracker.announced.elapsed())
+                );
+                let _ = writeln!(out, "{}", truncate(&line, width));
+            }
+        }
+
+        let mut peers: Vec<_> = worker
+            .peer_stats()
+            .into_iter()
+            .map(|peer| {
+                let (downloaded, uploaded) = self
+                    .peer_samples
+                    .get(&peer.addr)
+                    .copied()
+                    .unwrap_or((peer.downloaded, peer.uploaded));
+                let rates = (
+                    rate(peer.downloaded, downloaded),
+                    rate(peer.uploaded, uploaded),
+                );
+                (peer, rates)
+            })
+            .collect();
+        self.peer_samples = peers
+            .iter()
+            .map(|(peer, _)| (peer.addr, (peer.downloaded, peer.uploaded)))
+            .collect();
+        peers.sort_by_key(|(peer, (down, up))| (std::cmp::Reverse(down + up), peer.addr));
+        let _ = writeln!(out, "\nPeers ({})", peers.len());
+        for (peer, (down, up)) in peers.iter().take(MAX_ROWS) {
+            let mut flags = String::new();
+            flags.push(if peer.unchoking_us { 'D' } else { '-' });
+            flags.push(if peer.unchoked { 'U' } else { '-' });
+            flags.push(if peer.encrypted { 'E' } else { '-' });
+            flags.push(if peer.utp { 'P' } else { '-' });
+            let line = format!(
+                "  {:<22} {flags}  down {:>10}/s  up {:>10}/s  {}",
+                peer.addr,
+                bytes(*down),
+                bytes(*up),
+                peer.client
+            );
+            let _ = writeln!(out, "{}", truncate(&line, width));
+        }
+
+        let _ = writeln!(out, "\nFiles ({})", files.len());
+        for ((file, downloaded), priority) in
+            files.iter().zip(progress).zip(priorities).take(MAX_ROWS)
+        {
+            let status = match priority {
+                Priority::Skip => "skip".to_string(),
+                _ => format!("{:.0}%", percent(downloaded, file.length)),
+            };
+            let line = format!(
+                "  {status:>5}  {:>10}  {}",
+                bytes(file.length as u64),
+                file.path.display()
+            );
+            let _ = writeln!(out, "{}", truncate(&line, width));
+        }
+        if files.len() > MAX_ROWS {
+            let _ = writeln!(out, "  and {} more", files.len() - MAX_ROWS);
+        }
+        out
+    }
+}
+
+/// `have` squeezed into `width` cells: `#` when every piece of a cell is
+/// verified, `+` when some are and `.` when none is
+fn piece_map(have: &Bitfield, width: usize) -> String {
+    let pieces = have.len();
+    let cells = width.clamp(1, pieces.max(1));
+    (0..cells)
+        .map(|cell| {
+            let range = cell * pieces / cells..(cell + 1) * pieces / cells;
+            let count = range.clone().filter(|&piece| have.has(piece)).count();
+            match count {
+                0 => '.',
+                n if n == range.len() => '#',
+                _ => '+',
+            }
+        })
+        .collect()
+}
+
+fn percent(part: usize, whole: usize) -> f64 {
+    if whole == 0 {
+        100.0
+    } else {
+        part as f64 * 100.0 / whole as f64
+    }
+}
+
+/// A byte count in binary units, as in `1.5 MiB`
+fn bytes(n: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = n as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{n} B"),
+        _ => format!("{value:.1} {}", UNITS[unit]),
+    }
+}
+
+/// A duration as `1h02m`, `3m04s` or `5s`
+fn duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match seconds {
+        0..60 => format!("{seconds}s"),
+        60..3600 => format!("{}m{:02}s", seconds / 60, seconds % 60),
+        _ => format!("{}h{:02}m", seconds / 3600, seconds / 60 % 60),
+    }
+}
+
+fn truncate(line: &str, width: usize) -> &str {
+    match line.char_indices().nth(width) {
+        Some((end, _)) => &line[..end],
+        None => line,
+    }
+}
+
+#[test]
+fn piece_maps_fit_the_width() {
+    let mut have = Bitfield::new(10);
+    for piece in [0, 1, 2, 3, 5] {
+        have.set(piece);
+    }
+    assert_eq!(piece_map(&have, 5), "##+..");
+    assert_eq!(piece_map(&have, 20), "####.#....");
+    assert_eq!(piece_map(&Bitfield::new(0), 8), ".");
+}
+
+#[test]
+fn human_units() {
+    assert_eq!(bytes(512), "512 B");
+    assert_eq!(bytes(1536), "1.5 KiB");
+    assert_eq!(bytes(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    assert_eq!(duration(Duration::from_secs(5)), "5s");
+    assert_eq!(duration(Duration::from_secs(184)), "3m04s");
+    assert_eq!(duration(Duration::from_secs(3720)), "1h02m");
+    assert_eq!(truncate("peers", 3), "pee");
+}
@@ -0,0 +1,348 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+
+/// Fewest bytes worth waiting for, so slow rates do not turn into tiny reads
+const MIN_CHUNK: usize = 1024;
+/// Longest sleep before the limits are checked again, so new rates apply
+/// quickly
+const MAX_WAIT: Duration = Duration::from_millis(100);
+
+/// A token bucket in bytes per second, shared by every connection it limits.
+/// Clones share the bucket.
+#[derive(Clone)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+struct Bucket {
+    /// None when unlimited
+    rate: Option<u64>,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    /// Tokens saved up while idle, a quarter second of traffic
+    fn capacity(rate: u64) -> f64 {
+        (rate as f64 / 4.0).max(4.0 * MIN_CHUNK as f64)
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        if let Some(rate) = self.rate {
+            let earned = (now - self.last).as_secs_f64() * rate as f64;
+            self.tokens = (self.tokens + earned).min(Self::capacity(rate));
+        }
+        self.last = now;
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::unlimited()
+    }
+}
+
+impl RateLimiter {
+    /// A limiter of `rate` bytes per second, None or zero for no limit
+    pub fn new(rate: Option<u64>) -> Self {
+        let rate = rate.filter(|&rate| rate > 0);
+        Self {
+            bucket: Arc::new(Mutex::new(Bucket {
+                rate,
+                tokens: rate.map_or(0.0, Bucket::capacity),
+                last: Instant::now(),
+            })),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        Self::new(None)
+    }
+
+    fn bucket(&self) -> MutexGuard<'_, Bucket> {
+        self.bucket.lock().expect("rate limiter lock poisoned")
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.bucket().rate
+    }
+
+    /// Changes the rate, connections waiting on the limiter see it within
+    /// a tenth of a second
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let rate = rate.filter(|&rate| rate > 0);
+        let mut bucket = self.bucket();
+        bucket.refill();
+        match (bucket.rate, rate) {
+            (None, Some(rate)) => bucket.tokens = Bucket::capacity(rate),
+            (_, Some(rate)) => bucket.tokens = bucket.tokens.min(Bucket::capacity(rate)),
+            (_, None) => bucket.tokens = 0.0,
+        }
+        bucket.rate = rate;
+    }
+
+    /// Bytes that may go through now, up to `wanted`, or the time to wait
+    /// until some may
+    fn available(&self, wanted: usize) -> Result<usize, Duration> {
+        let mut bucket = self.bucket();
+        bucket.refill();
+        let Some(rate) = bucket.rate else {
+            return Ok(wanted);
+        };
+        let threshold = wanted.min(MIN_CHUNK) as f64;
+        if bucket.tokens >= threshold {
+            Ok(wanted.min(bucket.tokens as usize))
+        } else {
+            Err(Duration::from_secs_f64(
+                (threshold - bucket.tokens) / rate as f64,
+            ))
+        }
+    }
+
+    /// Takes tokens for bytes that went through, the bucket may go in debt
+    fn consume(&self, amount: usize) {
+        let mut bucket = self.bucket();
+        if bucket.rate.is_some() {
+            bucket.tokens -= amount as f64;
+        }
+    }
+
+    /// Waits until `amount` bytes may go through, and takes them
+    pub async fn acquire(&self, amount: usize) {
+        loop {
+            match self.available(amount) {
+                Ok(_) => {
+                    self.consume(amount);
+                    return;
+                }
+                Err(wait) => tokio::time::sleep(wait.min(MAX_WAIT)).await,
+            }
+        }
+    }
+}
+
+/// The download and upload limiters of a peer, a torrent or everything
+#[derive(Clone, Default)]
+pub struct Limits {
+    pub download: RateLimiter,
+    pub upload: RateLimiter,
+}
+
+impl Limits {
+    pub fn new(download: Option<u64>, upload: Option<u64>) -> Self {
+        Self {
+            download: RateLimiter::new(download),
+            upload: RateLimiter::new(upload),
+        }
+    }
+
+    pub fn set(&self, download: Option<u64>, upload: Option<u64>) {
+        self.download.set_rate(download);
+        self.upload.set_rate(upload);
+    }
+
+    pub fn rates(&self) -> (Option<u64>, Option<u64>) {
+        (self.download.rate(), self.upload.rate())
+    }
+}
+
+/// Bytes that every limiter lets through, or the longest wait
+fn allowance(limiters: &[RateLimiter], wanted: usize) -> Result<usize, Duration> {
+    let mut allowed = wanted;
+    let mut wait = None;
+    for limiter in limiters {
+        match limiter.available(wanted) {
+            Ok(available) => allowed = allowed.min(available),
+            Err(duration) => wait = Some(wait.unwrap_or(duration).max(duration)),
+        }
+    }
+    match wait {
+        Some(wait) => Err(wait.min(MAX_WAIT)),
+        None => Ok(allowed),
+    }
+}
+
+/// A connection whose reads and writes go through rate limiters, every byte
+/// on the wire counts
+pub struct Throttled<S> {
+    inner: S,
+    download: Vec<RateLimiter>,
+    upload: Vec<RateLimiter>,
+    read_delay: Option<Pin<Box<Sleep>>>,
+    write_delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Throttled<S> {
+    /// A connection that is not limited yet
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            download: Vec::new(),
+            upload: Vec::new(),
+            read_delay: None,
+            write_delay: None,
+        }
+    }
+
+    /// Limits the connection by every limiter given, usually the ones of the
+    /// peer, of its torrent and the global ones
+    pub fn set_limits(&mut self, download: Vec<RateLimiter>, upload: Vec<RateLimiter>) {
+        self.download = download;
+        self.upload = upload;
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+/// Waits out `delay` if set, then sets it again when the limiters say so
+fn poll_allowance(
+    delay: &mut Option<Pin<Box<Sleep>>>,
+    limiters: &[RateLimiter],
+    wanted: usize,
+    cx: &mut Context<'_>,
+) -> Poll<usize> {
+    loop {
+        if let Some(sleep) = delay {
+            ready!(sleep.as_mut().poll(cx));
+            *delay = None;
+        }
+        match allowance(limiters, wanted) {
+            Ok(allowed) => return Poll::Ready(allowed),
+            Err(wait) => *delay = Some(Box::pin(tokio::time::sleep(wait))),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let allowed = ready!(poll_allowance(
+            &mut this.read_delay,
+            &this.download,
+            buf.remaining(),
+            cx
+        ));
+        let read = if allowed < buf.remaining() {
+            let mut limited = ReadBuf::new(buf.initialize_unfilled_to(allowed));
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+            let read = limited.filled().len();
+            buf.advance(read);
+            read
+        } else {
+            let start = buf.filled().len();
+            ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+            buf.filled().len() - start
+        };
+        this.download
+            .iter()
+            .for_each(|limiter| limiter.consume(read));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let allowed = ready!(poll_allowance(
+            &mut this.write_delay,
+            &this.upload,
+            data.len(),
+            cx
+        ));
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, &data[..allowed]))?;
+        this.upload
+            .iter()
+            .for_each(|limiter| limiter.consume(written));
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Time to push `length` bytes through a throttled pipe
+#[cfg(test)]
+async fn timed_transfer(
+    download: Vec<RateLimiter>,
+    upload: Vec<RateLimiter>,
+    length: usize,
+) -> Duration {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (a, b) = tokio::io::duplex(64 * 1024);
+    let mut writer = Throttled::new(a);
+    writer.set_limits(Vec::new(), upload);
+    let mut reader = Throttled::new(b);
+    reader.set_limits(download, Vec::new());
+    let start = Instant::now();
+    let send = async {
+        writer.write_all(&vec![7; length]).await.unwrap();
+        writer.shutdown().await.unwrap();
+    };
+    let receive = async {
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received.len(), length);
+    };
+    tokio::join!(send, receive);
+    start.elapsed()
+}
+
+#[tokio::test]
+async fn limits_both_directions() {
+    // 10 KB go out of the bucket at once, the other 30 KB take 3/4 second
+    let elapsed = timed_transfer(Vec::new(), vec![RateLimiter::new(Some(40_000))], 40_000).await;
+    assert!(elapsed >= Duration::from_millis(650), "{elapsed:?}");
+    assert!(elapsed < Duration::from_millis(1500), "{elapsed:?}");
+
+    // The slowest of the limiters wins
+    let download = vec![RateLimiter::new(Some(40_000)), RateLimiter::unlimited()];
+    let elapsed = timed_transfer(download, Vec::new(), 40_000).await;
+    assert!(elapsed >= Duration::from_millis(650), "{elapsed:?}");
+
+    let elapsed = timed_transfer(Vec::new(), Vec::new(), 1_000_000).await;
+    assert!(elapsed < Duration::from_millis(500), "{elapsed:?}");
+}
+
+#[tokio::test]
+async fn rate_changes_apply_while_running() {
+    let limiter = RateLimiter::new(Some(10_000));
+    let changer = limiter.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        changer.set_rate(None);
+    });
+    // Four seconds at the first rate
+    let elapsed = timed_transfer(vec![limiter.clone()], Vec::new(), 40_000).await;
+    assert!(elapsed < Duration::from_secs(1), "{elapsed:?}");
+    assert_eq!(limiter.rate(), None);
+
+    let start = Instant::now();
+    limiter.set_rate(Some(20_000));
+    limiter.acquire(10_000).await;
+    limiter.acquire(10_000).await;
+    assert!(start.elapsed() >= Duration::from_millis(250));
+}
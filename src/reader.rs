@@ -0,0 +1,115 @@
+use std::future::Future;
+use std::io::{self, SeekFrom};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+
+use crate::storage::FileEntry;
+use crate::worker::Worker;
+
+/// Pieces from the read position on that get a deadline
+const READAHEAD: usize = 4;
+/// Time between the deadlines of two pieces read one after the other
+const DEADLINE_STEP: Duration = Duration::from_millis(500);
+
+/// Reads a file of a torrent while it downloads. Reads wait until the pieces
+/// they reach are verified, and the pieces from the read position on get
+/// deadlines so they are downloaded first.
+pub struct FileReader {
+    worker: Worker,
+    file: FileEntry,
+    plength: usize,
+    position: u64,
+    waiting: Option<Pin<Box<dyn Future<Output = ()> + Send>>>,
+}
+
+impl FileReader {
+    pub(crate) fn new(worker: Worker, file: FileEntry, plength: usize) -> Self {
+        Self {
+            worker,
+            file,
+            plength,
+            position: 0,
+            waiting: None,
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.file.length as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.file.length == 0
+    }
+
+    /// Sets deadlines on the pieces from `piece` on, up to the end of the file
+    fn read_ahead(&self, piece: usize) {
+        let last = (self.file.offset + self.file.length).div_ceil(self.plength);
+        for (i, piece) in (piece..last).take(READAHEAD).enumerate() {
+            self.worker
+                .set_piece_deadline(piece, DEADLINE_STEP * i as u32);
+        }
+    }
+}
+
+impl AsyncRead for FileReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let position = this.position as usize;
+        if position >= this.file.length || buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        let absolute = this.file.offset + position;
+        let piece = absolute / this.plength;
+        loop {
+            if let Some(waiting) = &mut this.waiting {
+                ready!(waiting.as_mut().poll(cx));
+                this.waiting = None;
+            }
+            this.read_ahead(piece);
+            if this.worker.has_piece(piece) {
+                break;
+            }
+            let worker = this.worker.clone();
+            this.waiting = Some(Box::pin(async move { worker.wait_for_piece(piece).await }));
+        }
+
+        let begin = absolute - piece * this.plength;
+        let length = buf
+            .remaining()
+            .min(this.plength - begin)
+            .min(this.file.length - position);
+        let data = this
+            .worker
+            .read(piece, begin, length)
+            .map_err(io::Error::other)?;
+        buf.put_slice(&data);
+        this.position += length as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for FileReader {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let this = self.get_mut();
+        let position = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => this.len().checked_add_signed(offset),
+            SeekFrom::Current(offset) => this.position.checked_add_signed(offset),
+        };
+        this.position = position
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before the start"))?;
+        this.waiting = None;
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.position))
+    }
+}
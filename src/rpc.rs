@@ -0,0 +1,325 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::debug;
+use crate::session::{Session, TorrentHandle, TorrentState};
+use crate::torrent::Torrent;
+
+/// The request is not JSON
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// The method ran and failed
+const FAILED: i64 = -32000;
+
+/// Controls a [`Session`] over JSON-RPC 2.0 on a Unix socket, one request or
+/// response per line.
+///
+/// | method | params | result |
+/// |---|---|---|
+/// | `add` | `torrent` (metainfo in hex), `output` (absolute path) | `info_hash` |
+/// | `list` | | status of every torrent |
+/// | `pause`, `resume`, `remove` | `info_hash` | null |
+/// | `stats` | | totals of the session |
+/// | `set_limits` | `download`, `upload` (bytes per second or null) | null |
+#[derive(Clone)]
+pub struct RpcServer {
+    session: Session,
+}
+
+impl RpcServer {
+    pub fn new(session: Session) -> Self {
+        Self { session }
+    }
+
+    /// Binds the socket at `path`, replacing a stale one left by a previous
+    /// run. Fails when a daemon already answers there.
+    pub async fn bind(path: &Path) -> Result<UnixListener> {
+        if UnixStream::connect(path).await.is_ok() {
+            bail!("a daemon already listens on {}", path.display());
+        }
+        let _ = std::fs::remove_file(path);
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        UnixListener::bind(path).with_context(|| format!("listening on {}", path.display()))
+    }
+
+    /// Answers clients until the future is dropped
+    pub async fn run(&self, listener: UnixListener) -> Result<()> {
+        let mut connections = tokio::task::JoinSet::new();
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let (stream, _) = accepted.context("accepting rpc connection")?;
+                    let server = self.clone();
+                    connections.spawn(async move {
+                        if let Err(e) = server.serve(stream).await {
+                            debug!("rpc connection failed: {e:#}");
+                        }
+                    });
+                }
+                Some(_) = connections.join_next() => {}
+            }
+        }
+    }
+
+    async fn serve(&self, stream: UnixStream) -> Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        while let Some(line) = lines.next_line().await? {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let mut response = self.handle(&line).to_string();
+            response.push('\n');
+            writer.write_all(response.as_bytes()).await?;
+        }
+        Ok(())
+    }
+
+    /// The response to one request line
+    pub fn handle(&self, line: &str) -> Value {
+        let request: Value = match serde_json::from_str(line) {
+            Ok(request) => request,
+            Err(e) => return error_response(Value::Null, PARSE_ERROR, e.to_string()),
+        };
+        let id = request.get("id").cloned().unwrap_or(Value::Null);
+        let Some(method) = request.get("method").and_then(Value::as_str) else {
+            return error_response(id, INVALID_REQUEST, "no method".to_string());
+        };
+        let params = request.get("params").cloned().unwrap_or(json!({}));
+        match self.call(method, params) {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err((code, message)) => error_response(id, code, message),
+        }
+    }
+
+    fn call(&self, method: &str, params: Value) -> Result<Value, (i64, String)> {
+        let failed = |e: anyhow::Error| (FAILED, format!("{e:#}"));
+        match method {
+            "add" => {
+                let Add { torrent, output } = parse(params)?;
+                if !output.is_absolute() {
+                    return Err((INVALID_PARAMS, "output is not an absolute path".to_string()));
+                }
+                let torrent = hex::decode(torrent)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|bytes| Ok(serde_bencode::from_bytes::<Torrent>(&bytes)?))
+                    .map_err(|e| (INVALID_PARAMS, format!("invalid torrent: {e:#}")))?;
+                let handle = self.session.add_torrent(torrent, output).map_err(failed)?;
+                Ok(json!({ "info_hash": hex::encode(handle.info_hash()) }))
+            }
+            "list" => Ok(self.session.torrents().iter().map(status).collect()),
+            "pause" | "resume" | "remove" => {
+                let ByHash { info_hash } = parse(params)?;
+                let handle = self.torrent(&info_hash)?;
+                match method {
+                    "pause" => handle.pause(),
+                    "resume" => handle.resume(),
+                    _ => handle.remove(),
+                }
+                Ok(Value::Null)
+            }
+            "stats" => Ok(self.stats()),
+            "set_limits" => {
+                let Limits { download, upload } = parse(params)?;
+                self.session.set_rate_limits(download, upload);
+                Ok(Value::Null)
+            }
+            _ => Err((METHOD_NOT_FOUND, format!("no method {method:?}"))),
+        }
+    }
+
+    fn torrent(&self, info_hash: &str) -> Result<TorrentHandle, (i64, String)> {
+        let hash: [u8; 20] = hex::decode(info_hash)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or((INVALID_PARAMS, format!("invalid info hash {info_hash:?}")))?;
+        self.session
+            .torrent(&hash)
+            .ok_or((FAILED, format!("no torrent {info_hash}")))
+    }
+
+    fn stats(&self) -> Value {
+        let torrents = self.session.torrents();
+        let count = |state: fn(&TorrentState) -> bool| {
+            torrents.iter().filter(|t| state(&t.status().state)).count()
+        };
+        let metrics = self.session.metrics();
+        let (download_limit, upload_limit) = self.session.rate_limits();
+        json!({
+            "torrents": torrents.len(),
+            "checking": count(|s| *s == TorrentState::Checking),
+            "downloading": count(|s| *s == TorrentState::Downloading),
+            "seeding": count(|s| *s == TorrentState::Seeding),
+            "paused": count(|s| *s == TorrentState::Paused),
+            "failed": count(|s| matches!(s, TorrentState::Failed(_))),
+            "peers": metrics.iter().map(|m| m.peers).sum::<usize>(),
+            "downloaded": metrics.iter().map(|m| m.downloaded).sum::<u64>(),
+            "uploaded": metrics.iter().map(|m| m.uploaded).sum::<u64>(),
+            "download_limit": download_limit,
+            "upload_limit": upload_limit,
+            "port": self.session.port(),
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct Add {
+    torrent: String,
+    output: PathBuf,
+}
+
+#[derive(Deserialize)]
+struct ByHash {
+    info_hash: String,
+}
+
+#[derive(Deserialize)]
+struct Limits {
+    download: Option<u64>,
+    upload: Option<u64>,
+}
+
+fn parse<T: DeserializeOwned>(params: Value) -> Result<T, (i64, String)> {
+    serde_json::from_value(params).map_err(|e| (INVALID_PARAMS, e.to_string()))
+}
+
+fn error_response(id: Value, code: i64, message: String) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code, "message": message },
+    })
+}
+
+fn status(handle: &TorrentHandle) -> Value {
+    let status = handle.status();
+    let (state, error) = match status.state {
+        TorrentState::Paused => ("paused", None),
+        TorrentState::Checking => ("checking", None),
+        TorrentState::Downloading => ("downloading", None),
+        TorrentState::Seeding => ("seeding", None),
+        TorrentState::Failed(error) => ("failed", Some(error)),
+    };
+    json!({
+        "info_hash": hex::encode(status.info_hash),
+        "name": status.name,
+        "state": state,
+        "error": error,
+        "pieces": status.pieces,
+        "total_pieces": status.total_pieces,
+        "length": status.length,
+        "left": status.left,
+        "peers": status.peers,
+    })
+}
+
+/// Talks to an [`RpcServer`]
+pub struct RpcClient {
+    stream: BufReader<UnixStream>,
+    next_id: u64,
+}
+
+impl RpcClient {
+    pub async fn connect(path: &Path) -> Result<Self> {
+        let stream = UnixStream::connect(path)
+            .await
+            .with_context(|| format!("no daemon listens on {}", path.display()))?;
+        Ok(Self {
+            stream: BufReader::new(stream),
+            next_id: 1,
+        })
+    }
+
+    /// Calls a method and waits for its result
+    pub async fn call(&mut self, method: &str, params: Value) -> Result<Value> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let mut request =
+            json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }).to_string();
+        request.push('\n');
+        self.stream.get_mut().write_all(request.as_bytes()).await?;
+        let mut line = String::new();
+        if self.stream.read_line(&mut line).await? == 0 {
+            bail!("the daemon closed the connection");
+        }
+        let mut response: Value = serde_json::from_str(&line).context("invalid rpc response")?;
+        if let Some(error) = response.get("error") {
+            let message = error.get("message").and_then(Value::as_str).unwrap_or("");
+            bail!("{method} failed: {message}");
+        }
+        Ok(response
+            .get_mut("result")
+            .map(Value::take)
+            .unwrap_or_default())
+    }
+}
+
+#[tokio::test]
+async fn control_a_session_over_the_socket() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("data.bin");
+    std::fs::write(&path, vec![7; 50_000]).unwrap();
+    let torrent = crate::builder::TorrentBuilder::new(&path)
+        .piece_length(16 * 1024)
+        .build()
+        .unwrap();
+
+    let socket = dir.path().join("rpc.sock");
+    let listener = RpcServer::bind(&socket).await.unwrap();
+    let server = RpcServer::new(crate::session::local_session().await);
+    tokio::spawn(async move { server.run(listener).await });
+    assert!(RpcServer::bind(&socket).await.is_err());
+
+    let mut client = RpcClient::connect(&socket).await.unwrap();
+    let added = client
+        .call(
+            "add",
+            json!({ "torrent": hex::encode(torrent.to_bytes().unwrap()), "output": path }),
+        )
+        .await
+        .unwrap();
+    let info_hash = added["info_hash"].as_str().unwrap().to_string();
+    assert_eq!(info_hash, hex::encode(torrent.info_hash().unwrap()));
+
+    let mut list = client.call("list", Value::Null).await.unwrap();
+    while list[0]["state"] == "checking" {
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        list = client.call("list", Value::Null).await.unwrap();
+    }
+    assert_eq!(list[0]["state"], "seeding");
+    assert_eq!(list[0]["name"], "data.bin");
+
+    client
+        .call("pause", json!({ "info_hash": info_hash }))
+        .await
+        .unwrap();
+    client
+        .call("set_limits", json!({ "download": 1024, "upload": null }))
+        .await
+        .unwrap();
+    let stats = client.call("stats", Value::Null).await.unwrap();
+    assert_eq!(stats["paused"], 1);
+    assert_eq!(stats["download_limit"], 1024);
+    assert_eq!(stats["upload_limit"], Value::Null);
+
+    client
+        .call("remove", json!({ "info_hash": info_hash }))
+        .await
+        .unwrap();
+    let error = client
+        .call("resume", json!({ "info_hash": info_hash }))
+        .await
+        .unwrap_err();
+    assert!(error.to_string().contains("no torrent"));
+    assert!(client.call("shutdown", Value::Null).await.is_err());
+}
@@ -0,0 +1,185 @@
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+
+use crate::picker::Priority;
+use crate::torrent::Info;
+
+/// Files of a torrent chosen by index, index range or glob, as in
+/// `0,3-5,*.mkv`. Indexes start at zero. A glob with a `/` matches the path of
+/// the file in the torrent, one without matches its name. `*` and `?` stay
+/// within a path component, `**` crosses them.
+#[derive(Debug, Clone)]
+pub struct FileSelection {
+    selectors: Vec<Selector>,
+}
+
+#[derive(Debug, Clone)]
+enum Selector {
+    Range(usize, usize),
+    Glob {
+        glob: String,
+        regex: Regex,
+        whole_path: bool,
+    },
+}
+
+impl FromStr for FileSelection {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let selectors = s
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(Selector::parse)
+            .collect::<Result<Vec<_>>>()?;
+        if selectors.is_empty() {
+            bail!("no file selected");
+        }
+        Ok(Self { selectors })
+    }
+}
+
+impl Selector {
+    fn parse(item: &str) -> Result<Self> {
+        let index = |s: &str| {
+            s.parse::<usize>()
+                .with_context(|| format!("invalid file index {s:?}"))
+        };
+        if item.bytes().all(|b| b.is_ascii_digit()) {
+            let index = index(item)?;
+            return Ok(Self::Range(index, index));
+        }
+        if let Some((start, end)) = item.split_once('-') {
+            if !start.is_empty() && start.bytes().chain(end.bytes()).all(|b| b.is_ascii_digit()) {
+                return Ok(Self::Range(index(start)?, index(end)?));
+            }
+        }
+        Ok(Self::Glob {
+            glob: item.to_string(),
+            regex: glob_regex(item)?,
+            whole_path: item.contains('/'),
+        })
+    }
+
+    fn matches(&self, index: usize, path: &[String]) -> bool {
+        match self {
+            Self::Range(start, end) => (*start..=*end).contains(&index),
+            Self::Glob {
+                regex, whole_path, ..
+            } => {
+                if *whole_path {
+                    regex.is_match(&path.join("/"))
+                } else {
+                    path.last().is_some_and(|name| regex.is_match(name))
+                }
+            }
+        }
+    }
+}
+
+/// A regex matching the same paths as a glob
+fn glob_regex(glob: &str) -> Result<Regex> {
+    let mut pattern = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                pattern.push_str(".*");
+            }
+            '*' => pattern.push_str("[^/]*"),
+            '?' => pattern.push_str("[^/]"),
+            c => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    pattern.push('$');
+    Regex::new(&pattern).with_context(|| format!("invalid glob {glob:?}"))
+}
+
+impl FileSelection {
+    /// Priority of each file of the torrent, normal for the selected files
+    /// and skip for the others. Fails when an index is out of range or a
+    /// glob matches nothing, as that is likely a typo.
+    pub fn priorities(&self, info: &Info) -> Result<Vec<Priority>> {
+        let paths: Vec<Vec<String>> = match &info.files {
+            Some(files) => files.iter().map(|f| f.path.clone()).collect(),
+            None => vec![vec![info.name.clone()]],
+        };
+        for selector in &self.selectors {
+            let used = (0..paths.len()).any(|index| selector.matches(index, &paths[index]));
+            if !used {
+                match selector {
+                    Selector::Range(start, _) => {
+                        bail!(
+                            "file index {start} is out of range, the torrent has {} files",
+                            paths.len()
+                        )
+                    }
+                    Selector::Glob { glob, .. } => bail!("no file matches {glob}"),
+                }
+            }
+        }
+        Ok(paths
+            .iter()
+            .enumerate()
+            .map(|(index, path)| {
+                if self.selectors.iter().any(|s| s.matches(index, path)) {
+                    Priority::Normal
+                } else {
+                    Priority::Skip
+                }
+            })
+            .collect())
+    }
+}
+
+#[test]
+fn select_by_index_range_and_glob() {
+    let paths: [&[&str]; 5] = [
+        &["readme.txt"],
+        &["s01", "e01.mkv"],
+        &["s01", "e02.mkv"],
+        &["s02", "e01.mkv"],
+        &["s02", "extras", "e01.srt"],
+    ];
+    let info = Info {
+        name: "show".into(),
+        plength: 16384,
+        pieces: crate::torrent::Pieces(Vec::new()),
+        length: None,
+        files: Some(
+            paths
+                .iter()
+                .map(|path| crate::torrent::File {
+                    length: 1,
+                    path: path.iter().map(|s| s.to_string()).collect(),
+                    extra: Default::default(),
+                })
+                .collect(),
+        ),
+        private: None,
+        source: None,
+        extra: Default::default(),
+    };
+    let select = |spec: &str| {
+        let priorities = spec.parse::<FileSelection>()?.priorities(&info)?;
+        anyhow::Ok(
+            priorities
+                .iter()
+                .map(|&p| p == Priority::Normal)
+                .collect::<Vec<_>>(),
+        )
+    };
+    assert_eq!(select("0").unwrap(), [true, false, false, false, false]);
+    assert_eq!(select("1-2, 4").unwrap(), [false, true, true, false, true]);
+    assert_eq!(select("*.mkv").unwrap(), [false, true, true, true, false]);
+    assert_eq!(select("s02/*").unwrap(), [false, false, false, true, false]);
+    assert_eq!(select("s02/**").unwrap(), [false, false, false, true, true]);
+    assert_eq!(select("e0?.*").unwrap(), [false, true, true, true, true]);
+    assert!(select("7").is_err());
+    assert!(select("*.avi").is_err());
+    assert!(",".parse::<FileSelection>().is_err());
+}
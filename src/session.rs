@@ -0,0 +1,930 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use tokio::net::TcpListener;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+
+use crate::alert::{Alert, Alerts, Event};
+use crate::connections::{self, ConnectionLimits};
+use crate::dht::{self, Dht};
+use crate::logging::Span;
+use crate::lsd::Lsd;
+use crate::metrics::TorrentMetrics;
+use crate::mse::EncryptionPolicy;
+use crate::peer::Peer;
+use crate::picker::{self, Priority};
+use crate::ratelimit::Limits;
+use crate::storage::Storage;
+use crate::torrent::Torrent;
+use crate::tracker::{self, AnnounceEvent};
+use crate::transport::Transport;
+use crate::utp::UtpSocket;
+use crate::worker::{self, Worker};
+use crate::{debug, warn};
+
+/// Shortest time between two announces to a tracker, whatever it asks for
+const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+/// Time before the trackers are tried again when none answered
+const TRACKER_RETRY: Duration = Duration::from_secs(60);
+
+/// Settings of a [`Session`], shared by all its torrents
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    /// Address peers connect to for every torrent, port 0 picks a free one.
+    /// uTP and the DHT use the same UDP port when they can.
+    pub listen: SocketAddr,
+    /// Nodes to join the DHT through, None to stay out of it
+    pub dht_bootstrap: Option<Vec<String>>,
+    /// Where the DHT node is restored from, and saved to once the session is
+    /// dropped
+    pub dht_state: Option<PathBuf>,
+    /// Look for peers on the local network
+    pub lsd: bool,
+    /// Reach peers over uTP first and accept uTP connections
+    pub utp: bool,
+    pub encryption: EncryptionPolicy,
+    /// Download and upload rates of every torrent together, in bytes per
+    /// second
+    pub download_limit: Option<u64>,
+    pub upload_limit: Option<u64>,
+    /// Most peer connections of every torrent together
+    pub max_connections: usize,
+    pub max_half_open: usize,
+    /// Download and upload rates of each peer connection, in bytes per
+    /// second
+    pub peer_download_limit: Option<u64>,
+    pub peer_upload_limit: Option<u64>,
+    /// Most peer connections of each torrent
+    pub max_peers: usize,
+    /// Exchange peer lists with peers
+    pub pex: bool,
+    /// How long trackers have to answer announces
+    pub tracker_timeout: Duration,
+    /// Size of the blocks pieces are requested in, in bytes
+    pub block_size: usize,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            listen: SocketAddr::from(([0, 0, 0, 0], 6881)),
+            dht_bootstrap: Some(dht::BOOTSTRAP_NODES.iter().map(|n| n.to_string()).collect()),
+            dht_state: None,
+            lsd: true,
+            utp: true,
+            encryption: EncryptionPolicy::Enabled,
+            download_limit: None,
+            upload_limit: None,
+            max_connections: connections::MAX_CONNECTIONS,
+            max_half_open: connections::MAX_HALF_OPEN,
+            peer_download_limit: None,
+            peer_upload_limit: None,
+            max_peers: worker::MAX_PEERS,
+            pex: true,
+            tracker_timeout: tracker::TIMEOUT,
+            block_size: picker::BLOCK_SIZE,
+        }
+    }
+}
+
+/// Downloads and seeds many torrents at once. They share one listener, one
+/// uTP socket, one DHT node, the rate limits, the connection caps and one
+/// stream of alerts.
+///
+/// Clones share the session. Its torrents stop once every clone is dropped,
+/// their [`TorrentHandle`]s then only report their last state.
+#[derive(Clone)]
+pub struct Session {
+    shared: Arc<Shared>,
+    _running: Arc<Running>,
+}
+
+struct Shared {
+    config: SessionConfig,
+    port: u16,
+    limits: Limits,
+    connection_limits: ConnectionLimits,
+    utp: Option<UtpSocket>,
+    dht: Option<Dht>,
+    lsd: Option<Lsd>,
+    alerts: Alerts,
+    torrents: Mutex<HashMap<[u8; 20], Arc<Entry>>>,
+}
+
+/// Stops the listeners and the torrents once every handle of the session is
+/// dropped, and saves the DHT node
+struct Running {
+    shared: Arc<Shared>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.tasks.iter().for_each(JoinHandle::abort);
+        for entry in self.shared.torrents().values() {
+            entry.stop(&self.shared);
+        }
+        if let (Some(dht), Some(path)) = (&self.shared.dht, &self.shared.config.dht_state) {
+            if let Err(e) = dht.save(path) {
+                warn!("saving the dht state failed: {e:#}");
+            }
+        }
+    }
+}
+
+/// A torrent of the session
+struct Entry {
+    torrent: Torrent,
+    info_hash: [u8; 20],
+    /// Where the torrent is written
+    output: PathBuf,
+    worker: Worker,
+    /// Downloads and seeds the torrent, None while paused
+    task: Mutex<Option<JoinHandle<()>>>,
+    /// Why the torrent stopped on its own
+    error: Arc<Mutex<Option<String>>>,
+    /// Whether the data already on disk is still to be checked
+    checking: Arc<AtomicBool>,
+}
+
+impl Session {
+    /// Binds the listener, the uTP socket and the DHT node of `config`. The
+    /// DHT is joined in the background.
+    pub async fn new(config: SessionConfig) -> Result<Self> {
+        let listener = TcpListener::bind(config.listen)
+            .await
+            .with_context(|| format!("listening on {}", config.listen))?;
+        let port = listener.local_addr()?.port();
+        let ip = config.listen.ip();
+
+        // uTP takes the UDP port peers expect, before the DHT node binds
+        let utp = if config.utp {
+            match UtpSocket::bind((ip, port)).await {
+                Ok(socket) => Some(socket),
+                Err(e) => {
+                    warn!("uTP unavailable: {e:#}");
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let mut tasks = Vec::new();
+        let dht = match &config.dht_bootstrap {
+            Some(nodes) => {
+                let state = config.dht_state.as_deref();
+                let bind = |port: u16| async move {
+                    match state {
+                        Some(path) => Dht::restore((ip, port), path).await,
+                        None => Dht::bind((ip, port)).await,
+                    }
+                };
+                let dht = match bind(port).await {
+                    Ok(dht) => dht,
+                    Err(_) => bind(0).await?,
+                };
+                let (node, nodes) = (dht.clone(), nodes.clone());
+                tasks.push(tokio::spawn(async move {
+                    if let Err(e) = node.bootstrap(&nodes).await {
+                        warn!("joining the dht failed: {e:#}");
+                    }
+                }));
+                Some(dht)
+            }
+            None => None,
+        };
+        let lsd = if config.lsd {
+            match Lsd::bind().await {
+                Ok(lsd) => Some(lsd),
+                Err(e) => {
+                    warn!("local service discovery unavailable: {e:#}");
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let shared = Arc::new(Shared {
+            limits: Limits::new(config.download_limit, config.upload_limit),
+            connection_limits: ConnectionLimits::new(config.max_connections, config.max_half_open),
+            config,
+            port,
+            utp: utp.clone(),
+            dht,
+            lsd,
+            alerts: Alerts::new(),
+            torrents: Mutex::new(HashMap::new()),
+        });
+        tasks.push(tokio::spawn(accept_tcp(shared.clone(), listener)));
+        if let Some(utp) = utp {
+            tasks.push(tokio::spawn(accept_utp(shared.clone(), utp)));
+        }
+        Ok(Self {
+            _running: Arc::new(Running {
+                shared: shared.clone(),
+                tasks,
+            }),
+            shared,
+        })
+    }
+
+    /// Port of the listener, which peers connect to
+    pub fn port(&self) -> u16 {
+        self.shared.port
+    }
+
+    pub fn dht(&self) -> Option<Dht> {
+        self.shared.dht.clone()
+    }
+
+    /// Alerts of every torrent from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<Alert> {
+        self.shared.alerts.subscribe()
+    }
+
+    /// Adds a torrent, written to `output` as [`Storage::new`] lays it out,
+    /// and starts it. The data already there is checked first in the
+    /// background, so a torrent resumes where it stopped.
+    pub fn add_torrent(&self, torrent: Torrent, output: impl AsRef<Path>) -> Result<TorrentHandle> {
+        let info_hash = torrent.info_hash()?;
+        if self.shared.torrents().contains_key(&info_hash) {
+            bail!("torrent {} is already added", hex::encode(info_hash));
+        }
+        let storage = Storage::new(&torrent.info, output.as_ref());
+        let worker = Worker::new(torrent.clone(), storage)?;
+        worker.set_encryption(self.shared.config.encryption);
+        worker.set_peer_rate_limits(
+            self.shared.config.peer_download_limit,
+            self.shared.config.peer_upload_limit,
+        );
+        worker.set_max_peers(self.shared.config.max_peers);
+        worker.set_pex(self.shared.config.pex);
+        worker.set_tracker_timeout(self.shared.config.tracker_timeout);
+        worker.set_block_size(self.shared.config.block_size);
+        worker.share_limits(self.shared.limits.clone());
+        worker.share_connection_limits(self.shared.connection_limits.clone());
+        worker.set_listen_port(self.shared.port);
+        worker.share_alerts(self.shared.alerts.clone());
+        if let Some(utp) = &self.shared.utp {
+            worker.share_utp(utp.clone());
+        }
+        if let Some(dht) = &self.shared.dht {
+            worker.use_dht(dht.clone());
+        }
+        if let Some(lsd) = &self.shared.lsd {
+            worker.use_lsd(lsd.clone());
+        }
+
+        let entry = Arc::new(Entry {
+            torrent,
+            info_hash,
+            output: output.as_ref().to_path_buf(),
+            worker,
+            task: Mutex::new(None),
+            error: Arc::new(Mutex::new(None)),
+            checking: Arc::new(AtomicBool::new(true)),
+        });
+        {
+            let mut torrents = self.shared.torrents();
+            // Another add of the same torrent may have won the race
+            if torrents.contains_key(&info_hash) {
+                bail!("torrent {} is already added", hex::encode(info_hash));
+            }
+            torrents.insert(info_hash, entry.clone());
+        }
+        self.shared.alerts.post(info_hash, Event::TorrentAdded);
+        entry.start(&self.shared);
+        Ok(TorrentHandle {
+            shared: self.shared.clone(),
+            entry,
+        })
+    }
+
+    pub fn torrent(&self, info_hash: &[u8; 20]) -> Option<TorrentHandle> {
+        let entry = self.shared.torrents().get(info_hash)?.clone();
+        Some(TorrentHandle {
+            shared: self.shared.clone(),
+            entry,
+        })
+    }
+
+    pub fn torrents(&self) -> Vec<TorrentHandle> {
+        self.shared
+            .torrents()
+            .values()
+            .map(|entry| TorrentHandle {
+                shared: self.shared.clone(),
+                entry: entry.clone(),
+            })
+            .collect()
+    }
+
+    /// Sets the download and upload rates of every torrent together, in
+    /// bytes per second, None for no limit
+    pub fn set_rate_limits(&self, download: Option<u64>, upload: Option<u64>) {
+        self.shared.limits.set(download, upload);
+    }
+
+    /// Download and upload rates of every torrent together
+    pub fn rate_limits(&self) -> (Option<u64>, Option<u64>) {
+        self.shared.limits.rates()
+    }
+
+    /// The metrics of every torrent of the session, for
+    /// [`MetricsServer`](crate::metrics::MetricsServer) or any other use
+    pub fn metrics(&self) -> Vec<TorrentMetrics> {
+        self.torrents().iter().map(TorrentHandle::metrics).collect()
+    }
+}
+
+impl Shared {
+    fn torrents(&self) -> MutexGuard<'_, HashMap<[u8; 20], Arc<Entry>>> {
+        self.torrents.lock().expect("session lock poisoned")
+    }
+
+    /// Completes the handshake of an incoming connection and hands it to the
+    /// running torrent it asks for
+    async fn hand_over(&self, connection: Transport) -> Result<()> {
+        let (running, dht) = {
+            let torrents = self.torrents();
+            let running: Vec<&Arc<Entry>> = torrents
+                .values()
+                .filter(|entry| entry.is_running())
+                .collect();
+            let dht: HashSet<[u8; 20]> = running
+                .iter()
+                .filter(|entry| entry.worker.advertises_dht())
+                .map(|entry| entry.info_hash)
+                .collect();
+            let running: Vec<[u8; 20]> = running.iter().map(|entry| entry.info_hash).collect();
+            (running, dht)
+        };
+        let policy = self.config.encryption;
+        let peer = Peer::accept_any(connection, &running, policy, |info_hash| {
+            dht.contains(info_hash)
+        })
+        .await?;
+        let worker = self
+            .torrents()
+            .get(&peer.info_hash)
+            .map(|entry| entry.worker.clone());
+        if let Some(worker) = worker {
+            worker.accept(peer);
+        }
+        Ok(())
+    }
+}
+
+async fn accept_tcp(shared: Arc<Shared>, listener: TcpListener) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                debug!("accepting connection failed: {e}");
+                continue;
+            }
+        };
+        let shared = shared.clone();
+        tokio::spawn(async move {
+            if let Err(e) = shared.hand_over(stream.into()).await {
+                debug!({ peer = addr }, "incoming connection failed: {e:#}");
+            }
+        });
+    }
+}
+
+async fn accept_utp(shared: Arc<Shared>, utp: UtpSocket) {
+    while let Ok(stream) = utp.accept().await {
+        let shared = shared.clone();
+        tokio::spawn(async move {
+            let addr = stream.peer_addr();
+            if let Err(e) = shared.hand_over(stream.into()).await {
+                debug!({ peer = addr }, "incoming uTP connection failed: {e:#}");
+            }
+        });
+    }
+}
+
+impl Entry {
+    fn task(&self) -> MutexGuard<'_, Option<JoinHandle<()>>> {
+        self.task.lock().expect("torrent lock poisoned")
+    }
+
+    fn error(&self) -> Option<String> {
+        self.error.lock().expect("torrent lock poisoned").clone()
+    }
+
+    fn is_running(&self) -> bool {
+        self.task().as_ref().is_some_and(|task| !task.is_finished())
+    }
+
+    /// Starts downloading and seeding, false when the torrent runs already
+    fn start(&self, session: &Shared) -> bool {
+        let mut task = self.task();
+        if task.as_ref().is_some_and(|task| !task.is_finished()) {
+            return false;
+        }
+        *self.error.lock().expect("torrent lock poisoned") = None;
+        let worker = self.worker.clone();
+        let torrent = self.torrent.clone();
+        let error = self.error.clone();
+        let alerts = session.alerts.clone();
+        let port = session.port;
+        let checking = self.checking.clone();
+        let span = Span::current().with("torrent", &self.torrent.info.name);
+        *task = Some(tokio::spawn(async move {
+            let result = async {
+                if checking.load(Ordering::Relaxed) {
+                    // Hashing the data takes a while, it stays off the runtime
+                    let checker = worker.clone();
+                    let checked = checking.clone();
+                    tokio::task::spawn_blocking(move || {
+                        checker.check_existing();
+                        checked.store(false, Ordering::Relaxed);
+                    })
+                    .await
+                    .context("checking the data")?;
+                }
+                tokio::select! {
+                    result = worker.seed(None) => result,
+                    _ = span.instrument(announce(&worker, &torrent, port, &alerts)) => Ok(()),
+                }
+            }
+            .await;
+            if let Err(e) = result {
+                let e = format!("{e:#}");
+                *error.lock().expect("torrent lock poisoned") = Some(e.clone());
+                alerts.post(worker.info_hash(), Event::TorrentFailed { error: e });
+            }
+        }));
+        true
+    }
+
+    /// Stops the torrent, false when it was not running
+    fn stop(&self, session: &Shared) -> bool {
+        let running = match self.task().take() {
+            Some(task) => {
+                let running = !task.is_finished();
+                task.abort();
+                running
+            }
+            None => false,
+        };
+        if running {
+            self.announce_stopped(session.port);
+        }
+        running
+    }
+
+    /// Tells the trackers that last answered that we are gone, so they stop
+    /// handing us out. Nothing is sent once the runtime shuts down.
+    fn announce_stopped(&self, port: u16) {
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let trackers: Vec<String> = self
+            .worker
+            .trackers()
+            .into_iter()
+            .filter(|status| status.result.is_ok())
+            .map(|status| status.url)
+            .collect();
+        if trackers.is_empty() {
+            return;
+        }
+        let worker = self.worker.clone();
+        runtime.spawn(async move {
+            for tracker in trackers {
+                if let Err(e) = worker
+                    .announce(&tracker, port, Some(AnnounceEvent::Stopped))
+                    .await
+                {
+                    debug!({ tracker = tracker }, "stopped announce failed: {e:#}");
+                }
+            }
+        });
+    }
+}
+
+/// Announces the torrent to its trackers for as long as it runs, at the
+/// interval of the first tracker that answers. The first announce that
+/// gets through is `started`, and a download finishing is announced as
+/// `completed` right away.
+async fn announce(worker: &Worker, torrent: &Torrent, port: u16, alerts: &Alerts) {
+    let trackers = torrent.trackers();
+    if trackers.is_empty() {
+        return std::future::pending().await;
+    }
+    let mut event = Some(AnnounceEvent::Started);
+    let mut downloading = !worker.is_complete();
+    loop {
+        let mut interval = TRACKER_RETRY;
+        for tracker in trackers.iter().flatten() {
+            match worker.announce(tracker, port, event).await {
+                Ok(response) => {
+                    alerts.post(
+                        worker.info_hash(),
+                        Event::TrackerReply {
+                            tracker: tracker.clone(),
+                            peers: response.peers.0.len(),
+                        },
+                    );
+                    worker.add_peers(response.peers.0.into_iter().map(SocketAddr::V4));
+                    interval = Duration::from_secs(response.interval as u64);
+                    event = None;
+                    break;
+                }
+                Err(e) => {
+                    warn!({ tracker = tracker }, "announce failed: {e:#}");
+                    alerts.post(
+                        worker.info_hash(),
+                        Event::TrackerError {
+                            tracker: tracker.clone(),
+                            error: format!("{e:#}"),
+                        },
+                    );
+                }
+            }
+        }
+        let wait = tokio::time::sleep(interval.max(MIN_ANNOUNCE_INTERVAL));
+        if downloading {
+            tokio::select! {
+                _ = wait => {}
+                _ = worker.completed() => {
+                    downloading = false;
+                    event = event.or(Some(AnnounceEvent::Completed));
+                }
+            }
+        } else {
+            wait.await;
+        }
+    }
+}
+
+/// What a torrent of a session is doing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TorrentState {
+    Paused,
+    /// The data already on disk is checked against the piece hashes
+    Checking,
+    Downloading,
+    /// Every wanted piece is verified, the torrent uploads to other peers
+    Seeding,
+    /// The torrent stopped on its own, with the reason
+    Failed(String),
+}
+
+/// A snapshot of a torrent of a session
+#[derive(Debug, Clone)]
+pub struct TorrentStatus {
+    pub info_hash: [u8; 20],
+    pub name: String,
+    pub state: TorrentState,
+    /// Verified pieces
+    pub pieces: usize,
+    pub total_pieces: usize,
+    /// Length of the content
+    pub length: usize,
+    /// Bytes of the wanted pieces still missing
+    pub left: usize,
+    /// Peers we are connected to
+    pub peers: usize,
+}
+
+/// A file of a torrent of a session, with how much of it is verified
+#[derive(Debug, Clone)]
+pub struct FileStatus {
+    pub path: PathBuf,
+    pub length: usize,
+    pub downloaded: usize,
+    pub priority: Priority,
+}
+
+/// Controls a torrent of a [`Session`]. Clones control the same torrent.
+#[derive(Clone)]
+pub struct TorrentHandle {
+    shared: Arc<Shared>,
+    entry: Arc<Entry>,
+}
+
+impl TorrentHandle {
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.entry.info_hash
+    }
+
+    pub fn torrent(&self) -> &Torrent {
+        &self.entry.torrent
+    }
+
+    /// The output the torrent was added with
+    pub fn output(&self) -> &Path {
+        &self.entry.output
+    }
+
+    /// The worker of the torrent, for what the handle does not cover such as
+    /// reading files while they download
+    pub fn worker(&self) -> &Worker {
+        &self.entry.worker
+    }
+
+    pub fn status(&self) -> TorrentStatus {
+        let worker = &self.entry.worker;
+        let state = match self.entry.task().as_ref() {
+            None => TorrentState::Paused,
+            Some(task) if task.is_finished() => {
+                TorrentState::Failed(self.entry.error().unwrap_or_else(|| "stopped".to_string()))
+            }
+            Some(_) if self.entry.checking.load(Ordering::Relaxed) => TorrentState::Checking,
+            Some(_) if worker.is_complete() => TorrentState::Seeding,
+            Some(_) => TorrentState::Downloading,
+        };
+        let (pieces, total_pieces) = worker.progress();
+        TorrentStatus {
+            info_hash: self.entry.info_hash,
+            name: self.entry.torrent.info.name.clone(),
+            state,
+            pieces,
+            total_pieces,
+            length: self.entry.torrent.info.length(),
+            left: worker.left(),
+            peers: worker.peers().len(),
+        }
+    }
+
+    pub fn metrics(&self) -> TorrentMetrics {
+        self.entry.worker.metrics()
+    }
+
+    pub fn files(&self) -> Vec<FileStatus> {
+        let worker = &self.entry.worker;
+        worker
+            .files()
+            .iter()
+            .zip(worker.file_progress())
+            .zip(worker.file_priorities())
+            .map(|((file, downloaded), priority)| FileStatus {
+                path: file.path.clone(),
+                length: file.length,
+                downloaded,
+                priority,
+            })
+            .collect()
+    }
+
+    /// Sets the priority of each file, skipped files are not downloaded
+    pub fn set_file_priorities(&self, priorities: Vec<Priority>) -> Result<()> {
+        self.entry.worker.set_file_priorities(priorities)
+    }
+
+    /// Adds peers to connect to, on top of the ones the torrent finds
+    pub fn add_peers(&self, peers: impl IntoIterator<Item = SocketAddr>) {
+        self.entry.worker.add_peers(peers);
+    }
+
+    /// Closes the connections of the torrent and stops announcing it
+    pub fn pause(&self) {
+        if self.entry.stop(&self.shared) {
+            self.post(Event::TorrentPaused);
+        }
+    }
+
+    /// Starts a paused or failed torrent again
+    pub fn resume(&self) {
+        if self.entry.start(&self.shared) {
+            self.post(Event::TorrentResumed);
+        }
+    }
+
+    /// Stops the torrent and removes it from the session, its data stays on
+    /// disk
+    pub fn remove(&self) {
+        self.entry.stop(&self.shared);
+        if self
+            .shared
+            .torrents()
+            .remove(&self.entry.info_hash)
+            .is_some()
+        {
+            self.post(Event::TorrentRemoved);
+        }
+    }
+
+    fn post(&self, event: Event) {
+        self.shared.alerts.post(self.entry.info_hash, event);
+    }
+}
+
+#[cfg(test)]
+pub(crate) async fn local_session() -> Session {
+    Session::new(SessionConfig {
+        listen: "127.0.0.1:0".parse().unwrap(),
+        dht_bootstrap: None,
+        lsd: false,
+        utp: false,
+        ..SessionConfig::default()
+    })
+    .await
+    .unwrap()
+}
+
+#[cfg(test)]
+pub async fn wait_for_state(handle: &TorrentHandle, state: TorrentState) {
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while handle.status().state != state {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .unwrap_or_else(|_| panic!("torrent gets {state:?}"));
+}
+
+#[tokio::test]
+async fn torrents_share_the_listener_of_a_session() {
+    let fixtures =
+        [200_000, 120_000].map(|length| crate::worker::TestTorrent::new(length, 32 * 1024));
+
+    let seeder = local_session().await;
+    let leecher = local_session().await;
+    let seeder_addr = SocketAddr::from(([127, 0, 0, 1], seeder.port()));
+    let mut handles = Vec::new();
+    for fixture in &fixtures {
+        let seeding = seeder
+            .add_torrent(fixture.torrent.clone(), &fixture.path)
+            .unwrap();
+        assert_eq!(seeding.status().state, TorrentState::Checking);
+        crate::session::wait_for_state(&seeding, TorrentState::Seeding).await;
+        let handle = leecher
+            .add_torrent(fixture.torrent.clone(), fixture.output("copy"))
+            .unwrap();
+        handle.add_peers([seeder_addr]);
+        handles.push(handle);
+    }
+    assert!(leecher
+        .add_torrent(fixtures[0].torrent.clone(), fixtures[0].dir.path())
+        .is_err());
+
+    for (handle, fixture) in handles.iter().zip(&fixtures) {
+        crate::session::wait_for_state(handle, TorrentState::Seeding).await;
+        assert_eq!(fixture.read("copy"), fixture.content);
+        let files = handle.files();
+        assert_eq!(files[0].downloaded, files[0].length);
+    }
+
+    let handle = &handles[0];
+    handle.pause();
+    assert_eq!(handle.status().state, TorrentState::Paused);
+    handle.resume();
+    assert_eq!(handle.status().state, TorrentState::Seeding);
+    handle.remove();
+    assert_eq!(leecher.torrents().len(), 1);
+    assert!(leecher.torrent(&handle.info_hash()).is_none());
+}
+
+#[tokio::test]
+async fn trackers_hear_when_a_download_starts_completes_and_stops() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // A tracker that reports the event of each announce and knows no peers
+    let tracker = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/announce", tracker.local_addr().unwrap());
+    let (events, mut announced) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = tracker.accept().await {
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = stream.read(&mut buf).await.unwrap();
+                assert!(n > 0, "request ends early");
+                request.extend_from_slice(&buf[..n]);
+            }
+            let request = String::from_utf8_lossy(&request).into_owned();
+            let line = request.lines().next().unwrap();
+            let event = line
+                .split(['?', '&', ' '])
+                .find_map(|pair| pair.strip_prefix("event="))
+                .unwrap_or("none");
+            events.send(event.to_string()).unwrap();
+            let body = b"d8:intervali60e5:peers0:e";
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            stream.write_all(head.as_bytes()).await.unwrap();
+            stream.write_all(body).await.unwrap();
+        }
+    });
+    async fn next_event(announced: &mut tokio::sync::mpsc::UnboundedReceiver<String>) -> String {
+        tokio::time::timeout(Duration::from_secs(10), announced.recv())
+            .await
+            .expect("the tracker is announced to")
+            .unwrap()
+    }
+
+    let fixture = crate::worker::TestTorrent::new(100_000, 32 * 1024);
+    let seeder = local_session().await;
+    seeder
+        .add_torrent(fixture.torrent.clone(), &fixture.path)
+        .unwrap();
+    let leecher = local_session().await;
+    let mut torrent = fixture.torrent.clone();
+    torrent.announce = Some(url);
+    let handle = leecher
+        .add_torrent(torrent, fixture.output("copy.bin"))
+        .unwrap();
+    handle.add_peers([SocketAddr::from(([127, 0, 0, 1], seeder.port()))]);
+
+    assert_eq!(next_event(&mut announced).await, "started");
+    assert_eq!(next_event(&mut announced).await, "completed");
+    assert_eq!(handle.status().state, TorrentState::Seeding);
+    handle.pause();
+    assert_eq!(next_event(&mut announced).await, "stopped");
+}
+
+#[tokio::test]
+async fn alerts_follow_a_download() {
+    let fixture = crate::worker::TestTorrent::new(150_000, 32 * 1024);
+    let info_hash = fixture.torrent.info_hash().unwrap();
+    let seeder = local_session().await;
+    seeder
+        .add_torrent(fixture.torrent.clone(), &fixture.path)
+        .unwrap();
+    let leecher = local_session().await;
+    let mut alerts = leecher.subscribe();
+    let handle = leecher
+        .add_torrent(fixture.torrent.clone(), fixture.output("copy.bin"))
+        .unwrap();
+    handle.add_peers([SocketAddr::from(([127, 0, 0, 1], seeder.port()))]);
+
+    let mut events = Vec::new();
+    tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            let alert = alerts.recv().await.unwrap();
+            assert_eq!(alert.info_hash, info_hash);
+            events.push(alert.event.clone());
+            if alert.event == Event::TorrentFinished {
+                break;
+            }
+        }
+    })
+    .await
+    .expect("torrent finishes");
+    assert_eq!(events[0], Event::TorrentAdded);
+    assert!(events
+        .iter()
+        .any(|event| matches!(event, Event::PeerConnected { .. })));
+    let verified = events
+        .iter()
+        .filter(|event| matches!(event, Event::PieceVerified { .. }))
+        .count();
+    assert_eq!(verified, fixture.torrent.info.pieces.0.len());
+
+    handle.pause();
+    handle.pause();
+    handle.resume();
+    handle.remove();
+    let mut events = Vec::new();
+    while let Ok(alert) = alerts.try_recv() {
+        events.push(alert.event);
+    }
+    events.retain(|event| !matches!(event, Event::PeerDisconnected { .. }));
+    assert_eq!(
+        events,
+        [
+            Event::TorrentPaused,
+            Event::TorrentResumed,
+            Event::TorrentRemoved
+        ]
+    );
+}
+
+#[tokio::test]
+async fn paused_connection_attempts_are_retried_on_resume() {
+    let fixture = crate::worker::TestTorrent::new(40_000, 32 * 1024);
+    // A peer that accepts connections and never answers the handshake
+    let silent = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let session = local_session().await;
+    let handle = session
+        .add_torrent(fixture.torrent.clone(), fixture.output("copy.bin"))
+        .unwrap();
+    handle.add_peers([silent.local_addr().unwrap()]);
+    let wait = Duration::from_secs(5);
+    let (first, _) = tokio::time::timeout(wait, silent.accept())
+        .await
+        .expect("the peer is dialled")
+        .unwrap();
+
+    handle.pause();
+    handle.resume();
+    let (_second, _) = tokio::time::timeout(wait, silent.accept())
+        .await
+        .expect("the peer is dialled again after resuming")
+        .unwrap();
+    drop(first);
+}
@@ -0,0 +1,106 @@
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+
+use sha1::{Digest, Sha1};
+
+/// Versions of a block that were received, by sender and hash of the data
+type Received = HashSet<(IpAddr, [u8; 20])>;
+
+/// Finds the peers that sent corrupt data.
+///
+/// When a piece fails its hash check, the hash of each of its blocks is kept
+/// along with the peer that sent it. Once the piece passes with other data,
+/// the peers whose blocks differ from the good ones sent bad data.
+#[derive(Default)]
+pub struct SmartBan {
+    /// Blocks of the pieces that failed, by piece and block index
+    failed: HashMap<usize, HashMap<usize, Received>>,
+}
+
+impl SmartBan {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a piece that failed its hash check, `senders` has the peer
+    /// of each block of `data`, blocks being `block_size` long
+    pub fn piece_failed(
+        &mut self,
+        piece: usize,
+        data: &[u8],
+        block_size: usize,
+        senders: &[IpAddr],
+    ) {
+        let blocks = self.failed.entry(piece).or_default();
+        for (index, (block, &sender)) in data.chunks(block_size).zip(senders).enumerate() {
+            blocks
+                .entry(index)
+                .or_default()
+                .insert((sender, Sha1::digest(block).into()));
+        }
+    }
+
+    /// Peers that sent blocks of a failed piece, they are not asked for it
+    /// again while others have it
+    pub fn suspects(&self, piece: usize) -> HashSet<IpAddr> {
+        self.failed
+            .get(&piece)
+            .into_iter()
+            .flat_map(|blocks| blocks.values().flatten())
+            .map(|&(sender, _)| sender)
+            .collect()
+    }
+
+    /// Failed pieces the peer sent blocks of
+    pub fn suspected_pieces(&self, ip: IpAddr) -> Vec<usize> {
+        self.failed
+            .keys()
+            .copied()
+            .filter(|&piece| self.suspects(piece).contains(&ip))
+            .collect()
+    }
+
+    /// The piece passed with `data`, returns the peers that sent a block of
+    /// it that did not match
+    pub fn piece_passed(&mut self, piece: usize, data: &[u8], block_size: usize) -> Vec<IpAddr> {
+        let Some(blocks) = self.failed.remove(&piece) else {
+            return Vec::new();
+        };
+        let mut bad = HashSet::new();
+        for (index, block) in data.chunks(block_size).enumerate() {
+            let Some(received) = blocks.get(&index) else {
+                continue;
+            };
+            let good: [u8; 20] = Sha1::digest(block).into();
+            bad.extend(
+                received
+                    .iter()
+                    .filter(|(_, hash)| *hash != good)
+                    .map(|&(sender, _)| sender),
+            );
+        }
+        bad.into_iter().collect()
+    }
+}
+
+#[test]
+fn blame_the_senders_of_bad_blocks() {
+    use crate::picker::BLOCK_SIZE;
+
+    let honest: IpAddr = "10.0.0.1".parse().unwrap();
+    let liar: IpAddr = "10.0.0.2".parse().unwrap();
+    let good = vec![7u8; 2 * BLOCK_SIZE + 100];
+    let mut bad = good.clone();
+    bad[BLOCK_SIZE + 5] = 0;
+
+    let mut smartban = SmartBan::new();
+    assert!(smartban.piece_passed(0, &good, BLOCK_SIZE).is_empty());
+    // The honest peer sent the first and last block, the liar the middle one
+    smartban.piece_failed(0, &bad, BLOCK_SIZE, &[honest, liar, honest]);
+    assert_eq!(smartban.suspects(0), HashSet::from([honest, liar]));
+    assert!(smartban.suspects(1).is_empty());
+    assert_eq!(smartban.suspected_pieces(liar), vec![0]);
+
+    assert_eq!(smartban.piece_passed(0, &good, BLOCK_SIZE), vec![liar]);
+    assert!(smartban.suspects(0).is_empty());
+}
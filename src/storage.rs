@@ -0,0 +1,352 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{ensure, Context, Result};
+use sha1::{Digest, Sha1};
+
+use crate::picker::Priority;
+use crate::torrent::Info;
+
+/// Key of the partfile among the open files
+const PARTFILE: usize = usize::MAX;
+
+/// A file of the torrent, placed at `offset` in the concatenation of all the files
+#[derive(Debug, Clone)]
+pub struct FileEntry {
+    pub path: PathBuf,
+    pub length: usize,
+    pub offset: usize,
+}
+
+/// Maps pieces to the files they span and does the disk io.
+///
+/// Skipped files are not created. The parts of the pieces we download that
+/// fall in them go to a partfile instead, at their offset in the content, and
+/// move to the file if it is wanted later.
+pub struct Storage {
+    files: Vec<FileEntry>,
+    plength: usize,
+    length: usize,
+    handles: Mutex<HashMap<usize, fs::File>>,
+    skipped: Mutex<Vec<bool>>,
+    partfile: PathBuf,
+}
+
+impl Storage {
+    /// Storage for the content of `info`. A single file torrent is written to
+    /// `output`, a multi file torrent to files inside the `output` directory.
+    pub fn new(info: &Info, output: &Path) -> Self {
+        let files = match &info.files {
+            Some(files) => files
+                .iter()
+                .map(|f| {
+                    let mut path = output.to_path_buf();
+                    path.extend(&f.path);
+                    (path, f.length)
+                })
+                .collect(),
+            None => vec![(output.to_path_buf(), info.length())],
+        };
+        let mut storage = Self::from_files(files, info.plength);
+        if info.files.is_some() {
+            storage.partfile = output.join(".parts");
+        }
+        storage
+    }
+
+    /// Storage for `files` one after the other, with the partfile next to the
+    /// first one
+    pub fn from_files(files: Vec<(PathBuf, usize)>, plength: usize) -> Self {
+        let mut partfile = files
+            .first()
+            .map(|(path, _)| path.clone())
+            .unwrap_or_default();
+        partfile.as_mut_os_string().push(".parts");
+        let skipped = Mutex::new(vec![false; files.len()]);
+        let mut offset = 0;
+        let files = files
+            .into_iter()
+            .map(|(path, length)| {
+                let entry = FileEntry {
+                    path,
+                    length,
+                    offset,
+                };
+                offset += length;
+                entry
+            })
+            .collect();
+        Self {
+            files,
+            plength,
+            length: offset,
+            handles: Mutex::new(HashMap::new()),
+            skipped,
+            partfile,
+        }
+    }
+
+    pub fn files(&self) -> &[FileEntry] {
+        &self.files
+    }
+
+    /// Total length of the content
+    pub fn length(&self) -> usize {
+        self.length
+    }
+
+    /// Skips the files with the skip priority, and moves the parts of the
+    /// files wanted again out of the partfile
+    pub fn set_file_priorities(&self, priorities: &[Priority]) -> Result<()> {
+        ensure!(
+            priorities.len() == self.files.len(),
+            "{} priorities for {} files",
+            priorities.len(),
+            self.files.len()
+        );
+        let mut skipped = self.skipped.lock().expect("storage lock poisoned");
+        for (index, &priority) in priorities.iter().enumerate() {
+            let skip = priority == Priority::Skip;
+            if skipped[index] && !skip && self.in_partfile(index, true) {
+                self.restore(index).with_context(|| {
+                    format!(
+                        "moving {} out of the partfile",
+                        self.files[index].path.display()
+                    )
+                })?;
+            }
+            skipped[index] = skip;
+        }
+        Ok(())
+    }
+
+    /// Priority of each piece, the highest of the files it spans. Pieces only
+    /// in skipped files are skipped.
+    pub fn piece_priorities(&self, file_priorities: &[Priority]) -> Vec<Priority> {
+        let pieces = self.length.div_ceil(self.plength);
+        (0..pieces)
+            .map(|piece| {
+                self.spans(piece * self.plength, self.piece_length(piece))
+                    .map(|(index, _, _)| file_priorities.get(index).copied().unwrap_or_default())
+                    .max()
+                    .unwrap_or_default()
+            })
+            .collect()
+    }
+
+    /// Whether the data of a file lives in the partfile, as for skipped files
+    /// not on disk
+    fn in_partfile(&self, index: usize, skipped: bool) -> bool {
+        skipped
+            && !self
+                .handles
+                .lock()
+                .expect("storage lock poisoned")
+                .contains_key(&index)
+            && !self.files[index].path.exists()
+    }
+
+    /// Copies what the partfile has of a file into the file
+    fn restore(&self, index: usize) -> Result<()> {
+        let entry = &self.files[index];
+        let available = match fs::metadata(&self.partfile) {
+            Ok(metadata) => (metadata.len() as usize).saturating_sub(entry.offset),
+            Err(_) => 0,
+        };
+        let mut chunk = vec![0; 1024 * 1024];
+        let mut copied = 0;
+        while copied < entry.length.min(available) {
+            let length = chunk.len().min(entry.length.min(available) - copied);
+            let data = &mut chunk[..length];
+            self.with_file(PARTFILE, |file| {
+                file.seek(SeekFrom::Start((entry.offset + copied) as u64))?;
+                file.read_exact(data)?;
+                Ok(())
+            })?;
+            self.with_file(index, |file| {
+                file.seek(SeekFrom::Start(copied as u64))?;
+                file.write_all(data)?;
+                Ok(())
+            })?;
+            copied += length;
+        }
+        // Creates the file even when the partfile had nothing of it
+        self.with_file(index, |_| Ok(()))
+    }
+
+    /// Where a span of a file is read and written: the file, or the partfile
+    /// at the offset of the span in the content
+    fn locate(&self, index: usize, offset: usize) -> (usize, usize) {
+        let skipped = self.skipped.lock().expect("storage lock poisoned")[index];
+        if self.in_partfile(index, skipped) {
+            (PARTFILE, self.files[index].offset + offset)
+        } else {
+            (index, offset)
+        }
+    }
+
+    /// The parts of the files that hold `length` bytes starting at `offset` of
+    /// the content, as (file index, offset in the file, length)
+    fn spans(
+        &self,
+        offset: usize,
+        length: usize,
+    ) -> impl Iterator<Item = (usize, usize, usize)> + '_ {
+        let end = offset + length;
+        self.files
+            .iter()
+            .enumerate()
+            .filter(move |(_, f)| f.offset < end && f.offset + f.length > offset)
+            .map(move |(i, f)| {
+                let start = offset.max(f.offset);
+                let stop = end.min(f.offset + f.length);
+                (i, start - f.offset, stop - start)
+            })
+    }
+
+    fn with_file<T>(&self, index: usize, f: impl FnOnce(&mut fs::File) -> Result<T>) -> Result<T> {
+        let mut handles = self.handles.lock().expect("storage lock poisoned");
+        let file = match handles.entry(index) {
+            std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+            std::collections::hash_map::Entry::Vacant(entry) => {
+                let path = match index {
+                    PARTFILE => &self.partfile,
+                    index => &self.files[index].path,
+                };
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                let file = fs::OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .open(path)
+                    .with_context(|| format!("opening {}", path.display()))?;
+                if index != PARTFILE {
+                    file.set_len(self.files[index].length as u64)?;
+                }
+                entry.insert(file)
+            }
+        };
+        f(file)
+    }
+
+    /// Writes `data` at `begin` inside the piece
+    pub fn write(&self, piece: usize, begin: usize, data: &[u8]) -> Result<()> {
+        let mut written = 0;
+        for (index, offset, length) in self.spans(piece * self.plength + begin, data.len()) {
+            let (index, offset) = self.locate(index, offset);
+            self.with_file(index, |file| {
+                file.seek(SeekFrom::Start(offset as u64))?;
+                file.write_all(&data[written..written + length])?;
+                Ok(())
+            })?;
+            written += length;
+        }
+        Ok(())
+    }
+
+    /// Reads `length` bytes at `begin` inside the piece
+    pub fn read(&self, piece: usize, begin: usize, length: usize) -> Result<Vec<u8>> {
+        let mut data = vec![0; length];
+        let mut read = 0;
+        for (index, offset, length) in self.spans(piece * self.plength + begin, length) {
+            let (index, offset) = self.locate(index, offset);
+            self.with_file(index, |file| {
+                file.seek(SeekFrom::Start(offset as u64))?;
+                file.read_exact(&mut data[read..read + length])?;
+                Ok(())
+            })?;
+            read += length;
+        }
+        Ok(data)
+    }
+
+    /// Length of the piece at `piece`, the last one may be shorter
+    pub fn piece_length(&self, piece: usize) -> usize {
+        self.plength.min(self.length - self.plength * piece)
+    }
+
+    /// Checks a piece already on disk against its hash, missing files fail the check
+    pub fn verify(&self, piece: usize, hash: &[u8; 20]) -> bool {
+        let exists = self
+            .spans(piece * self.plength, self.piece_length(piece))
+            .all(|(index, offset, _)| match self.locate(index, offset) {
+                (PARTFILE, _) => self.partfile.exists(),
+                (index, _) => self.files[index].path.exists(),
+            });
+        exists
+            && self
+                .read(piece, 0, self.piece_length(piece))
+                .map(|data| Sha1::digest(&data).as_slice() == hash)
+                .unwrap_or(false)
+    }
+}
+
+#[test]
+fn write_across_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let storage = Storage::from_files(
+        vec![
+            (dir.path().join("a"), 3),
+            (dir.path().join("sub").join("b"), 5),
+            (dir.path().join("c"), 4),
+        ],
+        4,
+    );
+    storage.write(0, 0, b"abcd").unwrap();
+    storage.write(1, 0, b"efgh").unwrap();
+    storage.write(2, 0, b"ijkl").unwrap();
+
+    assert_eq!(fs::read(dir.path().join("a")).unwrap(), b"abc");
+    assert_eq!(
+        fs::read(dir.path().join("sub").join("b")).unwrap(),
+        b"defgh"
+    );
+    assert_eq!(fs::read(dir.path().join("c")).unwrap(), b"ijkl");
+    assert_eq!(storage.read(0, 2, 4).unwrap(), b"cdef");
+    assert!(storage.verify(1, &Sha1::digest(b"efgh").into()));
+    assert!(!storage.verify(1, &Sha1::digest(b"efgi").into()));
+}
+
+#[test]
+fn skipped_files_go_to_the_partfile() {
+    let dir = tempfile::tempdir().unwrap();
+    let storage = Storage::from_files(
+        vec![
+            (dir.path().join("a"), 3),
+            (dir.path().join("b"), 5),
+            (dir.path().join("c"), 4),
+        ],
+        4,
+    );
+    use Priority::*;
+    assert_eq!(
+        storage.piece_priorities(&[High, Skip, Low]),
+        vec![High, Skip, Low]
+    );
+    storage
+        .set_file_priorities(&[Normal, Skip, Normal])
+        .unwrap();
+    assert!(storage.set_file_priorities(&[Normal]).is_err());
+
+    // The first piece straddles the skipped file, its end goes to the partfile
+    storage.write(0, 0, b"abcd").unwrap();
+    storage.write(2, 0, b"ijkl").unwrap();
+    assert_eq!(fs::read(dir.path().join("a")).unwrap(), b"abc");
+    assert!(!dir.path().join("b").exists());
+    assert_eq!(storage.read(0, 0, 4).unwrap(), b"abcd");
+    assert!(storage.verify(0, &Sha1::digest(b"abcd").into()));
+    assert!(!storage.verify(1, &Sha1::digest(b"efgh").into()));
+
+    storage
+        .set_file_priorities(&[Normal, Normal, Normal])
+        .unwrap();
+    assert_eq!(fs::read(dir.path().join("b")).unwrap(), b"d\0\0\0\0");
+    storage.write(1, 0, b"efgh").unwrap();
+    assert_eq!(fs::read(dir.path().join("b")).unwrap(), b"defgh");
+}
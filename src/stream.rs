@@ -0,0 +1,435 @@
+use std::fmt::Write as _;
+use std::io::SeekFrom;
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::debug;
+use crate::torrent::Info;
+use crate::worker::Worker;
+
+/// Biggest request head we read
+const MAX_HEAD: usize = 8 * 1024;
+/// Biggest request body we read, enough for a torrent file in base64
+const MAX_BODY: usize = 16 * 1024 * 1024;
+/// Bytes read from the file and written to the socket at once
+const CHUNK: usize = 64 * 1024;
+
+/// Serves the files of a torrent over HTTP while it downloads.
+///
+/// `/` lists the files, `/<index>/<name>` serves one with support for a single
+/// byte range. The pieces a request reaches are downloaded first, and the
+/// response waits for them, so media can be played and logs tailed from a
+/// browser or `curl`.
+pub struct StreamServer {
+    worker: Worker,
+    /// Path of each file in the torrent
+    files: Arc<Vec<String>>,
+}
+
+impl StreamServer {
+    pub fn new(worker: Worker, info: &Info) -> Self {
+        let files = match &info.files {
+            Some(files) => files.iter().map(|f| f.path.join("/")).collect(),
+            None => vec![info.name.clone()],
+        };
+        Self {
+            worker,
+            files: Arc::new(files),
+        }
+    }
+
+    /// Answers requests until the future is dropped
+    pub async fn run(&self, listener: TcpListener) -> Result<()> {
+        let mut connections = tokio::task::JoinSet::new();
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let (stream, _) = accepted.context("accepting http connection")?;
+                    let worker = self.worker.clone();
+                    let files = self.files.clone();
+                    connections.spawn(async move {
+                        if let Err(e) = handle(stream, worker, &files).await {
+                            debug!("http request failed: {e:#}");
+                        }
+                    });
+                }
+                Some(_) = connections.join_next() => {}
+            }
+        }
+    }
+}
+
+/// A parsed request
+pub(crate) struct Request {
+    pub method: String,
+    pub path: String,
+    headers: Vec<(String, String)>,
+    /// As long as its `Content-Length`, empty without one
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// Value of a header, whatever the case of its name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Reads the request head and the body it announces
+pub(crate) async fn read_request(stream: &mut TcpStream) -> Result<Request> {
+    let mut head = Vec::new();
+    let mut buffer = [0; 1024];
+    let end = loop {
+        if let Some(end) = head.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end;
+        }
+        if head.len() >= MAX_HEAD {
+            bail!("request head too long");
+        }
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            bail!("connection closed before the request ended");
+        }
+        head.extend_from_slice(&buffer[..read]);
+    };
+    let mut body = head.split_off(end + 4);
+    head.truncate(end);
+    let head = String::from_utf8(head).context("request is not utf-8")?;
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let (Some(method), Some(path)) = (request_line.next(), request_line.next()) else {
+        bail!("invalid request line");
+    };
+    let mut request = Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        headers: lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .collect(),
+        body: Vec::new(),
+    };
+    let length = match request.header("content-length") {
+        Some(length) => length.parse::<usize>().context("invalid content length")?,
+        None => 0,
+    };
+    if length > MAX_BODY {
+        bail!("request body too long");
+    }
+    body.truncate(length);
+    if body.len() < length {
+        let start = body.len();
+        body.resize(length, 0);
+        stream.read_exact(&mut body[start..]).await?;
+    }
+    request.body = body;
+    Ok(request)
+}
+
+async fn handle(mut stream: TcpStream, worker: Worker, files: &[String]) -> Result<()> {
+    let request = read_request(&mut stream).await?;
+    let head_only = match request.method.as_str() {
+        "GET" => false,
+        "HEAD" => true,
+        _ => return respond(&mut stream, "405 Method Not Allowed", &[], "").await,
+    };
+    let path = request.path.split('?').next().unwrap_or_default();
+    if path == "/" {
+        let page = index_page(files);
+        let headers = [("Content-Type", "text/html; charset=utf-8".to_string())];
+        return respond(
+            &mut stream,
+            "200 OK",
+            &headers,
+            if head_only { "" } else { &page },
+        )
+        .await;
+    }
+    // The name after the index is only there for browsers and players
+    let index = path
+        .trim_start_matches('/')
+        .split('/')
+        .next()
+        .and_then(|index| index.parse::<usize>().ok())
+        .filter(|&index| index < files.len());
+    let Some(index) = index else {
+        return respond(&mut stream, "404 Not Found", &[], "no such file\n").await;
+    };
+
+    let mut reader = worker.reader(index)?;
+    let length = reader.len();
+    let range = match request
+        .header("range")
+        .map(|range| parse_range(range, length))
+    {
+        None | Some(Range::Ignored) => None,
+        Some(Range::Satisfiable(start, end)) => Some((start, end)),
+        Some(Range::Unsatisfiable) => {
+            let headers = [("Content-Range", format!("bytes */{length}"))];
+            return respond(&mut stream, "416 Range Not Satisfiable", &headers, "").await;
+        }
+    };
+    let (status, start, end) = match range {
+        Some((start, end)) => ("206 Partial Content", start, end),
+        None => ("200 OK", 0, length),
+    };
+    let mut headers = vec![
+        ("Content-Type", content_type(&files[index]).to_string()),
+        ("Content-Length", (end - start).to_string()),
+        ("Accept-Ranges", "bytes".to_string()),
+    ];
+    if range.is_some() {
+        headers.push((
+            "Content-Range",
+            format!("bytes {start}-{}/{length}", end - 1),
+        ));
+    }
+    write_head(&mut stream, status, &headers).await?;
+    if head_only {
+        return Ok(());
+    }
+
+    reader.seek(SeekFrom::Start(start)).await?;
+    let mut left = end - start;
+    let mut chunk = vec![0; CHUNK];
+    while left > 0 {
+        let wanted = chunk.len().min(left as usize);
+        let read = reader.read(&mut chunk[..wanted]).await?;
+        if read == 0 {
+            bail!("file ended early");
+        }
+        stream.write_all(&chunk[..read]).await?;
+        left -= read as u64;
+    }
+    stream.flush().await?;
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Range {
+    /// Start and end, exclusive
+    Satisfiable(u64, u64),
+    Unsatisfiable,
+    /// Not a single byte range, the whole file is sent
+    Ignored,
+}
+
+/// Parses a `Range` header of a file of `length` bytes, only single byte
+/// ranges are supported
+fn parse_range(header: &str, length: u64) -> Range {
+    let Some(spec) = header.strip_prefix("bytes=") else {
+        return Range::Ignored;
+    };
+    if spec.contains(',') {
+        return Range::Ignored;
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return Range::Ignored;
+    };
+    let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+        // The last bytes of the file
+        (Err(_), Ok(suffix)) if start.is_empty() => {
+            if suffix == 0 || length == 0 {
+                return Range::Unsatisfiable;
+            }
+            (length.saturating_sub(suffix), length)
+        }
+        (Ok(start), Err(_)) if end.is_empty() => (start, length),
+        (Ok(start), Ok(end)) if start <= end => (start, (end + 1).min(length)),
+        _ => return Range::Ignored,
+    };
+    if start >= length {
+        return Range::Unsatisfiable;
+    }
+    Range::Satisfiable(start, end)
+}
+
+async fn write_head(
+    stream: &mut (impl AsyncWrite + Unpin),
+    status: &str,
+    headers: &[(&str, String)],
+) -> Result<()> {
+    let mut head = format!("HTTP/1.1 {status}\r\nConnection: close\r\n");
+    for (name, value) in headers {
+        let _ = write!(head, "{name}: {value}\r\n");
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes()).await?;
+    Ok(())
+}
+
+/// Sends a small response in one go
+pub(crate) async fn respond(
+    stream: &mut (impl AsyncWrite + Unpin),
+    status: &str,
+    headers: &[(&str, String)],
+    body: &str,
+) -> Result<()> {
+    let mut headers = headers.to_vec();
+    headers.push(("Content-Length", body.len().to_string()));
+    write_head(stream, status, &headers).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+fn index_page(files: &[String]) -> String {
+    let mut page = String::from("<!DOCTYPE html>\n<ul>\n");
+    for (index, path) in files.iter().enumerate() {
+        let name = path.rsplit('/').next().unwrap_or_default();
+        let _ = writeln!(
+            page,
+            "<li><a href=\"/{index}/{}\">{}</a></li>",
+            percent_encode(name),
+            html_escape(path)
+        );
+    }
+    page.push_str("</ul>\n");
+    page
+}
+
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            b => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Media type from the file extension, for players to know what they get
+fn content_type(path: &str) -> &'static str {
+    let extension = path.rsplit_once('.').map(|(_, e)| e.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("mp4" | "m4v") => "video/mp4",
+        Some("mkv") => "video/x-matroska",
+        Some("webm") => "video/webm",
+        Some("avi") => "video/x-msvideo",
+        Some("mp3") => "audio/mpeg",
+        Some("flac") => "audio/flac",
+        Some("ogg") => "audio/ogg",
+        Some("txt" | "log" | "srt" | "md") => "text/plain; charset=utf-8",
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("json") => "application/json",
+        Some("pdf") => "application/pdf",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("png") => "image/png",
+        _ => "application/octet-stream",
+    }
+}
+
+#[test]
+fn byte_ranges() {
+    assert_eq!(parse_range("bytes=0-99", 1000), Range::Satisfiable(0, 100));
+    assert_eq!(
+        parse_range("bytes=900-", 1000),
+        Range::Satisfiable(900, 1000)
+    );
+    assert_eq!(
+        parse_range("bytes=-100", 1000),
+        Range::Satisfiable(900, 1000)
+    );
+    assert_eq!(
+        parse_range("bytes=-2000", 1000),
+        Range::Satisfiable(0, 1000)
+    );
+    assert_eq!(
+        parse_range("bytes=500-5000", 1000),
+        Range::Satisfiable(500, 1000)
+    );
+    assert_eq!(parse_range("bytes=1000-", 1000), Range::Unsatisfiable);
+    assert_eq!(parse_range("bytes=-0", 1000), Range::Unsatisfiable);
+    assert_eq!(parse_range("bytes=0-1,5-9", 1000), Range::Ignored);
+    assert_eq!(parse_range("bytes=9-5", 1000), Range::Ignored);
+    assert_eq!(parse_range("items=0-1", 1000), Range::Ignored);
+}
+
+#[tokio::test]
+async fn stream_a_file_while_it_downloads() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("movie.mp4");
+    let content: Vec<u8> = (0..300_000u32).map(|i| (i % 211) as u8).collect();
+    std::fs::write(&path, &content).unwrap();
+    let torrent = crate::builder::TorrentBuilder::new(&path)
+        .piece_length(32 * 1024)
+        .build()
+        .unwrap();
+    let seeder = Worker::new(
+        torrent.clone(),
+        crate::storage::Storage::new(&torrent.info, &path),
+    )
+    .unwrap();
+    seeder.check_existing();
+    seeder.set_rate_limits(None, Some(300_000));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let seeder_addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { seeder.seed(Some(listener)).await });
+
+    let output = dir.path().join("leecher");
+    let leecher = Worker::new(
+        torrent.clone(),
+        crate::storage::Storage::new(&torrent.info, &output),
+    )
+    .unwrap();
+    leecher.add_peers([seeder_addr]);
+    let server = StreamServer::new(leecher.clone(), &torrent.info);
+    let http = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base = format!("http://{}", http.local_addr().unwrap());
+    tokio::spawn(async move { server.run(http).await });
+    tokio::spawn(async move { leecher.seed(None).await });
+
+    let client = reqwest::Client::new();
+    let page = client
+        .get(&base)
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(page.contains("href=\"/0/movie.mp4\""), "{page}");
+
+    let response = client
+        .get(format!("{base}/0/movie.mp4"))
+        .header("Range", "bytes=200000-200099")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 206);
+    assert_eq!(
+        response.headers()["content-range"],
+        "bytes 200000-200099/300000"
+    );
+    assert_eq!(response.headers()["content-type"], "video/mp4");
+    let body = response.bytes().await.unwrap();
+    assert_eq!(&body[..], &content[200_000..200_100]);
+
+    let response = client.get(format!("{base}/0")).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(&response.bytes().await.unwrap()[..], &content[..]);
+
+    let response = client
+        .get(format!("{base}/0"))
+        .header("Range", "bytes=400000-")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 416);
+    let response = client.get(format!("{base}/1")).send().await.unwrap();
+    assert_eq!(response.status(), 404);
+}
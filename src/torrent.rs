@@ -1,11 +1,44 @@
-use anyhow::Result;
-use pieces::Pieces;
-use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{bail, Result};
+pub use pieces::Pieces;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_bencode::value::Value;
 use sha1::{Digest, Sha1};
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Torrent {
-    pub announce: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub announce: Option<String>,
+    #[serde(
+        rename = "announce-list",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub announce_list: Option<Vec<Vec<String>>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    #[serde(
+        rename = "created by",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub created_by: Option<String>,
+    #[serde(
+        rename = "creation date",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub creation_date: Option<i64>,
+    /// Web seeds (BEP 19), a single url is accepted as well as a list
+    #[serde(
+        rename = "url-list",
+        default,
+        skip_serializing_if = "Vec::is_empty",
+        with = "url_list"
+    )]
+    pub url_list: Vec<String>,
     pub info: Info,
 }
 
@@ -14,17 +47,139 @@ impl Torrent {
         let mut hasher = Sha1::new();
         let encoded = serde_bencode::to_bytes(&self.info)?;
         hasher.update(&encoded);
-        Ok(hasher.finalize().try_into()?)
+        Ok(hasher.finalize().into())
+    }
+
+    /// Encodes the torrent as canonical bencode, dictionary keys are sorted and
+    /// empty optional keys are left out.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(serde_bencode::to_bytes(self)?)
+    }
+
+    /// All the trackers of the torrent grouped by tier, the `announce-list` takes
+    /// precedence over `announce` as described in BEP 12.
+    pub fn trackers(&self) -> Vec<Vec<String>> {
+        match (&self.announce_list, &self.announce) {
+            (Some(list), _) if !list.is_empty() => list.clone(),
+            (_, Some(announce)) => vec![vec![announce.clone()]],
+            _ => Vec::new(),
+        }
     }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Info {
-    pub length: usize,
+    /// Name of the file, or of the directory of the files, a single path
+    /// component
+    #[serde(deserialize_with = "component")]
     pub name: String,
     #[serde(rename = "piece length")]
     pub plength: usize,
     pub pieces: Pieces,
+    /// Length of the file, only present in single file torrents
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub length: Option<usize>,
+    /// Files of the torrent, only present in multi file torrents
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub files: Option<Vec<File>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// Keys unknown to this client, kept so the info hash does not change when
+    /// the dictionary is encoded again
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
+}
+
+impl Info {
+    /// Parses an info dictionary, as received from the metadata exchange
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let info: Info = serde_bencode::from_bytes(bytes)?;
+        match (&info.length, &info.files) {
+            (Some(_), None) | (None, Some(_)) => Ok(info),
+            _ => anyhow::bail!("info must have either a length or a list of files"),
+        }
+    }
+
+    /// Total length of the content, the sum of all the files for multi file torrents
+    pub fn length(&self) -> usize {
+        match &self.files {
+            Some(files) => files.iter().map(|f| f.length).sum(),
+            None => self.length.unwrap_or_default(),
+        }
+    }
+
+    /// Length of the piece at `piece_index`, the last piece may be shorter
+    pub fn piece_length(&self, piece_index: usize) -> usize {
+        self.plength.min(self.length() - self.plength * piece_index)
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.private == Some(1)
+    }
+
+    /// Where the torrent goes in `dir`, refused when its name would leave
+    /// `dir`
+    pub fn path_in(&self, dir: &Path) -> Result<PathBuf> {
+        check_component(&self.name)?;
+        Ok(dir.join(&self.name))
+    }
+
+    /// The files of the torrent with their path relative to the download
+    /// directory, a single file torrent has only one file named as the torrent.
+    pub fn files(&self) -> Vec<(PathBuf, usize)> {
+        match &self.files {
+            Some(files) => files
+                .iter()
+                .map(|f| {
+                    let mut path = PathBuf::from(&self.name);
+                    path.extend(&f.path);
+                    (path, f.length)
+                })
+                .collect(),
+            None => vec![(PathBuf::from(&self.name), self.length())],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct File {
+    pub length: usize,
+    /// Path in the directory of the torrent, one component per element
+    #[serde(deserialize_with = "components")]
+    pub path: Vec<String>,
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
+}
+
+/// Refuses a file name that is empty, has a separator or does not name an
+/// entry of the directory it is joined to, such as `..` or `/etc`
+pub fn check_component(component: &str) -> Result<()> {
+    let mut components = Path::new(component).components();
+    let normal =
+        matches!(components.next(), Some(Component::Normal(_))) && components.next().is_none();
+    if !normal || component.contains(['/', '\\', '\0']) {
+        bail!("invalid path component {component:?}");
+    }
+    Ok(())
+}
+
+fn component<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let component = String::deserialize(deserializer)?;
+    check_component(&component).map_err(serde::de::Error::custom)?;
+    Ok(component)
+}
+
+fn components<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    let path = Vec::<String>::deserialize(deserializer)?;
+    if path.is_empty() {
+        return Err(serde::de::Error::custom("empty file path"));
+    }
+    for component in &path {
+        check_component(component).map_err(serde::de::Error::custom)?;
+    }
+    Ok(path)
 }
 
 mod pieces {
@@ -51,7 +206,7 @@ mod pieces {
         where
             E: de::Error,
         {
-            if v.len() % 20 != 0 {
+            if !v.len().is_multiple_of(20) {
                 return Err(E::custom("length is not correct"));
             }
             Ok(Pieces(
@@ -81,3 +236,96 @@ mod pieces {
         }
     }
 }
+
+mod url_list {
+    use std::fmt;
+
+    use serde::{
+        de::{self, SeqAccess, Visitor},
+        Deserializer, Serialize, Serializer,
+    };
+
+    struct UrlListVisitor;
+
+    impl<'de> Visitor<'de> for UrlListVisitor {
+        type Value = Vec<String>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("an url or a list of urls")
+        }
+
+        fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            let url = std::str::from_utf8(v).map_err(E::custom)?;
+            if url.is_empty() {
+                return Ok(Vec::new());
+            }
+            Ok(vec![url.to_string()])
+        }
+
+        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+        where
+            A: SeqAccess<'de>,
+        {
+            let mut urls = Vec::new();
+            while let Some(url) = seq.next_element::<String>()? {
+                urls.push(url);
+            }
+            Ok(urls)
+        }
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(UrlListVisitor)
+    }
+
+    pub fn serialize<S>(urls: &[String], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        urls.serialize(serializer)
+    }
+}
+
+#[test]
+fn paths_leaving_the_download_dir_are_refused() {
+    let info = |name: &str, path: &[&str]| Info {
+        name: name.to_string(),
+        plength: 16 * 1024,
+        pieces: Pieces(vec![[0; 20]]),
+        length: None,
+        files: Some(vec![File {
+            length: 1,
+            path: path.iter().map(|c| c.to_string()).collect(),
+            extra: BTreeMap::new(),
+        }]),
+        private: None,
+        source: None,
+        extra: BTreeMap::new(),
+    };
+    let parse = |info: Info| Info::from_bytes(&serde_bencode::to_bytes(&info).unwrap());
+
+    assert!(parse(info("t", &["dir", "x"])).is_ok());
+    for path in [
+        &["..", "x"][..],
+        &["/etc", "passwd"],
+        &["a/../../b"],
+        &[""],
+        &[],
+    ] {
+        assert!(parse(info("t", path)).is_err(), "{path:?}");
+    }
+    for name in ["..", "/tmp/t", ".", "a\\b", ""] {
+        assert!(parse(info(name, &["x"])).is_err(), "{name:?}");
+        assert!(info(name, &["x"]).path_in(Path::new("/downloads")).is_err());
+    }
+    assert_eq!(
+        info("t", &["x"]).path_in(Path::new("/downloads")).unwrap(),
+        Path::new("/downloads/t")
+    );
+}
@@ -1,7 +1,12 @@
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
 use serde::{Deserialize, Serialize};
 
 use peers::Peers;
 
+use crate::{debug, peer};
+
 #[derive(Debug, Serialize)]
 pub struct TrackerRequest {
     pub peer_id: String,
@@ -10,6 +15,21 @@ pub struct TrackerRequest {
     pub downloaded: usize,
     pub left: usize,
     pub compact: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event: Option<AnnounceEvent>,
+}
+
+/// What changed for the torrent since the last announce, a regular announce
+/// has none
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AnnounceEvent {
+    /// The first announce of a torrent that was not running
+    Started,
+    /// The download finished while running
+    Completed,
+    /// The torrent stops, the tracker can forget us
+    Stopped,
 }
 
 pub fn hash_encoder(t: &[u8; 20]) -> String {
@@ -27,6 +47,53 @@ pub struct TrackerResponse {
     pub peers: Peers,
 }
 
+/// How long a tracker has to answer unless configured otherwise
+pub const TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Announces a torrent to a tracker, as listening on `port` with `left`
+/// bytes to download, giving up after `timeout`
+pub async fn announce(
+    tracker: &str,
+    info_hash: &[u8; 20],
+    port: u16,
+    left: usize,
+    event: Option<AnnounceEvent>,
+    timeout: Duration,
+) -> Result<TrackerResponse> {
+    let tracker_request = TrackerRequest {
+        peer_id: String::from_utf8_lossy(&peer::local_id()).into_owned(),
+        port,
+        uploaded: 0,
+        downloaded: 0,
+        left,
+        compact: 1,
+        event,
+    };
+
+    let query = serde_urlencoded::to_string(&tracker_request)?;
+    let url = format!(
+        "{}?{}&info_hash={}",
+        tracker,
+        query,
+        hash_encoder(info_hash)
+    );
+    let started = Instant::now();
+    let client = reqwest::Client::builder().timeout(timeout).build()?;
+    let response = client.get(url).send().await?;
+    let response = response.bytes().await?;
+    let response: TrackerResponse = serde_bencode::from_bytes(&response)?;
+    debug!(
+        {
+            tracker = tracker,
+            peers = response.peers.0.len(),
+            interval = response.interval,
+            elapsed_ms = started.elapsed().as_millis(),
+        },
+        "announced"
+    );
+    Ok(response)
+}
+
 mod peers {
     use std::{
         fmt,
@@ -54,7 +121,7 @@ mod peers {
         where
             E: de::Error,
         {
-            if v.len() % 6 != 0 {
+            if !v.len().is_multiple_of(6) {
                 return Err(E::custom("length is not correct"));
             }
             Ok(Peers(
@@ -0,0 +1,641 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
+
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Map, Value};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::picker::Priority;
+use crate::session::{Session, TorrentHandle, TorrentState};
+use crate::stream::{read_request, respond};
+use crate::torrent::Torrent;
+use crate::{debug, CLIENT_VERSION};
+
+/// Where Transmission clients send their requests
+const RPC_PATH: &str = "/transmission/rpc";
+const SESSION_ID_HEADER: &str = "X-Transmission-Session-Id";
+/// Speeds are in kB/s of 1000 bytes, as Transmission counts them by default
+const SPEED_UNIT: u64 = 1000;
+const RPC_VERSION: u32 = 17;
+const RPC_VERSION_MINIMUM: u32 = 14;
+
+/// Torrent status codes of the protocol
+const STATUS_STOPPED: u8 = 0;
+const STATUS_CHECK: u8 = 2;
+const STATUS_DOWNLOAD: u8 = 4;
+const STATUS_SEED: u8 = 6;
+/// Error code of a torrent that stopped on a local error
+const ERROR_LOCAL: u8 = 3;
+
+/// Speaks a subset of the Transmission RPC protocol over HTTP, so tools made
+/// for Transmission can drive a [`Session`].
+///
+/// Supported are `torrent-get`, `torrent-add`, `torrent-start`,
+/// `torrent-start-now`, `torrent-stop`, `torrent-remove`, `session-get`,
+/// `session-set` and `session-stats`, with the `X-Transmission-Session-Id`
+/// handshake. Torrents get numeric ids in the order they are first seen.
+///
+/// There is no authentication, whoever reaches the server controls the
+/// session and makes it fetch urls, so it must only listen on localhost.
+/// Local torrent files are only read from the download directory.
+#[derive(Clone)]
+pub struct TransmissionServer {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    session: Session,
+    /// Clients must send it back, they learn it from a 409 response
+    session_id: String,
+    state: Mutex<State>,
+}
+
+struct State {
+    /// Where added torrents go unless the request says otherwise
+    download_dir: PathBuf,
+    ids: HashMap<[u8; 20], i64>,
+    next_id: i64,
+    /// Speed limits in kB/s, kept when disabled as Transmission does
+    download_limit: u64,
+    download_limited: bool,
+    upload_limit: u64,
+    upload_limited: bool,
+    /// Bytes downloaded and uploaded of each torrent when last asked, to
+    /// tell their rates
+    samples: HashMap<[u8; 20], (Instant, u64, u64)>,
+}
+
+impl TransmissionServer {
+    /// Serves `session`, adding torrents to `download_dir` by default
+    pub fn new(session: Session, download_dir: PathBuf) -> Self {
+        let (download, upload) = session.rate_limits();
+        let state = State {
+            download_dir,
+            ids: HashMap::new(),
+            next_id: 1,
+            download_limit: download.map_or(100, |rate| rate / SPEED_UNIT),
+            download_limited: download.is_some(),
+            upload_limit: upload.map_or(100, |rate| rate / SPEED_UNIT),
+            upload_limited: upload.is_some(),
+            samples: HashMap::new(),
+        };
+        Self {
+            shared: Arc::new(Shared {
+                session,
+                session_id: hex::encode(crate::random_bytes()),
+                state: Mutex::new(state),
+            }),
+        }
+    }
+
+    /// Answers requests until the future is dropped
+    pub async fn run(&self, listener: TcpListener) -> Result<()> {
+        let mut connections = tokio::task::JoinSet::new();
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let (stream, _) = accepted.context("accepting http connection")?;
+                    let server = self.clone();
+                    connections.spawn(async move {
+                        if let Err(e) = server.handle(stream).await {
+                            debug!("transmission request failed: {e:#}");
+                        }
+                    });
+                }
+                Some(_) = connections.join_next() => {}
+            }
+        }
+    }
+
+    async fn handle(&self, mut stream: TcpStream) -> Result<()> {
+        let request = read_request(&mut stream).await?;
+        if request.path.split('?').next() != Some(RPC_PATH) {
+            return respond(&mut stream, "404 Not Found", &[], "").await;
+        }
+        let session_id = &self.shared.session_id;
+        if request.header(SESSION_ID_HEADER) != Some(session_id) {
+            let headers = [(SESSION_ID_HEADER, session_id.clone())];
+            let body = format!("{SESSION_ID_HEADER}: {session_id}\n");
+            return respond(&mut stream, "409 Conflict", &headers, &body).await;
+        }
+        if request.method != "POST" {
+            return respond(&mut stream, "405 Method Not Allowed", &[], "").await;
+        }
+        let Ok(request) = serde_json::from_slice::<Value>(&request.body) else {
+            return respond(&mut stream, "400 Bad Request", &[], "invalid json\n").await;
+        };
+        let method = request["method"].as_str().unwrap_or_default();
+        let arguments = request.get("arguments").cloned().unwrap_or(json!({}));
+        let mut response = match self.call(method, &arguments).await {
+            Ok(arguments) => json!({ "result": "success", "arguments": arguments }),
+            Err(e) => json!({ "result": format!("{e:#}"), "arguments": {} }),
+        };
+        if let Some(tag) = request.get("tag") {
+            response["tag"] = tag.clone();
+        }
+        let headers = [("Content-Type", "application/json".to_string())];
+        respond(&mut stream, "200 OK", &headers, &response.to_string()).await
+    }
+
+    /// Runs a method, with the arguments of its response
+    async fn call(&self, method: &str, arguments: &Value) -> Result<Value> {
+        match method {
+            "torrent-get" => self.torrent_get(arguments),
+            "torrent-add" => self.torrent_add(arguments).await,
+            "torrent-start" | "torrent-start-now" => {
+                self.select(arguments.get("ids"))?
+                    .iter()
+                    .for_each(|(_, torrent)| torrent.resume());
+                Ok(json!({}))
+            }
+            "torrent-stop" => {
+                self.select(arguments.get("ids"))?
+                    .iter()
+                    .for_each(|(_, torrent)| torrent.pause());
+                Ok(json!({}))
+            }
+            "torrent-remove" => {
+                let delete = arguments["delete-local-data"].as_bool().unwrap_or(false);
+                for (_, torrent) in self.select(arguments.get("ids"))? {
+                    torrent.remove();
+                    if delete {
+                        delete_data(&torrent);
+                    }
+                }
+                Ok(json!({}))
+            }
+            "session-get" => Ok(filter(self.session_fields(), arguments.get("fields"))),
+            "session-set" => self.session_set(arguments),
+            "session-stats" => Ok(self.session_stats()),
+            _ => bail!("method name not recognized"),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.shared
+            .state
+            .lock()
+            .expect("transmission state lock poisoned")
+    }
+
+    /// Every torrent of the session with its id, by id
+    fn torrents(&self) -> Vec<(i64, TorrentHandle)> {
+        let mut state = self.state();
+        let state = &mut *state;
+        let mut torrents: Vec<(i64, TorrentHandle)> = self
+            .shared
+            .session
+            .torrents()
+            .into_iter()
+            .map(|torrent| {
+                let id = *state.ids.entry(torrent.info_hash()).or_insert_with(|| {
+                    state.next_id += 1;
+                    state.next_id - 1
+                });
+                (id, torrent)
+            })
+            .collect();
+        torrents.sort_by_key(|(id, _)| *id);
+        torrents
+    }
+
+    /// The torrents `ids` names: all of them when missing or
+    /// `recently-active`, else an id, a hash or a list of both
+    fn select(&self, ids: Option<&Value>) -> Result<Vec<(i64, TorrentHandle)>> {
+        let torrents = self.torrents();
+        let wanted = match ids {
+            None => return Ok(torrents),
+            Some(Value::String(s)) if s == "recently-active" => return Ok(torrents),
+            Some(Value::Array(ids)) => ids.clone(),
+            Some(id) => vec![id.clone()],
+        };
+        let mut selected = Vec::new();
+        for id in wanted {
+            let matches = |(number, torrent): &&(i64, TorrentHandle)| match &id {
+                Value::Number(n) => n.as_i64() == Some(*number),
+                Value::String(hash) => hash.eq_ignore_ascii_case(&hex::encode(torrent.info_hash())),
+                _ => false,
+            };
+            match torrents.iter().find(matches) {
+                Some(torrent) => selected.push(torrent.clone()),
+                None if matches!(id, Value::Number(_) | Value::String(_)) => {}
+                None => bail!("invalid id {id}"),
+            }
+        }
+        Ok(selected)
+    }
+
+    fn torrent_get(&self, arguments: &Value) -> Result<Value> {
+        let fields = arguments.get("fields").context("no fields requested")?;
+        let torrents: Vec<Value> = self
+            .select(arguments.get("ids"))?
+            .into_iter()
+            .map(|(id, torrent)| filter(self.torrent_fields(id, &torrent), Some(fields)))
+            .collect();
+        Ok(json!({ "torrents": torrents }))
+    }
+
+    fn torrent_fields(&self, id: i64, torrent: &TorrentHandle) -> Map<String, Value> {
+        let status = torrent.status();
+        let metrics = torrent.metrics();
+        let (rate_download, rate_upload) = {
+            let mut state = self.state();
+            let now = Instant::now();
+            let sample = (now, metrics.downloaded, metrics.uploaded);
+            match state.samples.insert(status.info_hash, sample) {
+                Some((then, downloaded, uploaded)) => {
+                    let seconds = now.duration_since(then).as_secs_f64().max(1e-3);
+                    (
+                        (metrics.downloaded.saturating_sub(downloaded) as f64 / seconds) as u64,
+                        (metrics.uploaded.saturating_sub(uploaded) as f64 / seconds) as u64,
+                    )
+                }
+                None => (0, 0),
+            }
+        };
+        let (code, error) = match &status.state {
+            TorrentState::Paused => (STATUS_STOPPED, None),
+            TorrentState::Checking => (STATUS_CHECK, None),
+            TorrentState::Downloading => (STATUS_DOWNLOAD, None),
+            TorrentState::Seeding => (STATUS_SEED, None),
+            TorrentState::Failed(error) => (STATUS_STOPPED, Some(error.clone())),
+        };
+        let info = &torrent.torrent().info;
+        let names: Vec<String> = match &info.files {
+            Some(files) => files
+                .iter()
+                .map(|file| format!("{}/{}", info.name, file.path.join("/")))
+                .collect(),
+            None => vec![info.name.clone()],
+        };
+        let files = torrent.files();
+        let wanted = |priority: Priority| priority != Priority::Skip;
+        let size_when_done: usize = files
+            .iter()
+            .filter(|file| wanted(file.priority))
+            .map(|file| file.length)
+            .sum();
+        let have: usize = files.iter().map(|file| file.downloaded).sum();
+        let done = size_when_done.saturating_sub(status.left);
+        let eta = match rate_download {
+            0 if status.left > 0 => -1,
+            0 => 0,
+            rate => (status.left as u64 / rate) as i64,
+        };
+        let download_dir = torrent
+            .output()
+            .parent()
+            .map(|dir| dir.display().to_string())
+            .unwrap_or_default();
+        let fields = json!({
+            "id": id,
+            "hashString": hex::encode(status.info_hash),
+            "name": status.name,
+            "status": code,
+            "error": if error.is_some() { ERROR_LOCAL } else { 0 },
+            "errorString": error.unwrap_or_default(),
+            "totalSize": status.length,
+            "sizeWhenDone": size_when_done,
+            "leftUntilDone": status.left,
+            "haveValid": have,
+            "percentDone": if size_when_done == 0 { 1.0 } else { done as f64 / size_when_done as f64 },
+            "isFinished": status.state == TorrentState::Seeding,
+            "downloadedEver": metrics.downloaded,
+            "uploadedEver": metrics.uploaded,
+            "uploadRatio": if have == 0 { -1.0 } else { metrics.uploaded as f64 / have as f64 },
+            "rateDownload": rate_download,
+            "rateUpload": rate_upload,
+            "eta": eta,
+            "peersConnected": status.peers,
+            "pieceCount": status.total_pieces,
+            "pieceSize": info.plength,
+            "downloadDir": download_dir,
+            "isPrivate": info.is_private(),
+            "files": files.iter().zip(&names).map(|(file, name)| json!({
+                "name": name,
+                "length": file.length,
+                "bytesCompleted": file.downloaded,
+            })).collect::<Vec<_>>(),
+            "fileStats": files.iter().map(|file| json!({
+                "bytesCompleted": file.downloaded,
+                "wanted": wanted(file.priority),
+                "priority": match file.priority {
+                    Priority::Low => -1,
+                    Priority::High => 1,
+                    _ => 0,
+                },
+            })).collect::<Vec<_>>(),
+        });
+        match fields {
+            Value::Object(fields) => fields,
+            _ => unreachable!("fields are an object"),
+        }
+    }
+
+    async fn torrent_add(&self, arguments: &Value) -> Result<Value> {
+        let bytes = match (
+            arguments["metainfo"].as_str(),
+            arguments["filename"].as_str(),
+        ) {
+            (Some(metainfo), _) => base64_decode(metainfo).context("invalid metainfo")?,
+            (None, Some(url)) if url.starts_with("http://") || url.starts_with("https://") => {
+                let response = reqwest::get(url).await?.error_for_status()?;
+                response.bytes().await?.to_vec()
+            }
+            (None, Some(magnet)) if magnet.starts_with("magnet:") => {
+                bail!("magnet links are not supported, add the torrent file")
+            }
+            (None, Some(path)) => {
+                let path = self.local_file(path)?;
+                std::fs::read(&path).with_context(|| format!("reading {}", path.display()))?
+            }
+            (None, None) => bail!("no metainfo or filename"),
+        };
+        let torrent: Torrent = serde_bencode::from_bytes(&bytes).context("invalid torrent")?;
+        let info_hash = torrent.info_hash()?;
+        if self.shared.session.torrent(&info_hash).is_some() {
+            let (id, torrent) = self
+                .select(Some(&json!(hex::encode(info_hash))))?
+                .pop()
+                .context("torrent removed meanwhile")?;
+            return Ok(json!({ "torrent-duplicate": added(id, &torrent) }));
+        }
+        let download_dir = match arguments["download-dir"].as_str() {
+            Some(dir) => PathBuf::from(dir),
+            None => self.state().download_dir.clone(),
+        };
+        let output = torrent.info.path_in(&download_dir)?;
+        let torrent = self.shared.session.add_torrent(torrent, output)?;
+        if arguments["paused"].as_bool() == Some(true) {
+            torrent.pause();
+        }
+        let (id, torrent) = self
+            .select(Some(&json!(hex::encode(info_hash))))?
+            .pop()
+            .context("torrent removed meanwhile")?;
+        Ok(json!({ "torrent-added": added(id, &torrent) }))
+    }
+
+    /// A torrent file of the download directory, others are refused so
+    /// clients cannot read any file of ours
+    fn local_file(&self, path: &str) -> Result<PathBuf> {
+        let dir = self.state().download_dir.clone();
+        let dir =
+            std::fs::canonicalize(&dir).with_context(|| format!("reading {}", dir.display()))?;
+        let path = std::fs::canonicalize(path).with_context(|| format!("reading {path}"))?;
+        if !path.starts_with(&dir) {
+            bail!("{} is outside the download directory", path.display());
+        }
+        Ok(path)
+    }
+
+    fn session_fields(&self) -> Map<String, Value> {
+        let state = self.state();
+        let fields = json!({
+            "version": CLIENT_VERSION,
+            "rpc-version": RPC_VERSION,
+            "rpc-version-minimum": RPC_VERSION_MINIMUM,
+            "session-id": self.shared.session_id,
+            "download-dir": state.download_dir.display().to_string(),
+            "peer-port": self.shared.session.port(),
+            "speed-limit-down": state.download_limit,
+            "speed-limit-down-enabled": state.download_limited,
+            "speed-limit-up": state.upload_limit,
+            "speed-limit-up-enabled": state.upload_limited,
+            "units": {
+                "speed-units": ["kB/s", "MB/s", "GB/s", "TB/s"],
+                "speed-bytes": SPEED_UNIT,
+                "size-units": ["kB", "MB", "GB", "TB"],
+                "size-bytes": 1000,
+                "memory-units": ["KiB", "MiB", "GiB", "TiB"],
+                "memory-bytes": 1024,
+            },
+        });
+        match fields {
+            Value::Object(fields) => fields,
+            _ => unreachable!("fields are an object"),
+        }
+    }
+
+    fn session_set(&self, arguments: &Value) -> Result<Value> {
+        let mut state = self.state();
+        if let Some(dir) = arguments["download-dir"].as_str() {
+            let dir = PathBuf::from(dir);
+            if !dir.is_absolute() {
+                bail!("download directory path is not absolute");
+            }
+            state.download_dir = dir;
+        }
+        let number = |key: &str| arguments[key].as_u64();
+        let flag = |key: &str| arguments[key].as_bool();
+        state.download_limit = number("speed-limit-down").unwrap_or(state.download_limit);
+        state.download_limited = flag("speed-limit-down-enabled").unwrap_or(state.download_limited);
+        state.upload_limit = number("speed-limit-up").unwrap_or(state.upload_limit);
+        state.upload_limited = flag("speed-limit-up-enabled").unwrap_or(state.upload_limited);
+        self.shared.session.set_rate_limits(
+            state
+                .download_limited
+                .then_some(state.download_limit * SPEED_UNIT),
+            state
+                .upload_limited
+                .then_some(state.upload_limit * SPEED_UNIT),
+        );
+        Ok(json!({}))
+    }
+
+    fn session_stats(&self) -> Value {
+        let torrents = self.shared.session.torrents();
+        let paused = torrents
+            .iter()
+            .filter(|torrent| torrent.status().state == TorrentState::Paused)
+            .count();
+        let metrics = self.shared.session.metrics();
+        json!({
+            "torrentCount": torrents.len(),
+            "activeTorrentCount": torrents.len() - paused,
+            "pausedTorrentCount": paused,
+            "downloadedBytes": metrics.iter().map(|m| m.downloaded).sum::<u64>(),
+            "uploadedBytes": metrics.iter().map(|m| m.uploaded).sum::<u64>(),
+        })
+    }
+}
+
+/// The fields of `object` listed in `fields`, all of them without a list
+fn filter(mut object: Map<String, Value>, fields: Option<&Value>) -> Value {
+    if let Some(fields) = fields.and_then(Value::as_array) {
+        let fields: Vec<&str> = fields.iter().filter_map(Value::as_str).collect();
+        object.retain(|key, _| fields.contains(&key.as_str()));
+    }
+    Value::Object(object)
+}
+
+fn added(id: i64, torrent: &TorrentHandle) -> Value {
+    json!({
+        "id": id,
+        "name": torrent.torrent().info.name,
+        "hashString": hex::encode(torrent.info_hash()),
+    })
+}
+
+/// Deletes the files of a removed torrent, and its directory once empty
+fn delete_data(torrent: &TorrentHandle) {
+    for file in torrent.worker().files() {
+        if let Err(e) = std::fs::remove_file(&file.path) {
+            debug!("deleting {} failed: {e}", file.path.display());
+        }
+    }
+    if torrent.torrent().info.files.is_some() {
+        let _ = std::fs::remove_dir(torrent.output());
+    }
+}
+
+/// Decodes standard base64, line breaks and other whitespace are skipped
+fn base64_decode(s: &str) -> Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(s.len() / 4 * 3);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in s.bytes().filter(|c| !c.is_ascii_whitespace()) {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            _ => bail!("invalid base64 character {:?}", c as char),
+        };
+        buffer = buffer << 6 | u32::from(value);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Ok(bytes)
+}
+
+#[test]
+fn decode_base64() {
+    assert_eq!(base64_decode("").unwrap(), b"");
+    assert_eq!(base64_decode("Zg==").unwrap(), b"f");
+    assert_eq!(base64_decode("Zm9v").unwrap(), b"foo");
+    assert_eq!(base64_decode("Zm9vYg==").unwrap(), b"foob");
+    assert_eq!(base64_decode("ZDg6\nYW5u+/8=").unwrap(), b"d8:ann\xfb\xff");
+    assert!(base64_decode("Zm9v!").is_err());
+}
+
+#[tokio::test]
+async fn drive_a_session_like_transmission() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let dir = tempfile::tempdir().unwrap();
+    let content = dir.path().join("data.bin");
+    std::fs::write(&content, vec![3; 40_000]).unwrap();
+    let torrent = crate::builder::TorrentBuilder::new(&content)
+        .piece_length(16 * 1024)
+        .build()
+        .unwrap();
+    let metainfo = dir.path().join("data.torrent");
+    std::fs::write(&metainfo, torrent.to_bytes().unwrap()).unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let session = crate::session::local_session().await;
+    let server = TransmissionServer::new(session, dir.path().to_path_buf());
+    tokio::spawn(async move { server.run(listener).await });
+
+    let post = |session_id: String, body: Value| async move {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let body = body.to_string();
+        let request = format!(
+            "POST {RPC_PATH} HTTP/1.1\r\n{SESSION_ID_HEADER}: {session_id}\r\n\
+             Content-Length: {}\r\n\r\n{body}",
+            body.len()
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head.to_string(), body.to_string())
+    };
+
+    let (head, _) = post(String::new(), json!({ "method": "session-get" })).await;
+    assert!(head.starts_with("HTTP/1.1 409"));
+    let session_id = head
+        .lines()
+        .find_map(|line| line.strip_prefix(&format!("{SESSION_ID_HEADER}: ")))
+        .unwrap()
+        .to_string();
+    let call = |body: Value| {
+        let post = &post;
+        let session_id = session_id.clone();
+        async move {
+            let (_, body) = post(session_id, body).await;
+            serde_json::from_str::<Value>(&body).unwrap()
+        }
+    };
+
+    let added = call(json!({
+        "method": "torrent-add",
+        "arguments": { "filename": metainfo },
+        "tag": 7,
+    }))
+    .await;
+    assert_eq!(added["result"], "success");
+    assert_eq!(added["tag"], 7);
+    assert_eq!(added["arguments"]["torrent-added"]["id"], 1);
+    let duplicate = call(json!({
+        "method": "torrent-add",
+        "arguments": { "filename": metainfo },
+    }))
+    .await;
+    assert_eq!(duplicate["arguments"]["torrent-duplicate"]["id"], 1);
+    let elsewhere = tempfile::NamedTempFile::new().unwrap();
+    std::fs::write(elsewhere.path(), torrent.to_bytes().unwrap()).unwrap();
+    let refused = call(json!({
+        "method": "torrent-add",
+        "arguments": { "filename": elsewhere.path() },
+    }))
+    .await;
+    assert!(refused["result"]
+        .as_str()
+        .unwrap()
+        .contains("outside the download directory"));
+
+    let get = json!({
+        "method": "torrent-get",
+        "arguments": { "ids": [1], "fields": ["id", "name", "status", "percentDone"] },
+    });
+    let mut torrents = call(get.clone()).await["arguments"]["torrents"].take();
+    while torrents[0]["status"] == STATUS_CHECK {
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        torrents = call(get.clone()).await["arguments"]["torrents"].take();
+    }
+    assert_eq!(
+        torrents[0],
+        json!({ "id": 1, "name": "data.bin", "status": STATUS_SEED, "percentDone": 1.0 })
+    );
+
+    call(json!({ "method": "torrent-stop", "arguments": { "ids": 1 } })).await;
+    let torrents = &call(get.clone()).await["arguments"]["torrents"];
+    assert_eq!(torrents[0]["status"], STATUS_STOPPED);
+
+    call(json!({
+        "method": "session-set",
+        "arguments": { "speed-limit-down": 50, "speed-limit-down-enabled": true },
+    }))
+    .await;
+    let session = call(json!({ "method": "session-get" })).await;
+    assert_eq!(session["arguments"]["speed-limit-down"], 50);
+    assert_eq!(session["arguments"]["speed-limit-down-enabled"], true);
+    assert_eq!(session["arguments"]["rpc-version"], RPC_VERSION);
+
+    call(json!({ "method": "torrent-remove", "arguments": { "ids": [1] } })).await;
+    let torrents = &call(get).await["arguments"]["torrents"];
+    assert_eq!(torrents, &json!([]));
+    assert!(content.exists());
+    let unknown = call(json!({ "method": "blocklist-update" })).await;
+    assert_eq!(unknown["result"], "method name not recognized");
+}
@@ -0,0 +1,442 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use std::time::Instant;
+
+use anyhow::{anyhow, bail, Result};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{ToSocketAddrs, UdpSocket};
+use tokio::sync::{mpsc, oneshot, Notify};
+use tokio::task::JoinHandle;
+
+use crate::debug;
+
+mod connection;
+mod packet;
+
+use connection::Connection;
+use packet::{Packet, PacketType};
+
+/// Incoming connections waiting for `accept`
+const ACCEPT_BACKLOG: usize = 32;
+/// Biggest datagram read
+const MAX_DATAGRAM: usize = 64 * 1024;
+
+/// A UDP socket carrying uTP connections (BEP 29), both the ones we open and
+/// the ones peers open to us
+#[derive(Clone)]
+pub struct UtpSocket {
+    shared: Arc<Shared>,
+    _receiver: Arc<Receiver>,
+}
+
+/// Stops the receive loop once every handle of the socket is dropped
+struct Receiver(JoinHandle<()>);
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+struct Shared {
+    socket: UdpSocket,
+    /// Packets of each connection go to its driver, by peer address and the
+    /// connection id of the packets it sends us
+    connections: Mutex<HashMap<(SocketAddr, u16), mpsc::UnboundedSender<Packet>>>,
+    incoming: mpsc::Sender<UtpStream>,
+    accept: tokio::sync::Mutex<mpsc::Receiver<UtpStream>>,
+}
+
+impl UtpSocket {
+    pub async fn bind(addr: impl ToSocketAddrs) -> Result<Self> {
+        let socket = UdpSocket::bind(addr).await?;
+        let (incoming, accept) = mpsc::channel(ACCEPT_BACKLOG);
+        let shared = Arc::new(Shared {
+            socket,
+            connections: Mutex::new(HashMap::new()),
+            incoming,
+            accept: tokio::sync::Mutex::new(accept),
+        });
+        let receiver = tokio::spawn(receive(shared.clone()));
+        Ok(Self {
+            shared,
+            _receiver: Arc::new(Receiver(receiver)),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.shared.socket.local_addr()?)
+    }
+
+    /// Opens a connection to `addr`, retrying the SYN a few times
+    pub async fn connect(&self, addr: SocketAddr) -> Result<UtpStream> {
+        let (packets, connection) = {
+            let mut connections = self.shared.connections();
+            let recv_id = std::iter::repeat_with(|| {
+                let random = crate::random_bytes();
+                u16::from_be_bytes([random[0], random[1]])
+            })
+            .find(|id| !connections.contains_key(&(addr, *id)))
+            .expect("random ids are endless");
+            let (sender, packets) = mpsc::unbounded_channel();
+            connections.insert((addr, recv_id), sender);
+            (packets, Connection::connect(recv_id))
+        };
+        let (connection, syn) = connection;
+        let (established, connected) = oneshot::channel();
+        let stream = UtpStream::spawn(
+            self.shared.clone(),
+            addr,
+            connection,
+            packets,
+            Some(established),
+        );
+        self.shared.send(&syn, addr).await;
+        match connected.await {
+            Ok(Ok(())) => Ok(stream),
+            Ok(Err(e)) => Err(anyhow!(e).context(format!("connecting to {addr} over uTP"))),
+            Err(_) => bail!("connecting to {addr} over uTP failed"),
+        }
+    }
+
+    /// Waits for a peer to connect
+    pub async fn accept(&self) -> Result<UtpStream> {
+        match self.shared.accept.lock().await.recv().await {
+            Some(stream) => Ok(stream),
+            None => bail!("uTP socket closed"),
+        }
+    }
+}
+
+impl Shared {
+    fn connections(
+        &self,
+    ) -> MutexGuard<'_, HashMap<(SocketAddr, u16), mpsc::UnboundedSender<Packet>>> {
+        self.connections.lock().expect("utp lock poisoned")
+    }
+
+    async fn send(&self, packet: &Packet, addr: SocketAddr) {
+        if let Err(e) = self.socket.send_to(&packet.to_bytes(), addr).await {
+            debug!({ peer = addr }, "sending uTP packet failed: {e}");
+        }
+    }
+}
+
+async fn receive(shared: Arc<Shared>) {
+    let mut buffer = vec![0; MAX_DATAGRAM];
+    loop {
+        let Ok((length, from)) = shared.socket.recv_from(&mut buffer).await else {
+            continue;
+        };
+        let Ok(packet) = Packet::parse(&buffer[..length]) else {
+            continue;
+        };
+        // A SYN carries the id its sender receives on, we send on it
+        let recv_id = match packet.kind {
+            PacketType::Syn => packet.connection_id.wrapping_add(1),
+            _ => packet.connection_id,
+        };
+        let known = shared.connections().get(&(from, recv_id)).cloned();
+        match known {
+            Some(sender) => {
+                let _ = sender.send(packet);
+            }
+            None if packet.kind == PacketType::Syn => accept(&shared, packet, from).await,
+            None if packet.kind != PacketType::Reset && packet.kind != PacketType::State => {
+                let reset = Packet::new(PacketType::Reset, packet.connection_id, 0, packet.seq_nr);
+                shared.send(&reset, from).await;
+            }
+            None => {}
+        }
+    }
+}
+
+/// Starts a connection asked for by a SYN, refused when nobody accepts them
+async fn accept(shared: &Arc<Shared>, syn: Packet, from: SocketAddr) {
+    let Ok(permit) = shared.incoming.try_reserve() else {
+        let reset = Packet::new(PacketType::Reset, syn.connection_id, 0, syn.seq_nr);
+        shared.send(&reset, from).await;
+        return;
+    };
+    let random = crate::random_bytes();
+    let (connection, ack) = Connection::accept(&syn, u16::from_be_bytes([random[0], random[1]]));
+    let (sender, packets) = mpsc::unbounded_channel();
+    shared
+        .connections()
+        .insert((from, connection.recv_id()), sender);
+    permit.send(UtpStream::spawn(
+        shared.clone(),
+        from,
+        connection,
+        packets,
+        None,
+    ));
+    shared.send(&ack, from).await;
+}
+
+/// A uTP connection, read and written like a TCP stream
+pub struct UtpStream {
+    inner: Arc<Inner>,
+    peer_addr: SocketAddr,
+}
+
+struct Inner {
+    state: Mutex<StreamState>,
+    /// Wakes the driver when there is something to send
+    wake: Notify,
+}
+
+struct StreamState {
+    connection: Connection,
+    reader: Option<Waker>,
+    writer: Option<Waker>,
+}
+
+impl UtpStream {
+    fn spawn(
+        shared: Arc<Shared>,
+        peer_addr: SocketAddr,
+        connection: Connection,
+        packets: mpsc::UnboundedReceiver<Packet>,
+        established: Option<oneshot::Sender<io::Result<()>>>,
+    ) -> Self {
+        let inner = Arc::new(Inner {
+            state: Mutex::new(StreamState {
+                connection,
+                reader: None,
+                writer: None,
+            }),
+            wake: Notify::new(),
+        });
+        tokio::spawn(drive(
+            shared,
+            inner.clone(),
+            peer_addr,
+            packets,
+            established,
+        ));
+        Self { inner, peer_addr }
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+}
+
+impl Inner {
+    fn state(&self) -> MutexGuard<'_, StreamState> {
+        self.state.lock().expect("utp stream lock poisoned")
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        self.inner.state().connection.close();
+        self.inner.wake.notify_one();
+    }
+}
+
+/// Runs a connection: hands it the packets received, sends what it has to
+/// send and fires its timers, until it is done
+async fn drive(
+    shared: Arc<Shared>,
+    inner: Arc<Inner>,
+    addr: SocketAddr,
+    mut packets: mpsc::UnboundedReceiver<Packet>,
+    mut established: Option<oneshot::Sender<io::Result<()>>>,
+) {
+    let recv_id = inner.state().connection.recv_id();
+    loop {
+        let deadline = inner.state().connection.deadline();
+        let timer = async {
+            match deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+                None => std::future::pending().await,
+            }
+        };
+        let mut outgoing = Vec::new();
+        tokio::select! {
+            packet = packets.recv() => {
+                let Some(packet) = packet else { break };
+                outgoing = inner.state().connection.handle(packet, Instant::now());
+            }
+            _ = timer => inner.state().connection.on_timeout(Instant::now()),
+            _ = inner.wake.notified() => {}
+        }
+
+        let done = {
+            let mut state = inner.state();
+            outgoing.extend(state.connection.poll_send(Instant::now()));
+            if let Some(waker) = state.reader.take() {
+                waker.wake();
+            }
+            if let Some(waker) = state.writer.take() {
+                waker.wake();
+            }
+            if !state.connection.is_connecting() {
+                if let Some(established) = established.take() {
+                    let result = match state.connection.error() {
+                        Some(e) => Err(e),
+                        None => Ok(()),
+                    };
+                    let _ = established.send(result);
+                }
+            }
+            state.connection.is_done()
+        };
+        for packet in &outgoing {
+            shared.send(packet, addr).await;
+        }
+        if done {
+            break;
+        }
+    }
+    shared.connections().remove(&(addr, recv_id));
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut state = self.inner.state();
+        match state.connection.read(buf.initialize_unfilled())? {
+            Some(length) => {
+                buf.advance(length);
+                drop(state);
+                // The driver sends a window update if the window reopened
+                self.inner.wake.notify_one();
+                Poll::Ready(Ok(()))
+            }
+            None => {
+                state.reader = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut state = self.inner.state();
+        match state.connection.write(data)? {
+            Some(length) => {
+                drop(state);
+                self.inner.wake.notify_one();
+                Poll::Ready(Ok(length))
+            }
+            None => {
+                state.writer = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    /// Data is sent as soon as the windows allow, like TCP there is nothing
+    /// to wait for
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner.state().connection.shutdown();
+        self.inner.wake.notify_one();
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Forwards datagrams between the first client and `server`, dropping
+/// `loss` percent of them and duplicating a few
+#[cfg(test)]
+async fn lossy_relay(server: SocketAddr, loss: u32) -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut client = None;
+        let mut random = 0x2545_f491_u32;
+        let mut buffer = vec![0; MAX_DATAGRAM];
+        loop {
+            let (length, from) = socket.recv_from(&mut buffer).await.unwrap();
+            let to = if from == server {
+                match client {
+                    Some(client) => client,
+                    None => continue,
+                }
+            } else {
+                client = Some(from);
+                server
+            };
+            random = random.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let roll = (random >> 16) % 100;
+            if roll < loss {
+                continue;
+            }
+            let copies = if roll >= 98 { 2 } else { 1 };
+            for _ in 0..copies {
+                let _ = socket.send_to(&buffer[..length], to).await;
+            }
+        }
+    });
+    addr
+}
+
+#[cfg(test)]
+async fn transfer(stream: &mut UtpStream, data: &[u8]) -> Vec<u8> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let send = async {
+        writer.write_all(data).await.unwrap();
+        writer.shutdown().await.unwrap();
+    };
+    let receive = async {
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).await.unwrap();
+        received
+    };
+    tokio::join!(send, receive).1
+}
+
+#[tokio::test]
+async fn transfer_through_a_lossy_relay() {
+    let server = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+    let client = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+    let relay = lossy_relay(server.local_addr().unwrap(), 10).await;
+
+    let upload: Vec<u8> = (0..300_000u32).map(|i| (i * 7 % 251) as u8).collect();
+    let download: Vec<u8> = (0..200_000u32).map(|i| (i * 13 % 241) as u8).collect();
+    let result = tokio::time::timeout(std::time::Duration::from_secs(60), async {
+        let (connected, accepted) = tokio::join!(client.connect(relay), server.accept());
+        let (mut connected, mut accepted) = (connected.unwrap(), accepted.unwrap());
+        assert_eq!(connected.peer_addr(), relay);
+        tokio::join!(
+            transfer(&mut connected, &upload),
+            transfer(&mut accepted, &download)
+        )
+    })
+    .await
+    .expect("transfer timed out");
+    assert!(result.0 == download);
+    assert!(result.1 == upload);
+}
+
+#[tokio::test]
+async fn connecting_to_a_closed_port_fails() {
+    let client = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+    // Nobody accepts on a socket whose backlog is full, it resets the SYN
+    let server = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = server.local_addr().unwrap();
+    for _ in 0..ACCEPT_BACKLOG {
+        client.connect(addr).await.unwrap();
+    }
+    assert!(client.connect(addr).await.is_err());
+}
@@ -0,0 +1,597 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use bytes::{Buf, Bytes, BytesMut};
+
+use super::packet::{seq_less, Packet, PacketType};
+
+/// Biggest payload of a packet, small enough to avoid IP fragmentation
+pub const MAX_PAYLOAD: usize = 1200;
+/// Congestion window after a timeout, and its floor
+const MIN_WINDOW: usize = 2 * MAX_PAYLOAD;
+const INITIAL_WINDOW: usize = 4 * MAX_PAYLOAD;
+const MAX_WINDOW: usize = 1024 * 1024;
+/// Queuing delay LEDBAT aims for
+const TARGET_DELAY: u32 = 100_000;
+/// Most the congestion window grows per round trip
+const MAX_WINDOW_INCREASE: f64 = 3000.0;
+/// Bytes written and not sent yet, beyond which writes wait
+const SEND_BUFFER: usize = 1024 * 1024;
+/// Bytes received and not read yet that we accept
+const RECV_BUFFER: usize = 1024 * 1024;
+/// Packets received ahead of a missing one that are kept
+const MAX_OUT_OF_ORDER: u16 = 1024;
+/// Bits of the selective acks we send
+const SACK_BITS: u16 = 64;
+const INITIAL_TIMEOUT: Duration = Duration::from_secs(1);
+const MIN_TIMEOUT: Duration = Duration::from_millis(500);
+const MAX_TIMEOUT: Duration = Duration::from_secs(30);
+/// Timeouts in a row after which the connection is dropped
+const MAX_TIMEOUTS: u32 = 6;
+/// Unanswered SYNs after which connecting fails
+const MAX_SYN_TIMEOUTS: u32 = 4;
+/// Duplicate acks, or acks of later packets, that mark a packet lost
+const LOSS_THRESHOLD: u32 = 3;
+/// How long a base delay measurement is remembered
+const BASE_DELAY_HISTORY: Duration = Duration::from_secs(120);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    SynSent,
+    Connected,
+    /// Reset by the peer or timed out
+    Failed(io::ErrorKind),
+}
+
+/// A packet waiting for its ack
+#[derive(Debug)]
+struct Sent {
+    kind: PacketType,
+    seq_nr: u16,
+    payload: Bytes,
+    sent_at: Instant,
+    transmissions: u32,
+    acked: bool,
+    /// Considered lost, to be sent again
+    resend: bool,
+}
+
+/// State of one uTP connection, without IO: packets received are handed to
+/// [`Connection::handle`] and the packets to send come back
+#[derive(Debug)]
+pub struct Connection {
+    state: State,
+    /// Connection id of the packets we receive, and of those we send
+    recv_id: u16,
+    send_id: u16,
+    /// Sequence number of our next packet
+    seq_nr: u16,
+    /// Last packet received in order
+    ack_nr: u16,
+
+    /// Written by the application, not sent yet
+    send_buffer: BytesMut,
+    in_flight: VecDeque<Sent>,
+    /// Congestion window in bytes
+    max_window: usize,
+    /// Receive window of the peer
+    peer_window: usize,
+    rtt: Option<Duration>,
+    rtt_var: Duration,
+    timeout: Duration,
+    /// Time the retransmission timer started
+    timer: Instant,
+    timeouts: u32,
+    duplicate_acks: u32,
+    last_loss: Option<Instant>,
+    /// Lowest one way delay of our packets seen each minute
+    delays: VecDeque<(Instant, u32)>,
+    /// Receive time minus send time of the last packet received
+    reply_micro: u32,
+    /// One way delay of our last packet acked, as measured by the peer
+    current_delay: u32,
+
+    /// Received in order, not read yet
+    received: BytesMut,
+    out_of_order: HashMap<u16, Bytes>,
+    /// Sequence number of the peer FIN
+    fin_nr: Option<u16>,
+    /// Everything up to the peer FIN was received
+    eof: bool,
+    /// The application stopped writing, a FIN follows the data
+    closing: bool,
+    fin_sent: bool,
+    /// Nobody reads anymore, received data is dropped
+    reader_gone: bool,
+    /// Our receive window reopened, the peer has to know
+    window_update: bool,
+}
+
+impl Connection {
+    fn new(state: State, recv_id: u16, send_id: u16, seq_nr: u16, ack_nr: u16) -> Self {
+        Self {
+            state,
+            recv_id,
+            send_id,
+            seq_nr,
+            ack_nr,
+            send_buffer: BytesMut::new(),
+            in_flight: VecDeque::new(),
+            max_window: INITIAL_WINDOW,
+            peer_window: RECV_BUFFER,
+            rtt: None,
+            rtt_var: Duration::ZERO,
+            timeout: INITIAL_TIMEOUT,
+            timer: Instant::now(),
+            timeouts: 0,
+            duplicate_acks: 0,
+            last_loss: None,
+            delays: VecDeque::new(),
+            reply_micro: 0,
+            current_delay: 0,
+            received: BytesMut::new(),
+            out_of_order: HashMap::new(),
+            fin_nr: None,
+            eof: false,
+            closing: false,
+            fin_sent: false,
+            reader_gone: false,
+            window_update: false,
+        }
+    }
+
+    /// An outgoing connection receiving on `recv_id`, with its SYN
+    pub fn connect(recv_id: u16) -> (Self, Packet) {
+        let mut connection = Self::new(State::SynSent, recv_id, recv_id.wrapping_add(1), 1, 0);
+        // The SYN carries the id we receive on, unlike every other packet
+        let syn = connection.push(PacketType::Syn, Bytes::new(), Instant::now());
+        let mut syn = connection.header(syn.0, syn.1);
+        syn.connection_id = recv_id;
+        (connection, syn)
+    }
+
+    /// The connection asked for by `syn`, with the ack to send back
+    pub fn accept(syn: &Packet, seq_nr: u16) -> (Self, Packet) {
+        let mut connection = Self::new(
+            State::Connected,
+            syn.connection_id.wrapping_add(1),
+            syn.connection_id,
+            seq_nr,
+            syn.seq_nr,
+        );
+        connection.reply_micro = timestamp().wrapping_sub(syn.timestamp);
+        connection.peer_window = syn.window as usize;
+        let ack = connection.ack();
+        (connection, ack)
+    }
+
+    pub fn recv_id(&self) -> u16 {
+        self.recv_id
+    }
+
+    pub fn is_connecting(&self) -> bool {
+        self.state == State::SynSent
+    }
+
+    pub fn error(&self) -> Option<io::Error> {
+        match self.state {
+            State::Failed(kind) => Some(kind.into()),
+            _ => None,
+        }
+    }
+
+    /// Whether the connection has nothing left to do: it failed, or both
+    /// sides finished and our FIN was acked
+    pub fn is_done(&self) -> bool {
+        match self.state {
+            State::Failed(_) => true,
+            State::SynSent => false,
+            State::Connected => {
+                self.fin_sent && self.in_flight.is_empty() && (self.eof || self.reader_gone)
+            }
+        }
+    }
+
+    /// Copies received data to `buf`, None when there is none yet
+    pub fn read(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        if !self.received.is_empty() {
+            let length = buf.len().min(self.received.len());
+            let was_full = self.receive_window() < MAX_PAYLOAD;
+            buf[..length].copy_from_slice(&self.received[..length]);
+            self.received.advance(length);
+            self.window_update |= was_full && self.receive_window() >= MAX_PAYLOAD;
+            return Ok(Some(length));
+        }
+        if self.eof {
+            return Ok(Some(0));
+        }
+        if let Some(error) = self.error() {
+            return Err(error);
+        }
+        Ok(None)
+    }
+
+    /// Buffers `data` to be sent, None when the send buffer is full
+    pub fn write(&mut self, data: &[u8]) -> io::Result<Option<usize>> {
+        if let Some(error) = self.error() {
+            return Err(error);
+        }
+        if self.closing {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        let length = data.len().min(SEND_BUFFER - self.send_buffer.len());
+        if length == 0 {
+            return Ok(None);
+        }
+        self.send_buffer.extend_from_slice(&data[..length]);
+        Ok(Some(length))
+    }
+
+    /// Sends a FIN once the data written so far is sent
+    pub fn shutdown(&mut self) {
+        self.closing = true;
+    }
+
+    /// The application is gone: no more writes and received data is dropped
+    pub fn close(&mut self) {
+        self.closing = true;
+        self.reader_gone = true;
+        self.received.clear();
+    }
+
+    /// When [`Connection::on_timeout`] has to run next
+    pub fn deadline(&self) -> Option<Instant> {
+        let waiting = self.in_flight.iter().any(|sent| !sent.acked)
+            || (!self.send_buffer.is_empty() && self.peer_window < MAX_PAYLOAD);
+        (waiting && !matches!(self.state, State::Failed(_))).then(|| self.timer + self.timeout)
+    }
+
+    pub fn on_timeout(&mut self, now: Instant) {
+        if self.deadline().is_none_or(|deadline| now < deadline) {
+            return;
+        }
+        self.timeouts += 1;
+        let limit = match self.state {
+            State::SynSent => MAX_SYN_TIMEOUTS,
+            _ => MAX_TIMEOUTS,
+        };
+        if self.timeouts > limit {
+            self.state = State::Failed(io::ErrorKind::TimedOut);
+            return;
+        }
+        self.timeout = (self.timeout * 2).min(MAX_TIMEOUT);
+        self.max_window = MIN_WINDOW;
+        // Probes a closed receive window with one packet
+        self.peer_window = self.peer_window.max(MAX_PAYLOAD);
+        for sent in self.in_flight.iter_mut().filter(|sent| !sent.acked) {
+            sent.resend = true;
+        }
+        self.timer = now;
+    }
+
+    /// Handles a packet of this connection, returns the packets to answer with
+    pub fn handle(&mut self, packet: Packet, now: Instant) -> Vec<Packet> {
+        if matches!(self.state, State::Failed(_)) {
+            return Vec::new();
+        }
+        if packet.kind == PacketType::Reset {
+            self.state = State::Failed(match self.state {
+                State::SynSent => io::ErrorKind::ConnectionRefused,
+                _ => io::ErrorKind::ConnectionReset,
+            });
+            return Vec::new();
+        }
+        self.reply_micro = timestamp().wrapping_sub(packet.timestamp);
+        self.peer_window = packet.window as usize;
+        if packet.timestamp_diff != 0 {
+            self.delay_sample(packet.timestamp_diff, now);
+        }
+
+        match packet.kind {
+            // Our ack of the SYN was lost
+            PacketType::Syn => return vec![self.ack()],
+            _ if self.state == State::SynSent => {
+                // The peer numbers its packets from the one after its ack
+                self.state = State::Connected;
+                self.ack_nr = packet.seq_nr.wrapping_sub(1);
+            }
+            _ => {}
+        }
+        self.acked(&packet, now);
+
+        match packet.kind {
+            PacketType::Data => {
+                self.receive(packet.seq_nr, packet.payload);
+                vec![self.ack()]
+            }
+            PacketType::Fin => {
+                if self.fin_nr.is_none() {
+                    self.fin_nr = Some(packet.seq_nr);
+                    self.advance();
+                }
+                vec![self.ack()]
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Packets that can be sent now: the lost ones again, then new data
+    /// within the windows, then our FIN
+    pub fn poll_send(&mut self, now: Instant) -> Vec<Packet> {
+        let mut packets = Vec::new();
+        let window = self.max_window.min(self.peer_window);
+        let mut in_flight = self.bytes_in_flight();
+
+        for i in 0..self.in_flight.len() {
+            let sent = &self.in_flight[i];
+            if !sent.resend || (in_flight > 0 && in_flight + sent.payload.len() > window) {
+                continue;
+            }
+            in_flight += sent.payload.len();
+            let sent = &mut self.in_flight[i];
+            sent.resend = false;
+            sent.transmissions += 1;
+            sent.sent_at = now;
+            let (kind, seq_nr, payload) = (sent.kind, sent.seq_nr, sent.payload.clone());
+            let mut packet = self.header(kind, seq_nr);
+            if kind == PacketType::Syn {
+                packet.connection_id = self.recv_id;
+            }
+            packet.payload = payload;
+            packets.push(packet);
+        }
+
+        if self.state == State::Connected {
+            while !self.send_buffer.is_empty() {
+                let length = self.send_buffer.len().min(MAX_PAYLOAD);
+                if in_flight + length > window {
+                    break;
+                }
+                in_flight += length;
+                let payload = self.send_buffer.split_to(length).freeze();
+                let (kind, seq_nr) = self.push(PacketType::Data, payload.clone(), now);
+                let mut packet = self.header(kind, seq_nr);
+                packet.payload = payload;
+                packets.push(packet);
+            }
+            if self.closing && self.send_buffer.is_empty() && !self.fin_sent {
+                self.fin_sent = true;
+                let (kind, seq_nr) = self.push(PacketType::Fin, Bytes::new(), now);
+                packets.push(self.header(kind, seq_nr));
+            }
+        }
+
+        if self.window_update && packets.is_empty() {
+            packets.push(self.ack());
+        }
+        self.window_update = false;
+        packets
+    }
+
+    /// Records a packet we send, returns its type and sequence number
+    fn push(&mut self, kind: PacketType, payload: Bytes, now: Instant) -> (PacketType, u16) {
+        if !self.in_flight.iter().any(|sent| !sent.acked) {
+            self.timer = now;
+        }
+        let seq_nr = self.seq_nr;
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        self.in_flight.push_back(Sent {
+            kind,
+            seq_nr,
+            payload,
+            sent_at: now,
+            transmissions: 1,
+            acked: false,
+            resend: false,
+        });
+        (kind, seq_nr)
+    }
+
+    fn header(&self, kind: PacketType, seq_nr: u16) -> Packet {
+        let mut packet = Packet::new(kind, self.send_id, seq_nr, self.ack_nr);
+        packet.timestamp = timestamp();
+        packet.timestamp_diff = self.reply_micro;
+        packet.window = self.receive_window() as u32;
+        packet
+    }
+
+    /// A STATE packet acking what we received
+    fn ack(&self) -> Packet {
+        let mut packet = self.header(PacketType::State, self.seq_nr);
+        if !self.out_of_order.is_empty() {
+            let mut sack = vec![0u8; SACK_BITS as usize / 8];
+            for i in 0..SACK_BITS {
+                if self
+                    .out_of_order
+                    .contains_key(&self.ack_nr.wrapping_add(2 + i))
+                {
+                    sack[i as usize / 8] |= 1 << (i % 8);
+                }
+            }
+            packet.sack = Some(sack);
+        }
+        packet
+    }
+
+    fn receive_window(&self) -> usize {
+        let buffered =
+            self.received.len() + self.out_of_order.values().map(Bytes::len).sum::<usize>();
+        RECV_BUFFER.saturating_sub(buffered)
+    }
+
+    fn bytes_in_flight(&self) -> usize {
+        self.in_flight
+            .iter()
+            .filter(|sent| !sent.acked && !sent.resend)
+            .map(|sent| sent.payload.len())
+            .sum()
+    }
+
+    /// Stores the data of packet `seq_nr`, delivering what is now in order
+    fn receive(&mut self, seq_nr: u16, payload: Bytes) {
+        let distance = seq_nr.wrapping_sub(self.ack_nr);
+        if distance == 0 || distance > MAX_OUT_OF_ORDER {
+            // Already received, or too far ahead to keep
+            return;
+        }
+        if self.fin_nr.is_some_and(|fin| !seq_less(seq_nr, fin)) {
+            return;
+        }
+        self.out_of_order.insert(seq_nr, payload);
+        self.advance();
+    }
+
+    /// Moves the packets following `ack_nr` to the received data
+    fn advance(&mut self) {
+        loop {
+            let next = self.ack_nr.wrapping_add(1);
+            if let Some(payload) = self.out_of_order.remove(&next) {
+                if !self.reader_gone {
+                    self.received.extend_from_slice(&payload);
+                }
+                self.ack_nr = next;
+            } else if self.fin_nr == Some(next) {
+                self.ack_nr = next;
+                self.eof = true;
+                self.out_of_order.clear();
+                return;
+            } else {
+                return;
+            }
+        }
+    }
+
+    /// Handles the acks carried by any packet of the peer
+    fn acked(&mut self, packet: &Packet, now: Instant) {
+        let mut acked_bytes = 0;
+        let mut samples = Vec::new();
+        let mut ack = |sent: &mut Sent| {
+            if !sent.acked {
+                sent.acked = true;
+                acked_bytes += sent.payload.len();
+                if sent.transmissions == 1 {
+                    samples.push(now - sent.sent_at);
+                }
+            }
+        };
+        for sent in &mut self.in_flight {
+            if seq_less(packet.ack_nr, sent.seq_nr) {
+                break;
+            }
+            ack(sent);
+        }
+        let mut newly_sacked = false;
+        for seq_nr in packet.sacked() {
+            if let Some(sent) = self.in_flight.iter_mut().find(|s| s.seq_nr == seq_nr) {
+                newly_sacked |= !sent.acked;
+                ack(sent);
+            }
+        }
+        let progress = matches!(self.in_flight.front(), Some(sent) if sent.acked);
+        while self.in_flight.front().is_some_and(|sent| sent.acked) {
+            self.in_flight.pop_front();
+        }
+        samples
+            .into_iter()
+            .for_each(|sample| self.rtt_sample(sample));
+
+        if progress || newly_sacked {
+            self.timeouts = 0;
+            self.duplicate_acks = 0;
+            self.timer = now;
+            self.grow_window(acked_bytes);
+        } else if packet.kind == PacketType::State && !self.in_flight.is_empty() {
+            self.duplicate_acks += 1;
+            if self.duplicate_acks == LOSS_THRESHOLD {
+                if let Some(first) = self.in_flight.front_mut() {
+                    first.resend = true;
+                }
+                self.lost(now);
+            }
+        }
+
+        // A packet is lost when enough of the following ones arrived
+        let mut later_acked = 0;
+        let mut lost = false;
+        for sent in self.in_flight.iter_mut().rev() {
+            if sent.acked {
+                later_acked += 1;
+            } else if later_acked >= LOSS_THRESHOLD && sent.transmissions == 1 && !sent.resend {
+                sent.resend = true;
+                lost = true;
+            }
+        }
+        if lost {
+            self.lost(now);
+        }
+    }
+
+    fn rtt_sample(&mut self, sample: Duration) {
+        match self.rtt {
+            None => {
+                self.rtt = Some(sample);
+                self.rtt_var = sample / 2;
+            }
+            Some(rtt) => {
+                let delta = rtt.abs_diff(sample);
+                self.rtt_var = (self.rtt_var * 3 + delta) / 4;
+                self.rtt = Some((rtt * 7 + sample) / 8);
+            }
+        }
+        let rtt = self.rtt.expect("set above");
+        self.timeout = (rtt + self.rtt_var * 4).clamp(MIN_TIMEOUT, MAX_TIMEOUT);
+    }
+
+    /// Halves the window, once per round trip
+    fn lost(&mut self, now: Instant) {
+        let rtt = self.rtt.unwrap_or(INITIAL_TIMEOUT);
+        if self.last_loss.is_none_or(|last| now - last > rtt) {
+            self.max_window = (self.max_window / 2).max(MIN_WINDOW);
+            self.last_loss = Some(now);
+        }
+    }
+
+    /// Keeps the lowest delay of each minute, the lowest of all is the base
+    /// delay that LEDBAT compares the current one with
+    fn delay_sample(&mut self, delay: u32, now: Instant) {
+        while self
+            .delays
+            .front()
+            .is_some_and(|(start, _)| now - *start > BASE_DELAY_HISTORY)
+        {
+            self.delays.pop_front();
+        }
+        match self.delays.back_mut() {
+            Some((start, lowest)) if now - *start < Duration::from_secs(60) => {
+                *lowest = (*lowest).min(delay);
+            }
+            _ => self.delays.push_back((now, delay)),
+        }
+        self.current_delay = delay;
+    }
+
+    /// LEDBAT: grows the window while our packets queue less than the
+    /// target delay, shrinks it when they queue more
+    fn grow_window(&mut self, acked_bytes: usize) {
+        if acked_bytes == 0 {
+            return;
+        }
+        let base = self.delays.iter().map(|(_, delay)| *delay).min();
+        let queuing = base.map_or(0, |base| {
+            self.current_delay.wrapping_sub(base).min(u32::MAX / 2)
+        });
+        let off_target = (TARGET_DELAY as f64 - queuing as f64) / TARGET_DELAY as f64;
+        let change = MAX_WINDOW_INCREASE * off_target * acked_bytes as f64 / self.max_window as f64;
+        self.max_window =
+            (self.max_window as f64 + change).clamp(MIN_WINDOW as f64, MAX_WINDOW as f64) as usize;
+    }
+}
+
+/// Microseconds of the local clock, the unit of packet timestamps
+fn timestamp() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u32)
+        .unwrap_or_default()
+}
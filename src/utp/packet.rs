@@ -0,0 +1,176 @@
+use anyhow::{bail, Result};
+use bytes::Bytes;
+
+/// Size of the header, without extensions
+pub const HEADER_SIZE: usize = 20;
+const VERSION: u8 = 1;
+/// Extension carrying a selective ack bitmask
+const EXTENSION_SACK: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketType {
+    Data = 0,
+    Fin = 1,
+    State = 2,
+    Reset = 3,
+    Syn = 4,
+}
+
+impl PacketType {
+    fn from_u8(kind: u8) -> Result<Self> {
+        Ok(match kind {
+            0 => PacketType::Data,
+            1 => PacketType::Fin,
+            2 => PacketType::State,
+            3 => PacketType::Reset,
+            4 => PacketType::Syn,
+            kind => bail!("unknown packet type {kind}"),
+        })
+    }
+}
+
+/// A uTP packet (BEP 29)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub kind: PacketType,
+    pub connection_id: u16,
+    /// Send time in microseconds, of the sender clock
+    pub timestamp: u32,
+    /// Receive time minus send time of the last packet the sender received
+    pub timestamp_diff: u32,
+    /// Bytes the sender can still receive
+    pub window: u32,
+    pub seq_nr: u16,
+    /// Last packet the sender received in order
+    pub ack_nr: u16,
+    /// Selective acks, bit `i` acks packet `ack_nr + 2 + i`
+    pub sack: Option<Vec<u8>>,
+    pub payload: Bytes,
+}
+
+impl Packet {
+    pub fn new(kind: PacketType, connection_id: u16, seq_nr: u16, ack_nr: u16) -> Self {
+        Self {
+            kind,
+            connection_id,
+            timestamp: 0,
+            timestamp_diff: 0,
+            window: 0,
+            seq_nr,
+            ack_nr,
+            sack: None,
+            payload: Bytes::new(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + self.payload.len());
+        bytes.push((self.kind as u8) << 4 | VERSION);
+        bytes.push(if self.sack.is_some() {
+            EXTENSION_SACK
+        } else {
+            0
+        });
+        bytes.extend(self.connection_id.to_be_bytes());
+        bytes.extend(self.timestamp.to_be_bytes());
+        bytes.extend(self.timestamp_diff.to_be_bytes());
+        bytes.extend(self.window.to_be_bytes());
+        bytes.extend(self.seq_nr.to_be_bytes());
+        bytes.extend(self.ack_nr.to_be_bytes());
+        if let Some(sack) = &self.sack {
+            bytes.push(0);
+            bytes.push(sack.len() as u8);
+            bytes.extend(sack);
+        }
+        bytes.extend(&self.payload);
+        bytes
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < HEADER_SIZE {
+            bail!("packet of {} bytes is too short", bytes.len());
+        }
+        if bytes[0] & 0x0f != VERSION {
+            bail!("unknown uTP version {}", bytes[0] & 0x0f);
+        }
+        let kind = PacketType::from_u8(bytes[0] >> 4)?;
+        let u16_at = |i: usize| u16::from_be_bytes([bytes[i], bytes[i + 1]]);
+        let u32_at = |i: usize| u32::from_be_bytes(bytes[i..i + 4].try_into().expect("4 bytes"));
+
+        let mut sack = None;
+        let mut extension = bytes[1];
+        let mut offset = HEADER_SIZE;
+        while extension != 0 {
+            let Some(&[next, length]) = bytes.get(offset..offset + 2) else {
+                bail!("truncated extension");
+            };
+            let Some(data) = bytes.get(offset + 2..offset + 2 + length as usize) else {
+                bail!("truncated extension");
+            };
+            if extension == EXTENSION_SACK {
+                sack = Some(data.to_vec());
+            }
+            extension = next;
+            offset += 2 + length as usize;
+        }
+
+        Ok(Self {
+            kind,
+            connection_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_diff: u32_at(8),
+            window: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+            sack,
+            payload: Bytes::copy_from_slice(&bytes[offset..]),
+        })
+    }
+
+    /// Packets selectively acked by this packet
+    pub fn sacked(&self) -> impl Iterator<Item = u16> + '_ {
+        let ack_nr = self.ack_nr;
+        self.sack.iter().flat_map(move |sack| {
+            sack.iter().enumerate().flat_map(move |(i, byte)| {
+                (0..8)
+                    .filter(move |bit| byte & (1 << bit) != 0)
+                    .map(move |bit| ack_nr.wrapping_add(2 + (i * 8 + bit) as u16))
+            })
+        })
+    }
+}
+
+/// Whether sequence number `a` comes before `b`, allowing for wrap around
+pub fn seq_less(a: u16, b: u16) -> bool {
+    a != b && b.wrapping_sub(a) < 0x8000
+}
+
+#[test]
+fn packet_roundtrip() {
+    let mut packet = Packet::new(PacketType::Data, 0x1234, 7, 3);
+    packet.timestamp = 1_000_000;
+    packet.timestamp_diff = 250;
+    packet.window = 1 << 20;
+    packet.payload = Bytes::from_static(b"payload");
+    let bytes = packet.to_bytes();
+    assert_eq!(bytes[0], 0x01);
+    assert_eq!(&bytes[2..4], &[0x12, 0x34]);
+    assert_eq!(Packet::parse(&bytes).unwrap(), packet);
+
+    let mut ack = Packet::new(PacketType::State, 1, 10, 100);
+    ack.sack = Some(vec![0b0000_0101, 0, 0, 0b1000_0000]);
+    let parsed = Packet::parse(&ack.to_bytes()).unwrap();
+    assert_eq!(parsed.sacked().collect::<Vec<_>>(), vec![102, 104, 133]);
+    assert!(parsed.payload.is_empty());
+
+    assert!(Packet::parse(&bytes[..10]).is_err());
+    assert!(Packet::parse(&[0x51; 20]).is_err());
+}
+
+#[test]
+fn sequence_numbers_wrap() {
+    assert!(seq_less(1, 2));
+    assert!(seq_less(65535, 0));
+    assert!(!seq_less(2, 1));
+    assert!(!seq_less(5, 5));
+}
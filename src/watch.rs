@@ -0,0 +1,492 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{bail, Context, Result};
+use tokio::task::JoinSet;
+
+use crate::magnet::Magnet;
+use crate::session::{Session, TorrentHandle, TorrentState};
+use crate::torrent::Torrent;
+use crate::{info, warn};
+
+/// Time between two scans of the directory
+pub const SCAN_INTERVAL: Duration = Duration::from_secs(5);
+/// Files changed more recently than this may still be written, they wait
+/// for the next scan
+const SETTLE_TIME: Duration = Duration::from_secs(1);
+/// Time before a magnet file whose metadata was not found is tried again,
+/// doubled on each failure up to `MAX_MAGNET_RETRY`
+const MAGNET_RETRY: Duration = Duration::from_secs(60);
+const MAX_MAGNET_RETRY: Duration = Duration::from_secs(3600);
+
+/// What happens to a torrent of the watch directory once every wanted piece
+/// is verified, in this order
+#[derive(Debug, Clone, Default)]
+pub struct CompletionActions {
+    /// Directory the data is moved to, the torrent then seeds from there
+    pub move_to: Option<PathBuf>,
+    /// Command run by `sh -c`, with `TORRENT_NAME`, `TORRENT_HASH`,
+    /// `TORRENT_PATH` and `TORRENT_SIZE` in its environment
+    pub hook: Option<String>,
+    /// Uploaded bytes over the torrent length at which it is removed from
+    /// the session, its data stays
+    pub seed_ratio: Option<f64>,
+}
+
+/// Adds the `.torrent` files and the `.magnet` files, holding a magnet link,
+/// dropped in a directory to a [`Session`]. A file is renamed with `.added`
+/// appended once added, or `.invalid` when it cannot be, so it is seen once.
+pub struct Watcher {
+    session: Session,
+    dir: PathBuf,
+    /// Where torrents are written, each in a file or directory of its name
+    download_dir: PathBuf,
+    actions: CompletionActions,
+    /// Torrents added from the directory and not yet done with
+    added: HashSet<[u8; 20]>,
+    /// Torrents whose completion actions ran, seeding until their ratio
+    completed: HashSet<[u8; 20]>,
+    /// Magnet files whose metadata is being downloaded
+    resolving: HashSet<PathBuf>,
+    /// Magnet files whose metadata was not found, with how many times and
+    /// when they are tried again
+    unresolved: HashMap<PathBuf, (u32, Instant)>,
+}
+
+impl Watcher {
+    pub fn new(
+        session: Session,
+        dir: PathBuf,
+        download_dir: PathBuf,
+        actions: CompletionActions,
+    ) -> Self {
+        Self {
+            session,
+            dir,
+            download_dir,
+            actions,
+            added: HashSet::new(),
+            completed: HashSet::new(),
+            resolving: HashSet::new(),
+            unresolved: HashMap::new(),
+        }
+    }
+
+    /// Scans the directory and checks the torrents every `interval`, until
+    /// the future is dropped. A failed scan is logged and tried again on the
+    /// next tick.
+    pub async fn run(&mut self, interval: Duration) -> Result<()> {
+        std::fs::create_dir_all(&self.dir)
+            .with_context(|| format!("creating {}", self.dir.display()))?;
+        let mut magnets: JoinSet<(PathBuf, Result<Torrent>)> = JoinSet::new();
+        let mut tick = tokio::time::interval(interval);
+        loop {
+            tokio::select! {
+                _ = tick.tick() => {
+                    if let Err(e) = self.scan(&mut magnets) {
+                        warn!("scanning {} failed: {e:#}", self.dir.display());
+                    }
+                    self.check_torrents().await;
+                }
+                Some(resolved) = magnets.join_next() => {
+                    let (path, torrent) = match resolved {
+                        Ok(resolved) => resolved,
+                        Err(e) => {
+                            warn!("resolving a magnet file failed: {e}");
+                            continue;
+                        }
+                    };
+                    self.resolving.remove(&path);
+                    match torrent {
+                        Ok(torrent) => {
+                            self.unresolved.remove(&path);
+                            self.add(&path, torrent);
+                        }
+                        Err(e) => self.unresolved(path, e),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Peers may show up later, the magnet file is tried again after a
+    /// delay growing with each failure
+    fn unresolved(&mut self, path: PathBuf, error: anyhow::Error) {
+        let failures = self.unresolved.get(&path).map_or(0, |&(n, _)| n) + 1;
+        let delay = MAGNET_RETRY
+            .saturating_mul(1 << (failures - 1).min(16))
+            .min(MAX_MAGNET_RETRY);
+        warn!(
+            { failures = failures },
+            "resolving {} failed, trying again in {}s: {error:#}",
+            path.display(),
+            delay.as_secs()
+        );
+        self.unresolved
+            .insert(path, (failures, Instant::now() + delay));
+    }
+
+    /// Adds the torrent files that appeared and starts resolving the magnet
+    /// files
+    fn scan(&mut self, magnets: &mut JoinSet<(PathBuf, Result<Torrent>)>) -> Result<()> {
+        let entries = std::fs::read_dir(&self.dir)
+            .with_context(|| format!("reading {}", self.dir.display()))?;
+        // Files taken out of the directory are forgotten
+        self.unresolved.retain(|path, _| path.exists());
+        let now = Instant::now();
+        for entry in entries {
+            let path = entry?.path();
+            let extension = path.extension().and_then(|e| e.to_str());
+            if !matches!(extension, Some("torrent" | "magnet"))
+                || !path.is_file()
+                || self.resolving.contains(&path)
+                || self
+                    .unresolved
+                    .get(&path)
+                    .is_some_and(|&(_, retry)| now < retry)
+                || !settled(&path)
+            {
+                continue;
+            }
+            if extension == Some("torrent") {
+                match read_torrent(&path) {
+                    Ok(torrent) => self.add(&path, torrent),
+                    Err(e) => self.reject(&path, e),
+                }
+                continue;
+            }
+            match read_magnet(&path) {
+                Ok(magnet) => {
+                    self.resolving.insert(path.clone());
+                    let (port, dht) = (self.session.port(), self.session.dht());
+                    magnets.spawn(async move {
+                        let torrent = magnet.resolve(port, dht.as_ref()).await;
+                        (path, torrent)
+                    });
+                }
+                Err(e) => self.reject(&path, e),
+            }
+        }
+        Ok(())
+    }
+
+    fn add(&mut self, path: &Path, torrent: Torrent) {
+        let output = match torrent.info.path_in(&self.download_dir) {
+            Ok(output) => output,
+            Err(e) => return self.reject(path, e),
+        };
+        match self.session.add_torrent(torrent, output) {
+            Ok(handle) => {
+                info!(
+                    { torrent = handle.torrent().info.name },
+                    "added {}",
+                    path.display()
+                );
+                self.added.insert(handle.info_hash());
+                mark(path, "added");
+            }
+            Err(e) => self.reject(path, e),
+        }
+    }
+
+    fn reject(&self, path: &Path, error: anyhow::Error) {
+        warn!("cannot add {}: {error:#}", path.display());
+        mark(path, "invalid");
+    }
+
+    /// Runs the completion actions of the torrents that completed, and
+    /// removes the ones that seeded enough
+    async fn check_torrents(&mut self) {
+        let torrents: Vec<TorrentHandle> = self
+            .added
+            .iter()
+            .filter_map(|hash| self.session.torrent(hash))
+            .collect();
+        // Removed by someone else
+        self.added
+            .retain(|hash| torrents.iter().any(|t| t.info_hash() == *hash));
+        self.completed.retain(|hash| self.added.contains(hash));
+
+        for torrent in torrents {
+            let info_hash = torrent.info_hash();
+            if !self.completed.contains(&info_hash) {
+                if torrent.status().state != TorrentState::Seeding {
+                    continue;
+                }
+                self.completed.insert(info_hash);
+                let name = torrent.torrent().info.name.clone();
+                match self.complete(torrent).await {
+                    Ok(torrent) => self.seed_until_ratio(&torrent),
+                    Err(e) => warn!({ torrent = name }, "completion actions failed: {e:#}"),
+                }
+            } else if let Some(torrent) = self.session.torrent(&info_hash) {
+                self.seed_until_ratio(&torrent);
+            }
+        }
+    }
+
+    /// Runs the completion actions, returns the torrent as it now seeds.
+    /// The hook runs in the background, so a slow one does not hold up the
+    /// scans.
+    async fn complete(&self, mut torrent: TorrentHandle) -> Result<TorrentHandle> {
+        info!(
+            { torrent = torrent.torrent().info.name },
+            "download complete"
+        );
+        if let Some(dir) = &self.actions.move_to {
+            torrent = self.move_torrent(torrent, dir).await?;
+        }
+        if let Some(hook) = &self.actions.hook {
+            let (hook, handle) = (hook.clone(), torrent.clone());
+            tokio::spawn(async move {
+                if let Err(e) = run_hook(&hook, &handle).await {
+                    warn!({ torrent = handle.torrent().info.name }, "{e:#}");
+                }
+            });
+        }
+        Ok(torrent)
+    }
+
+    /// Moves the data of a torrent to `dir` and seeds it from there. When
+    /// the move fails the torrent seeds from where it was and the error is
+    /// returned.
+    async fn move_torrent(&self, torrent: TorrentHandle, dir: &Path) -> Result<TorrentHandle> {
+        let from = torrent.output().to_path_buf();
+        let to = torrent.torrent().info.path_in(dir)?;
+        if to.exists() {
+            bail!("cannot move to {}, it exists", to.display());
+        }
+        std::fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+        torrent.remove();
+        // Another filesystem means copying all the data, off the runtime
+        let (source, target) = (from.clone(), to.clone());
+        let moved = tokio::task::spawn_blocking(move || {
+            move_path(&source, &target)?;
+            // A single file torrent keeps its partfile next to the file
+            let (mut source_parts, mut target_parts) = (source, target);
+            source_parts.as_mut_os_string().push(".parts");
+            target_parts.as_mut_os_string().push(".parts");
+            if source_parts.exists() {
+                if let Err(e) = move_path(&source_parts, &target_parts) {
+                    warn!("moving {} failed: {e}", source_parts.display());
+                }
+            }
+            Ok(())
+        })
+        .await
+        .unwrap_or_else(|e| Err(io::Error::other(e)));
+        if let Err(e) = moved {
+            let error = anyhow::Error::new(e).context(format!(
+                "moving {} to {}",
+                from.display(),
+                to.display()
+            ));
+            // Seed from where it was rather than not at all
+            return match self.session.add_torrent(torrent.torrent().clone(), &from) {
+                Ok(_) => Err(error.context(format!("seeding from {}", from.display()))),
+                Err(again) => Err(error.context(format!("adding it back failed: {again:#}"))),
+            };
+        }
+        info!("moved {} to {}", from.display(), to.display());
+        self.session.add_torrent(torrent.torrent().clone(), to)
+    }
+
+    fn seed_until_ratio(&mut self, torrent: &TorrentHandle) {
+        let Some(ratio) = self.actions.seed_ratio else {
+            return;
+        };
+        let length = torrent.torrent().info.length().max(1) as f64;
+        if torrent.metrics().uploaded as f64 / length >= ratio {
+            info!(
+                { torrent = torrent.torrent().info.name },
+                "seed ratio {ratio} reached, removing"
+            );
+            torrent.remove();
+            self.added.remove(&torrent.info_hash());
+            self.completed.remove(&torrent.info_hash());
+        }
+    }
+}
+
+/// Whether a file was left alone long enough to be complete
+fn settled(path: &Path) -> bool {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .is_ok_and(|modified| {
+            SystemTime::now()
+                .duration_since(modified)
+                .is_ok_and(|age| age >= SETTLE_TIME)
+        })
+}
+
+fn read_torrent(path: &Path) -> Result<Torrent> {
+    let bytes = std::fs::read(path)?;
+    Ok(serde_bencode::from_bytes(&bytes)?)
+}
+
+/// The magnet link of a `.magnet` file, its first non empty line
+fn read_magnet(path: &Path) -> Result<Magnet> {
+    let text = std::fs::read_to_string(path)?;
+    let link = text
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .context("the file is empty")?;
+    link.parse()
+}
+
+/// Moves a file or directory, by copying it then removing the original when
+/// `to` is on another filesystem
+fn move_path(from: &Path, to: &Path) -> io::Result<()> {
+    match std::fs::rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            if let Err(e) = copy_all(from, to) {
+                // No half copy is left behind, the original is intact
+                let _ = remove_all(to);
+                return Err(e);
+            }
+            remove_all(from)
+        }
+        result => result,
+    }
+}
+
+fn copy_all(from: &Path, to: &Path) -> io::Result<()> {
+    if !std::fs::symlink_metadata(from)?.is_dir() {
+        return std::fs::copy(from, to).map(|_| ());
+    }
+    std::fs::create_dir(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        copy_all(&entry.path(), &to.join(entry.file_name()))?;
+    }
+    Ok(())
+}
+
+fn remove_all(path: &Path) -> io::Result<()> {
+    if std::fs::symlink_metadata(path)?.is_dir() {
+        std::fs::remove_dir_all(path)
+    } else {
+        std::fs::remove_file(path)
+    }
+}
+
+/// Appends `.suffix` to the name of a file of the watch directory
+fn mark(path: &Path, suffix: &str) {
+    let mut marked = path.as_os_str().to_owned();
+    marked.push(".");
+    marked.push(suffix);
+    if let Err(e) = std::fs::rename(path, &marked) {
+        warn!("renaming {} failed: {e}", path.display());
+    }
+}
+
+/// Runs the hook of a completed torrent and waits for it
+async fn run_hook(hook: &str, torrent: &TorrentHandle) -> Result<()> {
+    let status = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(hook)
+        .env("TORRENT_NAME", &torrent.torrent().info.name)
+        .env("TORRENT_HASH", hex::encode(torrent.info_hash()))
+        .env("TORRENT_PATH", torrent.output())
+        .env("TORRENT_SIZE", torrent.torrent().info.length().to_string())
+        .status()
+        .await
+        .context("running the completion hook")?;
+    if !status.success() {
+        bail!("the completion hook failed with {status}");
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn added_torrents_are_moved_and_announced_to_the_hook() {
+    let dir = tempfile::tempdir().unwrap();
+    let (watch, downloads, done) = (
+        dir.path().join("watch"),
+        dir.path().join("downloads"),
+        dir.path().join("done"),
+    );
+    std::fs::create_dir_all(&watch).unwrap();
+    std::fs::create_dir_all(&downloads).unwrap();
+    // Already complete in the download directory, so it completes on add
+    let content = downloads.join("data.bin");
+    std::fs::write(&content, vec![9; 30_000]).unwrap();
+    let torrent = crate::builder::TorrentBuilder::new(&content)
+        .piece_length(16 * 1024)
+        .build()
+        .unwrap();
+    let file = watch.join("data.torrent");
+    std::fs::write(&file, torrent.to_bytes().unwrap()).unwrap();
+    std::fs::write(watch.join("broken.magnet"), "magnet:?xt=nonsense").unwrap();
+    // Nobody has its metadata
+    let unknown = format!("magnet:?xt=urn:btih:{}", "ab".repeat(20));
+    std::fs::write(watch.join("unknown.magnet"), unknown).unwrap();
+    let old = SystemTime::now() - Duration::from_secs(10);
+    for name in ["data.torrent", "broken.magnet", "unknown.magnet"] {
+        let file = std::fs::File::options()
+            .write(true)
+            .open(watch.join(name))
+            .unwrap();
+        file.set_modified(old).unwrap();
+    }
+
+    let session = crate::session::local_session().await;
+    let hook_output = dir.path().join("hook.txt");
+    let actions = CompletionActions {
+        move_to: Some(done.clone()),
+        hook: Some(format!(
+            "echo \"$TORRENT_NAME $TORRENT_PATH $TORRENT_SIZE\" > {}",
+            hook_output.display()
+        )),
+        seed_ratio: None,
+    };
+    let mut watcher = Watcher::new(session.clone(), watch.clone(), downloads, actions);
+    let watching = tokio::time::timeout(
+        Duration::from_secs(3),
+        watcher.run(Duration::from_millis(50)),
+    );
+    assert!(watching.await.is_err(), "the watcher runs until dropped");
+
+    assert!(watch.join("data.torrent.added").exists());
+    assert!(watch.join("broken.magnet.invalid").exists());
+    // Tried once, then left alone until its retry
+    let (failures, _) = watcher.unresolved[&watch.join("unknown.magnet")];
+    assert_eq!(failures, 1);
+    assert!(!content.exists());
+    assert_eq!(
+        std::fs::read(done.join("data.bin")).unwrap(),
+        vec![9; 30_000]
+    );
+    let hook = std::fs::read_to_string(hook_output).unwrap();
+    assert_eq!(
+        hook.trim(),
+        format!("data.bin {} 30000", done.join("data.bin").display())
+    );
+    let torrents = session.torrents();
+    assert_eq!(torrents.len(), 1);
+    assert_eq!(torrents[0].output(), done.join("data.bin"));
+}
+
+#[test]
+fn copies_replace_moves_across_filesystems() {
+    let dir = tempfile::tempdir().unwrap();
+    let from = dir.path().join("data");
+    std::fs::create_dir_all(from.join("sub")).unwrap();
+    std::fs::write(from.join("0.bin"), [1; 100]).unwrap();
+    std::fs::write(from.join("sub").join("1.bin"), [2; 50]).unwrap();
+
+    let to = dir.path().join("done");
+    copy_all(&from, &to).unwrap();
+    remove_all(&from).unwrap();
+    assert!(!from.exists());
+    assert_eq!(std::fs::read(to.join("0.bin")).unwrap(), [1; 100]);
+    assert_eq!(
+        std::fs::read(to.join("sub").join("1.bin")).unwrap(),
+        [2; 50]
+    );
+    // A copy never lands on existing data
+    assert!(copy_all(&to, &to).is_err());
+}
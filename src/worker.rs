@@ -1,13 +1,2129 @@
-// TODO: Create a worker queue that will have all the parts
-#![allow(dead_code)]
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
 
-use crate::peer::Peer;
+use anyhow::{bail, Context, Result};
+use sha1::{Digest, Sha1};
+use tokio::net::TcpListener;
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::sync::{broadcast, mpsc, watch, Notify};
+use tokio::task::JoinSet;
 
+use crate::alert::{Alert, Alerts, Event};
+use crate::connections::{ConnectionLimits, PeerList};
+use crate::dht::Dht;
+use crate::fast;
+use crate::logging::Span;
+use crate::lsd::{self, Lsd};
+use crate::metadata::MetadataExtension;
+use crate::metrics::{Counters, TorrentMetrics};
+use crate::mse::EncryptionPolicy;
+use crate::peer::{self, Bitfield, Message, MessageTag, Peer, Piece, Request};
+use crate::pex::{self, PexExtension, PexPeer, PexSwarm};
+use crate::picker::{Block, PiecePicker, Priority};
+use crate::ratelimit::Limits;
+use crate::reader::FileReader;
+use crate::smartban::SmartBan;
+use crate::storage::{FileEntry, Storage};
+use crate::torrent::Torrent;
+use crate::tracker::{self, AnnounceEvent, TrackerResponse};
+use crate::utp::UtpSocket;
+use crate::{debug, info, trace, warn};
+
+/// Most peer connections of one torrent, unless set otherwise
+pub const MAX_PEERS: usize = 50;
+/// Peers we upload to at the same time
+const UPLOAD_SLOTS: usize = 4;
+/// Most requests outstanding with a single peer, whatever its `reqq`
+const MAX_REQUESTS: usize = 64;
+/// Biggest block a peer may request from us
+const MAX_REQUEST_LENGTH: usize = 128 * 1024;
+/// How often peer connections run their periodic work
+const TICK: Duration = Duration::from_secs(5);
+/// Idle time after which a keep-alive is sent
+const KEEP_ALIVE: Duration = Duration::from_secs(90);
+/// Time without a byte from a peer, keep-alives included, before it is
+/// dropped
+const INACTIVITY_TIMEOUT: Duration = Duration::from_secs(3 * 60);
+/// Time without a block while requests are pending before the peer counts as
+/// snubbing us, its requests then go to other peers
+const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+/// Time between checks for a free connection slot while peers are waiting
+const CONNECT_RETRY: Duration = Duration::from_secs(1);
+/// Time between two DHT announces of the torrent
+const DHT_INTERVAL: Duration = Duration::from_secs(15 * 60);
+/// Time before a DHT lookup is retried when it found nobody
+const DHT_RETRY: Duration = Duration::from_secs(60);
+/// Most pieces suggested by a peer that we remember
+const MAX_SUGGESTED: usize = 32;
+/// Time to get a uTP connection going before trying TCP
+const UTP_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Downloads and seeds one torrent, talking to many peers at once.
+///
+/// Peers come from [`Worker::add_peers`], from incoming connections, from
+/// peer exchange, from the DHT and from local service discovery.
+#[derive(Clone)]
 pub struct Worker {
-    queue: Vec<Part>,
-    peers: Vec<Peer>,
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    torrent: Torrent,
+    info_hash: [u8; 20],
+    /// Bencoded info dictionary, served to peers with ut_metadata
+    metadata: Arc<Vec<u8>>,
+    storage: Storage,
+    state: Mutex<State>,
+    /// Pieces verified, every peer connection announces them
+    have: broadcast::Sender<usize>,
+    /// Set once every piece is verified
+    complete: watch::Sender<bool>,
+    /// Wakes up the connection loop when new peers are known
+    wake: Notify,
+    /// Connections accepted for the torrent elsewhere, see [`Worker::accept`]
+    incoming: mpsc::UnboundedSender<Peer>,
+    inbox: tokio::sync::Mutex<mpsc::UnboundedReceiver<Peer>>,
+    counters: Counters,
+}
+
+struct State {
+    picker: PiecePicker,
+    file_priorities: Vec<Priority>,
+    /// Blocks received of the pieces not complete yet
+    buffers: HashMap<usize, PartialPiece>,
+    /// Senders of the pieces that failed their hash check
+    smartban: SmartBan,
+    /// Every peer we know of, with its failures and bans
+    peer_list: PeerList,
+    peers: HashMap<SocketAddr, PeerEntry>,
+    /// Peers we are not choking
+    uploading: usize,
+    /// Our listen port, advertised in the extended handshake
+    port: Option<u16>,
+    /// DHT node to find peers with, unused for private torrents
+    dht: Option<Dht>,
+    /// Local service discovery, unused for private torrents
+    lsd: Option<Lsd>,
+    /// Encryption of new peer connections
+    encryption: EncryptionPolicy,
+    /// Socket for uTP connections, tried before TCP
+    utp: Option<UtpSocket>,
+    /// Whether we accept the incoming uTP connections of the socket
+    accept_utp: bool,
+    /// Peers whose uTP connection failed, they get TCP from then on
+    tcp_only: HashSet<SocketAddr>,
+    /// Limiters shared with other torrents
+    global_limits: Limits,
+    limits: Limits,
+    /// Download and upload rates of each peer connection
+    peer_rates: (Option<u64>, Option<u64>),
+    /// Connection caps shared with other torrents
+    connection_limits: ConnectionLimits,
+    max_peers: usize,
+    /// Where alerts go, maybe shared with other torrents
+    alerts: Alerts,
+    /// What each tracker answered last, in the order they were first
+    /// announced to
+    trackers: Vec<TrackerStatus>,
+    tracker_timeout: Duration,
+    /// Whether peers exchange their peer lists with us
+    pex: bool,
+}
+
+/// A piece being downloaded and the peer that sent each of its blocks
+struct PartialPiece {
+    data: Vec<u8>,
+    senders: Vec<Option<IpAddr>>,
+}
+
+struct PeerEntry {
+    /// Address the peer accepts connections on, if known
+    listen: Option<SocketAddr>,
+    flags: u8,
+    unchoked: bool,
+    /// The peer lets us download from it
+    unchoking_us: bool,
+    limits: Limits,
+    client: String,
+    /// Block payload received from and sent to the peer
+    downloaded: u64,
+    uploaded: u64,
+}
+
+/// A snapshot of a peer connection
+#[derive(Debug, Clone)]
+pub struct PeerStats {
+    pub addr: SocketAddr,
+    /// Client name and version, `unknown` when the peer does not tell
+    pub client: String,
+    /// Block payload received from and sent to the peer
+    pub downloaded: u64,
+    pub uploaded: u64,
+    /// We upload to the peer
+    pub unchoked: bool,
+    /// The peer lets us download from it
+    pub unchoking_us: bool,
+    pub encrypted: bool,
+    pub utp: bool,
+}
+
+/// What a tracker answered last
+#[derive(Debug, Clone)]
+pub struct TrackerStatus {
+    pub url: String,
+    pub announced: Instant,
+    /// Peers it gave, or why the announce failed
+    pub result: Result<usize, String>,
 }
 
-struct Part {
-    piece_index: usize,
+impl Worker {
+    pub fn new(torrent: Torrent, storage: Storage) -> Result<Self> {
+        let info_hash = torrent.info_hash()?;
+        let metadata = Arc::new(serde_bencode::to_bytes(&torrent.info)?);
+        let picker = PiecePicker::new(torrent.info.length(), torrent.info.plength);
+        let file_priorities = vec![Priority::Normal; storage.files().len()];
+        let state = State {
+            picker,
+            file_priorities,
+            buffers: HashMap::new(),
+            smartban: SmartBan::new(),
+            peer_list: PeerList::new(),
+            peers: HashMap::new(),
+            uploading: 0,
+            port: None,
+            dht: None,
+            lsd: None,
+            encryption: EncryptionPolicy::default(),
+            utp: None,
+            accept_utp: false,
+            tcp_only: HashSet::new(),
+            global_limits: Limits::default(),
+            limits: Limits::default(),
+            peer_rates: (None, None),
+            connection_limits: ConnectionLimits::default(),
+            max_peers: MAX_PEERS,
+            alerts: Alerts::new(),
+            trackers: Vec::new(),
+            tracker_timeout: tracker::TIMEOUT,
+            pex: true,
+        };
+        let (have, _) = broadcast::channel(256);
+        let (complete, _) = watch::channel(false);
+        let (incoming, inbox) = mpsc::unbounded_channel();
+        Ok(Self {
+            shared: Arc::new(Shared {
+                torrent,
+                info_hash,
+                metadata,
+                storage,
+                state: Mutex::new(state),
+                have,
+                complete,
+                wake: Notify::new(),
+                incoming,
+                inbox: tokio::sync::Mutex::new(inbox),
+                counters: Counters::default(),
+            }),
+        })
+    }
+
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.shared.info_hash
+    }
+
+    /// Adds peers to connect to, the ones already known are ignored
+    pub fn add_peers(&self, peers: impl IntoIterator<Item = SocketAddr>) {
+        self.shared.add_candidates(peers);
+    }
+
+    /// Finds peers in the DHT too, and tells peers about the node. Call it
+    /// before running the worker.
+    pub fn use_dht(&self, dht: Dht) {
+        self.shared.state().dht = Some(dht);
+    }
+
+    /// Announces the torrent on the local network and connects to the peers
+    /// announcing it. Call it before running the worker.
+    pub fn use_lsd(&self, lsd: Lsd) {
+        self.shared.state().lsd = Some(lsd);
+    }
+
+    /// Connects to peers over uTP first and accepts uTP connections on
+    /// `socket`. Call it before running the worker.
+    pub fn use_utp(&self, socket: UtpSocket) {
+        let mut state = self.shared.state();
+        state.utp = Some(socket);
+        state.accept_utp = true;
+    }
+
+    /// Connects to peers over uTP first from a socket shared with other
+    /// torrents, whose owner accepts its incoming connections. Call it before
+    /// running the worker.
+    pub fn share_utp(&self, socket: UtpSocket) {
+        let mut state = self.shared.state();
+        state.utp = Some(socket);
+        state.accept_utp = false;
+    }
+
+    /// Exchanges peer lists with the peers that support it, the default.
+    /// Private torrents never do.
+    pub fn set_pex(&self, enabled: bool) {
+        self.shared.state().pex = enabled;
+    }
+
+    /// Sets how long trackers have to answer announces
+    pub fn set_tracker_timeout(&self, timeout: Duration) {
+        self.shared.state().tracker_timeout = timeout;
+    }
+
+    /// Sets the size of the blocks requested from peers, [`BLOCK_SIZE`] by
+    /// default. Call it before running the worker.
+    ///
+    /// [`BLOCK_SIZE`]: crate::picker::BLOCK_SIZE
+    pub fn set_block_size(&self, size: usize) {
+        self.shared.state().picker.set_block_size(size);
+    }
+
+    /// Whether peers are told of our DHT node, which private torrents never
+    /// do
+    pub fn advertises_dht(&self) -> bool {
+        self.shared.dht().is_some()
+    }
+
+    /// Sets the port peers reach us on when connections are accepted
+    /// elsewhere and handed over with [`Worker::accept`]. It is announced to
+    /// the DHT, on the local network and to peers.
+    pub fn set_listen_port(&self, port: u16) {
+        self.shared.state().port = Some(port);
+    }
+
+    /// Takes a connection accepted for this torrent elsewhere, as when
+    /// torrents share a listener. It is dropped unless the worker runs.
+    pub fn accept(&self, peer: Peer) {
+        let _ = self.shared.incoming.send(peer);
+    }
+
+    /// Limits the torrent by `limits` too, limiters shared with the other
+    /// torrents. Call it before running the worker.
+    pub fn share_limits(&self, limits: Limits) {
+        self.shared.state().global_limits = limits;
+    }
+
+    /// Sets the download and upload rates of the torrent in bytes per second,
+    /// None for no limit. It can change while the worker runs.
+    pub fn set_rate_limits(&self, download: Option<u64>, upload: Option<u64>) {
+        self.shared.state().limits.set(download, upload);
+    }
+
+    /// Download and upload rates of the torrent
+    pub fn rate_limits(&self) -> (Option<u64>, Option<u64>) {
+        self.shared.state().limits.rates()
+    }
+
+    /// Sets the download and upload rates of every peer connection, the
+    /// running ones included
+    pub fn set_peer_rate_limits(&self, download: Option<u64>, upload: Option<u64>) {
+        let mut state = self.shared.state();
+        state.peer_rates = (download, upload);
+        for entry in state.peers.values() {
+            entry.limits.set(download, upload);
+        }
+    }
+
+    /// Posts the alerts of the torrent to `alerts`, maybe shared with other
+    /// torrents. Call it before running the worker.
+    pub fn share_alerts(&self, alerts: Alerts) {
+        self.shared.state().alerts = alerts;
+    }
+
+    /// Alerts of the torrent from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<Alert> {
+        self.shared.state().alerts.subscribe()
+    }
+
+    /// Counts the connections of the torrent against `limits`, caps shared
+    /// with the other torrents. Call it before running the worker.
+    pub fn share_connection_limits(&self, limits: ConnectionLimits) {
+        self.shared.state().connection_limits = limits;
+    }
+
+    /// Sets the most peer connections of the torrent, new connections wait
+    /// until some close when lowered
+    pub fn set_max_peers(&self, max_peers: usize) {
+        self.shared.state().max_peers = max_peers;
+        self.shared.wake.notify_one();
+    }
+
+    /// Peers refused for failing too often
+    pub fn banned(&self) -> Vec<IpAddr> {
+        self.shared.state().peer_list.banned()
+    }
+
+    /// Sets how new peer connections are encrypted, enabled by default
+    pub fn set_encryption(&self, policy: EncryptionPolicy) {
+        self.shared.state().encryption = policy;
+    }
+
+    /// Sets the priority of each file, skipped files are not downloaded.
+    /// It can change while the worker runs.
+    pub fn set_file_priorities(&self, priorities: Vec<Priority>) -> Result<()> {
+        self.shared.storage.set_file_priorities(&priorities)?;
+        let mut state = self.shared.state();
+        let pieces = self.shared.storage.piece_priorities(&priorities);
+        state.picker.set_priorities(pieces);
+        state.file_priorities = priorities;
+        self.shared.update_complete(&state);
+        Ok(())
+    }
+
+    pub fn file_priorities(&self) -> Vec<Priority> {
+        self.shared.state().file_priorities.clone()
+    }
+
+    /// Addresses of the peers we are connected to
+    pub fn peers(&self) -> Vec<SocketAddr> {
+        self.shared.state().peers.keys().copied().collect()
+    }
+
+    /// Downloads new pieces in order rather than rarest first, so files can
+    /// be played while they download
+    pub fn set_sequential(&self, sequential: bool) {
+        self.shared.state().picker.set_sequential(sequential);
+    }
+
+    /// Asks for a piece within `deadline`, before the pieces without one
+    pub fn set_piece_deadline(&self, piece: usize, deadline: Duration) {
+        self.shared
+            .state()
+            .picker
+            .set_deadline(piece, Instant::now() + deadline);
+    }
+
+    /// Drops the deadlines of every piece
+    pub fn clear_deadlines(&self) {
+        self.shared.state().picker.clear_deadlines();
+    }
+
+    pub fn has_piece(&self, piece: usize) -> bool {
+        self.shared.state().picker.has(piece)
+    }
+
+    /// Waits until a piece is verified
+    pub async fn wait_for_piece(&self, piece: usize) {
+        let mut have = self.shared.have.subscribe();
+        while !self.has_piece(piece) {
+            // Missed pieces are checked again above
+            let _ = have.recv().await;
+        }
+    }
+
+    /// Reads `length` bytes at `begin` inside a verified piece
+    pub fn read(&self, piece: usize, begin: usize, length: usize) -> Result<Vec<u8>> {
+        if !self.has_piece(piece) {
+            bail!("piece {piece} is not verified yet");
+        }
+        let _disk = self.shared.counters.disk_operation();
+        self.shared.storage.read(piece, begin, length)
+    }
+
+    /// A reader of a file of the torrent, that waits for the data it reaches
+    /// and has it downloaded first. The worker has to run for it to progress.
+    pub fn reader(&self, file: usize) -> Result<FileReader> {
+        let entry = self
+            .shared
+            .storage
+            .files()
+            .get(file)
+            .context("no such file in the torrent")?
+            .clone();
+        Ok(FileReader::new(
+            self.clone(),
+            entry,
+            self.shared.torrent.info.plength,
+        ))
+    }
+
+    /// Verified pieces and the total number of pieces
+    pub fn progress(&self) -> (usize, usize) {
+        let state = self.shared.state();
+        let have = state.picker.have();
+        (have.count(), have.len())
+    }
+
+    /// The counters and gauges of the torrent so far
+    pub fn metrics(&self) -> TorrentMetrics {
+        let counters = &self.shared.counters;
+        let (peers, unchoked, unchoking_us) = {
+            let state = self.shared.state();
+            let count = |f: fn(&PeerEntry) -> bool| state.peers.values().filter(|e| f(e)).count();
+            (
+                state.peers.len(),
+                count(|entry| entry.unchoked),
+                count(|entry| entry.unchoking_us),
+            )
+        };
+        TorrentMetrics {
+            info_hash: self.shared.info_hash,
+            name: self.shared.torrent.info.name.clone(),
+            downloaded: counters.downloaded.load(Ordering::Relaxed),
+            uploaded: counters.uploaded.load(Ordering::Relaxed),
+            peers,
+            choked: peers - unchoked,
+            unchoked,
+            unchoking_us,
+            pieces_verified: counters.pieces_verified.load(Ordering::Relaxed),
+            pieces_failed: counters.pieces_failed.load(Ordering::Relaxed),
+            announce_latency: counters.announce_latency(),
+            announce_failures: counters.announce_failures.load(Ordering::Relaxed),
+            disk_queue: counters.disk_queue(),
+        }
+    }
+
+    /// Announces the torrent to `tracker`, with what is left to download,
+    /// and counts how long the tracker took
+    pub async fn announce(
+        &self,
+        tracker: &str,
+        port: u16,
+        event: Option<AnnounceEvent>,
+    ) -> Result<TrackerResponse> {
+        let started = Instant::now();
+        let counters = &self.shared.counters;
+        let timeout = self.shared.state().tracker_timeout;
+        let info_hash = &self.shared.info_hash;
+        let response =
+            tracker::announce(tracker, info_hash, port, self.left(), event, timeout).await;
+        match &response {
+            Ok(_) => counters.announced(started.elapsed()),
+            Err(_) => {
+                counters.announce_failures.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        let status = TrackerStatus {
+            url: tracker.to_string(),
+            announced: Instant::now(),
+            result: match &response {
+                Ok(response) => Ok(response.peers.0.len()),
+                Err(e) => Err(format!("{e:#}")),
+            },
+        };
+        let trackers = &mut self.shared.state().trackers;
+        match trackers.iter_mut().find(|t| t.url == tracker) {
+            Some(known) => *known = status,
+            None => trackers.push(status),
+        }
+        response
+    }
+
+    /// What each tracker answered last
+    pub fn trackers(&self) -> Vec<TrackerStatus> {
+        self.shared.state().trackers.clone()
+    }
+
+    /// The peers we are connected to
+    pub fn peer_stats(&self) -> Vec<PeerStats> {
+        self.shared
+            .state()
+            .peers
+            .iter()
+            .map(|(&addr, entry)| PeerStats {
+                addr,
+                client: entry.client.clone(),
+                downloaded: entry.downloaded,
+                uploaded: entry.uploaded,
+                unchoked: entry.unchoked,
+                unchoking_us: entry.unchoking_us,
+                encrypted: entry.flags & pex::flags::ENCRYPTION != 0,
+                utp: entry.flags & pex::flags::UTP != 0,
+            })
+            .collect()
+    }
+
+    /// The pieces verified so far
+    pub fn have(&self) -> Bitfield {
+        self.shared.state().picker.have().clone()
+    }
+
+    /// Whether every wanted piece is verified
+    pub fn is_complete(&self) -> bool {
+        *self.shared.complete.borrow()
+    }
+
+    /// Waits until every wanted piece is verified
+    pub async fn completed(&self) {
+        let mut complete = self.shared.complete.subscribe();
+        // The sender lives as long as the worker
+        let _ = complete.wait_for(|&complete| complete).await;
+    }
+
+    /// Bytes of the wanted pieces still missing
+    pub fn left(&self) -> usize {
+        self.shared.state().picker.left()
+    }
+
+    /// Files of the torrent, where they are written
+    pub fn files(&self) -> &[FileEntry] {
+        self.shared.storage.files()
+    }
+
+    /// Bytes verified of each file
+    pub fn file_progress(&self) -> Vec<usize> {
+        let plength = self.shared.torrent.info.plength;
+        let state = self.shared.state();
+        self.shared
+            .storage
+            .files()
+            .iter()
+            .map(|file| {
+                let end = file.offset + file.length;
+                (file.offset / plength..end.div_ceil(plength))
+                    .filter(|&piece| state.picker.has(piece))
+                    .map(|piece| end.min((piece + 1) * plength) - file.offset.max(piece * plength))
+                    .sum()
+            })
+            .collect()
+    }
+
+    /// Checks the data already on disk, so interrupted downloads resume and
+    /// complete data can be seeded. Returns the number of valid pieces.
+    pub fn check_existing(&self) -> usize {
+        let pieces = &self.shared.torrent.info.pieces.0;
+        let valid: Vec<usize> = (0..pieces.len())
+            .filter(|&piece| self.shared.storage.verify(piece, &pieces[piece]))
+            .collect();
+        let mut state = self.shared.state();
+        for &piece in &valid {
+            state.picker.piece_passed(piece);
+        }
+        self.shared.update_complete(&state);
+        valid.len()
+    }
+
+    /// Downloads until every piece is verified
+    pub async fn run(&self, listener: Option<TcpListener>) -> Result<()> {
+        self.span().instrument(self.serve(listener, true)).await
+    }
+
+    /// Downloads and then keeps seeding until the future is dropped
+    pub async fn seed(&self, listener: Option<TcpListener>) -> Result<()> {
+        self.span().instrument(self.serve(listener, false)).await
+    }
+
+    /// Span of the events of the torrent
+    fn span(&self) -> Span {
+        Span::current().with("torrent", &self.shared.torrent.info.name)
+    }
+
+    async fn serve(&self, listener: Option<TcpListener>, stop_when_complete: bool) -> Result<()> {
+        if let Some(listener) = &listener {
+            self.shared.state().port = Some(listener.local_addr()?.port());
+        }
+        let utp = {
+            let state = self.shared.state();
+            state.utp.clone().filter(|_| state.accept_utp)
+        };
+        let mut inbox = self.shared.inbox.lock().await;
+        // Connections handed over while we were stopped are stale
+        while inbox.try_recv().is_ok() {}
+        let mut complete = self.shared.complete.subscribe();
+        let mut tasks = JoinSet::new();
+        let mut discovery = JoinSet::new();
+        if let Some(dht) = self.shared.dht() {
+            discovery.spawn(Span::current().instrument(dht_lookups(self.shared.clone(), dht)));
+        }
+        if let Some(lsd) = self.shared.lsd() {
+            discovery.spawn(Span::current().instrument(local_discovery(self.shared.clone(), lsd)));
+        }
+
+        loop {
+            if stop_when_complete && *complete.borrow() {
+                break;
+            }
+            // Connect to the peers due for an attempt, within the caps
+            loop {
+                let mut state = self.shared.state();
+                if tasks.len() >= state.max_peers {
+                    break;
+                }
+                let Some(half_open) = state.connection_limits.half_open() else {
+                    break;
+                };
+                let Some(permit) = state.connection_limits.connection() else {
+                    break;
+                };
+                let Some(addr) = state.peer_list.next_candidate(Instant::now()) else {
+                    break;
+                };
+                drop(state);
+                let shared = self.shared.clone();
+                let span = Span::current().with("peer", addr);
+                tasks.spawn(span.instrument(async move {
+                    let mut attempt = Attempt {
+                        shared: shared.clone(),
+                        addr,
+                        pending: true,
+                    };
+                    let peer = match shared.connect(addr).await {
+                        Ok(peer) => peer,
+                        Err(e) => {
+                            attempt.pending = false;
+                            shared.state().peer_list.failed(addr, Instant::now());
+                            return Err(e);
+                        }
+                    };
+                    // The connection gives the candidate back from now on
+                    attempt.pending = false;
+                    drop(half_open);
+                    run_peer(shared, peer, true, permit).await
+                }));
+            }
+            let retry = {
+                let state = self.shared.state();
+                // Connections accepted elsewhere come with a listen port
+                if tasks.is_empty()
+                    && discovery.is_empty()
+                    && listener.is_none()
+                    && utp.is_none()
+                    && state.port.is_none()
+                    && !state.peer_list.has_candidates()
+                {
+                    bail!("no peers left to download from");
+                }
+                state
+                    .peer_list
+                    .next_attempt()
+                    .map(|at| at.max(Instant::now() + CONNECT_RETRY))
+            };
+            let retry = async {
+                match retry {
+                    Some(at) => tokio::time::sleep_until(at.into()).await,
+                    None => std::future::pending().await,
+                }
+            };
+
+            let accept = async {
+                match &listener {
+                    Some(listener) => listener.accept().await,
+                    None => std::future::pending().await,
+                }
+            };
+            let accept_utp = async {
+                match &utp {
+                    Some(utp) => utp.accept().await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = self.shared.wake.notified() => {}
+                _ = complete.changed() => {}
+                _ = retry => {}
+                Some(result) = tasks.join_next() => {
+                    if let Ok(Err(e)) = result {
+                        debug!("peer connection closed: {e:#}");
+                    }
+                }
+                accepted = accept => {
+                    let (stream, addr) = accepted.context("accepting connection")?;
+                    let Some(permit) = self.shared.admit(addr, tasks.len()) else {
+                        continue;
+                    };
+                    let shared = self.shared.clone();
+                    let policy = shared.state().encryption;
+                    let dht = shared.dht().is_some();
+                    let span = Span::current().with("peer", addr);
+                    tasks.spawn(span.instrument(async move {
+                        let peer = Peer::accept_with(stream, shared.info_hash, policy, dht).await?;
+                        run_peer(shared, peer, false, permit).await
+                    }));
+                }
+                Some(peer) = inbox.recv() => {
+                    let Some(permit) = self.shared.admit(peer.addr, tasks.len()) else {
+                        continue;
+                    };
+                    let span = Span::current().with("peer", peer.addr);
+                    tasks.spawn(span.instrument(run_peer(self.shared.clone(), peer, false, permit)));
+                }
+                accepted = accept_utp => {
+                    let stream = accepted?;
+                    let Some(permit) = self.shared.admit(stream.peer_addr(), tasks.len()) else {
+                        continue;
+                    };
+                    let shared = self.shared.clone();
+                    let policy = shared.state().encryption;
+                    let dht = shared.dht().is_some();
+                    let span = Span::current().with("peer", stream.peer_addr());
+                    tasks.spawn(span.instrument(async move {
+                        let peer = Peer::accept_with(stream, shared.info_hash, policy, dht).await?;
+                        run_peer(shared, peer, false, permit).await
+                    }));
+                }
+            }
+        }
+        // Connections and attempts give their candidates back as they drop,
+        // here or when the worker task is aborted
+        tasks.shutdown().await;
+        discovery.shutdown().await;
+        Ok(())
+    }
+}
+
+impl Shared {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("worker state lock poisoned")
+    }
+
+    fn post(&self, event: Event) {
+        self.state().alerts.post(self.info_hash, event);
+    }
+
+    /// Tells whether every wanted piece is verified, with an alert when
+    /// that becomes true
+    fn update_complete(&self, state: &State) {
+        let complete = state.picker.is_complete();
+        let was_complete = self.complete.send_replace(complete);
+        if complete && !was_complete {
+            info!("every wanted piece is verified");
+            state.alerts.post(self.info_hash, Event::TorrentFinished);
+        }
+    }
+
+    /// Connects over uTP unless the peer failed it before, then over TCP
+    async fn connect(&self, addr: SocketAddr) -> Result<Peer> {
+        let (policy, utp) = {
+            let state = self.state();
+            let utp = state
+                .utp
+                .clone()
+                .filter(|_| !state.tcp_only.contains(&addr));
+            (state.encryption, utp)
+        };
+        let dht = self.dht().is_some();
+        if let Some(utp) = utp {
+            let connecting = Peer::connect_utp(&utp, addr, self.info_hash, policy, dht);
+            match tokio::time::timeout(UTP_CONNECT_TIMEOUT, connecting).await {
+                Ok(Ok(peer)) => return Ok(peer),
+                Ok(Err(e)) => debug!("uTP connection failed, trying TCP: {e:#}"),
+                Err(_) => debug!("uTP connection timed out, trying TCP"),
+            }
+            self.state().tcp_only.insert(addr);
+        }
+        Peer::connect_with(addr, self.info_hash, policy, dht).await
+    }
+
+    /// A connection slot for an incoming peer, None when it is banned or
+    /// there are enough connections already
+    fn admit(&self, addr: SocketAddr, connections: usize) -> Option<OwnedSemaphorePermit> {
+        let state = self.state();
+        if state.peer_list.is_banned(addr.ip()) || connections >= state.max_peers {
+            return None;
+        }
+        state.connection_limits.connection()
+    }
+
+    /// The DHT node, if the torrent may use one
+    fn dht(&self) -> Option<Dht> {
+        if self.torrent.info.is_private() {
+            return None;
+        }
+        self.state().dht.clone()
+    }
+
+    /// Local service discovery, if the torrent may use it
+    fn lsd(&self) -> Option<Lsd> {
+        if self.torrent.info.is_private() {
+            return None;
+        }
+        self.state().lsd.clone()
+    }
+
+    fn add_candidates(&self, peers: impl IntoIterator<Item = SocketAddr>) {
+        let mut state = self.state();
+        let now = Instant::now();
+        let mut added = false;
+        for addr in peers {
+            added |= state.peer_list.add(addr, now);
+        }
+        if added {
+            self.wake.notify_one();
+        }
+    }
+
+    /// Stores a block received from `sender`, verifying and writing its
+    /// piece once complete. A failed piece is downloaded again, and once it
+    /// passes the peers that sent bad blocks are banned.
+    fn block_received(&self, block: Block, data: &[u8], sender: IpAddr) -> Result<()> {
+        Span::current()
+            .with("piece", block.piece)
+            .in_scope(|| self.store_block(block, data, sender))
+    }
+
+    /// [`Shared::block_received`] within the span of the piece
+    fn store_block(&self, block: Block, data: &[u8], sender: IpAddr) -> Result<()> {
+        trace!(
+            { begin = block.begin, length = block.length },
+            "block received"
+        );
+        let buffer = {
+            let mut state = self.state();
+            if !state.picker.received(&block) {
+                return Ok(());
+            }
+            let length = state.picker.piece_length(block.piece);
+            let block_size = state.picker.block_size();
+            let buffer = state
+                .buffers
+                .entry(block.piece)
+                .or_insert_with(|| PartialPiece {
+                    data: vec![0; length],
+                    senders: vec![None; length.div_ceil(block_size)],
+                });
+            buffer.data[block.begin..block.begin + block.length].copy_from_slice(data);
+            buffer.senders[block.begin / block_size] = Some(sender);
+            if !state.picker.is_piece_received(block.piece) {
+                return Ok(());
+            }
+            state.buffers.remove(&block.piece).expect("buffer exists")
+        };
+
+        let hash: [u8; 20] = Sha1::digest(&buffer.data).into();
+        if hash != self.torrent.info.pieces.0[block.piece] {
+            warn!("piece failed the hash check");
+            self.counters.pieces_failed.fetch_add(1, Ordering::Relaxed);
+            let senders: Vec<IpAddr> = buffer.senders.into_iter().flatten().collect();
+            let mut state = self.state();
+            state
+                .alerts
+                .post(self.info_hash, Event::HashFailed { piece: block.piece });
+            let block_size = state.picker.block_size();
+            state
+                .smartban
+                .piece_failed(block.piece, &buffer.data, block_size, &senders);
+            state.picker.piece_failed(block.piece);
+            return Ok(());
+        }
+        let disk = self.counters.disk_operation();
+        self.storage
+            .write(block.piece, 0, &buffer.data)
+            .context("writing piece")
+            .inspect_err(|e| {
+                self.post(Event::StorageError {
+                    error: format!("{e:#}"),
+                })
+            })?;
+        drop(disk);
+        self.counters
+            .pieces_verified
+            .fetch_add(1, Ordering::Relaxed);
+
+        let mut state = self.state();
+        let block_size = state.picker.block_size();
+        for ip in state
+            .smartban
+            .piece_passed(block.piece, &buffer.data, block_size)
+        {
+            warn!({ ip = ip }, "banning a peer that sent corrupt data");
+            state.peer_list.ban(ip);
+            state.alerts.post(self.info_hash, Event::PeerBanned { ip });
+        }
+        state.picker.piece_passed(block.piece);
+        debug!({ bytes = buffer.data.len() }, "piece verified");
+        let _ = self.have.send(block.piece);
+        state
+            .alerts
+            .post(self.info_hash, Event::PieceVerified { piece: block.piece });
+        self.update_complete(&state);
+        Ok(())
+    }
+}
+
+impl PexSwarm for Shared {
+    fn connected(&self) -> Vec<PexPeer> {
+        self.state()
+            .peers
+            .values()
+            .filter_map(|entry| {
+                Some(PexPeer {
+                    addr: entry.listen?,
+                    flags: entry.flags,
+                })
+            })
+            .collect()
+    }
+
+    fn discovered(&self, peers: Vec<PexPeer>) {
+        self.add_candidates(peers.into_iter().map(|p| p.addr));
+    }
+}
+
+/// Looks up peers in the DHT for as long as the worker runs, announcing our
+/// listen port when we have one
+async fn dht_lookups(shared: Arc<Shared>, dht: Dht) {
+    loop {
+        // Nothing to look up until the node joined the DHT
+        if dht.nodes().is_empty() {
+            tokio::time::sleep(TICK).await;
+            continue;
+        }
+        let port = shared.state().port;
+        let peers = match port {
+            Some(port) => dht.announce(shared.info_hash, port).await,
+            None => dht.get_peers(shared.info_hash).await,
+        };
+        let found = !peers.is_empty();
+        shared.add_candidates(peers);
+        tokio::time::sleep(if found { DHT_INTERVAL } else { DHT_RETRY }).await;
+    }
+}
+
+/// Announces the torrent on the local network while the worker runs, once we
+/// listen for connections, and adds the peers announcing it
+async fn local_discovery(shared: Arc<Shared>, lsd: Lsd) {
+    let mut peers = lsd.subscribe();
+    let mut interval = tokio::time::interval(lsd::INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let port = shared.state().port;
+                if let Some(port) = port {
+                    if let Err(e) = lsd.announce(shared.info_hash, port).await {
+                        debug!("local announce failed: {e:#}");
+                    }
+                }
+            }
+            received = peers.recv() => match received {
+                Ok((info_hash, addr)) if info_hash == shared.info_hash => {
+                    shared.add_candidates([addr]);
+                }
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => return,
+            },
+        }
+    }
+}
+
+/// Runs a connection until it fails, then gives back what the peer held.
+/// The connection slot is `permit`, freed when it ends.
+async fn run_peer(
+    shared: Arc<Shared>,
+    mut peer: Peer,
+    outgoing: bool,
+    permit: OwnedSemaphorePermit,
+) -> Result<()> {
+    let addr = peer.addr;
+    let mut flags = 0;
+    if outgoing {
+        flags |= pex::flags::REACHABLE;
+    }
+    if peer.is_encrypted() {
+        flags |= pex::flags::ENCRYPTION;
+    }
+    if peer.is_utp() {
+        flags |= pex::flags::UTP;
+    }
+    {
+        let mut state = shared.state();
+        if state.peers.contains_key(&addr) {
+            bail!("already connected to {addr}");
+        }
+        if outgoing {
+            state.peer_list.connected(addr, Instant::now());
+        }
+        let (download, upload) = state.peer_rates;
+        let limits = Limits::new(download, upload);
+        let chain = [&limits, &state.limits, &state.global_limits];
+        peer.set_rate_limits(
+            chain.iter().map(|limits| limits.download.clone()).collect(),
+            chain.iter().map(|limits| limits.upload.clone()).collect(),
+        );
+        state.peers.insert(
+            addr,
+            PeerEntry {
+                listen: outgoing.then_some(addr),
+                flags,
+                unchoked: false,
+                unchoking_us: false,
+                limits,
+                client: peer.client(),
+                downloaded: 0,
+                uploaded: 0,
+            },
+        );
+        state
+            .alerts
+            .post(shared.info_hash, Event::PeerConnected { addr });
+    }
+    debug!(
+        {
+            outgoing = outgoing,
+            encrypted = peer.is_encrypted(),
+            utp = peer.is_utp(),
+        },
+        "peer connected"
+    );
+
+    let pieces = shared.torrent.info.pieces.0.len();
+    let mut connection = Connection {
+        shared: shared.clone(),
+        peer,
+        bitfield: Bitfield::new(pieces),
+        choked: true,
+        interested: false,
+        choking: true,
+        peer_interested: false,
+        requests: Vec::new(),
+        last_sent: Instant::now(),
+        fast: false,
+        allowed_fast: HashSet::new(),
+        allowed_by_peer: HashSet::new(),
+        suggested: VecDeque::new(),
+        last_block: Instant::now(),
+        snubbed: false,
+        ending: Ending::Stopped,
+        error: None,
+    };
+    let result = connection.run().await;
+    if connection.ending == Ending::Stopped {
+        connection.ending = Ending::Closed;
+    }
+    if let Err(e) = &result {
+        connection.error = Some(format!("{e:#}"));
+    }
+    drop(connection);
+    drop(permit);
+    result
+}
+
+/// A connection attempt to a candidate of the peer list. Dropped while
+/// pending, as when the worker is paused or removed, it makes the candidate
+/// idle again so the next run dials it.
+struct Attempt {
+    shared: Arc<Shared>,
+    addr: SocketAddr,
+    pending: bool,
+}
+
+impl Drop for Attempt {
+    fn drop(&mut self) {
+        if self.pending {
+            let mut state = self.shared.state();
+            state.peer_list.released(self.addr, Instant::now());
+            // A resumed worker may already wait for candidates
+            self.shared.wake.notify_one();
+        }
+    }
+}
+
+/// How a peer connection ended, which decides when the peer is tried again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Ending {
+    /// The worker stopped, the peer may be tried again right away
+    Stopped,
+    /// The connection was closed or broke, the peer is tried again later on
+    Closed,
+    /// The peer went silent, it counts as a failure
+    TimedOut,
+}
+
+/// Gives back what the peer held, also when the worker stops and the task is
+/// dropped
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let mut state = self.shared.state();
+        for block in &self.requests {
+            state.picker.abort(block);
+        }
+        state.picker.remove_peer(&self.bitfield);
+        let Some(entry) = state.peers.remove(&self.peer.addr) else {
+            return;
+        };
+        state.alerts.post(
+            self.shared.info_hash,
+            Event::PeerDisconnected {
+                addr: self.peer.addr,
+                error: self.error.take(),
+            },
+        );
+        if entry.unchoked {
+            state.uploading -= 1;
+        }
+        if let Some(listen) = entry.listen {
+            let now = Instant::now();
+            match self.ending {
+                Ending::Stopped => state.peer_list.released(listen, now),
+                Ending::Closed => state.peer_list.disconnected(listen, now),
+                Ending::TimedOut => state.peer_list.failed(listen, now),
+            }
+        }
+    }
+}
+
+/// State of one peer connection
+struct Connection {
+    shared: Arc<Shared>,
+    peer: Peer,
+    /// Pieces the peer has
+    bitfield: Bitfield,
+    /// The peer is choking us
+    choked: bool,
+    /// We are interested in the peer
+    interested: bool,
+    /// We are choking the peer
+    choking: bool,
+    /// The peer is interested in us
+    peer_interested: bool,
+    /// Blocks requested and not received yet
+    requests: Vec<Block>,
+    last_sent: Instant,
+    /// Both sides support the fast extension
+    fast: bool,
+    /// Pieces the peer may request while we choke it
+    allowed_fast: HashSet<usize>,
+    /// Pieces we may request while the peer chokes us
+    allowed_by_peer: HashSet<usize>,
+    /// Pieces the peer suggested, requested first
+    suggested: VecDeque<usize>,
+    /// When a block last arrived, or requests started after none were
+    /// pending
+    last_block: Instant,
+    /// No block came for too long, the peer only gets one request at a time
+    snubbed: bool,
+    ending: Ending,
+    /// What ended the connection, if it failed
+    error: Option<String>,
+}
+
+impl Connection {
+    async fn run(&mut self) -> Result<()> {
+        let mut have = self.shared.have.subscribe();
+        self.setup().await?;
+
+        let mut tick = tokio::time::interval(TICK);
+        loop {
+            tokio::select! {
+                message = self.peer.read_message() => {
+                    self.handle(message?).await?;
+                }
+                piece = have.recv() => match piece {
+                    Ok(piece) => {
+                        self.send(Message {
+                            tag: MessageTag::Have,
+                            payload: (piece as u32).to_be_bytes().to_vec(),
+                        })
+                        .await?;
+                        self.update_interest().await?;
+                    }
+                    Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                },
+                _ = tick.tick() => {
+                    // An upload slot may have been freed since the peer asked
+                    if self.peer_interested {
+                        self.try_unchoke().await?;
+                    }
+                    self.peer.tick_extensions().await?;
+                    // File priorities may have changed what we want
+                    self.update_interest().await?;
+                    if self.peer.idle_time() >= INACTIVITY_TIMEOUT {
+                        self.ending = Ending::TimedOut;
+                        bail!("nothing received for {INACTIVITY_TIMEOUT:?}");
+                    }
+                    if !self.requests.is_empty() && self.last_block.elapsed() >= SNUB_TIMEOUT {
+                        self.snubbed().await?;
+                    }
+                    if self.last_sent.elapsed() >= KEEP_ALIVE {
+                        self.peer.send_keep_alive().await?;
+                        self.last_sent = Instant::now();
+                    }
+                }
+            }
+            self.request_blocks().await?;
+        }
+    }
+
+    async fn setup(&mut self) -> Result<()> {
+        let (bitfield, port, pex) = {
+            let state = self.shared.state();
+            (state.picker.have().clone(), state.port, state.pex)
+        };
+
+        if self.peer.supports_extensions() {
+            self.peer.extensions.port = port;
+            self.peer
+                .extensions
+                .register(Box::new(MetadataExtension::serve(
+                    self.shared.info_hash,
+                    self.shared.metadata.clone(),
+                )));
+            // Private torrents only get peers from their trackers
+            if pex && !self.shared.torrent.info.is_private() {
+                let swarm: Arc<dyn PexSwarm> = self.shared.clone();
+                self.peer
+                    .extensions
+                    .register(Box::new(PexExtension::new(swarm, self.peer.addr)));
+            }
+            self.peer.send_extended_handshake().await?;
+        }
+        if let Some(dht) = self.shared.dht().filter(|_| self.peer.supports_dht()) {
+            self.send(Message {
+                tag: MessageTag::Port,
+                payload: dht.local_addr()?.port().to_be_bytes().to_vec(),
+            })
+            .await?;
+        }
+        self.fast = self.peer.supports_fast();
+        if self.fast && bitfield.is_full() {
+            self.send(Message {
+                tag: MessageTag::HaveAll,
+                payload: Vec::new(),
+            })
+            .await?;
+        } else if self.fast && bitfield.count() == 0 {
+            self.send(Message {
+                tag: MessageTag::HaveNone,
+                payload: Vec::new(),
+            })
+            .await?;
+        } else if bitfield.count() > 0 {
+            self.send(Message {
+                tag: MessageTag::Bitfield,
+                payload: bitfield.as_bytes().to_vec(),
+            })
+            .await?;
+        }
+
+        if self.fast {
+            // Lets a new peer get its first pieces before being unchoked
+            self.allowed_fast = fast::allowed_fast_set(
+                self.peer.addr.ip(),
+                &self.shared.info_hash,
+                bitfield.len(),
+                fast::ALLOWED_FAST_COUNT,
+            )
+            .into_iter()
+            .collect();
+            let allowed: Vec<usize> = self
+                .allowed_fast
+                .iter()
+                .copied()
+                .filter(|&piece| bitfield.has(piece))
+                .collect();
+            for piece in allowed {
+                self.send(Message {
+                    tag: MessageTag::AllowedFast,
+                    payload: (piece as u32).to_be_bytes().to_vec(),
+                })
+                .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn send(&mut self, message: Message) -> Result<()> {
+        self.last_sent = Instant::now();
+        self.peer.send_message(message).await
+    }
+
+    async fn handle(&mut self, message: Message) -> Result<()> {
+        match message.tag {
+            MessageTag::Choke => {
+                self.choked = true;
+                self.set_unchoking_us(false);
+                // Pending requests are discarded by the peer, unless it
+                // rejects each of them with the fast extension
+                if !self.fast {
+                    let mut state = self.shared.state();
+                    for block in self.requests.drain(..) {
+                        state.picker.abort(&block);
+                    }
+                }
+            }
+            MessageTag::Unchoke => {
+                self.choked = false;
+                self.set_unchoking_us(true);
+            }
+            MessageTag::Interested => {
+                self.peer_interested = true;
+                self.try_unchoke().await?;
+            }
+            MessageTag::NotInterested => {
+                self.peer_interested = false;
+                self.choke().await?;
+            }
+            MessageTag::Have => {
+                let piece = parse_index(&message.payload)?;
+                if !self.bitfield.has(piece) {
+                    self.bitfield.set(piece);
+                    self.shared.state().picker.peer_has(piece);
+                    self.peer_updated();
+                }
+                self.update_interest().await?;
+            }
+            MessageTag::Bitfield => {
+                let bitfield = Bitfield::from_bytes(&message.payload, self.bitfield.len())?;
+                self.bitfield_received(bitfield).await?;
+            }
+            MessageTag::HaveAll
+            | MessageTag::HaveNone
+            | MessageTag::Suggest
+            | MessageTag::RejectRequest
+            | MessageTag::AllowedFast
+                if !self.fast =>
+            {
+                bail!("{:?} without the fast extension", message.tag);
+            }
+            MessageTag::HaveAll => {
+                let bitfield = Bitfield::full(self.bitfield.len());
+                self.bitfield_received(bitfield).await?;
+            }
+            MessageTag::HaveNone => {
+                let bitfield = Bitfield::new(self.bitfield.len());
+                self.bitfield_received(bitfield).await?;
+            }
+            MessageTag::Suggest => {
+                let piece = parse_index(&message.payload)?;
+                if !self.suggested.contains(&piece) {
+                    if self.suggested.len() == MAX_SUGGESTED {
+                        self.suggested.pop_front();
+                    }
+                    self.suggested.push_back(piece);
+                }
+            }
+            MessageTag::RejectRequest => {
+                let block = parse_request(&message.payload)?;
+                if let Some(position) = self.requests.iter().position(|b| *b == block) {
+                    self.requests.swap_remove(position);
+                    self.shared.state().picker.abort(&block);
+                }
+            }
+            MessageTag::AllowedFast => {
+                let piece = parse_index(&message.payload)?;
+                self.allowed_by_peer.insert(piece);
+                self.update_interest().await?;
+            }
+            MessageTag::Request => self.upload(&message.payload).await?,
+            MessageTag::Piece => {
+                let piece = Piece::from_u8(&message.payload)?;
+                let block = Block {
+                    piece: piece.index() as usize,
+                    begin: piece.begin() as usize,
+                    length: piece.block().len(),
+                };
+                let Some(position) = self.requests.iter().position(|b| *b == block) else {
+                    // Not requested, or already given back after a choke
+                    return Ok(());
+                };
+                self.requests.swap_remove(position);
+                self.shared
+                    .counters
+                    .downloaded
+                    .fetch_add(block.length as u64, Ordering::Relaxed);
+                if let Some(entry) = self.shared.state().peers.get_mut(&self.peer.addr) {
+                    entry.downloaded += block.length as u64;
+                }
+                self.last_block = Instant::now();
+                self.snubbed = false;
+                self.shared
+                    .block_received(block, piece.block(), self.peer.addr.ip())?;
+                if self.shared.state().peer_list.is_banned(self.peer.addr.ip()) {
+                    bail!("peer banned for sending corrupt data");
+                }
+            }
+            MessageTag::Cancel => {}
+            MessageTag::Port => {
+                let port = u16::from_be_bytes(
+                    message.payload[..]
+                        .try_into()
+                        .context("invalid port message")?,
+                );
+                if let Some(dht) = self.shared.dht() {
+                    dht.add_node(SocketAddr::new(self.peer.addr.ip(), port));
+                }
+            }
+            MessageTag::Extended => {
+                let had_handshake = self.peer.extensions.remote().is_some();
+                self.peer.handle_extended(&message).await?;
+                if !had_handshake {
+                    self.extended_handshake_received().await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces what the peer has, from a bitfield or the fast extension
+    async fn bitfield_received(&mut self, bitfield: Bitfield) -> Result<()> {
+        {
+            let mut state = self.shared.state();
+            state.picker.remove_peer(&self.bitfield);
+            state.picker.add_peer(&bitfield);
+        }
+        self.bitfield = bitfield;
+        self.peer_updated();
+        self.update_interest().await
+    }
+
+    /// Learns the listen port of incoming peers and sends the first periodic
+    /// extension messages right away
+    async fn extended_handshake_received(&mut self) -> Result<()> {
+        let port = self.peer.extensions.remote().and_then(|r| r.p);
+        if let Some(port) = port.filter(|&p| p != 0) {
+            let listen = SocketAddr::new(self.peer.addr.ip(), port);
+            let mut state = self.shared.state();
+            state.peer_list.connected(listen, Instant::now());
+            if let Some(entry) = state.peers.get_mut(&self.peer.addr) {
+                entry.listen = Some(listen);
+            }
+        }
+        // The handshake may name the client better than the peer id
+        let client = self.peer.client();
+        if let Some(entry) = self.shared.state().peers.get_mut(&self.peer.addr) {
+            entry.client = client;
+        }
+        self.peer.tick_extensions().await
+    }
+
+    fn set_unchoking_us(&self, unchoking: bool) {
+        if let Some(entry) = self.shared.state().peers.get_mut(&self.peer.addr) {
+            entry.unchoking_us = unchoking;
+        }
+    }
+
+    /// Keeps the flags shared with peer exchange up to date
+    fn peer_updated(&self) {
+        let seed = self.bitfield.is_full();
+        if let Some(entry) = self.shared.state().peers.get_mut(&self.peer.addr) {
+            if seed {
+                entry.flags |= pex::flags::SEED;
+            }
+        }
+    }
+
+    async fn update_interest(&mut self) -> Result<()> {
+        let interesting = self.shared.state().picker.interesting(&self.bitfield);
+        if interesting != self.interested {
+            self.interested = interesting;
+            let tag = if interesting {
+                MessageTag::Interested
+            } else {
+                MessageTag::NotInterested
+            };
+            self.send(Message {
+                tag,
+                payload: Vec::new(),
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn try_unchoke(&mut self) -> Result<()> {
+        if !self.choking {
+            return Ok(());
+        }
+        {
+            let mut state = self.shared.state();
+            if state.uploading >= UPLOAD_SLOTS {
+                return Ok(());
+            }
+            state.uploading += 1;
+            if let Some(entry) = state.peers.get_mut(&self.peer.addr) {
+                entry.unchoked = true;
+            }
+        }
+        self.choking = false;
+        self.send(Message {
+            tag: MessageTag::Unchoke,
+            payload: Vec::new(),
+        })
+        .await
+    }
+
+    async fn choke(&mut self) -> Result<()> {
+        if self.choking {
+            return Ok(());
+        }
+        {
+            let mut state = self.shared.state();
+            state.uploading -= 1;
+            if let Some(entry) = state.peers.get_mut(&self.peer.addr) {
+                entry.unchoked = false;
+            }
+        }
+        self.choking = true;
+        self.send(Message {
+            tag: MessageTag::Choke,
+            payload: Vec::new(),
+        })
+        .await
+    }
+
+    /// Answers a request of the peer if we unchoked it, or the piece is in its
+    /// allowed fast set, and we have the piece. Other requests are rejected
+    /// with the fast extension and ignored without it.
+    async fn upload(&mut self, payload: &[u8]) -> Result<()> {
+        let Block {
+            piece,
+            begin,
+            length,
+        } = parse_request(payload)?;
+        let allowed = !self.choking || self.allowed_fast.contains(&piece);
+        let valid = allowed && {
+            let state = self.shared.state();
+            state.picker.has(piece)
+                && length <= MAX_REQUEST_LENGTH
+                && begin + length <= state.picker.piece_length(piece)
+        };
+        if !valid {
+            if self.fast {
+                self.send(Message {
+                    tag: MessageTag::RejectRequest,
+                    payload: payload.to_vec(),
+                })
+                .await?;
+            }
+            return Ok(());
+        }
+
+        let block = {
+            let _disk = self.shared.counters.disk_operation();
+            self.shared
+                .storage
+                .read(piece, begin, length)
+                .inspect_err(|e| {
+                    self.shared.post(Event::StorageError {
+                        error: format!("{e:#}"),
+                    })
+                })?
+        };
+        let mut payload = Vec::with_capacity(8 + block.len());
+        payload.extend((piece as u32).to_be_bytes());
+        payload.extend((begin as u32).to_be_bytes());
+        payload.extend(block);
+        self.send(Message {
+            tag: MessageTag::Piece,
+            payload,
+        })
+        .await?;
+        self.shared
+            .counters
+            .uploaded
+            .fetch_add(length as u64, Ordering::Relaxed);
+        if let Some(entry) = self.shared.state().peers.get_mut(&self.peer.addr) {
+            entry.uploaded += length as u64;
+        }
+        Ok(())
+    }
+
+    /// Cancels the requests the peer sits on and gives their blocks to other
+    /// peers, the peer then gets one request at a time until it sends a block
+    async fn snubbed(&mut self) -> Result<()> {
+        self.snubbed = true;
+        self.last_block = Instant::now();
+        let requests: Vec<Block> = self.requests.drain(..).collect();
+        {
+            let mut state = self.shared.state();
+            for block in &requests {
+                state.picker.abort(block);
+            }
+        }
+        for block in requests {
+            let mut cancel =
+                Request::new(block.piece as u32, block.begin as u32, block.length as u32);
+            self.send(Message {
+                tag: MessageTag::Cancel,
+                payload: Vec::from(peer::as_bytes_mut(&mut cancel)),
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Fills the request pipeline with blocks picked for this peer, the pieces
+    /// it suggested first. While choked only its allowed fast pieces are
+    /// requested.
+    async fn request_blocks(&mut self) -> Result<()> {
+        if !self.interested || (self.choked && self.allowed_by_peer.is_empty()) {
+            return Ok(());
+        }
+        let limit = if self.snubbed {
+            1
+        } else {
+            self.peer.pipeline_limit().min(MAX_REQUESTS)
+        };
+        if self.requests.len() >= limit {
+            return Ok(());
+        }
+        let mut available = if self.choked {
+            restrict(&self.bitfield, &self.allowed_by_peer)
+        } else {
+            self.bitfield.clone()
+        };
+        if self.requests.is_empty() {
+            self.last_block = Instant::now();
+        }
+        let count = limit - self.requests.len();
+        let blocks = {
+            let mut state = self.shared.state();
+            // Failed pieces come from other peers than the ones that sent
+            // them, while other peers have them
+            let ip = self.peer.addr.ip();
+            for piece in state.smartban.suspected_pieces(ip) {
+                let suspects = state.smartban.suspects(piece).len() as u32;
+                if state.picker.availability(piece) > suspects {
+                    available.unset(piece);
+                }
+            }
+            self.suggested.retain(|&piece| !state.picker.has(piece));
+            let suggested = restrict(&available, &self.suggested);
+            let mut blocks = state.picker.pick(&suggested, count);
+            blocks.extend(state.picker.pick(&available, count - blocks.len()));
+            blocks
+        };
+        for block in blocks {
+            let mut request =
+                Request::new(block.piece as u32, block.begin as u32, block.length as u32);
+            self.requests.push(block);
+            let span = Span::current().with("piece", block.piece);
+            span.instrument(async {
+                trace!(
+                    { begin = block.begin, length = block.length },
+                    "requesting block"
+                );
+                self.send(Message {
+                    tag: MessageTag::Request,
+                    payload: Vec::from(peer::as_bytes_mut(&mut request)),
+                })
+                .await
+            })
+            .await?;
+        }
+        Ok(())
+    }
+}
+
+/// Index of a have, suggest or allowed fast message
+fn parse_index(payload: &[u8]) -> Result<usize> {
+    let index: [u8; 4] = payload.try_into().context("invalid piece index")?;
+    Ok(u32::from_be_bytes(index) as usize)
+}
+
+/// Block of a request, cancel or reject message
+fn parse_request(payload: &[u8]) -> Result<Block> {
+    if payload.len() != 12 {
+        bail!("invalid request message");
+    }
+    let field =
+        |i: usize| u32::from_be_bytes(payload[i..i + 4].try_into().expect("4 bytes")) as usize;
+    Ok(Block {
+        piece: field(0),
+        begin: field(4),
+        length: field(8),
+    })
+}
+
+/// The pieces of `bitfield` that are also in `pieces`
+fn restrict<'a>(bitfield: &Bitfield, pieces: impl IntoIterator<Item = &'a usize>) -> Bitfield {
+    let mut restricted = Bitfield::new(bitfield.len());
+    for &piece in pieces {
+        if bitfield.has(piece) {
+            restricted.set(piece);
+        }
+    }
+    restricted
+}
+
+#[cfg(test)]
+async fn spawn_worker(
+    torrent: &Torrent,
+    output: &std::path::Path,
+    peers: Vec<SocketAddr>,
+) -> (Worker, SocketAddr) {
+    let storage = Storage::new(&torrent.info, output);
+    let worker = Worker::new(torrent.clone(), storage).unwrap();
+    worker.check_existing();
+    worker.add_peers(peers);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let seeding = worker.clone();
+    tokio::spawn(async move { seeding.seed(Some(listener)).await });
+    (worker, addr)
+}
+
+/// Generated data and its torrent, in a temporary directory
+#[cfg(test)]
+pub(crate) struct TestTorrent {
+    pub dir: tempfile::TempDir,
+    /// The complete data, a file or a directory of files
+    pub path: std::path::PathBuf,
+    /// The data of every file one after the other
+    pub content: Vec<u8>,
+    pub torrent: Torrent,
+}
+
+#[cfg(test)]
+impl TestTorrent {
+    /// A torrent of one file of `length` bytes, `data.bin`
+    pub fn new(length: usize, piece_length: usize) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+        let content = Self::data(length);
+        std::fs::write(&path, &content).unwrap();
+        Self::build(dir, path, content, piece_length)
+    }
+
+    /// A torrent of files of `lengths` bytes in a `data` directory, named
+    /// `0.bin`, `1.bin` and so on
+    pub fn with_files(lengths: &[usize], piece_length: usize) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data");
+        std::fs::create_dir(&path).unwrap();
+        let content = Self::data(lengths.iter().sum());
+        let mut rest = content.as_slice();
+        for (index, &length) in lengths.iter().enumerate() {
+            let (file, next) = rest.split_at(length);
+            std::fs::write(path.join(format!("{index}.bin")), file).unwrap();
+            rest = next;
+        }
+        Self::build(dir, path, content, piece_length)
+    }
+
+    fn data(length: usize) -> Vec<u8> {
+        (0..length).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn build(
+        dir: tempfile::TempDir,
+        path: std::path::PathBuf,
+        content: Vec<u8>,
+        piece_length: usize,
+    ) -> Self {
+        let torrent = crate::builder::TorrentBuilder::new(&path)
+            .piece_length(piece_length)
+            .build()
+            .unwrap();
+        Self {
+            dir,
+            path,
+            content,
+            torrent,
+        }
+    }
+
+    /// A path in the temporary directory
+    pub fn output(&self, name: &str) -> std::path::PathBuf {
+        self.dir.path().join(name)
+    }
+
+    /// What was written to `output(name)`
+    pub fn read(&self, name: &str) -> Vec<u8> {
+        std::fs::read(self.output(name)).unwrap()
+    }
+
+    /// A worker writing to `output`, not running yet
+    pub fn worker(&self, output: &std::path::Path) -> Worker {
+        let storage = Storage::new(&self.torrent.info, output);
+        Worker::new(self.torrent.clone(), storage).unwrap()
+    }
+
+    /// A worker seeding the complete data from its own listener
+    pub async fn seeder(&self) -> (Worker, SocketAddr) {
+        spawn_worker(&self.torrent, &self.path, Vec::new()).await
+    }
+}
+
+#[tokio::test]
+async fn download_and_learn_peers_with_pex() {
+    let fixture = TestTorrent::new(300_000, 32 * 1024);
+    let (_seeder, seeder_addr) = fixture.seeder().await;
+    let (first, first_addr) = spawn_worker(
+        &fixture.torrent,
+        &fixture.output("first"),
+        vec![seeder_addr],
+    )
+    .await;
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while first.progress().0 < first.progress().1 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("first leecher completes");
+
+    // The second leecher only knows the seeder, which tells it about the first
+    let (second, _) = spawn_worker(
+        &fixture.torrent,
+        &fixture.output("second"),
+        vec![seeder_addr],
+    )
+    .await;
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while !second.peers().contains(&first_addr) {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("second leecher connects to the first");
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while second.progress().0 < second.progress().1 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("second leecher completes");
+
+    assert_eq!(fixture.read("first"), fixture.content);
+    assert_eq!(fixture.read("second"), fixture.content);
+}
+
+#[tokio::test]
+async fn find_seeder_through_the_dht() {
+    let fixture = TestTorrent::new(100_000, 32 * 1024);
+    let router = Dht::bind("127.0.0.1:0").await.unwrap();
+    let bootstrap = vec![router.local_addr().unwrap().to_string()];
+    let seeder = fixture.worker(&fixture.path);
+    seeder.check_existing();
+    let seeder_dht = Dht::bind("127.0.0.1:0").await.unwrap();
+    seeder_dht.bootstrap(&bootstrap).await.unwrap();
+    seeder.use_dht(seeder_dht);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let seeding = seeder.clone();
+    tokio::spawn(async move { seeding.seed(Some(listener)).await });
+
+    // The leecher knows no peer, it starts once the seeder announced itself
+    let leecher_dht = Dht::bind("127.0.0.1:0").await.unwrap();
+    leecher_dht.bootstrap(&bootstrap).await.unwrap();
+    let info_hash = fixture.torrent.info_hash().unwrap();
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while leecher_dht.get_peers(info_hash).await.is_empty() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("seeder announced");
+
+    let leecher = fixture.worker(&fixture.output("leecher"));
+    leecher.use_dht(leecher_dht);
+    tokio::time::timeout(Duration::from_secs(10), leecher.run(None))
+        .await
+        .expect("download completes")
+        .unwrap();
+    assert_eq!(fixture.read("leecher"), fixture.content);
+}
+
+#[tokio::test]
+async fn download_over_utp() {
+    let fixture = TestTorrent::new(200_000, 32 * 1024);
+    // The seeder has no TCP listener, only uTP reaches it
+    let seeder = fixture.worker(&fixture.path);
+    seeder.check_existing();
+    let socket = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+    let seeder_addr = socket.local_addr().unwrap();
+    seeder.use_utp(socket);
+    let seeding = seeder.clone();
+    tokio::spawn(async move { seeding.seed(None).await });
+
+    let leecher = fixture.worker(&fixture.output("leecher"));
+    leecher.use_utp(UtpSocket::bind("127.0.0.1:0").await.unwrap());
+    leecher.add_peers([seeder_addr]);
+    tokio::time::timeout(Duration::from_secs(20), leecher.run(None))
+        .await
+        .expect("download completes")
+        .unwrap();
+    assert_eq!(fixture.read("leecher"), fixture.content);
+}
+
+#[tokio::test]
+async fn rate_limits_slow_down_a_running_download() {
+    let fixture = TestTorrent::new(400_000, 32 * 1024);
+    let (seeder, seeder_addr) = fixture.seeder().await;
+    seeder.set_rate_limits(None, Some(100_000));
+    let leecher = fixture.worker(&fixture.output("leecher"));
+    leecher.add_peers([seeder_addr]);
+    let running = leecher.clone();
+    let download = tokio::spawn(async move { running.run(None).await });
+
+    // Four seconds at the upload rate of the seeder, unless it is lifted, a
+    // loose limit of the leecher on its peers does not get in the way
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert!(leecher.progress().0 < leecher.progress().1 / 2);
+    seeder.set_rate_limits(None, None);
+    leecher.set_peer_rate_limits(Some(1_000_000), None);
+    tokio::time::timeout(Duration::from_secs(2), download)
+        .await
+        .expect("download completes once unlimited")
+        .unwrap()
+        .unwrap();
+    assert_eq!(fixture.read("leecher"), fixture.content);
+}
+
+#[tokio::test]
+async fn connections_beyond_the_cap_are_refused() {
+    let fixture = TestTorrent::new(50_000, 32 * 1024);
+    let info_hash = fixture.torrent.info_hash().unwrap();
+    let (seeder, seeder_addr) = fixture.seeder().await;
+    seeder.set_max_peers(1);
+    let _first = Peer::connect_peer(seeder_addr, info_hash).await.unwrap();
+    assert!(Peer::connect_peer(seeder_addr, info_hash).await.is_err());
+
+    // A slot shared with another torrent is taken too
+    let limits = ConnectionLimits::new(1, 1);
+    let _taken = limits.connection().unwrap();
+    let (other, other_addr) = fixture.seeder().await;
+    other.share_connection_limits(limits);
+    assert!(Peer::connect_peer(other_addr, info_hash).await.is_err());
+}
+
+#[tokio::test]
+async fn peers_sending_corrupt_data_get_banned() {
+    let fixture = TestTorrent::new(100_000, 32 * 1024);
+    // The liar checked its data, then the second piece got corrupted on disk
+    let corrupt = fixture.output("corrupt.bin");
+    std::fs::write(&corrupt, &fixture.content).unwrap();
+    let liar = fixture.worker(&corrupt);
+    liar.check_existing();
+    let mut data = fixture.content.clone();
+    data[40_000] ^= 0xff;
+    std::fs::write(&corrupt, &data).unwrap();
+    let listener = TcpListener::bind("127.0.0.2:0").await.unwrap();
+    let liar_addr = listener.local_addr().unwrap();
+    let seeding = liar.clone();
+    tokio::spawn(async move { seeding.seed(Some(listener)).await });
+
+    let leecher = fixture.worker(&fixture.output("leecher"));
+    leecher.add_peers([liar_addr]);
+    let running = leecher.clone();
+    let download = tokio::spawn(async move { running.run(None).await });
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while leecher.progress().0 < leecher.progress().1 - 1 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("every other piece arrives");
+    assert!(leecher.banned().is_empty());
+
+    // An honest seeder shows up, the piece passes and the liar is found out
+    let (_seeder, seeder_addr) = fixture.seeder().await;
+    leecher.add_peers([seeder_addr]);
+    tokio::time::timeout(Duration::from_secs(10), download)
+        .await
+        .expect("download completes")
+        .unwrap()
+        .unwrap();
+    assert_eq!(fixture.read("leecher"), fixture.content);
+    assert_eq!(leecher.banned(), vec![liar_addr.ip()]);
+}
+
+#[tokio::test]
+async fn skipped_files_are_not_downloaded() {
+    let fixture = TestTorrent::with_files(&[50_000, 70_000, 40_000], 32 * 1024);
+    let (_seeder, seeder_addr) = fixture.seeder().await;
+
+    // The pieces shared with the skipped file are fetched, its part of them
+    // waits in the partfile
+    let output = fixture.output("leecher");
+    let leecher = fixture.worker(&output);
+    leecher
+        .set_file_priorities(vec![Priority::High, Priority::Skip, Priority::Normal])
+        .unwrap();
+    leecher.add_peers([seeder_addr]);
+    tokio::time::timeout(Duration::from_secs(10), leecher.run(None))
+        .await
+        .expect("download completes")
+        .unwrap();
+    assert_eq!(fixture.read("leecher/0.bin"), fixture.content[..50_000]);
+    assert_eq!(fixture.read("leecher/2.bin"), fixture.content[120_000..]);
+    assert!(!output.join("1.bin").exists());
+    assert!(output.join(".parts").exists());
+    assert_eq!(leecher.progress(), (4, 5));
+
+    leecher
+        .set_file_priorities(vec![Priority::Normal; 3])
+        .unwrap();
+    tokio::time::timeout(Duration::from_secs(10), leecher.run(None))
+        .await
+        .expect("download completes")
+        .unwrap();
+    assert_eq!(
+        fixture.read("leecher/1.bin"),
+        fixture.content[50_000..120_000]
+    );
+}
+
+#[tokio::test]
+async fn read_a_file_while_it_downloads() {
+    use std::io::SeekFrom;
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+    let fixture = TestTorrent::new(400_000, 32 * 1024);
+    let (seeder, seeder_addr) = fixture.seeder().await;
+    seeder.set_rate_limits(None, Some(200_000));
+
+    let leecher = fixture.worker(&fixture.output("leecher"));
+    leecher.set_sequential(true);
+    let mut reader = leecher.reader(0).unwrap();
+    assert_eq!(reader.len(), 400_000);
+    let middle = {
+        let mut reader = leecher.reader(0).unwrap();
+        tokio::spawn(async move {
+            reader.seek(SeekFrom::Start(300_000)).await.unwrap();
+            let mut data = vec![0; 50_000];
+            reader.read_exact(&mut data).await.unwrap();
+            data
+        })
+    };
+    // The deadlines are set before the first requests go out
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    leecher.add_peers([seeder_addr]);
+    let running = leecher.clone();
+    tokio::spawn(async move { running.seed(None).await });
+
+    // The pieces read come first, a sequential download would get them last
+    let data = tokio::time::timeout(Duration::from_secs(10), middle)
+        .await
+        .expect("the middle arrives")
+        .unwrap();
+    assert_eq!(data, fixture.content[300_000..350_000]);
+    assert!(leecher.progress().0 < leecher.progress().1);
+
+    let mut data = Vec::new();
+    tokio::time::timeout(Duration::from_secs(10), reader.read_to_end(&mut data))
+        .await
+        .expect("the whole file arrives")
+        .unwrap();
+    assert_eq!(data, fixture.content);
+    assert_eq!(reader.seek(SeekFrom::End(-10)).await.unwrap(), 399_990);
+}
+
+#[tokio::test]
+async fn metrics_count_a_download() {
+    let fixture = TestTorrent::new(100_000, 32 * 1024);
+    let (seeder, seeder_addr) = fixture.seeder().await;
+    let (leecher, _) =
+        spawn_worker(&fixture.torrent, &fixture.output("copy"), vec![seeder_addr]).await;
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while !leecher.is_complete() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("leecher completes");
+
+    let metrics = leecher.metrics();
+    assert_eq!(metrics.downloaded, fixture.content.len() as u64);
+    assert_eq!(metrics.pieces_verified, 4);
+    assert_eq!(metrics.pieces_failed, 0);
+    assert_eq!(metrics.peers, 1);
+    assert_eq!(metrics.unchoking_us, 1);
+    assert_eq!(metrics.disk_queue, 0);
+    let peers = leecher.peer_stats();
+    assert_eq!(peers[0].addr, seeder_addr);
+    assert_eq!(peers[0].downloaded, fixture.content.len() as u64);
+    assert!(leecher.have().is_full());
+    let metrics = seeder.metrics();
+    assert_eq!(metrics.uploaded, fixture.content.len() as u64);
+    assert_eq!(metrics.unchoked + metrics.choked, 1);
 }